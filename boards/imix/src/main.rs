@@ -70,7 +70,10 @@ type RF233Device =
     capsules::rf233::RF233<'static, VirtualSpiMasterDevice<'static, sam4l::spi::SpiHw>>;
 
 struct Imix {
-    console: &'static capsules::console::Console<'static, sam4l::usart::USART>,
+    console: &'static capsules::console::Console<
+        'static,
+        capsules::cdc::CdcAcm<'static, sam4l::usbc::Usbc<'static>>,
+    >,
     gpio: &'static capsules::gpio::GPIO<'static, sam4l::gpio::GPIOPin>,
     alarm: &'static AlarmDriver<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast<'static>>>,
     temp: &'static capsules::temperature::TemperatureSensor<'static>,
@@ -84,14 +87,11 @@ struct Imix {
     ninedof: &'static capsules::ninedof::NineDof<'static>,
     radio_driver: &'static capsules::ieee802154::RadioDriver<'static>,
     crc: &'static capsules::crc::Crc<'static, sam4l::crccu::Crccu<'static>>,
-    usb_driver: &'static capsules::usb_user::UsbSyscallDriver<
-        'static,
-        capsules::usbc_client::Client<'static, sam4l::usbc::Usbc<'static>>,
-    >,
     nrf51822: &'static capsules::nrf51822_serialization::Nrf51822Serialization<
         'static,
         sam4l::usart::USART,
     >,
+    app_flash: &'static capsules::app_flash_driver::AppFlash<'static>,
 }
 
 // The RF233 radio stack requires our buffers for its SPI operations:
@@ -118,6 +118,10 @@ static mut RADIO_BUF: [u8; radio::MAX_BUF_SIZE] = [0x00; radio::MAX_BUF_SIZE];
 const CRYPT_SIZE: usize = 3 * symmetric_encryption::AES128_BLOCK_SIZE + radio::MAX_BUF_SIZE;
 static mut CRYPT_BUF: [u8; CRYPT_SIZE] = [0x00; CRYPT_SIZE];
 
+// Buffers for apps writing their own flash region.
+static mut APP_FLASH_BUFFER: [u8; 512] = [0; 512];
+static mut PAGEBUFFER: sam4l::flashcalw::Sam4lPage = sam4l::flashcalw::Sam4lPage::new();
+
 impl kernel::Platform for Imix {
     fn with_driver<F, R>(&self, driver_num: usize, f: F) -> R
     where
@@ -136,9 +140,9 @@ impl kernel::Platform for Imix {
             capsules::humidity::DRIVER_NUM => f(Some(self.humidity)),
             capsules::ninedof::DRIVER_NUM => f(Some(self.ninedof)),
             capsules::crc::DRIVER_NUM => f(Some(self.crc)),
-            capsules::usb_user::DRIVER_NUM => f(Some(self.usb_driver)),
             capsules::ieee802154::DRIVER_NUM => f(Some(self.radio_driver)),
             capsules::nrf51822_serialization::DRIVER_NUM => f(Some(self.nrf51822)),
+            capsules::app_flash_driver::DRIVER_NUM => f(Some(self.app_flash)),
             kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
             _ => f(None),
         }
@@ -241,18 +245,47 @@ pub unsafe fn reset_handler() {
 
     // # CONSOLE
 
+    // The console is a serial port on the native USB port. Panic messages
+    // still go out on USART3, the DBG_USB port.
+    let usb_serial = capsules::usb::serial_number(
+        &sam4l::serial_num::serial_number(),
+        static_init!(
+            [u8; 2 * sam4l::serial_num::SERIAL_NUMBER_LENGTH],
+            [0; 2 * sam4l::serial_num::SERIAL_NUMBER_LENGTH]
+        ),
+    );
+    let usb_strings = static_init!([&str; 3], ["Tock", "imix", usb_serial]);
+    let cdc = static_init!(
+        capsules::cdc::CdcAcm<'static, sam4l::usbc::Usbc<'static>>,
+        capsules::cdc::CdcAcm::new(
+            &sam4l::usbc::USBC,
+            0x6667,
+            0xabce,
+            usb_strings,
+            &mut capsules::cdc::CONFIGURATION_BUF
+        )
+    );
+    sam4l::usbc::USBC.set_client(cdc);
+    cdc.initialize_deferred_call();
+
     let console = static_init!(
-        capsules::console::Console<sam4l::usart::USART>,
+        capsules::console::Console<
+            'static,
+            capsules::cdc::CdcAcm<'static, sam4l::usbc::Usbc<'static>>,
+        >,
         capsules::console::Console::new(
-            &sam4l::usart::USART3,
+            cdc,
             115200,
             &mut capsules::console::WRITE_BUF,
             kernel::Grant::create()
         )
     );
-    hil::uart::UART::set_client(&sam4l::usart::USART3, console);
+    hil::uart::UART::set_client(cdc, console);
     console.initialize();
 
+    hil::usb::Client::enable(cdc);
+    hil::usb::Client::attach(cdc);
+
     // Attach the kernel debug interface to this console
     let kc = static_init!(capsules::console::App, capsules::console::App::default());
     kernel::debug::assign_console_driver(Some(console), kc);
@@ -556,21 +589,55 @@ pub unsafe fn reset_handler() {
     radio_mac.set_pan(0xABCD);
     radio_mac.set_address(0x1008);
 
-    // Configure the USB controller
-    let usb_client = static_init!(
-        capsules::usbc_client::Client<'static, sam4l::usbc::Usbc<'static>>,
-        capsules::usbc_client::Client::new(&sam4l::usbc::USBC)
+    extern "C" {
+        /// Beginning of the ROM region containing app images.
+        static _sapps: u8;
+    }
+
+    // Let apps write to their own flash region. Everything before the apps
+    // is the bootloader and the kernel, so only the app pages may be
+    // modified, and the kernel pages are locked as well.
+    sam4l::flashcalw::FLASH_CONTROLLER.configure();
+    let page_size = sam4l::flashcalw::FLASH_CONTROLLER.get_page_size() as usize;
+    let apps_first_page = &_sapps as *const u8 as usize / page_size;
+    sam4l::flashcalw::FLASH_CONTROLLER.protect_pages(0, apps_first_page as u32);
+
+    let mux_flash = static_init!(
+        capsules::virtual_flash::MuxFlash<'static, sam4l::flashcalw::FLASHCALW>,
+        capsules::virtual_flash::MuxFlash::new(&sam4l::flashcalw::FLASH_CONTROLLER)
     );
-    sam4l::usbc::USBC.set_client(usb_client);
+    hil::flash::HasClient::set_client(&sam4l::flashcalw::FLASH_CONTROLLER, mux_flash);
 
-    // Configure the USB userspace driver
-    let usb_driver = static_init!(
-        capsules::usb_user::UsbSyscallDriver<
+    let app_virtual_flash = static_init!(
+        capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>,
+        capsules::virtual_flash::FlashUser::new(mux_flash)
+    );
+    app_virtual_flash.set_allowed_pages(
+        apps_first_page,
+        sam4l::flashcalw::FLASH_CONTROLLER.get_number_pages() as usize,
+    );
+
+    let nv_to_page = static_init!(
+        capsules::nonvolatile_to_pages::NonvolatileToPages<
             'static,
-            capsules::usbc_client::Client<'static, sam4l::usbc::Usbc<'static>>,
+            capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>,
         >,
-        capsules::usb_user::UsbSyscallDriver::new(usb_client, kernel::Grant::create())
+        capsules::nonvolatile_to_pages::NonvolatileToPages::new(
+            app_virtual_flash,
+            &mut PAGEBUFFER
+        )
     );
+    hil::flash::HasClient::set_client(app_virtual_flash, nv_to_page);
+
+    let app_flash = static_init!(
+        capsules::app_flash_driver::AppFlash<'static>,
+        capsules::app_flash_driver::AppFlash::new(
+            nv_to_page,
+            kernel::Grant::create(),
+            &mut APP_FLASH_BUFFER
+        )
+    );
+    hil::nonvolatile_storage::NonvolatileStorage::set_client(nv_to_page, app_flash);
 
     let imix = Imix {
         console: console,
@@ -587,8 +654,8 @@ pub unsafe fn reset_handler() {
         ipc: kernel::ipc::IPC::new(),
         ninedof: ninedof,
         radio_driver: radio_driver,
-        usb_driver: usb_driver,
         nrf51822: nrf_serialization,
+        app_flash: app_flash,
     };
 
     let mut chip = sam4l::chip::Sam4l::new();
@@ -611,10 +678,6 @@ pub unsafe fn reset_handler() {
     rf233.start();
 
     debug!("Initialization complete. Entering main loop");
-    extern "C" {
-        /// Beginning of the ROM region containing app images.
-        static _sapps: u8;
-    }
     kernel::process::load_processes(
         &_sapps as *const u8,
         &mut APP_MEMORY,
@@ -108,6 +108,9 @@ static mut APP_MEMORY: [u8; 32768] = [0; 32768];
 
 static mut PROCESSES: [Option<kernel::Process<'static>>; NUM_PROCS] = [None, None, None, None];
 
+static mut APP_FLASH_BUFFER: [u8; 512] = [0; 512];
+static mut PAGEBUFFER: nrf52::nvmc::NrfPage = nrf52::nvmc::NrfPage::new();
+
 /// Supported drivers by the platform
 pub struct Platform {
     ble_radio: &'static capsules::ble_advertising_driver::BLE<
@@ -126,6 +129,7 @@ pub struct Platform {
         'static,
         capsules::virtual_alarm::VirtualMuxAlarm<'static, nrf5x::rtc::Rtc>,
     >,
+    app_flash: &'static capsules::app_flash_driver::AppFlash<'static>,
 }
 
 impl kernel::Platform for Platform {
@@ -142,6 +146,7 @@ impl kernel::Platform for Platform {
             capsules::rng::DRIVER_NUM => f(Some(self.rng)),
             capsules::ble_advertising_driver::DRIVER_NUM => f(Some(self.ble_radio)),
             capsules::temperature::DRIVER_NUM => f(Some(self.temp)),
+            capsules::app_flash_driver::DRIVER_NUM => f(Some(self.app_flash)),
             kernel::ipc::DRIVER_NUM => f(Some(&self.ipc)),
             _ => f(None),
         }
@@ -155,7 +160,7 @@ pub unsafe fn reset_handler() {
     nrf52::init();
 
     // make non-volatile memory writable and activate the reset button (pin 21)
-    let nvmc = nrf52::nvmc::Nvmc::new();
+    let nvmc = &nrf52::nvmc::NVMC;
     let uicr = nrf52::uicr::Uicr::new();
     nvmc.configure_writeable();
     while !nvmc.is_ready() {}
@@ -344,6 +349,50 @@ pub unsafe fn reset_handler() {
     );
     nrf5x::trng::TRNG.set_client(rng);
 
+    extern "C" {
+        /// Beginning of the ROM region containing app images.
+        static _sapps: u8;
+    }
+
+    // Let apps write to their own flash region. Everything before the apps
+    // is the kernel, so only the app pages may be modified.
+    let mux_flash = static_init!(
+        capsules::virtual_flash::MuxFlash<'static, nrf52::nvmc::Nvmc>,
+        capsules::virtual_flash::MuxFlash::new(&mut nrf52::nvmc::NVMC)
+    );
+    kernel::hil::flash::HasClient::set_client(&nrf52::nvmc::NVMC, mux_flash);
+
+    let app_virtual_flash = static_init!(
+        capsules::virtual_flash::FlashUser<'static, nrf52::nvmc::Nvmc>,
+        capsules::virtual_flash::FlashUser::new(mux_flash)
+    );
+    app_virtual_flash.set_allowed_pages(
+        &_sapps as *const u8 as usize / nrf52::nvmc::PAGE_SIZE,
+        nrf52::nvmc::NVMC.get_number_pages(),
+    );
+
+    let nv_to_page = static_init!(
+        capsules::nonvolatile_to_pages::NonvolatileToPages<
+            'static,
+            capsules::virtual_flash::FlashUser<'static, nrf52::nvmc::Nvmc>,
+        >,
+        capsules::nonvolatile_to_pages::NonvolatileToPages::new(
+            app_virtual_flash,
+            &mut PAGEBUFFER
+        )
+    );
+    kernel::hil::flash::HasClient::set_client(app_virtual_flash, nv_to_page);
+
+    let app_flash = static_init!(
+        capsules::app_flash_driver::AppFlash<'static>,
+        capsules::app_flash_driver::AppFlash::new(
+            nv_to_page,
+            kernel::Grant::create(),
+            &mut APP_FLASH_BUFFER
+        )
+    );
+    kernel::hil::nonvolatile_storage::NonvolatileStorage::set_client(nv_to_page, app_flash);
+
     // Start all of the clocks. Low power operation will require a better
     // approach than this.
     nrf52::clock::CLOCK.low_stop();
@@ -366,6 +415,7 @@ pub unsafe fn reset_handler() {
         rng: rng,
         temp: temp,
         alarm: alarm,
+        app_flash: app_flash,
         ipc: kernel::ipc::IPC::new(),
     };
 
@@ -373,10 +423,6 @@ pub unsafe fn reset_handler() {
 
     debug!("Initialization complete. Entering main loop\r");
     debug!("{}", &nrf52::ficr::FICR_INSTANCE);
-    extern "C" {
-        /// Beginning of the ROM region containing app images.
-        static _sapps: u8;
-    }
     kernel::process::load_processes(
         &_sapps as *const u8,
         &mut APP_MEMORY,
@@ -3,6 +3,7 @@
 
 use core::cell::Cell;
 use core::cmp;
+use energy::PeripheralUsage;
 use kernel::{AppId, AppSlice, Callback, Driver, ReturnCode, Shared};
 use kernel::common::take_cell::{MapCell, TakeCell};
 use kernel::hil;
@@ -21,8 +22,12 @@ pub struct Adc<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed + 'a> {
     active: Cell<bool>,
     mode: Cell<AdcMode>,
 
+    // Energy accounting, and the peripheral number the ADC reports
+    usage: Cell<Option<(&'static PeripheralUsage, usize)>>,
+
     // App state
     app: MapCell<App>,
+    appid: Cell<Option<AppId>>,
     channel: Cell<usize>,
     callback: Cell<Option<Callback>>,
     app_buf_offset: Cell<usize>,
@@ -95,8 +100,11 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed + 'a> Adc<'a, A> {
             active: Cell::new(false),
             mode: Cell::new(AdcMode::NoMode),
 
+            usage: Cell::new(None),
+
             // App state
             app: MapCell::new(App::default()),
+            appid: Cell::new(None),
             channel: Cell::new(0),
             callback: Cell::new(None),
             app_buf_offset: Cell::new(0),
@@ -112,6 +120,33 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed + 'a> Adc<'a, A> {
         }
     }
 
+    /// Report to energy accounting when the ADC samples, and for which app
+    ///
+    /// usage - energy accounting capsule
+    /// peripheral - index of the ADC in the board's peripheral current table
+    pub fn set_usage_reporting(&self, usage: &'static PeripheralUsage, peripheral: usize) {
+        self.usage.set(Some((usage, peripheral)));
+    }
+
+    /// Track whether the ADC is sampling, and for which app
+    ///
+    /// active - whether sampling started or stopped
+    fn set_active(&self, active: bool) {
+        self.active.set(active);
+        if !active {
+            self.appid.set(None);
+        }
+        self.usage.get().map(|(usage, peripheral)| {
+            if active {
+                self.appid
+                    .get()
+                    .map(|appid| usage.peripheral_active(peripheral, appid));
+            } else {
+                usage.peripheral_idle(peripheral);
+            }
+        });
+    }
+
     /// Initialize the ADC
     /// This can be called harmlessly if the ADC has already been initialized
     fn initialize(&self) -> ReturnCode {
@@ -158,7 +193,8 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed + 'a> Adc<'a, A> {
     /// Collect a single analog sample on a channel
     ///
     /// channel - index into `channels` array, which channel to sample
-    fn sample(&self, channel: usize) -> ReturnCode {
+    /// appid - application identifier, which sampling is charged to
+    fn sample(&self, channel: usize, appid: AppId) -> ReturnCode {
         // only one sample at a time
         if self.active.get() {
             return ReturnCode::EBUSY;
@@ -177,7 +213,8 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed + 'a> Adc<'a, A> {
         let chan = self.channels[channel];
 
         // save state for callback
-        self.active.set(true);
+        self.appid.set(Some(appid));
+        self.set_active(true);
         self.mode.set(AdcMode::SingleSample);
         self.channel.set(channel);
 
@@ -185,7 +222,7 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed + 'a> Adc<'a, A> {
         let res = self.adc.sample(chan);
         if res != ReturnCode::SUCCESS {
             // failure, clear state
-            self.active.set(false);
+            self.set_active(false);
             self.mode.set(AdcMode::NoMode);
 
             return res;
@@ -198,7 +235,8 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed + 'a> Adc<'a, A> {
     ///
     /// channel - index into `channels` array, which channel to sample
     /// frequency - number of samples per second to collect
-    fn sample_continuous(&self, channel: usize, frequency: u32) -> ReturnCode {
+    /// appid - application identifier, which sampling is charged to
+    fn sample_continuous(&self, channel: usize, frequency: u32, appid: AppId) -> ReturnCode {
         // only one sample at a time
         if self.active.get() {
             return ReturnCode::EBUSY;
@@ -217,7 +255,8 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed + 'a> Adc<'a, A> {
         let chan = self.channels[channel];
 
         // save state for callback
-        self.active.set(true);
+        self.appid.set(Some(appid));
+        self.set_active(true);
         self.mode.set(AdcMode::ContinuousSample);
         self.channel.set(channel);
 
@@ -225,7 +264,7 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed + 'a> Adc<'a, A> {
         let res = self.adc.sample_continuous(chan, frequency);
         if res != ReturnCode::SUCCESS {
             // failure, clear state
-            self.active.set(false);
+            self.set_active(false);
             self.mode.set(AdcMode::NoMode);
 
             return res;
@@ -240,7 +279,8 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed + 'a> Adc<'a, A> {
     ///
     /// channel - index into `channels` array, which channel to sample
     /// frequency - number of samples per second to collect
-    fn sample_buffer(&self, channel: usize, frequency: u32) -> ReturnCode {
+    /// appid - application identifier, which sampling is charged to
+    fn sample_buffer(&self, channel: usize, frequency: u32, appid: AppId) -> ReturnCode {
         // only one sample at a time
         if self.active.get() {
             return ReturnCode::EBUSY;
@@ -269,7 +309,8 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed + 'a> Adc<'a, A> {
         }
 
         // save state for callback
-        self.active.set(true);
+        self.appid.set(Some(appid));
+        self.set_active(true);
         self.mode.set(AdcMode::SingleBuffer);
         self.app_buf_offset.set(0);
         self.channel.set(channel);
@@ -313,7 +354,7 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed + 'a> Adc<'a, A> {
         });
         if res != ReturnCode::SUCCESS {
             // failure, clear state
-            self.active.set(false);
+            self.set_active(false);
             self.mode.set(AdcMode::NoMode);
             self.samples_remaining.set(0);
             self.samples_outstanding.set(0);
@@ -331,7 +372,8 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed + 'a> Adc<'a, A> {
     ///
     /// channel - index into `channels` array, which channel to sample
     /// frequency - number of samples per second to collect
-    fn sample_buffer_continuous(&self, channel: usize, frequency: u32) -> ReturnCode {
+    /// appid - application identifier, which sampling is charged to
+    fn sample_buffer_continuous(&self, channel: usize, frequency: u32, appid: AppId) -> ReturnCode {
         // only one sample at a time
         if self.active.get() {
             return ReturnCode::EBUSY;
@@ -362,7 +404,8 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed + 'a> Adc<'a, A> {
         }
 
         // save state for callback
-        self.active.set(true);
+        self.appid.set(Some(appid));
+        self.set_active(true);
         self.mode.set(AdcMode::ContinuousBuffer);
         self.app_buf_offset.set(0);
         self.channel.set(channel);
@@ -420,7 +463,7 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed + 'a> Adc<'a, A> {
         });
         if res != ReturnCode::SUCCESS {
             // failure, clear state
-            self.active.set(false);
+            self.set_active(false);
             self.mode.set(AdcMode::NoMode);
             self.samples_remaining.set(0);
             self.samples_outstanding.set(0);
@@ -441,7 +484,7 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed + 'a> Adc<'a, A> {
         }
 
         // clean up state
-        self.active.set(false);
+        self.set_active(false);
         self.mode.set(AdcMode::NoMode);
         self.app_buf_offset.set(0);
 
@@ -476,7 +519,7 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed + 'a> hil::adc::Client for Ad
     fn sample_ready(&self, sample: u16) {
         if self.active.get() && self.mode.get() == AdcMode::SingleSample {
             // single sample complete, clean up state
-            self.active.set(false);
+            self.set_active(false);
             self.mode.set(AdcMode::NoMode);
 
             // perform callback
@@ -501,7 +544,7 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed + 'a> hil::adc::Client for Ad
         } else {
             // operation probably canceled. Make sure state is consistent. No
             // callback
-            self.active.set(false);
+            self.set_active(false);
             self.mode.set(AdcMode::NoMode);
         }
     }
@@ -728,7 +771,7 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed + 'a> hil::adc::HighSpeedClie
                         // if the mode is SingleBuffer, the operation is
                         // complete. Clean up state
                         if self.mode.get() == AdcMode::SingleBuffer {
-                            self.active.set(false);
+                            self.set_active(false);
                             self.mode.set(AdcMode::NoMode);
                             self.app_buf_offset.set(0);
 
@@ -754,7 +797,7 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed + 'a> hil::adc::HighSpeedClie
         } else {
             // operation was likely canceled. Make sure state is consistent. No
             // callback
-            self.active.set(false);
+            self.set_active(false);
             self.mode.set(AdcMode::NoMode);
             self.app_buf_offset.set(0);
 
@@ -832,13 +875,13 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed + 'a> Driver for Adc<'a, A> {
     ///
     /// command_num - which command call this is
     /// data - value sent by the application, varying uses
-    /// _appid - application identifier, unused
+    /// appid - application identifier, which sampling is charged to
     fn command(
         &self,
         command_num: usize,
         channel: usize,
         frequency: usize,
-        _appid: AppId,
+        appid: AppId,
     ) -> ReturnCode {
         match command_num {
             // check if present
@@ -847,16 +890,16 @@ impl<'a, A: hil::adc::Adc + hil::adc::AdcHighSpeed + 'a> Driver for Adc<'a, A> {
             },
 
             // Single sample on channel
-            1 => self.sample(channel),
+            1 => self.sample(channel, appid),
 
             // Repeated single samples on a channel
-            2 => self.sample_continuous(channel, frequency as u32),
+            2 => self.sample_continuous(channel, frequency as u32, appid),
 
             // Multiple sample on a channel
-            3 => self.sample_buffer(channel, frequency as u32),
+            3 => self.sample_buffer(channel, frequency as u32, appid),
 
             // Continuous buffered sampling on a channel
-            4 => self.sample_buffer_continuous(channel, frequency as u32),
+            4 => self.sample_buffer_continuous(channel, frequency as u32, appid),
 
             // Stop sampling
             5 => self.stop_sampling(),
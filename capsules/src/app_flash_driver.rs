@@ -3,7 +3,9 @@
 //! All write requests from userland are checked to ensure that they are only
 //! trying to write their own flash space, and not the TBF header either.
 //!
-//! This driver can handle non page aligned writes.
+//! This driver can handle non page aligned writes. Only the bytes of the
+//! app's buffer are written, so when the underlying flash supports partial
+//! page writes, writing to erased flash does not erase the page first.
 //!
 //! Userland apps should allocate buffers in flash when they are compiled to
 //! ensure that there is room to write to. This should be accomplished by
@@ -52,6 +54,8 @@ pub struct AppFlash<'a> {
     apps: Grant<App>,
     current_app: Cell<Option<AppId>>,
     buffer: TakeCell<'static, [u8]>,
+    /// Number of bytes the write in progress should write.
+    write_length: Cell<usize>,
 }
 
 impl<'a> AppFlash<'a> {
@@ -65,9 +69,27 @@ impl<'a> AppFlash<'a> {
             apps: grant,
             current_app: Cell::new(None),
             buffer: TakeCell::new(buffer),
+            write_length: Cell::new(0),
         }
     }
 
+    // Write `length` bytes of `buffer` to `flash_address`. If the write cannot
+    // be started, the buffer is put back and the flash is free again.
+    fn start_write(
+        &self,
+        buffer: &'static mut [u8],
+        flash_address: usize,
+        length: usize,
+    ) -> ReturnCode {
+        self.write_length.set(length);
+        let (rc, buffer) = self.driver.write(buffer, flash_address, length);
+        if rc != ReturnCode::SUCCESS {
+            buffer.map(|buffer| self.buffer.replace(buffer));
+            self.current_app.set(None);
+        }
+        rc
+    }
+
     // Check to see if we are doing something. If not, go ahead and do this
     // command. If so, this is queued and will be run when the pending command
     // completes.
@@ -97,7 +119,7 @@ impl<'a> AppFlash<'a> {
                                     *c = d[i];
                                 }
 
-                                self.driver.write(buffer, flash_address, length)
+                                self.start_write(buffer, flash_address, length)
                             })
                         })
                 } else {
@@ -118,16 +140,24 @@ impl<'a> AppFlash<'a> {
 impl<'a> hil::nonvolatile_storage::NonvolatileStorageClient for AppFlash<'a> {
     fn read_done(&self, _buffer: &'static mut [u8], _length: usize) {}
 
-    fn write_done(&self, buffer: &'static mut [u8], _length: usize) {
+    fn write_done(&self, buffer: &'static mut [u8], length: usize) {
         // Put our write buffer back.
         self.buffer.replace(buffer);
 
+        // The flash stops at the first error, so a short write means it
+        // failed.
+        let result = if length == self.write_length.get() {
+            ReturnCode::SUCCESS
+        } else {
+            ReturnCode::FAIL
+        };
+
         // Notify the current application that the command finished.
         self.current_app.get().map(|appid| {
             self.current_app.set(None);
             let _ = self.apps.enter(appid, |app, _| {
                 app.callback.map(|mut cb| {
-                    cb.schedule(0, 0, 0);
+                    cb.schedule(usize::from(result), 0, 0);
                 });
             });
         });
@@ -152,7 +182,7 @@ impl<'a> hil::nonvolatile_storage::NonvolatileStorageClient for AppFlash<'a> {
                                     *c = d[i];
                                 }
 
-                                self.driver.write(buffer, flash_address, length)
+                                self.start_write(buffer, flash_address, length)
                                     == ReturnCode::SUCCESS
                             }
                         })
@@ -195,7 +225,8 @@ impl<'a> Driver for AppFlash<'a> {
     ///
     /// ### `subscribe_num`
     ///
-    /// - `0`: Set a write_done callback.
+    /// - `0`: Set a write_done callback. The first argument is `SUCCESS`, or
+    ///        `FAIL` if the flash could not be written.
     fn subscribe(
         &self,
         subscribe_num: usize,
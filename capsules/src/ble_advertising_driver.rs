@@ -197,6 +197,7 @@
 
 use core::cell::Cell;
 use core::cmp;
+use energy::PeripheralUsage;
 use kernel;
 use kernel::hil::ble_advertising;
 use kernel::hil::ble_advertising::RadioChannel;
@@ -597,6 +598,7 @@ where
     alarm: &'a A,
     sending_app: Cell<Option<kernel::AppId>>,
     receiving_app: Cell<Option<kernel::AppId>>,
+    usage: Cell<Option<(&'static PeripheralUsage, usize)>>,
 }
 
 impl<'a, B, A> BLE<'a, B, A>
@@ -618,9 +620,26 @@ where
             alarm: alarm,
             sending_app: Cell::new(None),
             receiving_app: Cell::new(None),
+            usage: Cell::new(None),
         }
     }
 
+    /// Report to energy accounting which app the radio is on for.
+    /// `peripheral` is the index of the radio in the board's current table.
+    pub fn set_usage_reporting(&self, usage: &'static PeripheralUsage, peripheral: usize) {
+        self.usage.set(Some((usage, peripheral)));
+    }
+
+    // Marks the radio as busy with an advertising or scanning event of `app`,
+    // or as idle if `app` is `None`.
+    fn set_busy(&self, app: Option<kernel::AppId>) {
+        self.busy.set(app.is_some());
+        self.usage.get().map(|(usage, peripheral)| match app {
+            Some(appid) => usage.peripheral_active(peripheral, appid),
+            None => usage.peripheral_idle(peripheral),
+        });
+    }
+
     // Determines which app timer will expire next and sets the underlying alarm
     // to it.
     //
@@ -688,7 +707,7 @@ where
 
                     match app.process_status {
                         Some(BLEState::AdvertisingIdle) => {
-                            self.busy.set(true);
+                            self.set_busy(Some(app.appid()));
                             app.process_status =
                                 Some(BLEState::Advertising(RadioChannel::AdvertisingChannel37));
                             self.sending_app.set(Some(app.appid()));
@@ -696,7 +715,7 @@ where
                             app.send_advertisement(&self, RadioChannel::AdvertisingChannel37);
                         }
                         Some(BLEState::ScanningIdle) => {
-                            self.busy.set(true);
+                            self.set_busy(Some(app.appid()));
                             app.process_status =
                                 Some(BLEState::Scanning(RadioChannel::AdvertisingChannel37));
                             self.receiving_app.set(Some(app.appid()));
@@ -773,7 +792,7 @@ where
                             .receive_advertisement(RadioChannel::AdvertisingChannel39);
                     }
                     Some(BLEState::Scanning(RadioChannel::AdvertisingChannel39)) => {
-                        self.busy.set(false);
+                        self.set_busy(None);
                         app.process_status = Some(BLEState::ScanningIdle);
                         app.set_next_alarm::<A::Frequency>(self.alarm.now());
                     }
@@ -815,7 +834,7 @@ where
                     }
 
                     Some(BLEState::Advertising(RadioChannel::AdvertisingChannel39)) => {
-                        self.busy.set(false);
+                        self.set_busy(None);
                         app.process_status = Some(BLEState::AdvertisingIdle);
                         app.set_next_alarm::<A::Frequency>(self.alarm.now());
                     }
@@ -0,0 +1,583 @@
+//! USB CDC-ACM serial port
+//!
+//! This capsule makes the USB device show up on the host as a serial port
+//! (`/dev/ttyACM*` on Linux), and offers it to the rest of the kernel as a
+//! `hil::uart::UART`. It can take the place of a hardware UART, so `Console`
+//! and the debug writer can run over the native USB port of a board.
+//!
+//! Data is only sent while a program on the host has the port open, which it
+//! signals by setting DTR. Otherwise transmissions complete right away and the
+//! data is dropped, like on a UART with nothing connected. Flow control in the
+//! other direction is done by USB itself: the host is told to retry packets
+//! until the capsule has a receive buffer to put them in.
+//!
+//! A `transmit()` while another one is in progress is rejected with
+//! `RepeatCallError`, and a `receive()` while another one is in progress
+//! aborts it with `RepeatCallError`, as on the USART.
+//!
+//! The line coding set by the host (baud rate, stop bits, parity) is only
+//! stored and reported back, as it means nothing for USB.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! let cdc = static_init!(
+//!     capsules::cdc::CdcAcm<'static, sam4l::usbc::Usbc<'static>>,
+//!     capsules::cdc::CdcAcm::new(
+//!         &sam4l::usbc::USBC,
+//!         0x6667,
+//!         0xabce,
+//!         &["Tock", "Tock serial port", "0"],
+//!         &mut capsules::cdc::CONFIGURATION_BUF));
+//! sam4l::usbc::USBC.set_client(cdc);
+//! cdc.initialize_deferred_call();
+//!
+//! let console = static_init!(
+//!     capsules::console::Console<'static, capsules::cdc::CdcAcm<'static,
+//!         sam4l::usbc::Usbc<'static>>>,
+//!     capsules::console::Console::new(
+//!         cdc,
+//!         115200,
+//!         &mut capsules::console::WRITE_BUF,
+//!         kernel::Grant::create()));
+//! hil::uart::UART::set_client(cdc, console);
+//!
+//! hil::usb::Client::enable(cdc);
+//! hil::usb::Client::attach(cdc);
+//! ```
+
+use core::cell::Cell;
+use core::cmp::min;
+use kernel::common::VolatileCell;
+use kernel::common::deferred_call::{DeferredCall, DeferredCallClient};
+use kernel::common::take_cell::TakeCell;
+use kernel::hil;
+use kernel::hil::uart;
+use kernel::hil::usb::*;
+use usb::*;
+use usbc_client_ctrl::ClientCtrl;
+
+/// Interrupt IN endpoint for notifications, which this capsule never sends
+const NOTIFICATION_ENDPOINT: usize = 1;
+/// Bulk endpoints for the serial data
+const BULK_IN_ENDPOINT: usize = 2;
+const BULK_OUT_ENDPOINT: usize = 3;
+
+const NOTIFICATION_PACKET_SIZE: usize = 16;
+const BULK_PACKET_SIZE: usize = 64;
+
+static LANGUAGES: &'static [u16] = &[
+    0x0409 // English (United States)
+];
+
+/// Size of the configuration descriptor with the communication interface,
+/// its functional descriptors and notification endpoint, and the data
+/// interface with the bulk endpoints
+const CONFIGURATION_SIZE: usize = 67;
+
+pub static mut CONFIGURATION_BUF: [u8; CONFIGURATION_SIZE] = [0; CONFIGURATION_SIZE];
+
+/// The CDC functional descriptors of the communication interface
+#[cfg_attr(rustfmt, rustfmt_skip)]
+static FUNCTIONAL_DESCRIPTORS: &'static [u8] = &[
+    // Header functional descriptor: CDC 1.10
+    5, 0x24, 0x00, 0x10, 0x01,
+    // Call management functional descriptor: no call management, data
+    // interface 1
+    5, 0x24, 0x01, 0x00, 1,
+    // Abstract control management functional descriptor: supports line
+    // coding and control line state requests
+    4, 0x24, 0x02, 0x02,
+    // Union functional descriptor: interface 0 controls interface 1
+    5, 0x24, 0x06, 0, 1,
+];
+
+/// Class specific requests of the abstract control model
+const SET_LINE_CODING: u8 = 0x20;
+const GET_LINE_CODING: u8 = 0x21;
+const SET_CONTROL_LINE_STATE: u8 = 0x22;
+const SEND_BREAK: u8 = 0x23;
+
+/// DTR bit of SET_CONTROL_LINE_STATE, set while the port is open on the host
+const CONTROL_LINE_DTR: u16 = 1 << 0;
+
+const LINE_CODING_LENGTH: usize = 7;
+
+pub struct CdcAcm<'a, C: 'a> {
+    client_ctrl: ClientCtrl<'a, 'static, C>,
+    notification_storage: [VolatileCell<u8>; NOTIFICATION_PACKET_SIZE],
+    bulk_in_storage: [VolatileCell<u8>; BULK_PACKET_SIZE],
+    bulk_out_storage: [VolatileCell<u8>; BULK_PACKET_SIZE],
+
+    /// Baud rate, stop bits, parity and data bits, as sent in
+    /// GET_LINE_CODING
+    line_coding: Cell<[u8; LINE_CODING_LENGTH]>,
+    /// Whether the data of the current control transfer is a line coding
+    receiving_line_coding: Cell<bool>,
+    /// Whether the port is open on the host
+    dtr: Cell<bool>,
+
+    client: Cell<Option<&'static uart::Client>>,
+    deferred_call: DeferredCall,
+
+    /// The buffer being transmitted, its length, how much of it the host has
+    /// received, and the size of the packet in flight
+    tx_buffer: TakeCell<'static, [u8]>,
+    tx_len: Cell<usize>,
+    tx_offset: Cell<usize>,
+    tx_packet: Cell<usize>,
+    /// A buffer passed to `transmit()` while another one was in progress,
+    /// returned from the deferred call
+    tx_rejected: TakeCell<'static, [u8]>,
+
+    /// The buffer being received into, the number of bytes wanted, and how
+    /// many were received
+    rx_buffer: TakeCell<'static, [u8]>,
+    rx_len: Cell<usize>,
+    rx_offset: Cell<usize>,
+    /// Bytes of the last received packet that did not fit in `rx_buffer`
+    rx_pending: Cell<[u8; BULK_PACKET_SIZE]>,
+    rx_pending_start: Cell<usize>,
+    rx_pending_end: Cell<usize>,
+
+    /// Whether the bulk endpoints are waiting for data to send or for room
+    /// to receive
+    delayed_in: Cell<bool>,
+    delayed_out: Cell<bool>,
+}
+
+impl<'a, C: UsbController> CdcAcm<'a, C> {
+    /// `strings` are the manufacturer, product and serial number of the
+    /// device, and `configuration_buf` holds the generated configuration
+    /// descriptor
+    pub fn new(
+        controller: &'a C,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str],
+        configuration_buf: &'static mut [u8],
+    ) -> Self {
+        let notification_endpoints = [
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new(
+                    NOTIFICATION_ENDPOINT,
+                    TransferDirection::DeviceToHost,
+                ),
+                transfer_type: TransferType::Interrupt,
+                max_packet_size: NOTIFICATION_PACKET_SIZE as u16,
+                interval: 16,
+            },
+        ];
+        let bulk_endpoints = [
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new(
+                    BULK_IN_ENDPOINT,
+                    TransferDirection::DeviceToHost,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: BULK_PACKET_SIZE as u16,
+                interval: 0,
+            },
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new(
+                    BULK_OUT_ENDPOINT,
+                    TransferDirection::HostToDevice,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: BULK_PACKET_SIZE as u16,
+                interval: 0,
+            },
+        ];
+        let interfaces = [
+            Interface {
+                alternate_setting: 0,
+                interface_class: 0x02,    // Communications
+                interface_subclass: 0x02, // Abstract control model
+                interface_protocol: 0x01, // AT commands
+                string_index: 0,
+                class_descriptors: FUNCTIONAL_DESCRIPTORS,
+                endpoints: &notification_endpoints,
+            },
+            Interface {
+                alternate_setting: 0,
+                interface_class: 0x0a, // Data
+                interface_subclass: 0,
+                interface_protocol: 0,
+                string_index: 0,
+                class_descriptors: &[],
+                endpoints: &bulk_endpoints,
+            },
+        ];
+        let functions = [
+            Function {
+                function_class: 0x02,
+                function_subclass: 0x02,
+                function_protocol: 0x01,
+                string_index: 0,
+                interfaces: &interfaces,
+            },
+        ];
+        let device = DeviceBuilder {
+            vendor_id: vendor_id,
+            product_id: product_id,
+            device_release: 0x0001,
+            strings: strings,
+            functions: &functions,
+            attributes: ConfigurationAttributes::new(false, false),
+            max_power: 50, // 100 mA
+        };
+        // Older hosts only look for serial ports among the devices of the
+        // communications class
+        let device_descriptor = DeviceDescriptor {
+            class: 0x02,
+            ..device.device_descriptor()
+        };
+
+        CdcAcm {
+            client_ctrl: ClientCtrl::new(
+                controller,
+                device_descriptor,
+                device.write_configuration(configuration_buf),
+                LANGUAGES,
+                strings,
+            ),
+            notification_storage: [VolatileCell::new(0); NOTIFICATION_PACKET_SIZE],
+            bulk_in_storage: [VolatileCell::new(0); BULK_PACKET_SIZE],
+            bulk_out_storage: [VolatileCell::new(0); BULK_PACKET_SIZE],
+            // 115200 baud, 1 stop bit, no parity, 8 data bits
+            line_coding: Cell::new([0x00, 0xc2, 0x01, 0x00, 0, 0, 8]),
+            receiving_line_coding: Cell::new(false),
+            dtr: Cell::new(false),
+            client: Cell::new(None),
+            deferred_call: DeferredCall::new(),
+            tx_buffer: TakeCell::empty(),
+            tx_len: Cell::new(0),
+            tx_offset: Cell::new(0),
+            tx_packet: Cell::new(0),
+            tx_rejected: TakeCell::empty(),
+            rx_buffer: TakeCell::empty(),
+            rx_len: Cell::new(0),
+            rx_offset: Cell::new(0),
+            rx_pending: Cell::new([0; BULK_PACKET_SIZE]),
+            rx_pending_start: Cell::new(0),
+            rx_pending_end: Cell::new(0),
+            delayed_in: Cell::new(false),
+            delayed_out: Cell::new(false),
+        }
+    }
+
+    pub fn initialize_deferred_call(&'static self) {
+        self.deferred_call.register(self);
+    }
+
+    #[inline]
+    fn controller(&self) -> &'a C {
+        self.client_ctrl.controller()
+    }
+
+    /// Handle a request of the abstract control model
+    fn class_request(&self, setup_data: SetupData) -> CtrlSetupResult {
+        match (setup_data.request_type.request_type(), setup_data.request_code) {
+            (RequestType::Class, SET_LINE_CODING) => {
+                self.receiving_line_coding.set(true);
+                self.client_ctrl.accept_out()
+            }
+            (RequestType::Class, GET_LINE_CODING) => self.client_ctrl
+                .send_in(&self.line_coding.get(), setup_data.length),
+            (RequestType::Class, SET_CONTROL_LINE_STATE) => {
+                let dtr = setup_data.value & CONTROL_LINE_DTR != 0;
+                self.dtr.set(dtr);
+                if self.tx_buffer.is_some() {
+                    if dtr {
+                        self.resume_in();
+                    } else {
+                        // Nobody is listening anymore
+                        self.deferred_call.set();
+                    }
+                }
+                CtrlSetupResult::Ok
+            }
+            (RequestType::Class, SEND_BREAK) => CtrlSetupResult::Ok,
+            _ => CtrlSetupResult::ErrNonstandardRequest,
+        }
+    }
+
+    /// Ask for the next packet to send to the host
+    fn resume_in(&self) {
+        if self.delayed_in.get() {
+            self.delayed_in.set(false);
+            self.controller()
+                .endpoint_resume_in(BULK_IN_ENDPOINT as u32);
+        }
+    }
+
+    /// Drop the buffer being transmitted, because the port is closed
+    fn drop_tx(&self) {
+        if !self.dtr.get() {
+            self.tx_buffer.take().map(|buffer| {
+                self.client.get().map(move |client| {
+                    client.transmit_complete(buffer, uart::Error::CommandComplete);
+                });
+            });
+        }
+    }
+
+    /// Move received bytes into the receive buffer, and give it back once it
+    /// has the requested number of bytes
+    fn deliver_rx(&self) {
+        let start = self.rx_pending_start.get();
+        let end = self.rx_pending_end.get();
+        let pending = self.rx_pending.get();
+
+        let full = self.rx_buffer.map_or(false, |buffer| {
+            let offset = self.rx_offset.get();
+            let len = min(end - start, self.rx_len.get() - offset);
+            for i in 0..len {
+                buffer[offset + i] = pending[start + i];
+            }
+            self.rx_offset.set(offset + len);
+            self.rx_pending_start.set(start + len);
+            offset + len == self.rx_len.get()
+        });
+
+        if self.rx_pending_start.get() == self.rx_pending_end.get() && self.delayed_out.get() {
+            // There is room for the next packet
+            self.delayed_out.set(false);
+            self.controller()
+                .endpoint_resume_out(BULK_OUT_ENDPOINT as u32);
+        }
+
+        if full {
+            self.rx_buffer.take().map(|buffer| {
+                let len = self.rx_len.get();
+                self.client.get().map(move |client| {
+                    client.receive_complete(buffer, len, uart::Error::CommandComplete);
+                });
+            });
+        }
+    }
+}
+
+impl<'a, C: UsbController> hil::usb::Client for CdcAcm<'a, C> {
+    fn enable(&self) {
+        self.client_ctrl.enable();
+
+        self.controller().endpoint_set_buffer(
+            NOTIFICATION_ENDPOINT as u32,
+            &self.notification_storage,
+        );
+        self.controller()
+            .endpoint_in_enable(TransferType::Interrupt, NOTIFICATION_ENDPOINT as u32);
+        self.controller()
+            .endpoint_set_buffer(BULK_IN_ENDPOINT as u32, &self.bulk_in_storage);
+        self.controller()
+            .endpoint_in_enable(TransferType::Bulk, BULK_IN_ENDPOINT as u32);
+        self.controller()
+            .endpoint_set_buffer(BULK_OUT_ENDPOINT as u32, &self.bulk_out_storage);
+        self.controller()
+            .endpoint_out_enable(TransferType::Bulk, BULK_OUT_ENDPOINT as u32);
+    }
+
+    fn attach(&self) {
+        self.client_ctrl.attach();
+    }
+
+    fn bus_reset(&self) {
+        self.client_ctrl.bus_reset();
+        // The host opens the port again after it configured the device
+        self.dtr.set(false);
+        if self.tx_buffer.is_some() {
+            self.deferred_call.set();
+        }
+    }
+
+    fn ctrl_setup(&self) -> CtrlSetupResult {
+        self.receiving_line_coding.set(false);
+        match self.client_ctrl.ctrl_setup() {
+            CtrlSetupResult::ErrNonstandardRequest => self.client_ctrl
+                .setup_data()
+                .map_or(CtrlSetupResult::ErrNoParse, |setup_data| {
+                    self.class_request(setup_data)
+                }),
+            result => result,
+        }
+    }
+
+    fn ctrl_in(&self) -> CtrlInResult {
+        self.client_ctrl.ctrl_in()
+    }
+
+    fn ctrl_out(&self, packet_bytes: u32) -> CtrlOutResult {
+        if self.receiving_line_coding.get() {
+            if packet_bytes as usize != LINE_CODING_LENGTH {
+                return CtrlOutResult::Halted;
+            }
+            let mut line_coding = [0; LINE_CODING_LENGTH];
+            for (i, b) in self.client_ctrl.ctrl_buf()[..LINE_CODING_LENGTH]
+                .iter()
+                .enumerate()
+            {
+                line_coding[i] = b.get();
+            }
+            self.line_coding.set(line_coding);
+        }
+        self.client_ctrl.ctrl_out(packet_bytes)
+    }
+
+    fn ctrl_status(&self) {
+        self.client_ctrl.ctrl_status()
+    }
+
+    fn ctrl_status_complete(&self) {
+        self.receiving_line_coding.set(false);
+        self.client_ctrl.ctrl_status_complete()
+    }
+
+    fn packet_in(&self, transfer_type: TransferType, endpoint: usize) -> InResult {
+        match (transfer_type, endpoint) {
+            (TransferType::Bulk, BULK_IN_ENDPOINT) => {
+                if !self.dtr.get() || self.tx_buffer.is_none() {
+                    self.delayed_in.set(true);
+                    return InResult::Delay;
+                }
+
+                // This is an empty packet if the last one was full, so the
+                // host knows the transfer is over
+                let offset = self.tx_offset.get();
+                let len = min(BULK_PACKET_SIZE, self.tx_len.get() - offset);
+                self.tx_buffer.map(|buffer| {
+                    for i in 0..len {
+                        self.bulk_in_storage[i].set(buffer[offset + i]);
+                    }
+                });
+                self.tx_packet.set(len);
+                InResult::Packet(len)
+            }
+            (TransferType::Interrupt, NOTIFICATION_ENDPOINT) => InResult::Delay,
+            _ => InResult::Error,
+        }
+    }
+
+    fn packet_out(
+        &self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> OutResult {
+        match (transfer_type, endpoint) {
+            (TransferType::Bulk, BULK_OUT_ENDPOINT) => {
+                if self.rx_buffer.is_none()
+                    || self.rx_pending_start.get() != self.rx_pending_end.get()
+                {
+                    // No room for the packet, the host will try again
+                    self.delayed_out.set(true);
+                    return OutResult::Delay;
+                }
+
+                let len = min(packet_bytes as usize, BULK_PACKET_SIZE);
+                let mut pending = [0; BULK_PACKET_SIZE];
+                for i in 0..len {
+                    pending[i] = self.bulk_out_storage[i].get();
+                }
+                self.rx_pending.set(pending);
+                self.rx_pending_start.set(0);
+                self.rx_pending_end.set(len);
+
+                self.deliver_rx();
+                OutResult::Ok
+            }
+            _ => OutResult::Error,
+        }
+    }
+
+    fn packet_transmitted(&self, endpoint: usize) {
+        if endpoint != BULK_IN_ENDPOINT {
+            return;
+        }
+
+        let packet = self.tx_packet.get();
+        let offset = self.tx_offset.get() + packet;
+        self.tx_offset.set(offset);
+        if offset == self.tx_len.get() && packet < BULK_PACKET_SIZE {
+            self.tx_buffer.take().map(|buffer| {
+                self.client.get().map(move |client| {
+                    client.transmit_complete(buffer, uart::Error::CommandComplete);
+                });
+            });
+        }
+    }
+}
+
+impl<'a, C: UsbController> uart::UART for CdcAcm<'a, C> {
+    fn set_client(&self, client: &'static uart::Client) {
+        self.client.set(Some(client));
+    }
+
+    fn init(&self, params: uart::UARTParams) {
+        // Report the parameters to the host, even though they do not matter
+        let mut line_coding = self.line_coding.get();
+        for i in 0..4 {
+            line_coding[i] = (params.baud_rate >> (8 * i)) as u8;
+        }
+        line_coding[4] = params.stop_bits as u8;
+        line_coding[5] = params.parity as u8;
+        self.line_coding.set(line_coding);
+    }
+
+    fn transmit(&self, tx_data: &'static mut [u8], tx_len: usize) {
+        if self.tx_buffer.is_some() {
+            if self.tx_rejected.is_some() {
+                // Only one rejected buffer can wait for the deferred call, so
+                // give any further one back right away, like the USART does
+                self.client.get().map(move |client| {
+                    client.transmit_complete(tx_data, uart::Error::RepeatCallError);
+                });
+            } else {
+                self.tx_rejected.replace(tx_data);
+                self.deferred_call.set();
+            }
+            return;
+        }
+
+        self.tx_len.set(min(tx_len, tx_data.len()));
+        self.tx_offset.set(0);
+        self.tx_buffer.replace(tx_data);
+        if self.dtr.get() {
+            self.resume_in();
+        } else {
+            self.deferred_call.set();
+        }
+    }
+
+    fn receive(&self, rx_buffer: &'static mut [u8], rx_len: usize) {
+        let aborted_len = self.rx_offset.get();
+        self.rx_len.set(min(rx_len, rx_buffer.len()));
+        self.rx_offset.set(0);
+        let aborted = self.rx_buffer.replace(rx_buffer);
+
+        // Like the USART, a new receive aborts the one in progress. The new
+        // buffer is already in place in case the client receives again.
+        aborted.map(|buffer| {
+            self.client.get().map(move |client| {
+                client.receive_complete(buffer, aborted_len, uart::Error::RepeatCallError);
+            });
+        });
+
+        // Bytes left over from the last packet are delivered later, so the
+        // buffer is not returned from within this call
+        self.deferred_call.set();
+    }
+}
+
+impl<'a, C: UsbController> DeferredCallClient for CdcAcm<'a, C> {
+    fn handle_deferred_call(&self) {
+        self.tx_rejected.take().map(|buffer| {
+            self.client.get().map(move |client| {
+                client.transmit_complete(buffer, uart::Error::RepeatCallError);
+            });
+        });
+        self.drop_tx();
+        self.deliver_rx();
+    }
+}
@@ -0,0 +1,260 @@
+//! Per-process energy accounting.
+//!
+//! This capsule estimates how much energy each app has caused the board to
+//! consume. Energy is attributed for two things:
+//!
+//! - CPU time, which the kernel tracks for every process.
+//! - Peripheral active time. Capsules that drive a peripheral on behalf of an
+//!   app report when the peripheral is turned on and off through the
+//!   `PeripheralUsage` trait, passing the `AppId` of the app that requested
+//!   the operation.
+//!
+//! Both are converted to energy using a board-supplied table of the current
+//! each peripheral draws while active, the current the CPU draws while
+//! running, and the supply voltage. The estimates are exposed to userspace so
+//! a monitoring app can find which app drains the battery.
+//!
+//! While a peripheral is active, an alarm fires every half wrap of the alarm
+//! counter to charge the time so far, so long intervals are not lost when the
+//! counter wraps.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! // Indices into this table are the peripheral numbers capsules report.
+//! static mut PERIPHERALS: [capsules::energy::Peripheral; 2] = [
+//!     capsules::energy::Peripheral::new(9_000), // Radio, 9 mA
+//!     capsules::energy::Peripheral::new(300),   // ADC, 300 uA
+//! ];
+//!
+//! let energy_alarm = static_init!(
+//!     VirtualMuxAlarm<'static, sam4l::ast::Ast>,
+//!     VirtualMuxAlarm::new(mux_alarm));
+//! let energy = static_init!(
+//!     capsules::energy::EnergyAccounting<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     capsules::energy::EnergyAccounting::new(
+//!         energy_alarm,
+//!         &PERIPHERALS,
+//!         4_000, // CPU current, 4 mA
+//!         3_300, // Supply voltage, 3.3 V
+//!         kernel::Grant::create()));
+//! energy_alarm.set_client(energy);
+//!
+//! // Capsules driving the peripherals report which app uses them
+//! ble_radio.set_usage_reporting(energy, 0);
+//! adc.set_usage_reporting(energy, 1);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! ### Command
+//!
+//! - `0`: Driver check.
+//! - `1`: Return the energy consumed on behalf of the calling app, in
+//!   microjoules.
+//! - `2`: Return the energy consumed on behalf of the app with index `data`,
+//!   in microjoules. Returns `EINVAL` if there is no such app.
+//! - `3`: Return the CPU time used by the app with index `data`, in
+//!   milliseconds. Returns `EINVAL` if there is no such app.
+//!
+//! Values larger than `isize::max_value()` saturate, so they are not
+//! mistaken for error codes.
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::{AppId, Driver, Grant, ReturnCode};
+use kernel::hil::time::{self, Alarm, Frequency};
+
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = 0x10001;
+
+/// How often the time of active peripherals is charged, in alarm ticks. Half
+/// the range of the counter, so no measured interval spans a whole wrap.
+const ACCOUNTING_PERIOD: u32 = 1 << 31;
+
+/// Interface for capsules to report which app is using a peripheral.
+pub trait PeripheralUsage {
+    /// `peripheral` was turned on to serve a request from `app`. If the
+    /// peripheral was already active, the time so far is charged to the
+    /// previous app.
+    fn peripheral_active(&self, peripheral: usize, app: AppId);
+
+    /// `peripheral` was turned off.
+    fn peripheral_idle(&self, peripheral: usize);
+}
+
+/// One entry in the board's peripheral current table.
+pub struct Peripheral {
+    /// Current drawn while the peripheral is active, in microamps.
+    current_ua: u32,
+    /// The app currently using the peripheral and the alarm tick at which it
+    /// started.
+    user: Cell<Option<(AppId, u32)>>,
+}
+
+impl Peripheral {
+    pub const fn new(current_ua: u32) -> Peripheral {
+        Peripheral {
+            current_ua: current_ua,
+            user: Cell::new(None),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct App {
+    /// Charge drawn by peripherals on behalf of this app, in picocoulombs
+    /// (microamps times microseconds).
+    peripheral_charge_pc: u64,
+}
+
+pub struct EnergyAccounting<'a, A: Alarm + 'a> {
+    alarm: &'a A,
+    peripherals: &'a [Peripheral],
+    cpu_current_ua: u32,
+    voltage_mv: u32,
+    apps: Grant<App>,
+}
+
+impl<'a, A: Alarm + 'a> EnergyAccounting<'a, A> {
+    pub fn new(
+        alarm: &'a A,
+        peripherals: &'a [Peripheral],
+        cpu_current_ua: u32,
+        voltage_mv: u32,
+        grant: Grant<App>,
+    ) -> EnergyAccounting<'a, A> {
+        EnergyAccounting {
+            alarm: alarm,
+            peripherals: peripherals,
+            cpu_current_ua: cpu_current_ua,
+            voltage_mv: voltage_mv,
+            apps: grant,
+        }
+    }
+
+    /// Charge the time of all active peripherals so far, and keep the alarm
+    /// running while any of them is active.
+    fn update_accounting(&self, now: u32) {
+        let mut any_active = false;
+        for peripheral in self.peripherals.iter() {
+            if peripheral.user.get().is_some() {
+                self.charge_peripheral(peripheral, now);
+                any_active = true;
+            }
+        }
+        if any_active {
+            self.alarm.set_alarm(now.wrapping_add(ACCOUNTING_PERIOD));
+        } else {
+            self.alarm.disable();
+        }
+    }
+
+    fn ticks_to_us(ticks: u32) -> u64 {
+        let freq = <A::Frequency>::frequency() as u64;
+        (ticks as u64) * 1_000_000 / freq
+    }
+
+    /// Charge the time `peripheral` has been active so far to its current user
+    /// and restart the measurement at `now`.
+    fn charge_peripheral(&self, peripheral: &Peripheral, now: u32) {
+        peripheral.user.get().map(|(app, start)| {
+            let active_us = Self::ticks_to_us(now.wrapping_sub(start));
+            let charge = active_us * peripheral.current_ua as u64;
+            let _ = self.apps.enter(app, |app, _| {
+                app.peripheral_charge_pc += charge;
+            });
+            peripheral.user.set(Some((app, now)));
+        });
+    }
+
+    /// Estimated energy used on behalf of `app`, in microjoules, or `None` if
+    /// the app does not exist.
+    fn energy_uj(&self, app: AppId) -> Option<u64> {
+        app.get_cpu_time_us().map(|cpu_time_us| {
+            let now = self.alarm.now();
+
+            // Include peripherals the app is using right now.
+            let mut charge_pc = cpu_time_us * self.cpu_current_ua as u64;
+            for peripheral in self.peripherals.iter() {
+                peripheral.user.get().map(|(user, start)| {
+                    if user == app {
+                        let active_us = Self::ticks_to_us(now.wrapping_sub(start));
+                        charge_pc += active_us * peripheral.current_ua as u64;
+                    }
+                });
+            }
+            charge_pc += self.apps
+                .enter(app, |app, _| app.peripheral_charge_pc)
+                .unwrap_or(0);
+
+            // pC * mV = fJ, and 10^9 fJ = 1 uJ.
+            charge_pc / 1_000 * self.voltage_mv as u64 / 1_000_000
+        })
+    }
+}
+
+impl<'a, A: Alarm + 'a> PeripheralUsage for EnergyAccounting<'a, A> {
+    fn peripheral_active(&self, peripheral: usize, app: AppId) {
+        self.peripherals.get(peripheral).map(|p| {
+            let now = self.alarm.now();
+            self.charge_peripheral(p, now);
+            p.user.set(Some((app, now)));
+            self.update_accounting(now);
+        });
+    }
+
+    fn peripheral_idle(&self, peripheral: usize) {
+        self.peripherals.get(peripheral).map(|p| {
+            let now = self.alarm.now();
+            self.charge_peripheral(p, now);
+            p.user.set(None);
+            self.update_accounting(now);
+        });
+    }
+}
+
+impl<'a, A: Alarm + 'a> time::Client for EnergyAccounting<'a, A> {
+    fn fired(&self) {
+        self.update_accounting(self.alarm.now());
+    }
+}
+
+/// Convert a value returned to userspace, saturating at `isize::max_value()`
+/// as larger values would read as negative error codes.
+fn saturate(value: u64) -> usize {
+    cmp::min(value, isize::max_value() as u64) as usize
+}
+
+impl<'a, A: Alarm + 'a> Driver for EnergyAccounting<'a, A> {
+    fn command(&self, command_num: usize, data: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => /* Check if exists */ ReturnCode::SUCCESS,
+
+            // Energy used by the calling app
+            1 => self.energy_uj(appid)
+                .map_or(ReturnCode::EINVAL, |energy| ReturnCode::SuccessWithValue {
+                    value: saturate(energy),
+                }),
+
+            // Energy used by another app
+            2 => self.energy_uj(AppId::new(data))
+                .map_or(ReturnCode::EINVAL, |energy| ReturnCode::SuccessWithValue {
+                    value: saturate(energy),
+                }),
+
+            // CPU time used by another app
+            3 => AppId::new(data)
+                .get_cpu_time_us()
+                .map_or(ReturnCode::EINVAL, |cpu_time_us| {
+                    ReturnCode::SuccessWithValue {
+                        value: saturate(cpu_time_us / 1000),
+                    }
+                }),
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
@@ -0,0 +1,1905 @@
+//! FAT16 and FAT32 filesystem on an SD card.
+//!
+//! This gives apps files on an SD card that can also be read and written by
+//! any PC, instead of raw blocks. The card can either be formatted as a whole
+//! ("superfloppy"), or have a partition table, in which case the first FAT
+//! partition is used.
+//!
+//! Apps open files by path, such as `/LOGS/DATA.CSV`, and get back a handle
+//! that is used to read, write, seek in and close the file. Only 8.3 names are
+//! supported: files with long names can be accessed by their short alias, and
+//! new files get a short name. Names are case insensitive. Apps can also list
+//! directories and create and delete files, but not directories.
+//!
+//! Requests from different apps are executed one after another, and a file can
+//! only be open once at a time, so that apps cannot corrupt each other's
+//! files. Each operation that modifies the card has finished writing before
+//! its callback is delivered, and directory entries are updated after the
+//! data they describe, so that a file is never left pointing to data that was
+//! not written.
+//!
+//! The capsule reads and writes the card one sector at a time through a single
+//! buffer, which also caches the last sector used.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let fat = static_init!(
+//!     capsules::fat::FatFs<'static, VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     capsules::fat::FatFs::new(sdcard,
+//!                               kernel::Grant::create(),
+//!                               &mut capsules::fat::BUFFER));
+//! sdcard.set_client(fat);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! - Allow 0: the path for `open`, `list` and `delete`.
+//! - Allow 1: the buffer data is read in to and written from.
+//! - Subscribe 0: called when an operation finishes, with the operation (1
+//!   open, 2 read, 3 write, 6 list, 7 delete), the result and a value: the
+//!   handle for `open`, the number of bytes for `read` and `write` and the
+//!   index of the entry for `list`.
+//! - Command 0: check if the driver exists.
+//! - Command 1: open the file, `arg1` holds the `OPEN_*` flags.
+//! - Command 2: read `arg2` bytes from the handle `arg1`.
+//! - Command 3: write `arg2` bytes to the handle `arg1`.
+//! - Command 4: move the position of the handle `arg1` to `arg2`.
+//! - Command 5: close the handle `arg1`.
+//! - Command 6: find the first entry in the directory starting at index `arg1`
+//!   and write its name, attributes and size in to the buffer.
+//! - Command 7: delete the file.
+//! - Command 8: the size of the file of the handle `arg1`.
+//!
+//! Missing files and the end of a directory are reported as `ENOSUPPORT`.
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+use kernel::common::take_cell::TakeCell;
+use kernel::hil;
+use sdcard::{SDCard, SDCardClient};
+
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = 0x50004;
+
+/// Buffer for one sector of the card, assigned in board `main.rs` files.
+pub static mut BUFFER: [u8; 512] = [0; 512];
+
+/// How many files each app can have open at the same time.
+pub const MAX_OPEN_FILES: usize = 4;
+
+/// Longest path that can be used.
+pub const MAX_PATH_LENGTH: usize = 64;
+
+/// Create the file if it does not exist.
+pub const OPEN_CREATE: usize = 1 << 0;
+/// Remove the contents of the file when opening it.
+pub const OPEN_TRUNCATE: usize = 1 << 1;
+/// Always write at the end of the file.
+pub const OPEN_APPEND: usize = 1 << 2;
+
+/// Size of the records `list` writes in to the buffer: the name as a NUL
+/// terminated string in the first 13 bytes, the attributes in byte 13, and the
+/// size of the file as a little endian word in bytes 16 to 19.
+pub const LIST_RECORD_SIZE: usize = 20;
+
+const SECTOR_SIZE: usize = 512;
+const ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_SECTOR: u32 = (SECTOR_SIZE / ENTRY_SIZE) as u32;
+/// Directories cannot have more entries than this.
+const MAX_DIRECTORY_ENTRIES: u32 = 65536;
+
+// Attributes of directory entries.
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0F;
+
+// Markers in the first byte of a directory entry.
+const ENTRY_END: u8 = 0x00;
+const ENTRY_DELETED: u8 = 0xE5;
+const LONG_NAME_LAST: u8 = 0x40;
+
+// Flags for lower case short names, set by Windows.
+const NAME_LOWER_CASE: u8 = 0x08;
+const EXTENSION_LOWER_CASE: u8 = 0x10;
+
+/// There is no clock, so files are stamped with a fixed date, 2018-01-01.
+const FIXED_DATE: u16 = (2018 - 1980) << 9 | 1 << 5 | 1;
+
+const FSINFO_LEAD_SIGNATURE: u32 = 0x41615252;
+const FSINFO_STRUCT_SIGNATURE: u32 = 0x61417272;
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    buf[offset] as u16 | (buf[offset + 1] as u16) << 8
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    read_u16(buf, offset) as u32 | (read_u16(buf, offset + 2) as u32) << 16
+}
+
+fn write_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset] = value as u8;
+    buf[offset + 1] = (value >> 8) as u8;
+}
+
+fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+    write_u16(buf, offset, value as u16);
+    write_u16(buf, offset + 2, (value >> 16) as u16);
+}
+
+/// Whether `c` can be part of a short name.
+fn is_name_character(c: u8) -> bool {
+    match c {
+        b'A'...b'Z' | b'0'...b'9' => true,
+        b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'(' | b')' | b'-' | b'@' | b'^' | b'_'
+        | b'`' | b'{' | b'}' | b'~' => true,
+        _ => false,
+    }
+}
+
+/// Convert a path component to the padded 8.3 form used in directory entries.
+fn short_name(component: &[u8]) -> Option<[u8; 11]> {
+    let mut name = [b' '; 11];
+    let (base, extension) = match component.iter().position(|c| *c == b'.') {
+        Some(dot) => (&component[..dot], &component[dot + 1..]),
+        None => (component, &component[component.len()..]),
+    };
+    if base.len() == 0 || base.len() > 8 || extension.len() > 3 {
+        return None;
+    }
+    for (i, c) in base.iter().chain(extension.iter()).enumerate() {
+        let c = c.to_ascii_uppercase();
+        if !is_name_character(c) {
+            return None;
+        }
+        name[if i < base.len() { i } else { 8 + i - base.len() }] = c;
+    }
+    Some(name)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum FatType {
+    Fat16,
+    Fat32,
+}
+
+/// Layout of a FAT volume, from its boot sector. All sectors are relative to
+/// the start of the card.
+#[derive(Clone, Copy)]
+struct Volume {
+    fat_type: FatType,
+    sectors_per_cluster: u32,
+    // The first FAT, and how many copies of it there are.
+    fat_start: u32,
+    fat_size: u32,
+    fat_count: u32,
+    // The root directory of FAT16 volumes, which is not in a cluster.
+    root_start: u32,
+    root_sectors: u32,
+    // First cluster of the root directory of FAT32 volumes, 0 for FAT16.
+    root_cluster: u32,
+    // First sector of cluster 2, the first cluster.
+    data_start: u32,
+    cluster_count: u32,
+    // The FSInfo sector of FAT32 volumes, 0 if there is none.
+    fsinfo_sector: u32,
+}
+
+impl Volume {
+    /// Parse the boot sector of a volume starting at sector `start`.
+    fn parse(buf: &[u8], start: u32) -> Option<Volume> {
+        let sectors_per_cluster = buf[13] as u32;
+        let reserved_sectors = read_u16(buf, 14) as u32;
+        let fat_count = buf[16] as u32;
+        let root_entries = read_u16(buf, 17) as u32;
+        let total_sectors = match read_u16(buf, 19) {
+            0 => read_u32(buf, 32),
+            sectors => sectors as u32,
+        };
+        let fat_size = match read_u16(buf, 22) {
+            0 => read_u32(buf, 36),
+            sectors => sectors as u32,
+        };
+        if read_u16(buf, 510) != 0xAA55 || read_u16(buf, 11) as usize != SECTOR_SIZE
+            || !sectors_per_cluster.is_power_of_two() || reserved_sectors == 0
+            || fat_count == 0 || fat_size == 0
+        {
+            return None;
+        }
+
+        let root_sectors = (root_entries * ENTRY_SIZE as u32 + SECTOR_SIZE as u32 - 1)
+            / SECTOR_SIZE as u32;
+        let metadata_sectors = reserved_sectors
+            .checked_add(fat_count.checked_mul(fat_size)?)?
+            .checked_add(root_sectors)?;
+        let cluster_count = total_sectors.checked_sub(metadata_sectors)? / sectors_per_cluster;
+        start.checked_add(total_sectors)?;
+
+        // The type of a volume only depends on how many clusters it has.
+        let fat_type = if cluster_count < 4085 {
+            // FAT12 is not supported.
+            return None;
+        } else if cluster_count < 65525 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        };
+        let entry_size = if fat_type == FatType::Fat16 { 2 } else { 4 };
+        if (cluster_count as u64 + 2) * entry_size > fat_size as u64 * SECTOR_SIZE as u64 {
+            return None;
+        }
+
+        let fat_start = start + reserved_sectors;
+        let mut volume = Volume {
+            fat_type: fat_type,
+            sectors_per_cluster: sectors_per_cluster,
+            fat_start: fat_start,
+            fat_size: fat_size,
+            fat_count: fat_count,
+            root_start: fat_start + fat_count * fat_size,
+            root_sectors: root_sectors,
+            root_cluster: 0,
+            data_start: start + metadata_sectors,
+            cluster_count: cluster_count,
+            fsinfo_sector: 0,
+        };
+        match fat_type {
+            FatType::Fat16 => {
+                if root_sectors == 0 {
+                    return None;
+                }
+            }
+            FatType::Fat32 => {
+                volume.root_cluster = read_u32(buf, 44);
+                if root_sectors != 0 || !volume.is_cluster(volume.root_cluster) {
+                    return None;
+                }
+                volume.fsinfo_sector = match read_u16(buf, 48) as u32 {
+                    0 | 0xFFFF => 0,
+                    sector if sector < reserved_sectors => start + sector,
+                    _ => 0,
+                };
+            }
+        }
+        Some(volume)
+    }
+
+    fn cluster_bytes(&self) -> u32 {
+        self.sectors_per_cluster * SECTOR_SIZE as u32
+    }
+
+    fn cluster_sector(&self, cluster: u32) -> u32 {
+        self.data_start + (cluster - 2) * self.sectors_per_cluster
+    }
+
+    /// Whether `cluster` is a cluster of the volume, and not a marker.
+    fn is_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster - 2 < self.cluster_count
+    }
+
+    fn is_fat_sector(&self, sector: u32) -> bool {
+        sector >= self.fat_start && sector - self.fat_start < self.fat_size
+    }
+
+    /// The sector and offset of the FAT entry of `cluster`.
+    fn fat_entry(&self, cluster: u32) -> (u32, usize) {
+        let offset = match self.fat_type {
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+        };
+        (
+            self.fat_start + offset / SECTOR_SIZE as u32,
+            (offset % SECTOR_SIZE as u32) as usize,
+        )
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFFFFFF,
+        }
+    }
+
+    fn is_end_of_chain(&self, value: u32) -> bool {
+        match self.fat_type {
+            FatType::Fat16 => value >= 0xFFF8,
+            FatType::Fat32 => value >= 0x0FFFFFF8,
+        }
+    }
+}
+
+/// Where a directory entry is on the card.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct EntryLocation {
+    sector: u32,
+    index: usize,
+}
+
+/// A position in a chain of clusters.
+#[derive(Clone, Copy, Default)]
+struct Chain {
+    // The first cluster of the chain, 0 for empty files and the FAT16 root
+    // directory.
+    start: u32,
+    // A cluster in the chain and its index, to continue from.
+    cluster: u32,
+    index: u32,
+}
+
+impl Chain {
+    fn new(start: u32) -> Chain {
+        Chain {
+            start: start,
+            cluster: start,
+            index: 0,
+        }
+    }
+}
+
+/// An open file.
+#[derive(Clone, Copy, Default)]
+struct File {
+    entry: EntryLocation,
+    chain: Chain,
+    size: u32,
+    position: u32,
+    append: bool,
+    // Which mount of the card this handle belongs to.
+    generation: usize,
+}
+
+/// What was found when looking up a path.
+#[derive(Clone, Copy, Default)]
+struct Target {
+    // The directory entry, if the file exists, and its contents.
+    entry: Option<EntryLocation>,
+    attributes: u8,
+    cluster: u32,
+    size: u32,
+    // The first of the long name entries in front of the entry.
+    long_name: Option<EntryLocation>,
+    // First cluster of the directory the entry is in, 0 for the FAT16 root.
+    directory: u32,
+    // A free entry in the directory, if there is one, and whether it marks the
+    // end of the directory.
+    free: Option<EntryLocation>,
+    free_is_end: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operation {
+    Open = 1,
+    Read = 2,
+    Write = 3,
+    List = 6,
+    Delete = 7,
+}
+
+#[derive(Clone, Copy)]
+struct Request {
+    operation: Operation,
+    // The flags, handle, or index, depending on the operation.
+    argument: usize,
+    length: usize,
+}
+
+/// What the capsule is doing.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Step {
+    Idle,
+    /// Making sure the card is initialized.
+    Mount,
+    Initialize,
+    /// Looking for a volume in the first sector of the card.
+    MountCard,
+    /// Reading the boot sector of the partition starting at `start`.
+    MountPartition { start: u32 },
+    /// Looking for the path component starting at `component` in a directory.
+    Resolve { component: usize },
+    /// Deciding what to do with the file `Resolve` found.
+    Open,
+    /// Adding a zeroed cluster to a directory that is full.
+    CreateAllocate,
+    CreateZero { cluster: u32, sector: u32 },
+    CreateLink { cluster: u32 },
+    /// Writing a new directory entry.
+    CreateEntry { location: EntryLocation },
+    /// Emptying the directory entry of a file that is truncated.
+    TruncateEntry,
+    /// Releasing the clusters of a truncated or deleted file.
+    FreeChain { cluster: u32 },
+    /// Flushing the card and returning the handle of the opened file.
+    Opened,
+    ReadData,
+    WriteData,
+    WriteAllocate,
+    WriteLink { cluster: u32 },
+    /// Updating the size and first cluster of the written file.
+    WriteEntry,
+    ListEntry,
+    Delete,
+    DeleteEntry,
+    /// Removing the long name entries at the end of the previous sector.
+    DeleteLongName { location: EntryLocation },
+    /// Writing the cached sector before finishing with `result`.
+    Flush { result: ReturnCode },
+}
+
+/// Result of a step.
+enum Progress {
+    /// Continue with the next step.
+    Continue,
+    /// The card is busy, `run()` is called again when it is done.
+    Wait,
+    /// The operation finished, with this value for the callback.
+    Done(usize),
+}
+
+/// The next entry of a directory.
+enum Entry {
+    Wait,
+    /// There are no more entries.
+    End,
+    Found(EntryLocation, [u8; ENTRY_SIZE]),
+}
+
+/// Result of following a cluster chain.
+enum Walk {
+    Wait,
+    /// The chain is shorter. The chain is at its last cluster.
+    End,
+    Cluster(u32),
+}
+
+/// Make sure `$sector` is in the buffer, or return and wait for it.
+macro_rules! load {
+    ($fs:expr, $sector:expr) => {
+        if !try!($fs.load($sector)) {
+            return Ok(Progress::Wait);
+        }
+    };
+}
+
+pub struct App {
+    callback: Option<Callback>,
+    path: Option<AppSlice<Shared, u8>>,
+    buffer: Option<AppSlice<Shared, u8>>,
+    files: [Option<File>; MAX_OPEN_FILES],
+    pending: Option<Request>,
+}
+
+impl Default for App {
+    fn default() -> App {
+        App {
+            callback: None,
+            path: None,
+            buffer: None,
+            files: [None; MAX_OPEN_FILES],
+            pending: None,
+        }
+    }
+}
+
+pub struct FatFs<'a, A: hil::time::Alarm + 'a> {
+    sdcard: &'a SDCard<'a, A>,
+    apps: Grant<App>,
+
+    // One sector of the card, which sector it is and whether it was changed.
+    buffer: TakeCell<'static, [u8]>,
+    buffer_sector: Cell<Option<u32>>,
+    dirty: Cell<bool>,
+    // The sector being read, and which copy of the FAT is being written.
+    read_sector: Cell<u32>,
+    fat_copy: Cell<u32>,
+
+    // The mounted volume. Each time the card changes handles become invalid.
+    volume: Cell<Option<Volume>>,
+    generation: Cell<usize>,
+    fsinfo_invalidated: Cell<bool>,
+    // Where to look for a free cluster, and how many were already checked.
+    allocate_next: Cell<u32>,
+    allocate_checked: Cell<u32>,
+
+    // The operation being executed.
+    step: Cell<Step>,
+    current: Cell<Option<(AppId, Request)>>,
+    path: Cell<[u8; MAX_PATH_LENGTH]>,
+    path_length: Cell<usize>,
+    target: Cell<Target>,
+    file: Cell<File>,
+    // The directory or file being worked on, and the index of the next entry
+    // when scanning a directory.
+    chain: Cell<Chain>,
+    position: Cell<u32>,
+    // How many bytes to transfer, and how many were transferred.
+    length: Cell<usize>,
+    done: Cell<usize>,
+}
+
+impl<'a, A: hil::time::Alarm + 'a> FatFs<'a, A> {
+    pub fn new(
+        sdcard: &'a SDCard<'a, A>,
+        grant: Grant<App>,
+        buffer: &'static mut [u8; 512],
+    ) -> FatFs<'a, A> {
+        FatFs {
+            sdcard: sdcard,
+            apps: grant,
+            buffer: TakeCell::new(buffer),
+            buffer_sector: Cell::new(None),
+            dirty: Cell::new(false),
+            read_sector: Cell::new(0),
+            fat_copy: Cell::new(0),
+            volume: Cell::new(None),
+            generation: Cell::new(0),
+            fsinfo_invalidated: Cell::new(false),
+            allocate_next: Cell::new(2),
+            allocate_checked: Cell::new(0),
+            step: Cell::new(Step::Idle),
+            current: Cell::new(None),
+            path: Cell::new([0; MAX_PATH_LENGTH]),
+            path_length: Cell::new(0),
+            target: Cell::new(Target::default()),
+            file: Cell::new(File::default()),
+            chain: Cell::new(Chain::default()),
+            position: Cell::new(0),
+            length: Cell::new(0),
+            done: Cell::new(0),
+        }
+    }
+
+    /// Forget the volume, after the card was removed or changed.
+    fn unmount(&self) {
+        self.volume.set(None);
+        self.generation.set(self.generation.get().wrapping_add(1));
+        self.buffer_sector.set(None);
+        self.dirty.set(false);
+        self.fat_copy.set(0);
+    }
+
+    fn check_card(&self) -> ReturnCode {
+        if !self.sdcard.is_installed() {
+            ReturnCode::EUNINSTALLED
+        } else if !self.sdcard.is_initialized() {
+            // The card changed while it was in use.
+            ReturnCode::FAIL
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
+
+    /// Make sure `sector` is in the buffer. If it has to be read first, this
+    /// returns `false` and `run()` is called again once it is.
+    fn load(&self, sector: u32) -> Result<bool, ReturnCode> {
+        if self.buffer_sector.get() == Some(sector) {
+            return Ok(true);
+        }
+        if !try!(self.flush()) {
+            return Ok(false);
+        }
+        let rc = self.check_card();
+        if rc != ReturnCode::SUCCESS {
+            return Err(rc);
+        }
+        self.buffer.take().map_or(Err(ReturnCode::ENOMEM), |buffer| {
+            self.buffer_sector.set(None);
+            self.read_sector.set(sector);
+            let (rc, buffer) = self.sdcard.read_blocks(buffer, sector, 1);
+            buffer.map(|buffer| self.buffer.replace(buffer));
+            match rc {
+                ReturnCode::SUCCESS => Ok(false),
+                rc => Err(rc),
+            }
+        })
+    }
+
+    /// Use the buffer for `sector` without reading it, because the caller
+    /// fills all of it.
+    fn overwrite(&self, sector: u32) -> Result<bool, ReturnCode> {
+        if self.buffer_sector.get() != Some(sector) {
+            if !try!(self.flush()) {
+                return Ok(false);
+            }
+            self.buffer_sector.set(Some(sector));
+        }
+        Ok(true)
+    }
+
+    /// Write the buffer to the card if it was changed. Sectors of the FAT are
+    /// written to each copy of the FAT in turn.
+    fn flush(&self) -> Result<bool, ReturnCode> {
+        if !self.dirty.get() {
+            return Ok(true);
+        }
+        let sector = match (self.buffer_sector.get(), self.volume.get()) {
+            (Some(sector), Some(volume)) => sector + self.fat_copy.get() * volume.fat_size,
+            _ => return Err(ReturnCode::FAIL),
+        };
+        let rc = self.check_card();
+        if rc != ReturnCode::SUCCESS {
+            return Err(rc);
+        }
+        self.buffer.take().map_or(Err(ReturnCode::ENOMEM), |buffer| {
+            let (rc, buffer) = self.sdcard.write_blocks(buffer, sector, 1);
+            buffer.map(|buffer| self.buffer.replace(buffer));
+            match rc {
+                ReturnCode::SUCCESS => Ok(false),
+                rc => Err(rc),
+            }
+        })
+    }
+
+    /// Read the FAT entry of `cluster`, or `None` if the FAT has to be read
+    /// first.
+    fn read_fat(&self, volume: &Volume, cluster: u32) -> Result<Option<u32>, ReturnCode> {
+        let (sector, offset) = volume.fat_entry(cluster);
+        if !try!(self.load(sector)) {
+            return Ok(None);
+        }
+        Ok(self.buffer.map(|buffer| match volume.fat_type {
+            FatType::Fat16 => read_u16(buffer, offset) as u32,
+            FatType::Fat32 => read_u32(buffer, offset) & 0x0FFFFFFF,
+        }))
+    }
+
+    /// Set the FAT entry of `cluster`, returns `false` if the FAT has to be
+    /// read first.
+    fn write_fat(&self, volume: &Volume, cluster: u32, value: u32) -> Result<bool, ReturnCode> {
+        let (sector, offset) = volume.fat_entry(cluster);
+        if !try!(self.load(sector)) {
+            return Ok(false);
+        }
+        self.buffer.map(|buffer| match volume.fat_type {
+            FatType::Fat16 => write_u16(buffer, offset, value as u16),
+            FatType::Fat32 => {
+                // The upper four bits are reserved and must be kept.
+                let reserved = read_u32(buffer, offset) & 0xF0000000;
+                write_u32(buffer, offset, reserved | value);
+            }
+        });
+        self.dirty.set(true);
+        Ok(true)
+    }
+
+    /// Follow `self.chain` to the cluster with index `index`.
+    fn walk(&self, volume: &Volume, index: u32) -> Result<Walk, ReturnCode> {
+        let mut chain = self.chain.get();
+        if chain.start == 0 {
+            return Ok(Walk::End);
+        }
+        if chain.index > index || !volume.is_cluster(chain.cluster) {
+            chain = Chain::new(chain.start);
+            if !volume.is_cluster(chain.start) {
+                return Err(ReturnCode::FAIL);
+            }
+        }
+        while chain.index < index {
+            let next = match try!(self.read_fat(volume, chain.cluster)) {
+                Some(next) => next,
+                None => {
+                    self.chain.set(chain);
+                    return Ok(Walk::Wait);
+                }
+            };
+            if volume.is_end_of_chain(next) {
+                self.chain.set(chain);
+                return Ok(Walk::End);
+            }
+            if !volume.is_cluster(next) {
+                return Err(ReturnCode::FAIL);
+            }
+            chain.cluster = next;
+            chain.index += 1;
+        }
+        self.chain.set(chain);
+        Ok(Walk::Cluster(chain.cluster))
+    }
+
+    /// Return the next entry of the directory in `self.chain`.
+    fn next_entry(&self, volume: &Volume) -> Result<Entry, ReturnCode> {
+        let position = self.position.get();
+        if position >= MAX_DIRECTORY_ENTRIES {
+            return Ok(Entry::End);
+        }
+        let sector_index = position / ENTRIES_PER_SECTOR;
+        let sector = if self.chain.get().start == 0 {
+            if sector_index >= volume.root_sectors {
+                return Ok(Entry::End);
+            }
+            volume.root_start + sector_index
+        } else {
+            match try!(self.walk(volume, sector_index / volume.sectors_per_cluster)) {
+                Walk::Wait => return Ok(Entry::Wait),
+                Walk::End => return Ok(Entry::End),
+                Walk::Cluster(cluster) => {
+                    volume.cluster_sector(cluster) + sector_index % volume.sectors_per_cluster
+                }
+            }
+        };
+        if !try!(self.load(sector)) {
+            return Ok(Entry::Wait);
+        }
+
+        let index = (position % ENTRIES_PER_SECTOR) as usize;
+        let mut data = [0; ENTRY_SIZE];
+        self.buffer.map(|buffer| {
+            data.copy_from_slice(&buffer[index * ENTRY_SIZE..(index + 1) * ENTRY_SIZE]);
+        });
+        self.position.set(position + 1);
+        Ok(Entry::Found(
+            EntryLocation {
+                sector: sector,
+                index: index,
+            },
+            data,
+        ))
+    }
+
+    /// The FSInfo sector of FAT32 volumes caches the number of free clusters.
+    /// Mark it as unknown before the FAT is changed for the first time.
+    fn invalidate_fsinfo(&self, volume: &Volume) -> Result<bool, ReturnCode> {
+        if volume.fsinfo_sector == 0 || self.fsinfo_invalidated.get() {
+            return Ok(true);
+        }
+        if !try!(self.load(volume.fsinfo_sector)) {
+            return Ok(false);
+        }
+        self.buffer.map(|buffer| {
+            if read_u32(buffer, 0) == FSINFO_LEAD_SIGNATURE
+                && read_u32(buffer, 484) == FSINFO_STRUCT_SIGNATURE
+            {
+                write_u32(buffer, 488, 0xFFFFFFFF);
+                write_u32(buffer, 492, 0xFFFFFFFF);
+                self.dirty.set(true);
+            }
+        });
+        self.fsinfo_invalidated.set(true);
+        Ok(true)
+    }
+
+    /// Find a free cluster and mark it as the end of a chain. Returns `None`
+    /// if the FAT has to be read first.
+    fn allocate(&self, volume: &Volume) -> Result<Option<u32>, ReturnCode> {
+        loop {
+            let checked = self.allocate_checked.get();
+            if checked >= volume.cluster_count {
+                self.allocate_checked.set(0);
+                return Err(ReturnCode::ENOMEM);
+            }
+            let mut cluster = self.allocate_next.get();
+            if !volume.is_cluster(cluster) {
+                cluster = 2;
+            }
+            let value = match try!(self.read_fat(volume, cluster)) {
+                Some(value) => value,
+                None => {
+                    self.allocate_next.set(cluster);
+                    return Ok(None);
+                }
+            };
+            self.allocate_next.set(cluster + 1);
+            if value == 0 {
+                // The FAT sector was just read, so this finishes right away.
+                try!(self.write_fat(volume, cluster, volume.end_of_chain()));
+                self.allocate_checked.set(0);
+                return Ok(Some(cluster));
+            }
+            self.allocate_checked.set(checked + 1);
+        }
+    }
+
+    /// Whether the file with this directory entry is open.
+    fn is_open(&self, entry: EntryLocation) -> bool {
+        let generation = self.generation.get();
+        let mut open = false;
+        for cntr in self.apps.iter() {
+            open |= cntr.enter(|app, _| {
+                app.files.iter().any(|file| {
+                    file.map_or(false, |file| {
+                        file.generation == generation && file.entry == entry
+                    })
+                })
+            });
+        }
+        open
+    }
+
+    /// The path component starting at `start` and where the next one starts,
+    /// or `None` if there are no more components.
+    fn component(&self, start: usize) -> Result<Option<([u8; 11], usize)>, ReturnCode> {
+        let path = self.path.get();
+        let path = &path[..self.path_length.get()];
+        let start = start + path[start..].iter().take_while(|c| **c == b'/').count();
+        if start == path.len() {
+            return Ok(None);
+        }
+        let end = start + path[start..].iter().take_while(|c| **c != b'/').count();
+        match short_name(&path[start..end]) {
+            Some(name) => Ok(Some((name, end))),
+            None => Err(ReturnCode::EINVAL),
+        }
+    }
+
+    /// Start scanning the directory starting at `cluster`.
+    fn enter_directory(&self, cluster: u32) {
+        self.chain.set(Chain::new(cluster));
+        self.position.set(0);
+        self.target.set(Target {
+            directory: cluster,
+            ..Target::default()
+        });
+    }
+
+    /// The first step of an operation on a mounted volume.
+    fn begin(&self, volume: &Volume) -> Step {
+        match self.current.get().map(|(_, request)| request.operation) {
+            Some(Operation::Read) => Step::ReadData,
+            Some(Operation::Write) => Step::WriteData,
+            _ => {
+                self.enter_directory(volume.root_cluster);
+                Step::Resolve { component: 0 }
+            }
+        }
+    }
+
+    /// Copy `length` bytes at `offset` in the buffer to or from the app's
+    /// buffer, after the bytes already transferred. Returns how many bytes
+    /// were copied.
+    fn copy_app_data(
+        &self,
+        offset: usize,
+        length: usize,
+        to_app: bool,
+    ) -> Result<usize, ReturnCode> {
+        let app_id = match self.current.get() {
+            Some((app_id, _)) => app_id,
+            None => return Err(ReturnCode::FAIL),
+        };
+        let done = self.done.get();
+        let copied = try!(self.apps.enter(app_id, |app, _| {
+            app.buffer.as_mut().map_or(0, |data| {
+                let data = data.as_mut();
+                let length = cmp::min(length, data.len().saturating_sub(done));
+                self.buffer.map(|buffer| {
+                    if to_app {
+                        data[done..done + length].copy_from_slice(&buffer[offset..offset + length]);
+                    } else {
+                        buffer[offset..offset + length].copy_from_slice(&data[done..done + length]);
+                    }
+                });
+                length
+            })
+        }));
+        self.done.set(done + copied);
+        Ok(copied)
+    }
+
+    fn operation(&self) -> Option<Operation> {
+        self.current.get().map(|(_, request)| request.operation)
+    }
+
+    /// Execute steps until the card is busy or the operation finished.
+    fn run(&self) {
+        loop {
+            let step = self.step.get();
+            if step == Step::Idle {
+                return;
+            }
+            match self.step() {
+                Ok(Progress::Continue) => {}
+                Ok(Progress::Wait) => return,
+                Ok(Progress::Done(value)) => {
+                    self.finish(ReturnCode::SUCCESS, value);
+                    return;
+                }
+                Err(rc) => {
+                    // Keep what was already changed unless the card failed.
+                    let flushing = match step {
+                        Step::Flush { .. } => true,
+                        _ => false,
+                    };
+                    if self.dirty.get() && !flushing && self.check_card() == ReturnCode::SUCCESS {
+                        self.step.set(Step::Flush { result: rc });
+                    } else {
+                        self.finish(rc, 0);
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    fn step(&self) -> Result<Progress, ReturnCode> {
+        let step = self.step.get();
+        match step {
+            Step::Idle => return Ok(Progress::Wait),
+
+            Step::Mount => {
+                if !self.sdcard.is_installed() {
+                    return Err(ReturnCode::EUNINSTALLED);
+                }
+                if self.sdcard.is_initialized() {
+                    self.step.set(Step::MountCard);
+                    return Ok(Progress::Continue);
+                }
+                match self.sdcard.initialize() {
+                    ReturnCode::SUCCESS => {
+                        self.step.set(Step::Initialize);
+                        return Ok(Progress::Wait);
+                    }
+                    rc => return Err(rc),
+                }
+            }
+
+            Step::Initialize => {
+                if !self.sdcard.is_initialized() {
+                    return Err(ReturnCode::FAIL);
+                }
+                self.step.set(Step::MountCard);
+                return Ok(Progress::Continue);
+            }
+
+            Step::MountCard | Step::MountPartition { .. } => {
+                let start = match step {
+                    Step::MountPartition { start } => start,
+                    _ => 0,
+                };
+                load!(self, start);
+                let volume = self.buffer.map_or(None, |buffer| Volume::parse(buffer, start));
+                match volume {
+                    Some(volume) => {
+                        self.volume.set(Some(volume));
+                        self.fsinfo_invalidated.set(false);
+                        self.allocate_next.set(2);
+                        self.allocate_checked.set(0);
+                        self.step.set(self.begin(&volume));
+                        return Ok(Progress::Continue);
+                    }
+                    None if start == 0 => {
+                        // Look for a FAT partition in the partition table.
+                        let partition = self.buffer.map_or(None, |buffer| {
+                            if read_u16(buffer, 510) != 0xAA55 {
+                                return None;
+                            }
+                            (0..4)
+                                .map(|i| &buffer[446 + i * 16..446 + (i + 1) * 16])
+                                .find(|entry| match entry[4] {
+                                    0x04 | 0x06 | 0x0B | 0x0C | 0x0E => true,
+                                    _ => false,
+                                })
+                                .map(|entry| read_u32(entry, 8))
+                        });
+                        match partition {
+                            Some(start) if start != 0 => {
+                                self.step.set(Step::MountPartition { start: start });
+                                return Ok(Progress::Continue);
+                            }
+                            _ => return Err(ReturnCode::ENODEVICE),
+                        }
+                    }
+                    None => return Err(ReturnCode::ENODEVICE),
+                }
+            }
+
+            _ => {}
+        }
+
+        // All other steps need a mounted volume.
+        let volume = match self.volume.get() {
+            Some(volume) => volume,
+            None => return Err(ReturnCode::FAIL),
+        };
+        let cluster_bytes = volume.cluster_bytes();
+
+        match step {
+            Step::Resolve { component } => {
+                let operation = self.operation();
+                let (name, next) = match try!(self.component(component)) {
+                    Some(component) => component,
+                    None => {
+                        // The path names the directory that was entered.
+                        if operation != Some(Operation::List) {
+                            return Err(ReturnCode::EINVAL);
+                        }
+                        let chain = self.chain.get();
+                        self.chain.set(Chain::new(chain.start));
+                        self.position.set(self.current.get().map_or(0, |(_, r)| r.argument as u32));
+                        self.step.set(Step::ListEntry);
+                        return Ok(Progress::Continue);
+                    }
+                };
+                // Directories are listed, files are opened and deleted.
+                let last = operation != Some(Operation::List)
+                    && try!(self.component(next)).is_none();
+
+                let mut target = self.target.get();
+                let found = loop {
+                    let (location, data) = match try!(self.next_entry(&volume)) {
+                        Entry::Wait => {
+                            self.target.set(target);
+                            return Ok(Progress::Wait);
+                        }
+                        Entry::End => break None,
+                        Entry::Found(location, data) => (location, data),
+                    };
+                    let attributes = data[11];
+                    if data[0] == ENTRY_END {
+                        if target.free.is_none() {
+                            target.free = Some(location);
+                            target.free_is_end = true;
+                        }
+                        break None;
+                    } else if data[0] == ENTRY_DELETED {
+                        if target.free.is_none() {
+                            target.free = Some(location);
+                        }
+                        target.long_name = None;
+                    } else if attributes & ATTR_LONG_NAME == ATTR_LONG_NAME {
+                        if data[0] & LONG_NAME_LAST != 0 || target.long_name.is_none() {
+                            target.long_name = Some(location);
+                        }
+                    } else if attributes & ATTR_VOLUME_ID == 0 && data[..11] == name[..] {
+                        break Some((location, data));
+                    } else {
+                        target.long_name = None;
+                    }
+                };
+
+                match found {
+                    Some((location, data)) => {
+                        let cluster =
+                            (read_u16(&data, 20) as u32) << 16 | read_u16(&data, 26) as u32;
+                        if !last {
+                            if data[11] & ATTR_DIRECTORY == 0 || !volume.is_cluster(cluster) {
+                                return Err(ReturnCode::EINVAL);
+                            }
+                            self.enter_directory(cluster);
+                            self.step.set(Step::Resolve { component: next });
+                            return Ok(Progress::Continue);
+                        }
+                        target.entry = Some(location);
+                        target.attributes = data[11];
+                        target.cluster = cluster;
+                        target.size = read_u32(&data, 28);
+                    }
+                    None => {
+                        if !last {
+                            return Err(ReturnCode::ENOSUPPORT);
+                        }
+                        target.entry = None;
+                    }
+                }
+                self.target.set(target);
+                self.step.set(match operation {
+                    Some(Operation::Delete) => Step::Delete,
+                    _ => Step::Open,
+                });
+                Ok(Progress::Continue)
+            }
+
+            Step::Open => {
+                let target = self.target.get();
+                let flags = self.current.get().map_or(0, |(_, r)| r.argument);
+                let mut file = File {
+                    entry: EntryLocation::default(),
+                    chain: Chain::default(),
+                    size: 0,
+                    position: 0,
+                    append: flags & OPEN_APPEND != 0,
+                    generation: self.generation.get(),
+                };
+                match target.entry {
+                    Some(entry) => {
+                        if target.attributes & ATTR_DIRECTORY != 0 {
+                            return Err(ReturnCode::EINVAL);
+                        }
+                        if self.is_open(entry) {
+                            return Err(ReturnCode::EBUSY);
+                        }
+                        file.entry = entry;
+                        if flags & OPEN_TRUNCATE != 0 && (target.cluster != 0 || target.size != 0) {
+                            self.step.set(Step::TruncateEntry);
+                        } else {
+                            file.chain = Chain::new(target.cluster);
+                            file.size = target.size;
+                            self.step.set(Step::Opened);
+                        }
+                    }
+                    None => {
+                        if flags & OPEN_CREATE == 0 {
+                            return Err(ReturnCode::ENOSUPPORT);
+                        }
+                        match target.free {
+                            Some(location) => {
+                                self.step.set(Step::CreateEntry { location: location })
+                            }
+                            // The FAT16 root directory cannot grow.
+                            None if target.directory == 0 => return Err(ReturnCode::ENOMEM),
+                            None => self.step.set(Step::CreateAllocate),
+                        }
+                    }
+                }
+                self.file.set(file);
+                Ok(Progress::Continue)
+            }
+
+            Step::CreateAllocate => {
+                if !try!(self.invalidate_fsinfo(&volume)) {
+                    return Ok(Progress::Wait);
+                }
+                match try!(self.allocate(&volume)) {
+                    Some(cluster) => {
+                        self.step.set(Step::CreateZero {
+                            cluster: cluster,
+                            sector: 0,
+                        });
+                        Ok(Progress::Continue)
+                    }
+                    None => Ok(Progress::Wait),
+                }
+            }
+
+            Step::CreateZero { cluster, sector } => {
+                // Clear the new directory cluster before it becomes part of
+                // the directory.
+                if sector == volume.sectors_per_cluster {
+                    self.step.set(Step::CreateLink { cluster: cluster });
+                    return Ok(Progress::Continue);
+                }
+                if !try!(self.overwrite(volume.cluster_sector(cluster) + sector)) {
+                    return Ok(Progress::Wait);
+                }
+                self.buffer.map(|buffer| {
+                    for byte in buffer.iter_mut() {
+                        *byte = 0;
+                    }
+                });
+                self.dirty.set(true);
+                self.step.set(Step::CreateZero {
+                    cluster: cluster,
+                    sector: sector + 1,
+                });
+                Ok(Progress::Continue)
+            }
+
+            Step::CreateLink { cluster } => {
+                // The directory was scanned to its end, so `chain` is at its
+                // last cluster.
+                if !try!(self.write_fat(&volume, self.chain.get().cluster, cluster)) {
+                    return Ok(Progress::Wait);
+                }
+                let mut target = self.target.get();
+                target.free_is_end = true;
+                self.target.set(target);
+                self.step.set(Step::CreateEntry {
+                    location: EntryLocation {
+                        sector: volume.cluster_sector(cluster),
+                        index: 0,
+                    },
+                });
+                Ok(Progress::Continue)
+            }
+
+            Step::CreateEntry { location } => {
+                load!(self, location.sector);
+                let target = self.target.get();
+                let name = match try!(self.component(0)) {
+                    // The last component is the name of the file.
+                    Some(mut component) => loop {
+                        match try!(self.component(component.1)) {
+                            Some(next) => component = next,
+                            None => break component.0,
+                        }
+                    },
+                    None => return Err(ReturnCode::EINVAL),
+                };
+                self.buffer.map(|buffer| {
+                    let offset = location.index * ENTRY_SIZE;
+                    {
+                        let entry = &mut buffer[offset..offset + ENTRY_SIZE];
+                        for byte in entry.iter_mut() {
+                            *byte = 0;
+                        }
+                        entry[..11].copy_from_slice(&name);
+                        entry[11] = ATTR_ARCHIVE;
+                        write_u16(entry, 16, FIXED_DATE);
+                        write_u16(entry, 18, FIXED_DATE);
+                        write_u16(entry, 24, FIXED_DATE);
+                    }
+                    // All entries after the end marker are free, keep the
+                    // marker after the new entry. Directories are zeroed, so
+                    // the next sector already starts with one.
+                    if target.free_is_end && location.index + 1 < ENTRIES_PER_SECTOR as usize {
+                        buffer[offset + ENTRY_SIZE] = ENTRY_END;
+                    }
+                });
+                self.dirty.set(true);
+                let mut file = self.file.get();
+                file.entry = location;
+                self.file.set(file);
+                self.step.set(Step::Opened);
+                Ok(Progress::Continue)
+            }
+
+            Step::TruncateEntry => {
+                // Clear the entry first, so that it never points to clusters
+                // that are free.
+                let file = self.file.get();
+                load!(self, file.entry.sector);
+                self.buffer.map(|buffer| {
+                    let entry = &mut buffer[file.entry.index * ENTRY_SIZE..];
+                    write_u16(entry, 20, 0);
+                    write_u16(entry, 26, 0);
+                    write_u32(entry, 28, 0);
+                    write_u16(entry, 24, FIXED_DATE);
+                });
+                self.dirty.set(true);
+                self.step.set(Step::FreeChain {
+                    cluster: self.target.get().cluster,
+                });
+                Ok(Progress::Continue)
+            }
+
+            Step::FreeChain { cluster } => {
+                if !volume.is_cluster(cluster) {
+                    self.step.set(match self.operation() {
+                        Some(Operation::Open) => Step::Opened,
+                        _ => Step::Flush {
+                            result: ReturnCode::SUCCESS,
+                        },
+                    });
+                    return Ok(Progress::Continue);
+                }
+                if !try!(self.invalidate_fsinfo(&volume)) {
+                    return Ok(Progress::Wait);
+                }
+                let next = match try!(self.read_fat(&volume, cluster)) {
+                    Some(next) => next,
+                    None => return Ok(Progress::Wait),
+                };
+                // The FAT sector was just read, so this finishes right away.
+                try!(self.write_fat(&volume, cluster, 0));
+                self.step.set(Step::FreeChain { cluster: next });
+                Ok(Progress::Continue)
+            }
+
+            Step::Opened => {
+                if !try!(self.flush()) {
+                    return Ok(Progress::Wait);
+                }
+                Ok(Progress::Done(0))
+            }
+
+            Step::ReadData => {
+                let file = self.file.get();
+                let done = self.done.get();
+                let remaining = cmp::min(
+                    self.length.get() - done,
+                    (file.size - file.position) as usize,
+                );
+                if remaining == 0 {
+                    return Ok(Progress::Done(done));
+                }
+                let cluster = match try!(self.walk(&volume, file.position / cluster_bytes)) {
+                    Walk::Wait => return Ok(Progress::Wait),
+                    // The chain is shorter than the file.
+                    Walk::End => return Err(ReturnCode::FAIL),
+                    Walk::Cluster(cluster) => cluster,
+                };
+                let sector = volume.cluster_sector(cluster)
+                    + (file.position % cluster_bytes) / SECTOR_SIZE as u32;
+                load!(self, sector);
+
+                let offset = file.position as usize % SECTOR_SIZE;
+                let length = cmp::min(SECTOR_SIZE - offset, remaining);
+                let copied = try!(self.copy_app_data(offset, length, true));
+                if copied == 0 {
+                    return Ok(Progress::Done(done));
+                }
+                let mut file = file;
+                file.position += copied as u32;
+                self.file.set(file);
+                Ok(Progress::Continue)
+            }
+
+            Step::WriteData => {
+                let file = self.file.get();
+                // Files cannot be larger than 4 GiB.
+                let remaining = cmp::min(
+                    self.length.get() - self.done.get(),
+                    (u32::max_value() - file.position) as usize,
+                );
+                if remaining == 0 {
+                    self.step.set(Step::WriteEntry);
+                    return Ok(Progress::Continue);
+                }
+                let cluster = match try!(self.walk(&volume, file.position / cluster_bytes)) {
+                    Walk::Wait => return Ok(Progress::Wait),
+                    Walk::End => {
+                        self.step.set(Step::WriteAllocate);
+                        return Ok(Progress::Continue);
+                    }
+                    Walk::Cluster(cluster) => cluster,
+                };
+                let sector = volume.cluster_sector(cluster)
+                    + (file.position % cluster_bytes) / SECTOR_SIZE as u32;
+                let offset = file.position as usize % SECTOR_SIZE;
+                let length = cmp::min(SECTOR_SIZE - offset, remaining);
+                let sector_start = file.position - offset as u32;
+
+                // Sectors only have to be read if part of their contents stays.
+                if sector_start < file.size && length != SECTOR_SIZE {
+                    load!(self, sector);
+                } else {
+                    if !try!(self.overwrite(sector)) {
+                        return Ok(Progress::Wait);
+                    }
+                    if length != SECTOR_SIZE {
+                        self.buffer.map(|buffer| {
+                            for byte in buffer.iter_mut() {
+                                *byte = 0;
+                            }
+                        });
+                    }
+                }
+
+                let copied = try!(self.copy_app_data(offset, length, false));
+                if copied == 0 {
+                    self.length.set(self.done.get());
+                } else {
+                    self.dirty.set(true);
+                }
+                let mut file = file;
+                file.position += copied as u32;
+                file.size = cmp::max(file.size, file.position);
+                self.file.set(file);
+                Ok(Progress::Continue)
+            }
+
+            Step::WriteAllocate => {
+                if !try!(self.invalidate_fsinfo(&volume)) {
+                    return Ok(Progress::Wait);
+                }
+                match self.allocate(&volume) {
+                    Ok(Some(cluster)) => {
+                        self.step.set(Step::WriteLink { cluster: cluster });
+                        Ok(Progress::Continue)
+                    }
+                    Ok(None) => Ok(Progress::Wait),
+                    // Keep what fit on the card.
+                    Err(ReturnCode::ENOMEM) if self.done.get() > 0 => {
+                        self.length.set(self.done.get());
+                        self.step.set(Step::WriteEntry);
+                        Ok(Progress::Continue)
+                    }
+                    Err(rc) => Err(rc),
+                }
+            }
+
+            Step::WriteLink { cluster } => {
+                let mut chain = self.chain.get();
+                if chain.start == 0 {
+                    chain = Chain::new(cluster);
+                } else {
+                    if !try!(self.write_fat(&volume, chain.cluster, cluster)) {
+                        return Ok(Progress::Wait);
+                    }
+                    chain.cluster = cluster;
+                    chain.index += 1;
+                }
+                self.chain.set(chain);
+                self.step.set(Step::WriteData);
+                Ok(Progress::Continue)
+            }
+
+            Step::WriteEntry => {
+                let file = self.file.get();
+                let start = self.chain.get().start;
+                load!(self, file.entry.sector);
+                self.buffer.map(|buffer| {
+                    let entry = &mut buffer[file.entry.index * ENTRY_SIZE..];
+                    entry[11] |= ATTR_ARCHIVE;
+                    write_u16(entry, 18, FIXED_DATE);
+                    write_u16(entry, 20, (start >> 16) as u16);
+                    write_u16(entry, 22, 0);
+                    write_u16(entry, 24, FIXED_DATE);
+                    write_u16(entry, 26, start as u16);
+                    write_u32(entry, 28, file.size);
+                });
+                self.dirty.set(true);
+                self.step.set(Step::Flush {
+                    result: ReturnCode::SUCCESS,
+                });
+                Ok(Progress::Continue)
+            }
+
+            Step::ListEntry => loop {
+                let (_, data) = match try!(self.next_entry(&volume)) {
+                    Entry::Wait => return Ok(Progress::Wait),
+                    Entry::End => return Err(ReturnCode::ENOSUPPORT),
+                    Entry::Found(location, data) => (location, data),
+                };
+                if data[0] == ENTRY_END {
+                    return Err(ReturnCode::ENOSUPPORT);
+                }
+                if data[0] == ENTRY_DELETED || data[0] == b'.'
+                    || data[11] & ATTR_LONG_NAME == ATTR_LONG_NAME
+                    || data[11] & ATTR_VOLUME_ID != 0
+                {
+                    continue;
+                }
+
+                let mut record = [0; LIST_RECORD_SIZE];
+                let mut length = 0;
+                for (i, c) in data[..11].iter().enumerate() {
+                    if *c == b' ' {
+                        continue;
+                    }
+                    if i >= 8 && length > 0 && !data[8..i].iter().any(|c| *c != b' ') {
+                        record[length] = b'.';
+                        length += 1;
+                    }
+                    let lower = if i < 8 {
+                        data[12] & NAME_LOWER_CASE != 0
+                    } else {
+                        data[12] & EXTENSION_LOWER_CASE != 0
+                    };
+                    record[length] = match *c {
+                        // 0xE5 is stored as 0x05 in the first byte.
+                        0x05 if i == 0 => 0xE5,
+                        c if lower => c.to_ascii_lowercase(),
+                        c => c,
+                    };
+                    length += 1;
+                }
+                record[13] = data[11];
+                record[16..20].copy_from_slice(&data[28..32]);
+
+                let index = self.position.get() - 1;
+                let app_id = self.current.get().map(|(app_id, _)| app_id);
+                let copied = app_id.map_or(Ok(0), |app_id| {
+                    self.apps.enter(app_id, |app, _| {
+                        app.buffer.as_mut().map_or(0, |buffer| {
+                            let length = cmp::min(buffer.len(), LIST_RECORD_SIZE);
+                            buffer.as_mut()[..length].copy_from_slice(&record[..length]);
+                            length
+                        })
+                    })
+                });
+                if try!(copied) < LIST_RECORD_SIZE {
+                    return Err(ReturnCode::ESIZE);
+                }
+                return Ok(Progress::Done(index as usize));
+            },
+
+            Step::Delete => {
+                let target = self.target.get();
+                let entry = match target.entry {
+                    Some(entry) => entry,
+                    None => return Err(ReturnCode::ENOSUPPORT),
+                };
+                if target.attributes & ATTR_DIRECTORY != 0 {
+                    return Err(ReturnCode::EINVAL);
+                }
+                if self.is_open(entry) {
+                    return Err(ReturnCode::EBUSY);
+                }
+                self.step.set(Step::DeleteEntry);
+                Ok(Progress::Continue)
+            }
+
+            Step::DeleteEntry => {
+                // Remove the entry before freeing its clusters, so that it
+                // never points to clusters that are free.
+                let target = self.target.get();
+                let entry = match target.entry {
+                    Some(entry) => entry,
+                    None => return Err(ReturnCode::FAIL),
+                };
+                load!(self, entry.sector);
+                let mut first = entry.index;
+                let mut previous_sector = None;
+                match target.long_name {
+                    Some(long_name) if long_name.sector == entry.sector => first = long_name.index,
+                    Some(long_name) => {
+                        first = 0;
+                        previous_sector = Some(long_name);
+                    }
+                    None => {}
+                }
+                self.buffer.map(|buffer| {
+                    for i in first..entry.index {
+                        if buffer[i * ENTRY_SIZE + 11] & ATTR_LONG_NAME == ATTR_LONG_NAME {
+                            buffer[i * ENTRY_SIZE] = ENTRY_DELETED;
+                        }
+                    }
+                    buffer[entry.index * ENTRY_SIZE] = ENTRY_DELETED;
+                });
+                self.dirty.set(true);
+                self.step.set(match previous_sector {
+                    Some(location) => Step::DeleteLongName { location: location },
+                    None => Step::FreeChain {
+                        cluster: target.cluster,
+                    },
+                });
+                Ok(Progress::Continue)
+            }
+
+            Step::DeleteLongName { location } => {
+                load!(self, location.sector);
+                self.buffer.map(|buffer| {
+                    for i in location.index..ENTRIES_PER_SECTOR as usize {
+                        if buffer[i * ENTRY_SIZE + 11] & ATTR_LONG_NAME == ATTR_LONG_NAME {
+                            buffer[i * ENTRY_SIZE] = ENTRY_DELETED;
+                        }
+                    }
+                });
+                self.dirty.set(true);
+                self.step.set(Step::FreeChain {
+                    cluster: self.target.get().cluster,
+                });
+                Ok(Progress::Continue)
+            }
+
+            Step::Flush { result } => {
+                if !try!(self.flush()) {
+                    return Ok(Progress::Wait);
+                }
+                match result {
+                    ReturnCode::SUCCESS => Ok(Progress::Done(self.done.get())),
+                    rc => Err(rc),
+                }
+            }
+
+            _ => Err(ReturnCode::FAIL),
+        }
+    }
+
+    /// Report the result of the current operation and start the next one.
+    fn finish(&self, result: ReturnCode, value: usize) {
+        self.step.set(Step::Idle);
+        let current = self.current.get();
+        self.current.set(None);
+        current.map(|(app_id, request)| {
+            let mut file = self.file.get();
+            if request.operation != Operation::Open {
+                file.chain = self.chain.get();
+            }
+            let _ = self.apps.enter(app_id, |app, _| {
+                let mut result = result;
+                let mut value = value;
+                if result == ReturnCode::SUCCESS {
+                    match request.operation {
+                        Operation::Open => {
+                            let free = app.files.iter().position(|file| file.is_none());
+                            match free {
+                                Some(handle) => {
+                                    app.files[handle] = Some(file);
+                                    value = handle;
+                                }
+                                None => result = ReturnCode::ENOMEM,
+                            }
+                        }
+                        Operation::Read | Operation::Write => {
+                            // Unless the app closed the file in the meantime.
+                            if app.files[request.argument].is_some() {
+                                app.files[request.argument] = Some(file);
+                            }
+                        }
+                        Operation::List | Operation::Delete => {}
+                    }
+                }
+                app.callback.map(|mut cb| {
+                    cb.schedule(request.operation as usize, usize::from(result), value);
+                });
+            });
+        });
+        self.check_queue();
+    }
+
+    /// Start the next operation an app asked for.
+    fn check_queue(&self) {
+        if self.step.get() != Step::Idle {
+            return;
+        }
+        for cntr in self.apps.iter() {
+            let (app_id, request) = cntr.enter(|app, _| (app.appid(), app.pending.take()));
+            if let Some(request) = request {
+                self.start(app_id, request);
+                return;
+            }
+        }
+    }
+
+    fn start(&self, app_id: AppId, request: Request) {
+        self.current.set(Some((app_id, request)));
+        self.done.set(0);
+        self.length.set(request.length);
+
+        let generation = self.generation.get();
+        let rc = self.apps
+            .enter(app_id, |app, _| {
+                match request.operation {
+                    Operation::Open | Operation::List | Operation::Delete => {
+                        let mut path = [0; MAX_PATH_LENGTH];
+                        let length = app.path.as_ref().map_or(0, |app_path| {
+                            // Paths may be NUL terminated.
+                            let app_path = app_path.as_ref();
+                            let length = app_path
+                                .iter()
+                                .position(|c| *c == 0)
+                                .unwrap_or(app_path.len());
+                            let length = cmp::min(length, MAX_PATH_LENGTH);
+                            path[..length].copy_from_slice(&app_path[..length]);
+                            length
+                        });
+                        self.path.set(path);
+                        self.path_length.set(length);
+                        ReturnCode::SUCCESS
+                    }
+                    Operation::Read | Operation::Write => {
+                        match app.files[request.argument] {
+                            Some(file) if file.generation == generation => {
+                                let mut file = file;
+                                if request.operation == Operation::Write && file.append {
+                                    file.position = file.size;
+                                }
+                                self.file.set(file);
+                                self.chain.set(file.chain);
+                                let available = app.buffer.as_ref().map_or(0, |b| b.len());
+                                self.length.set(cmp::min(request.length, available));
+                                ReturnCode::SUCCESS
+                            }
+                            // The card changed since the file was opened.
+                            _ => ReturnCode::EINVAL,
+                        }
+                    }
+                }
+            })
+            .unwrap_or_else(|err| err.into());
+        if rc != ReturnCode::SUCCESS {
+            self.finish(rc, 0);
+            return;
+        }
+
+        match self.volume.get() {
+            Some(volume) => self.step.set(self.begin(&volume)),
+            None => self.step.set(Step::Mount),
+        }
+        self.run();
+    }
+
+    /// Check the arguments of an operation and queue it.
+    fn app_operation(&self, app_id: AppId, request: Request) -> ReturnCode {
+        let generation = self.generation.get();
+        // Each app can have one operation at a time.
+        let running = self.current.get().map_or(false, |(id, _)| id == app_id);
+        let rc = self.apps
+            .enter(app_id, |app, _| {
+                if running || app.pending.is_some() {
+                    return ReturnCode::EBUSY;
+                }
+                match request.operation {
+                    Operation::Open | Operation::Delete | Operation::List => {
+                        let path_length = app.path.as_ref().map_or(0, |path| {
+                            path.as_ref().iter().position(|c| *c == 0).unwrap_or(path.len())
+                        });
+                        if app.path.is_none() && request.operation != Operation::List {
+                            return ReturnCode::ERESERVE;
+                        }
+                        if path_length > MAX_PATH_LENGTH {
+                            return ReturnCode::ESIZE;
+                        }
+                        if request.operation == Operation::Open
+                            && app.files.iter().all(|file| file.is_some())
+                        {
+                            return ReturnCode::ENOMEM;
+                        }
+                        if request.operation == Operation::List
+                            && app.buffer.as_ref().map_or(0, |b| b.len()) < LIST_RECORD_SIZE
+                        {
+                            return ReturnCode::ESIZE;
+                        }
+                    }
+                    Operation::Read | Operation::Write => {
+                        if request.argument >= MAX_OPEN_FILES {
+                            return ReturnCode::EINVAL;
+                        }
+                        match app.files[request.argument] {
+                            Some(file) if file.generation == generation => {}
+                            _ => return ReturnCode::EINVAL,
+                        }
+                        if app.buffer.is_none() {
+                            return ReturnCode::ERESERVE;
+                        }
+                    }
+                }
+                app.pending = Some(request);
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into());
+        if rc == ReturnCode::SUCCESS {
+            self.check_queue();
+        }
+        rc
+    }
+
+    /// Run `f` on an open file of the app.
+    fn with_file<F>(&self, app_id: AppId, handle: usize, f: F) -> ReturnCode
+    where
+        F: FnOnce(&mut Option<File>) -> ReturnCode,
+    {
+        if handle >= MAX_OPEN_FILES {
+            return ReturnCode::EINVAL;
+        }
+        self.apps
+            .enter(app_id, |app, _| f(&mut app.files[handle]))
+            .unwrap_or_else(|err| err.into())
+    }
+}
+
+/// Handle callbacks from the SD card.
+impl<'a, A: hil::time::Alarm + 'a> SDCardClient for FatFs<'a, A> {
+    fn card_detection_changed(&self, _installed: bool) {
+        self.unmount();
+    }
+
+    fn init_done(&self, _block_size: u32, _total_size: u64) {
+        if self.step.get() == Step::Initialize {
+            self.run();
+        }
+    }
+
+    fn read_done(&self, data: &'static mut [u8], _len: usize) {
+        self.buffer.replace(data);
+        self.buffer_sector.set(Some(self.read_sector.get()));
+        self.run();
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8]) {
+        self.buffer.replace(buffer);
+        let copies = self.volume.get().map_or(1, |volume| {
+            if self.buffer_sector.get().map_or(false, |s| volume.is_fat_sector(s)) {
+                volume.fat_count
+            } else {
+                1
+            }
+        });
+        if self.fat_copy.get() + 1 < copies {
+            self.fat_copy.set(self.fat_copy.get() + 1);
+        } else {
+            self.fat_copy.set(0);
+            self.dirty.set(false);
+        }
+        self.run();
+    }
+
+    fn error(&self, _error: u32, buffer: Option<&'static mut [u8]>) {
+        buffer.map(|buffer| self.buffer.replace(buffer));
+
+        // Whatever was cached may not have reached the card.
+        self.buffer_sector.set(None);
+        self.dirty.set(false);
+        self.fat_copy.set(0);
+        if self.step.get() != Step::Idle {
+            self.finish(ReturnCode::FAIL, 0);
+        }
+    }
+}
+
+/// Provide an interface for userland.
+impl<'a, A: hil::time::Alarm + 'a> Driver for FatFs<'a, A> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The path of the next `open`, `list` or `delete`.
+    /// - `1`: The buffer data is read in to and written from.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.path = slice,
+                    1 => app.buffer = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Setup a callback for when an operation finishes.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self.apps
+                .enter(app_id, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return SUCCESS if this driver is included on the platform.
+    /// - `1`: Open the file at the path, with the `OPEN_*` flags in `arg1`.
+    /// - `2`: Read `arg2` bytes from the file `arg1`.
+    /// - `3`: Write `arg2` bytes to the file `arg1`.
+    /// - `4`: Move the position of the file `arg1` to `arg2`.
+    /// - `5`: Close the file `arg1`.
+    /// - `6`: List the entry of the directory at the path, starting at index
+    ///        `arg1`.
+    /// - `7`: Delete the file at the path.
+    /// - `8`: Return the size of the file `arg1`.
+    fn command(&self, command_num: usize, arg1: usize, arg2: usize, appid: AppId) -> ReturnCode {
+        let request = |operation, length| Request {
+            operation: operation,
+            argument: arg1,
+            length: length,
+        };
+        let generation = self.generation.get();
+        match command_num {
+            0 => /* This driver exists. */ ReturnCode::SUCCESS,
+
+            1 => self.app_operation(appid, request(Operation::Open, 0)),
+
+            2 => self.app_operation(appid, request(Operation::Read, arg2)),
+
+            3 => self.app_operation(appid, request(Operation::Write, arg2)),
+
+            4 => self.with_file(appid, arg1, |file| match *file {
+                Some(ref mut file) if file.generation == generation => {
+                    if arg2 > file.size as usize {
+                        return ReturnCode::EINVAL;
+                    }
+                    file.position = arg2 as u32;
+                    ReturnCode::SUCCESS
+                }
+                _ => ReturnCode::EINVAL,
+            }),
+
+            5 => self.with_file(appid, arg1, |file| {
+                if file.is_none() {
+                    return ReturnCode::EINVAL;
+                }
+                *file = None;
+                ReturnCode::SUCCESS
+            }),
+
+            6 => self.app_operation(appid, request(Operation::List, 0)),
+
+            7 => self.app_operation(appid, request(Operation::Delete, 0)),
+
+            8 => self.with_file(appid, arg1, |file| match *file {
+                Some(file) if file.generation == generation => ReturnCode::SuccessWithValue {
+                    value: file.size as usize,
+                },
+                _ => ReturnCode::EINVAL,
+            }),
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
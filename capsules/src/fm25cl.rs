@@ -37,6 +37,21 @@
 //! this driver to work with capsules like the `nonvolatile_storage_driver`
 //! that provide virtualization and a userspace interface. The second is a
 //! custom interface that exposes other chip-specific functions.
+//!
+//! Reads and writes longer than the SPI buffers are split in to several
+//! transfers, so any length can be accessed with one call. Only one access
+//! can be in progress at a time, others return `EBUSY`.
+//!
+//! To back the `nonvolatile_storage_driver` with the FRAM:
+//!
+//! ```rust
+//! let nonvolatile_storage = static_init!(
+//!     capsules::nonvolatile_storage_driver::NonvolatileStorage<'static>,
+//!     capsules::nonvolatile_storage_driver::NonvolatileStorage::new(
+//!         fm25cl, kernel::Grant::create(), 0x1000, 0x1000, 0, 0x1000,
+//!         &mut capsules::nonvolatile_storage_driver::BUFFER));
+//! hil::nonvolatile_storage::NonvolatileStorage::set_client(fm25cl, nonvolatile_storage);
+//! ```
 
 use core::cell::Cell;
 use core::cmp;
@@ -95,8 +110,9 @@ pub struct FM25CL<'a, S: hil::spi::SpiMasterDevice + 'a> {
     client: Cell<Option<&'static hil::nonvolatile_storage::NonvolatileStorageClient>>,
     client_custom: Cell<Option<&'static FM25CLClient>>,
     client_buffer: TakeCell<'static, [u8]>, // Store buffer and state for passing back to client
-    client_write_address: Cell<u16>,
-    client_write_len: Cell<u16>,
+    client_address: Cell<usize>,
+    client_len: Cell<usize>,
+    client_offset: Cell<usize>,
 }
 
 impl<'a, S: hil::spi::SpiMasterDevice + 'a> FM25CL<'a, S> {
@@ -114,8 +130,9 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a> FM25CL<'a, S> {
             client: Cell::new(None),
             client_custom: Cell::new(None),
             client_buffer: TakeCell::empty(),
-            client_write_address: Cell::new(0),
-            client_write_len: Cell::new(0),
+            client_address: Cell::new(0),
+            client_len: Cell::new(0),
+            client_offset: Cell::new(0),
         }
     }
 
@@ -132,50 +149,113 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a> FM25CL<'a, S> {
         );
     }
 
-    pub fn write(&self, address: u16, buffer: &'static mut [u8], len: u16) -> ReturnCode {
-        self.configure_spi();
+    /// Check that an access of `len` bytes at `address` to `buffer` can be
+    /// started.
+    fn check_transfer(&self, address: u16, buffer: &[u8], len: u16) -> ReturnCode {
+        if self.state.get() != State::Idle {
+            ReturnCode::EBUSY
+        } else if len as usize > buffer.len() || address as usize + len as usize > 0x10000 {
+            ReturnCode::EINVAL
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
 
-        self.txbuffer
-            .take()
-            .map_or(ReturnCode::ERESERVE, move |txbuffer| {
-                txbuffer[0] = Opcodes::WriteEnable as u8;
+    /// Save the user buffer for an access of `len` bytes at `address`.
+    fn start_transfer(&self, address: u16, buffer: &'static mut [u8], len: u16) {
+        // Need to save the buffer passed to us so we can give it back.
+        self.client_buffer.replace(buffer);
+        // Also save address and len for the transfers. Accesses longer than
+        // the SPI buffers are split in to several transfers.
+        self.client_address.set(address as usize);
+        self.client_len.set(len as usize);
+        self.client_offset.set(0);
+    }
 
-                let write_len = cmp::min(txbuffer.len(), len as usize);
+    /// Hand the user buffer back with the error if the first SPI transfer
+    /// could not be started.
+    fn started(&self, rc: ReturnCode) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if rc == ReturnCode::SUCCESS {
+            (rc, None)
+        } else {
+            self.state.set(State::Idle);
+            (rc, self.client_buffer.take())
+        }
+    }
 
-                // Need to save the buffer passed to us so we can give it back.
-                self.client_buffer.replace(buffer);
-                // Also save address and len for the actual write.
-                self.client_write_address.set(address);
-                self.client_write_len.set(write_len as u16);
+    /// Number of bytes the next transfer handles with an SPI buffer of
+    /// `buffer_len` bytes, three of which are used for the opcode and address.
+    fn chunk_len(&self, buffer_len: usize) -> usize {
+        cmp::min(buffer_len - 3, self.client_len.get() - self.client_offset.get())
+    }
 
-                self.state.set(State::WriteEnable);
-                self.spi.read_write_bytes(txbuffer, None, 1)
-            })
+    /// Send the opcode and address of the next transfer.
+    fn set_address(&self, buffer: &mut [u8], opcode: Opcodes) {
+        let address = self.client_address.get() + self.client_offset.get();
+        buffer[0] = opcode as u8;
+        buffer[1] = ((address >> 8) & 0xFF) as u8;
+        buffer[2] = (address & 0xFF) as u8;
     }
 
-    pub fn read(&self, address: u16, buffer: &'static mut [u8], len: u16) -> ReturnCode {
+    /// Write `len` bytes of `buffer` to `address`. If the write cannot be
+    /// started, the buffer is handed back with the error.
+    pub fn write(
+        &self,
+        address: u16,
+        buffer: &'static mut [u8],
+        len: u16,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        let rc = self.check_transfer(address, buffer, len);
+        if rc != ReturnCode::SUCCESS {
+            return (rc, Some(buffer));
+        }
+        let txbuffer = match self.txbuffer.take() {
+            Some(txbuffer) => txbuffer,
+            None => return (ReturnCode::ERESERVE, Some(buffer)),
+        };
+
         self.configure_spi();
+        self.start_transfer(address, buffer, len);
 
-        self.txbuffer
-            .take()
-            .map_or(ReturnCode::ERESERVE, |txbuffer| {
-                self.rxbuffer
-                    .take()
-                    .map_or(ReturnCode::ERESERVE, move |rxbuffer| {
-                        txbuffer[0] = Opcodes::ReadMemory as u8;
-                        txbuffer[1] = ((address >> 8) & 0xFF) as u8;
-                        txbuffer[2] = (address & 0xFF) as u8;
+        txbuffer[0] = Opcodes::WriteEnable as u8;
+
+        self.state.set(State::WriteEnable);
+        let rc = self.spi.read_write_bytes(txbuffer, None, 1);
+        self.started(rc)
+    }
 
-                        // Save the user buffer for later
-                        self.client_buffer.replace(buffer);
+    /// Read `len` bytes at `address` in to `buffer`. If the read cannot be
+    /// started, the buffer is handed back with the error.
+    pub fn read(
+        &self,
+        address: u16,
+        buffer: &'static mut [u8],
+        len: u16,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        let rc = self.check_transfer(address, buffer, len);
+        if rc != ReturnCode::SUCCESS {
+            return (rc, Some(buffer));
+        }
+        let (txbuffer, rxbuffer) = match (self.txbuffer.take(), self.rxbuffer.take()) {
+            (Some(txbuffer), Some(rxbuffer)) => (txbuffer, rxbuffer),
+            (txbuffer, rxbuffer) => {
+                txbuffer.map(|txbuffer| self.txbuffer.replace(txbuffer));
+                rxbuffer.map(|rxbuffer| self.rxbuffer.replace(rxbuffer));
+                return (ReturnCode::ERESERVE, Some(buffer));
+            }
+        };
 
-                        let read_len = cmp::min(rxbuffer.len() - 3, len as usize);
+        self.configure_spi();
+        self.start_transfer(address, buffer, len);
 
-                        self.state.set(State::ReadMemory);
-                        self.spi
-                            .read_write_bytes(txbuffer, Some(rxbuffer), read_len + 3)
-                    })
-            })
+        self.set_address(txbuffer, Opcodes::ReadMemory);
+        let read_len = self.chunk_len(cmp::min(txbuffer.len(), rxbuffer.len()));
+
+        self.state.set(State::ReadMemory);
+        let rc = self
+            .spi
+            .read_write_bytes(txbuffer, Some(rxbuffer), read_len + 3);
+        self.started(rc)
     }
 }
 
@@ -206,15 +286,13 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a> hil::spi::SpiMasterClient for FM25CL
                 self.state.set(State::WriteMemory);
 
                 self.client_buffer.map(move |buffer| {
-                    write_buffer[0] = Opcodes::WriteMemory as u8;
-                    write_buffer[1] = ((self.client_write_address.get() >> 8) & 0xFF) as u8;
-                    write_buffer[2] = (self.client_write_address.get() & 0xFF) as u8;
+                    self.set_address(write_buffer, Opcodes::WriteMemory);
 
-                    let write_len =
-                        cmp::min(write_buffer.len(), self.client_write_len.get() as usize);
+                    let offset = self.client_offset.get();
+                    let write_len = self.chunk_len(write_buffer.len());
 
                     for i in 0..write_len {
-                        write_buffer[(i + 3) as usize] = buffer[i as usize];
+                        write_buffer[i + 3] = buffer[offset + i];
                     }
 
                     self.spi
@@ -222,9 +300,19 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a> hil::spi::SpiMasterClient for FM25CL
                 });
             }
             State::WriteMemory => {
-                self.state.set(State::Idle);
+                let write_len = self.chunk_len(write_buffer.len());
+                self.client_offset.set(self.client_offset.get() + write_len);
+
+                if self.client_offset.get() < self.client_len.get() {
+                    // The write enable latch is cleared after each write, so
+                    // set it again for the next part.
+                    self.state.set(State::WriteEnable);
+                    write_buffer[0] = Opcodes::WriteEnable as u8;
+                    self.spi.read_write_bytes(write_buffer, read_buffer, 1);
+                    return;
+                }
 
-                let write_len = cmp::min(write_buffer.len(), self.client_write_len.get() as usize);
+                self.state.set(State::Idle);
 
                 // Replace these buffers
                 self.txbuffer.replace(write_buffer);
@@ -236,28 +324,42 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a> hil::spi::SpiMasterClient for FM25CL
                 self.client_buffer.take().map(move |buffer| {
                     self.client
                         .get()
-                        .map(move |client| client.write_done(buffer, write_len));
+                        .map(move |client| client.write_done(buffer, self.client_len.get()));
                 });
             }
             State::ReadMemory => {
-                self.state.set(State::Idle);
+                read_buffer.map(move |read_buffer| {
+                    self.client_buffer.map(|buffer| {
+                        let offset = self.client_offset.get();
+                        let read_len = len - 3;
 
-                // Replace the TX buffer
-                self.txbuffer.replace(write_buffer);
+                        for i in 0..read_len {
+                            buffer[offset + i] = read_buffer[i + 3];
+                        }
 
-                read_buffer.map(|read_buffer| {
-                    self.client_buffer.take().map(move |buffer| {
-                        let read_len = cmp::min(buffer.len(), len);
+                        self.client_offset.set(offset + read_len);
+                    });
 
-                        for i in 0..(read_len - 3) {
-                            buffer[i] = read_buffer[i + 3];
-                        }
+                    if self.client_offset.get() < self.client_len.get() {
+                        // Read the next part.
+                        self.set_address(write_buffer, Opcodes::ReadMemory);
+                        let read_len =
+                            self.chunk_len(cmp::min(write_buffer.len(), read_buffer.len()));
+                        self.spi
+                            .read_write_bytes(write_buffer, Some(read_buffer), read_len + 3);
+                        return;
+                    }
 
-                        self.rxbuffer.replace(read_buffer);
+                    self.state.set(State::Idle);
 
+                    // Replace the buffers
+                    self.txbuffer.replace(write_buffer);
+                    self.rxbuffer.replace(read_buffer);
+
+                    self.client_buffer.take().map(move |buffer| {
                         self.client
                             .get()
-                            .map(move |client| client.read_done(buffer, read_len - 3));
+                            .map(move |client| client.read_done(buffer, self.client_len.get()));
                     });
                 });
             }
@@ -298,11 +400,27 @@ impl<'a, S: hil::spi::SpiMasterDevice + 'a> hil::nonvolatile_storage::Nonvolatil
         self.client.set(Some(client));
     }
 
-    fn read(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode {
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if address > 0xFFFF || length > 0xFFFF {
+            return (ReturnCode::EINVAL, Some(buffer));
+        }
         self.read(address as u16, buffer, length as u16)
     }
 
-    fn write(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode {
+    fn write(
+        &self,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if address > 0xFFFF || length > 0xFFFF {
+            return (ReturnCode::EINVAL, Some(buffer));
+        }
         self.write(address as u16, buffer, length as u16)
     }
 }
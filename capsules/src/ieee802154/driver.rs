@@ -6,6 +6,7 @@
 
 use core::cell::Cell;
 use core::cmp::min;
+use energy::PeripheralUsage;
 use ieee802154::{device, framer};
 use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
 use kernel::common::take_cell::{MapCell, TakeCell};
@@ -187,6 +188,10 @@ pub struct RadioDriver<'a> {
     /// ID of app whose transmission request is being processed.
     current_app: Cell<Option<AppId>>,
 
+    /// Energy accounting that transmissions are reported to, and the index of
+    /// the radio in the board's peripheral current table.
+    usage: Cell<Option<(&'static PeripheralUsage, usize)>>,
+
     /// Buffer that stores the IEEE 802.15.4 frame to be transmitted.
     kernel_tx: TakeCell<'static, [u8]>,
 }
@@ -205,10 +210,26 @@ impl<'a> RadioDriver<'a> {
             num_keys: Cell::new(0),
             apps: grant,
             current_app: Cell::new(None),
+            usage: Cell::new(None),
             kernel_tx: TakeCell::new(kernel_tx),
         }
     }
 
+    /// Report to energy accounting which app the radio transmits for.
+    pub fn set_usage_reporting(&self, usage: &'static PeripheralUsage, peripheral: usize) {
+        self.usage.set(Some((usage, peripheral)));
+    }
+
+    /// Set the app whose transmission is in progress, or `None` once it is
+    /// done.
+    fn set_current_app(&self, appid: Option<AppId>) {
+        self.current_app.set(appid);
+        self.usage.get().map(|(usage, peripheral)| match appid {
+            Some(appid) => usage.peripheral_active(peripheral, appid),
+            None => usage.peripheral_idle(peripheral),
+        });
+    }
+
     // Neighbor management functions
 
     /// Add a new neighbor to the end of the list if there is still space
@@ -458,7 +479,7 @@ impl<'a> RadioDriver<'a> {
                 result
             });
             if result == ReturnCode::SUCCESS {
-                self.current_app.set(Some(appid));
+                self.set_current_app(Some(appid));
             }
             result
         })
@@ -803,7 +824,7 @@ impl<'a> device::TxClient for RadioDriver<'a> {
                     .map(|mut cb| cb.schedule(result.into(), acked as usize, 0));
             });
         });
-        self.current_app.set(None);
+        self.set_current_app(None);
         self.do_next_tx_async();
     }
 }
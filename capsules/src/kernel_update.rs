@@ -0,0 +1,781 @@
+//! A/B kernel updates with a boot record for the bootloader.
+//!
+//! A new kernel image is written into a staging slot in flash while the
+//! current kernel keeps running. The image can arrive over any transport, for
+//! example the console, USB or the radio. An app receives it and hands it to
+//! this capsule in chunks. Once the whole image is written, the staging slot
+//! is read back and its SHA-256 digest is checked against the digest the app
+//! provides. Only if they match, a boot record is written that tells the
+//! bootloader to swap the staging slot with the running kernel on the next
+//! reset.
+//!
+//! The new kernel starts on trial. It has to confirm itself, either from the
+//! board once it is initialized, or from an app after its own checks. The
+//! bootloader counts the boots of a kernel on trial, and if it was not
+//! confirmed within the number of boots allowed by the record, it swaps the
+//! images again to roll back to the previous kernel.
+//!
+//! Boot record
+//! -----------
+//!
+//! The record is stored at the start of its own flash page. All fields are
+//! little endian `u32`s, except the digest.
+//!
+//! ```text
+//! Offset  Field
+//!  0      Magic, 0x52424B54 ("TKBR")
+//!  4      Version, 1
+//!  8      State: 0 none, 1 pending, 2 testing, 3 confirmed, 4 rolled back
+//! 12      Length of the image in bytes
+//! 16      Number of boots of the kernel on trial
+//! 20      Maximum number of boots before rolling back
+//! 24      Address of the running kernel
+//! 28      Address of the staging slot
+//! 32      SHA-256 digest of the image (32 bytes)
+//! 64      Checksum: bitwise NOT of the wrapping sum of the words above
+//! ```
+//!
+//! Status
+//! ------
+//!
+//! This capsule only writes the staging slot and the boot record. The swap,
+//! the boot count and the rollback are up to the bootloader, and the
+//! bootloaders shipped in `boards/hail/bootloader` and `boards/imix/bootloader`
+//! do not implement them yet. No board instantiates this capsule or calls
+//! `confirm()` for that reason: with those bootloaders a written record is
+//! ignored and the running kernel keeps booting. Adding the record protocol
+//! to the bootloader and wiring this capsule into a board are left for later.
+//!
+//! A bootloader that supports the record has to do the following on each
+//! reset:
+//!
+//! - State pending: swap the contents of the kernel and the staging slot,
+//!   set the state to testing and the boot count to zero.
+//! - State testing: if the boot count reached the maximum, swap the slots
+//!   back and set the state to rolled back. Otherwise increment the count.
+//! - Any other state, or a record with a bad magic or checksum: boot the
+//!   kernel normally.
+//!
+//! Usage
+//! -----
+//!
+//! The staging slot and the record page must not overlap the kernel or the
+//! apps, and pages must be large enough to hold the 68 byte record. With
+//! `virtual_flash`, the `FlashUser` of this capsule should only be allowed to
+//! modify them. Only the app with the package name chosen by the board may
+//! start, confirm or abort updates.
+//!
+//! ```
+//! let kernel_update = static_init!(
+//!     capsules::kernel_update::KernelUpdate<'static, FlashUser<'static, FLASHCALW>>,
+//!     capsules::kernel_update::KernelUpdate::new(
+//!         virtual_flash,
+//!         kernel::Grant::create(),
+//!         &mut PAGEBUFFER,
+//!         0x10000,  // Address of the running kernel.
+//!         384,      // First page of the staging slot.
+//!         120,      // Number of pages in the staging slot.
+//!         504,      // Page of the boot record.
+//!         3,        // Boots allowed before rolling back.
+//!         "updater")); // Package name of the app allowed to update.
+//! hil::flash::HasClient::set_client(virtual_flash, kernel_update);
+//!
+//! // Once the board is initialized.
+//! kernel_update.confirm();
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! - Allow 0: image data to write.
+//! - Allow 1: the expected SHA-256 digest of the image, 32 bytes.
+//! - Subscribe 0: called when an operation finishes, with the operation
+//!   (2 write, 3 finish, 4 confirm) and the result. Finishing fails with
+//!   `FAIL` if the digest does not match.
+//! - Command 0: check if the driver exists.
+//! - Command 1: start an update with an image of `arg1` bytes.
+//! - Command 2: write the next `arg1` bytes of the image from the data buffer.
+//! - Command 3: verify the image and write the boot record.
+//! - Command 4: confirm the running kernel.
+//! - Command 5: the state of the boot record.
+//! - Command 6: abort the update.
+//!
+//! Commands 1, 4 and 6 return `ERESERVE` for apps other than the one chosen
+//! by the board. The data buffer is checked again before each part of a
+//! chunk is copied; if it was unallowed the update fails, and if it became
+//! too short the update is aborted with `ESIZE`.
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+use kernel::common::take_cell::TakeCell;
+use kernel::hil;
+use sha256::{Sha256, DIGEST_LENGTH};
+
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = 0x50005;
+
+/// Marks a valid boot record.
+const RECORD_MAGIC: u32 = 0x52424B54;
+
+/// Version of the boot record format.
+const RECORD_VERSION: u32 = 1;
+
+/// Offsets of the fields of the boot record.
+const RECORD_STATE: usize = 8;
+const RECORD_IMAGE_LENGTH: usize = 12;
+const RECORD_BOOT_COUNT: usize = 16;
+const RECORD_MAX_BOOTS: usize = 20;
+const RECORD_KERNEL_ADDRESS: usize = 24;
+const RECORD_STAGING_ADDRESS: usize = 28;
+const RECORD_DIGEST: usize = 32;
+const RECORD_CHECKSUM: usize = 64;
+
+/// State of the boot record, shared with the bootloader.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BootState {
+    /// There is no valid record, or no update was ever made.
+    None = 0,
+    /// A verified image is waiting in the staging slot.
+    Pending = 1,
+    /// The running kernel was just installed and has not confirmed itself.
+    Testing = 2,
+    /// The running kernel was installed by an update and confirmed itself.
+    Confirmed = 3,
+    /// The last update did not confirm itself and was rolled back.
+    RolledBack = 4,
+}
+
+impl BootState {
+    fn from_u32(state: u32) -> BootState {
+        match state {
+            1 => BootState::Pending,
+            2 => BootState::Testing,
+            3 => BootState::Confirmed,
+            4 => BootState::RolledBack,
+            _ => BootState::None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operation {
+    Write = 2,
+    Finish = 3,
+    Confirm = 4,
+}
+
+/// What the capsule is waiting for the flash to finish.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Idle,
+    /// Writing a page of the image.
+    Writing,
+    /// Reading back a page of the staging slot to compute its digest.
+    Verifying { page: usize },
+    /// Writing the record that tells the bootloader to install the image.
+    WritingRecord,
+    /// Reading the record before confirming the running kernel.
+    ReadingRecord,
+    /// Writing the record that confirms the running kernel.
+    Confirming,
+}
+
+pub struct App {
+    callback: Option<Callback>,
+    data: Option<AppSlice<Shared, u8>>,
+    digest: Option<AppSlice<Shared, u8>>,
+}
+
+impl Default for App {
+    fn default() -> App {
+        App {
+            callback: None,
+            data: None,
+            digest: None,
+        }
+    }
+}
+
+pub struct KernelUpdate<'a, F: hil::flash::Flash + 'static> {
+    /// The flash the staging slot and the record are in.
+    driver: &'a F,
+    apps: Grant<App>,
+    /// Page of image data that is not written yet, or a page being read or
+    /// written.
+    pagebuffer: TakeCell<'static, F::Page>,
+    page_size: usize,
+    /// Address of the running kernel, for the bootloader.
+    kernel_address: usize,
+    /// First page and number of pages of the staging slot.
+    staging_page: usize,
+    staging_pages: usize,
+    /// Page of the boot record.
+    record_page: usize,
+    /// Boots allowed before the bootloader rolls an update back.
+    max_boots: u32,
+    /// Package name of the only app allowed to run updates.
+    update_app: &'static str,
+    state: Cell<State>,
+    /// State of the boot record when it was last read or written.
+    boot_state: Cell<BootState>,
+    /// The app running the update, if one is in progress.
+    current_app: Cell<Option<AppId>>,
+    /// The app that asked to confirm the kernel, if it was not the kernel.
+    confirm_app: Cell<Option<AppId>>,
+    image_length: Cell<usize>,
+    /// Bytes of the image received so far, including those in `pagebuffer`.
+    received: Cell<usize>,
+    /// Bytes of the chunk being written that were copied to `pagebuffer`,
+    /// and the length of the chunk.
+    chunk_offset: Cell<usize>,
+    chunk_length: Cell<usize>,
+    /// Digest of the image read back so far, and the digest it should have.
+    sha: Cell<Sha256>,
+    digest: Cell<[u8; DIGEST_LENGTH]>,
+}
+
+impl<'a, F: hil::flash::Flash + 'a> KernelUpdate<'a, F> {
+    pub fn new(
+        driver: &'a F,
+        grant: Grant<App>,
+        pagebuffer: &'static mut F::Page,
+        kernel_address: usize,
+        staging_page: usize,
+        staging_pages: usize,
+        record_page: usize,
+        max_boots: u32,
+        update_app: &'static str,
+    ) -> KernelUpdate<'a, F> {
+        let page_size = pagebuffer.as_mut().len();
+        KernelUpdate {
+            driver: driver,
+            apps: grant,
+            pagebuffer: TakeCell::new(pagebuffer),
+            page_size: page_size,
+            kernel_address: kernel_address,
+            staging_page: staging_page,
+            staging_pages: staging_pages,
+            record_page: record_page,
+            max_boots: max_boots,
+            update_app: update_app,
+            state: Cell::new(State::Idle),
+            boot_state: Cell::new(BootState::None),
+            current_app: Cell::new(None),
+            confirm_app: Cell::new(None),
+            image_length: Cell::new(0),
+            received: Cell::new(0),
+            chunk_offset: Cell::new(0),
+            chunk_length: Cell::new(0),
+            sha: Cell::new(Sha256::new()),
+            digest: Cell::new([0; DIGEST_LENGTH]),
+        }
+    }
+
+    /// Confirm the running kernel, so the bootloader does not roll it back.
+    /// Boards should call this once the kernel is initialized, unless an app
+    /// confirms the kernel after checks of its own. This also loads the state
+    /// of the boot record.
+    pub fn confirm(&self) -> ReturnCode {
+        if self.state.get() != State::Idle || self.current_app.get().is_some() {
+            return ReturnCode::EBUSY;
+        }
+        self.pagebuffer
+            .take()
+            .map_or(ReturnCode::ERESERVE, |pagebuffer| {
+                self.state.set(State::ReadingRecord);
+                let rc = self.put_back(self.driver.read_page(self.record_page, pagebuffer));
+                if rc != ReturnCode::SUCCESS {
+                    self.state.set(State::Idle);
+                }
+                rc
+            })
+    }
+
+    /// Put the page buffer back if a flash operation could not be started.
+    fn put_back(&self, result: (ReturnCode, Option<&'static mut F::Page>)) -> ReturnCode {
+        let (rc, pagebuffer) = result;
+        pagebuffer.map(|pagebuffer| self.pagebuffer.replace(pagebuffer));
+        rc
+    }
+
+    /// The state of the boot record when it was last read or written.
+    pub fn get_boot_state(&self) -> BootState {
+        self.boot_state.get()
+    }
+
+    /// Whether `appid` is the app the board allows to run updates.
+    fn is_update_app(&self, appid: AppId) -> bool {
+        appid.get_package_name() == Some(self.update_app)
+    }
+
+    /// Whether an update is in progress for an app that still exists.
+    fn update_in_progress(&self) -> bool {
+        self.current_app.get().map_or(false, |appid| {
+            self.apps.enter(appid, |_, _| ()).is_ok()
+        })
+    }
+
+    fn start(&self, appid: AppId, image_length: usize) -> ReturnCode {
+        if self.state.get() != State::Idle || self.update_in_progress() {
+            return ReturnCode::EBUSY;
+        }
+        if image_length == 0 || image_length > self.staging_pages * self.page_size {
+            return ReturnCode::ESIZE;
+        }
+
+        self.current_app.set(Some(appid));
+        self.image_length.set(image_length);
+        self.received.set(0);
+        ReturnCode::SUCCESS
+    }
+
+    fn write(&self, appid: AppId, length: usize) -> ReturnCode {
+        if self.current_app.get() != Some(appid) {
+            return ReturnCode::ERESERVE;
+        }
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        let data_length = self.apps
+            .enter(appid, |app, _| app.data.as_ref().map_or(0, |data| data.len()))
+            .unwrap_or(0);
+        if length == 0 || length > data_length
+            || self.received.get() + length > self.image_length.get()
+        {
+            return ReturnCode::ESIZE;
+        }
+
+        self.chunk_offset.set(0);
+        self.chunk_length.set(length);
+        self.state.set(State::Writing);
+        self.continue_write();
+        ReturnCode::SUCCESS
+    }
+
+    /// Copy the rest of the chunk into the page buffer, writing the page each
+    /// time it fills up.
+    fn continue_write(&self) {
+        self.pagebuffer.take().map(|pagebuffer| {
+            let page_index = self.received.get() % self.page_size;
+            let length = cmp::min(
+                self.page_size - page_index,
+                self.chunk_length.get() - self.chunk_offset.get(),
+            );
+            let chunk_offset = self.chunk_offset.get();
+            // The app may have allowed another buffer since the write
+            // started, so check it again before copying.
+            let rc = self.current_app.get().map_or(ReturnCode::FAIL, |appid| {
+                self.apps
+                    .enter(appid, |app, _| {
+                        app.data.as_ref().map_or(ReturnCode::FAIL, |data| {
+                            let data = data.as_ref();
+                            if data.len() < chunk_offset + length {
+                                return ReturnCode::ESIZE;
+                            }
+                            for i in 0..length {
+                                pagebuffer.as_mut()[page_index + i] = data[chunk_offset + i];
+                            }
+                            ReturnCode::SUCCESS
+                        })
+                    })
+                    .unwrap_or(ReturnCode::FAIL)
+            });
+            if rc != ReturnCode::SUCCESS {
+                self.pagebuffer.replace(pagebuffer);
+                self.abort_update(Operation::Write, rc);
+                return;
+            }
+            self.chunk_offset.set(chunk_offset + length);
+            self.received.set(self.received.get() + length);
+
+            if page_index + length == self.page_size {
+                let page = self.staging_page + (self.received.get() - 1) / self.page_size;
+                let rc = self.put_back(self.driver.write_page(page, pagebuffer));
+                if rc != ReturnCode::SUCCESS {
+                    self.abort_update(Operation::Write, ReturnCode::FAIL);
+                }
+            } else {
+                // The chunk is done but the page is not full yet.
+                self.pagebuffer.replace(pagebuffer);
+                self.state.set(State::Idle);
+                self.app_done(Operation::Write, ReturnCode::SUCCESS);
+            }
+        });
+    }
+
+    fn finish(&self, appid: AppId) -> ReturnCode {
+        if self.current_app.get() != Some(appid) {
+            return ReturnCode::ERESERVE;
+        }
+        if self.state.get() != State::Idle {
+            return ReturnCode::EBUSY;
+        }
+        if self.received.get() != self.image_length.get() {
+            return ReturnCode::ESIZE;
+        }
+        let digest = self.apps
+            .enter(appid, |app, _| {
+                app.digest.as_ref().and_then(|digest| {
+                    if digest.len() != DIGEST_LENGTH {
+                        return None;
+                    }
+                    let mut expected = [0; DIGEST_LENGTH];
+                    expected.copy_from_slice(digest.as_ref());
+                    Some(expected)
+                })
+            })
+            .unwrap_or(None);
+        match digest {
+            Some(digest) => self.digest.set(digest),
+            None => return ReturnCode::EINVAL,
+        }
+
+        self.pagebuffer
+            .take()
+            .map_or(ReturnCode::ERESERVE, |pagebuffer| {
+                let page_index = self.received.get() % self.page_size;
+                let rc = if page_index != 0 {
+                    // Write the last, partial page first.
+                    for i in page_index..self.page_size {
+                        pagebuffer.as_mut()[i] = 0xFF;
+                    }
+                    self.state.set(State::Writing);
+                    let page = self.staging_page + self.received.get() / self.page_size;
+                    self.put_back(self.driver.write_page(page, pagebuffer))
+                } else {
+                    self.verify(0, pagebuffer)
+                };
+                if rc != ReturnCode::SUCCESS {
+                    self.state.set(State::Idle);
+                }
+                rc
+            })
+    }
+
+    /// Read back the given page of the staging slot to compute the digest of
+    /// the image.
+    fn verify(&self, page: usize, pagebuffer: &'static mut F::Page) -> ReturnCode {
+        if page == 0 {
+            self.sha.set(Sha256::new());
+        }
+        self.state.set(State::Verifying { page: page });
+        self.put_back(self.driver.read_page(self.staging_page + page, pagebuffer))
+    }
+
+    /// Write the record that tells the bootloader to install the staged
+    /// image.
+    fn write_pending_record(&self, pagebuffer: &'static mut F::Page) {
+        {
+            let record = pagebuffer.as_mut();
+            for byte in record.iter_mut() {
+                *byte = 0xFF;
+            }
+            write_u32(record, 0, RECORD_MAGIC);
+            write_u32(record, 4, RECORD_VERSION);
+            write_u32(record, RECORD_STATE, BootState::Pending as u32);
+            write_u32(record, RECORD_IMAGE_LENGTH, self.image_length.get() as u32);
+            write_u32(record, RECORD_BOOT_COUNT, 0);
+            write_u32(record, RECORD_MAX_BOOTS, self.max_boots);
+            write_u32(record, RECORD_KERNEL_ADDRESS, self.kernel_address as u32);
+            write_u32(
+                record,
+                RECORD_STAGING_ADDRESS,
+                (self.staging_page * self.page_size) as u32,
+            );
+            record[RECORD_DIGEST..RECORD_DIGEST + DIGEST_LENGTH]
+                .copy_from_slice(&self.digest.get());
+            let checksum = record_checksum(record);
+            write_u32(record, RECORD_CHECKSUM, checksum);
+        }
+
+        self.state.set(State::WritingRecord);
+        let rc = self.put_back(self.driver.write_page(self.record_page, pagebuffer));
+        if rc != ReturnCode::SUCCESS {
+            self.abort_update(Operation::Finish, ReturnCode::FAIL);
+        }
+    }
+
+    /// End the update after an error, and tell the app.
+    fn abort_update(&self, operation: Operation, result: ReturnCode) {
+        self.state.set(State::Idle);
+        self.app_done(operation, result);
+        self.current_app.set(None);
+    }
+
+    /// Tell the app running the update that an operation finished.
+    fn app_done(&self, operation: Operation, result: ReturnCode) {
+        self.current_app.get().map(|appid| {
+            let _ = self.apps.enter(appid, |app, _| {
+                app.callback.map(|mut cb| {
+                    cb.schedule(operation as usize, usize::from(result), 0);
+                });
+            });
+        });
+    }
+
+    /// Tell the app that asked to confirm the kernel, if any, that it is done.
+    fn confirm_done(&self, result: ReturnCode) {
+        self.state.set(State::Idle);
+        self.confirm_app.get().map(|appid| {
+            self.confirm_app.set(None);
+            let _ = self.apps.enter(appid, |app, _| {
+                app.callback.map(|mut cb| {
+                    cb.schedule(Operation::Confirm as usize, usize::from(result), 0);
+                });
+            });
+        });
+    }
+}
+
+/// Store `value` little endian at `offset`.
+fn write_u32(buffer: &mut [u8], offset: usize, value: u32) {
+    for i in 0..4 {
+        buffer[offset + i] = (value >> (8 * i)) as u8;
+    }
+}
+
+/// Load a little endian value from `offset`.
+fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+    (0..4).fold(0, |value, i| value | (buffer[offset + i] as u32) << (8 * i))
+}
+
+/// The checksum of the fields before it in a boot record.
+fn record_checksum(record: &[u8]) -> u32 {
+    !(0..RECORD_CHECKSUM / 4).fold(0u32, |sum, i| sum.wrapping_add(read_u32(record, i * 4)))
+}
+
+/// The state of the boot record in `record`, or `None` if it is not valid.
+fn record_state(record: &[u8]) -> BootState {
+    if read_u32(record, 0) != RECORD_MAGIC || read_u32(record, 4) != RECORD_VERSION
+        || read_u32(record, RECORD_CHECKSUM) != record_checksum(record)
+    {
+        BootState::None
+    } else {
+        BootState::from_u32(read_u32(record, RECORD_STATE))
+    }
+}
+
+impl<'a, F: hil::flash::Flash + 'a> hil::flash::Client<F> for KernelUpdate<'a, F> {
+    fn read_complete(&self, pagebuffer: &'static mut F::Page, error: hil::flash::Error) {
+        match self.state.get() {
+            State::Verifying { page } => {
+                if error != hil::flash::Error::CommandComplete {
+                    self.pagebuffer.replace(pagebuffer);
+                    self.abort_update(Operation::Finish, ReturnCode::FAIL);
+                    return;
+                }
+
+                let offset = page * self.page_size;
+                let length = cmp::min(self.page_size, self.image_length.get() - offset);
+                let mut sha = self.sha.get();
+                sha.update(&pagebuffer.as_mut()[0..length]);
+                self.sha.set(sha);
+
+                if offset + length < self.image_length.get() {
+                    if self.verify(page + 1, pagebuffer) != ReturnCode::SUCCESS {
+                        self.abort_update(Operation::Finish, ReturnCode::FAIL);
+                    }
+                } else if sha.finish() == self.digest.get() {
+                    self.write_pending_record(pagebuffer);
+                } else {
+                    // The image was corrupted on the way or in flash.
+                    self.pagebuffer.replace(pagebuffer);
+                    self.abort_update(Operation::Finish, ReturnCode::FAIL);
+                }
+            }
+            State::ReadingRecord => {
+                if error != hil::flash::Error::CommandComplete {
+                    self.pagebuffer.replace(pagebuffer);
+                    self.confirm_done(ReturnCode::FAIL);
+                    return;
+                }
+
+                let boot_state = record_state(pagebuffer.as_mut());
+                self.boot_state.set(boot_state);
+                if boot_state == BootState::Testing {
+                    {
+                        let record = pagebuffer.as_mut();
+                        write_u32(record, RECORD_STATE, BootState::Confirmed as u32);
+                        let checksum = record_checksum(record);
+                        write_u32(record, RECORD_CHECKSUM, checksum);
+                    }
+                    self.state.set(State::Confirming);
+                    let rc = self.put_back(self.driver.write_page(self.record_page, pagebuffer));
+                    if rc != ReturnCode::SUCCESS {
+                        self.confirm_done(ReturnCode::FAIL);
+                    }
+                } else {
+                    // Nothing to confirm.
+                    self.pagebuffer.replace(pagebuffer);
+                    self.confirm_done(ReturnCode::SUCCESS);
+                }
+            }
+            _ => {
+                self.pagebuffer.replace(pagebuffer);
+            }
+        }
+    }
+
+    fn write_complete(&self, pagebuffer: &'static mut F::Page, error: hil::flash::Error) {
+        let success = error == hil::flash::Error::CommandComplete;
+        match self.state.get() {
+            State::Writing => {
+                if !success {
+                    self.pagebuffer.replace(pagebuffer);
+                    let operation = if self.chunk_offset.get() < self.chunk_length.get()
+                        || self.received.get() < self.image_length.get()
+                    {
+                        Operation::Write
+                    } else {
+                        Operation::Finish
+                    };
+                    self.abort_update(operation, ReturnCode::FAIL);
+                } else if self.received.get() == self.image_length.get()
+                    && self.received.get() % self.page_size != 0
+                {
+                    // That was the partial last page written by finish.
+                    if self.verify(0, pagebuffer) != ReturnCode::SUCCESS {
+                        self.abort_update(Operation::Finish, ReturnCode::FAIL);
+                    }
+                } else if self.chunk_offset.get() < self.chunk_length.get() {
+                    self.pagebuffer.replace(pagebuffer);
+                    self.continue_write();
+                } else {
+                    self.pagebuffer.replace(pagebuffer);
+                    self.state.set(State::Idle);
+                    self.app_done(Operation::Write, ReturnCode::SUCCESS);
+                }
+            }
+            State::WritingRecord => {
+                self.pagebuffer.replace(pagebuffer);
+                if success {
+                    self.boot_state.set(BootState::Pending);
+                    self.state.set(State::Idle);
+                    self.app_done(Operation::Finish, ReturnCode::SUCCESS);
+                    self.current_app.set(None);
+                } else {
+                    self.abort_update(Operation::Finish, ReturnCode::FAIL);
+                }
+            }
+            State::Confirming => {
+                self.pagebuffer.replace(pagebuffer);
+                if success {
+                    self.boot_state.set(BootState::Confirmed);
+                    self.confirm_done(ReturnCode::SUCCESS);
+                } else {
+                    self.confirm_done(ReturnCode::FAIL);
+                }
+            }
+            _ => {
+                self.pagebuffer.replace(pagebuffer);
+            }
+        }
+    }
+
+    fn erase_complete(&self, _error: hil::flash::Error) {}
+}
+
+impl<'a, F: hil::flash::Flash + 'a> Driver for KernelUpdate<'a, F> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Image data to write.
+    /// - `1`: The expected SHA-256 digest of the image.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.data = slice,
+                    1 => app.digest = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Setup a callback for when an operation finishes.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self.apps
+                .enter(app_id, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return SUCCESS if this driver is included on the platform.
+    /// - `1`: Start an update with an image of `arg1` bytes.
+    /// - `2`: Write the next `arg1` bytes of the image from the data buffer.
+    /// - `3`: Verify the image and write the boot record.
+    /// - `4`: Confirm the running kernel.
+    /// - `5`: Return the state of the boot record.
+    /// - `6`: Abort the update.
+    fn command(&self, command_num: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => /* This driver exists. */ ReturnCode::SUCCESS,
+
+            1 => {
+                if !self.is_update_app(appid) {
+                    return ReturnCode::ERESERVE;
+                }
+                self.start(appid, arg1)
+            }
+
+            2 => self.write(appid, arg1),
+
+            3 => self.finish(appid),
+
+            4 => {
+                if !self.is_update_app(appid) {
+                    return ReturnCode::ERESERVE;
+                }
+                let rc = self.confirm();
+                if rc == ReturnCode::SUCCESS {
+                    self.confirm_app.set(Some(appid));
+                }
+                rc
+            }
+
+            5 => ReturnCode::SuccessWithValue {
+                value: self.boot_state.get() as usize,
+            },
+
+            6 => {
+                if !self.is_update_app(appid) || self.current_app.get() != Some(appid) {
+                    ReturnCode::ERESERVE
+                } else if self.state.get() != State::Idle {
+                    ReturnCode::EBUSY
+                } else {
+                    self.current_app.set(None);
+                    ReturnCode::SUCCESS
+                }
+            }
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
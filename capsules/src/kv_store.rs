@@ -0,0 +1,1204 @@
+//! Persistent key-value storage with a separate namespace for each app.
+//!
+//! Values are stored in a log on top of any `NonvolatileStorage`. The region
+//! given to this capsule is split into sectors the size of the buffers passed
+//! to `new()`. Setting or deleting a key appends a record to the newest
+//! sector, nothing is ever modified in place, and the log moves through all
+//! sectors in turn so that writes are spread evenly over the storage.
+//!
+//! Each record is protected by a CRC that also covers the sequence number of
+//! its sector. A record that was only partially written when power was lost
+//! fails the check and is ignored, as are leftovers from earlier uses of a
+//! sector, so a value is committed exactly when its record is complete.
+//!
+//! One sector is always kept free. When the log is about to run out of space,
+//! the records of the oldest sector that have not been overwritten or deleted
+//! by newer records are copied to the free sector, and the oldest sector is
+//! then released. The copy only becomes valid once its sector header is
+//! written, so losing power during garbage collection loses nothing.
+//!
+//! Apps are namespaced by their package name and cannot see each other's
+//! keys. Apps without a package name cannot use the store. The kernel has a
+//! namespace of its own and uses the store through `hil::kv_store::KVStore`.
+//!
+//! ```text
+//! +-------------------------+     +--------------+
+//! |         kernel          |     |  userspace   |
+//! +-------------------------+     +--------------+
+//!    hil::kv_store::KVStore        kernel::Driver
+//! +----------------------------------------------+
+//! |      capsules::kv_store::KVStore (this)      |
+//! +----------------------------------------------+
+//!  hil::nonvolatile_storage::NonvolatileStorage
+//! +----------------------------------------------+
+//! |   Physical nonvolatile storage driver, or    |
+//! |   capsules::nonvolatile_to_pages over flash  |
+//! +----------------------------------------------+
+//! ```
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! let kv_store = static_init!(
+//!     capsules::kv_store::KVStore<'static>,
+//!     capsules::kv_store::KVStore::new(
+//!         nv_to_page,                  // The underlying storage driver.
+//!         kernel::Grant::create(),
+//!         0x60000,                     // Start address of the store.
+//!         0x4000,                      // Length of the store.
+//!         &mut capsules::kv_store::BUFFER,
+//!         &mut capsules::kv_store::GC_BUFFER));
+//! hil::nonvolatile_storage::NonvolatileStorage::set_client(nv_to_page, kv_store);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! - Allow 0: the key of the next operation, up to `MAX_KEY_LENGTH` bytes.
+//! - Allow 1: the buffer values are read in to and stored from.
+//! - Subscribe 0: called when an operation finishes, with the operation
+//!   (1 get, 2 set, 3 delete), the result and the full length of the value
+//!   read. `ENOSUPPORT` means the key does not exist.
+//! - Command 0: check if the driver exists.
+//! - Command 1: get the value of the key.
+//! - Command 2: set the key to the first `arg1` bytes of the value buffer.
+//! - Command 3: delete the key.
+//! - Command 4: the maximum combined length of a key and its value.
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+use kernel::common::take_cell::TakeCell;
+use kernel::hil;
+
+/// Syscall driver number.
+pub const DRIVER_NUM: usize = 0x50003;
+
+pub static mut BUFFER: [u8; 512] = [0; 512];
+pub static mut GC_BUFFER: [u8; 512] = [0; 512];
+
+/// Longest key that can be stored.
+pub const MAX_KEY_LENGTH: usize = 32;
+
+/// Marks a sector that is part of the log.
+const SECTOR_MAGIC: u32 = 0x53564B54;
+
+/// Magic and sequence number at the start of each sector.
+const SECTOR_HEADER_SIZE: usize = 8;
+
+/// Namespace, key and value lengths, flags and CRC at the start of each record.
+const RECORD_HEADER_SIZE: usize = 12;
+
+/// Flag of records that delete their key.
+const RECORD_DELETED: u8 = 0x01;
+
+/// The namespace of the kernel. No app can use it as apps without a package
+/// name are not allowed to use the store.
+const KERNEL_NAMESPACE: &'static [u8] = b"";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operation {
+    Get = 1,
+    Set = 2,
+    Delete = 3,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum KVUser {
+    App { app_id: AppId },
+    Kernel,
+}
+
+/// What the capsule is waiting for the storage to finish.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Idle,
+    /// Reading the header of each sector to find the newest one.
+    MountFindHead { sector: usize },
+    /// Reading the headers of older sectors to find the oldest one.
+    MountFindTail { age: usize },
+    /// Reading the newest sector to find where the next record goes.
+    MountReadHead,
+    /// Releasing the oldest sector after an interrupted garbage collection.
+    MountCleanup,
+    /// Looking for a key in the sector `age` sectors older than the newest.
+    Lookup { age: usize },
+    /// Writing a new record.
+    Append { new_sector: bool, size: usize },
+    /// Reading the oldest sector to collect its garbage.
+    GcReadOldest,
+    /// Reading newer sectors to find records of the oldest that are stale.
+    GcScan { age: usize },
+    /// Writing the records that survived to the free sector.
+    GcWrite,
+    /// Writing the header that makes the new sector valid.
+    GcCommit,
+    /// Releasing the oldest sector.
+    GcRelease,
+}
+
+fn align4(value: usize) -> usize {
+    (value + 3) & !3
+}
+
+fn read_u16(buf: &[u8], offset: usize) -> u16 {
+    buf[offset] as u16 | (buf[offset + 1] as u16) << 8
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> u32 {
+    buf[offset] as u32 | (buf[offset + 1] as u32) << 8 | (buf[offset + 2] as u32) << 16
+        | (buf[offset + 3] as u32) << 24
+}
+
+fn write_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset] = value as u8;
+    buf[offset + 1] = (value >> 8) as u8;
+}
+
+fn write_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset] = value as u8;
+    buf[offset + 1] = (value >> 8) as u8;
+    buf[offset + 2] = (value >> 16) as u8;
+    buf[offset + 3] = (value >> 24) as u8;
+}
+
+/// Update a CRC-32 (IEEE 802.3) with `data`.
+fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for byte in data.iter() {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+/// The CRC of a record, which is `record` without padding, in a sector with
+/// the given sequence number.
+fn record_crc(sequence: u32, record: &[u8]) -> u32 {
+    let mut sequence_bytes = [0; 4];
+    write_u32(&mut sequence_bytes, 0, sequence);
+    let crc = crc32(0xFFFFFFFF, &sequence_bytes);
+    let crc = crc32(crc, &record[..8]);
+    crc32(crc, &record[RECORD_HEADER_SIZE..]) ^ 0xFFFFFFFF
+}
+
+/// Put the buffer the storage hands back when an access cannot be started
+/// back in `cell`, and return the error.
+fn put_back(
+    cell: &TakeCell<'static, [u8]>,
+    result: (ReturnCode, Option<&'static mut [u8]>),
+) -> ReturnCode {
+    let (rc, buffer) = result;
+    buffer.map(|buffer| cell.replace(buffer));
+    rc
+}
+
+fn write_sector_header(buf: &mut [u8], sequence: u32) {
+    write_u32(buf, 0, SECTOR_MAGIC);
+    write_u32(buf, 4, sequence);
+}
+
+/// The sequence number of a sector, or `None` if it is not part of the log.
+fn parse_sector_header(buf: &[u8]) -> Option<u32> {
+    if read_u32(buf, 0) == SECTOR_MAGIC {
+        Some(read_u32(buf, 4))
+    } else {
+        None
+    }
+}
+
+struct Record<'a> {
+    namespace: &'a [u8],
+    key: &'a [u8],
+    value: &'a [u8],
+    deleted: bool,
+    /// Length without padding.
+    length: usize,
+}
+
+impl<'a> Record<'a> {
+    fn matches(&self, namespace: &[u8], key: &[u8]) -> bool {
+        self.namespace == namespace && self.key == key
+    }
+}
+
+/// Parse the record at the start of `buf`, returning `None` if there is no
+/// complete and valid record.
+fn parse_record<'a>(buf: &'a [u8], sequence: u32) -> Option<Record<'a>> {
+    if buf.len() < RECORD_HEADER_SIZE {
+        return None;
+    }
+    let namespace_length = buf[0] as usize;
+    let key_length = buf[1] as usize;
+    let value_length = read_u16(buf, 2) as usize;
+    let length = RECORD_HEADER_SIZE + namespace_length + key_length + value_length;
+    if key_length == 0 || length > buf.len()
+        || read_u32(buf, 8) != record_crc(sequence, &buf[..length])
+    {
+        return None;
+    }
+
+    let key_start = RECORD_HEADER_SIZE + namespace_length;
+    let value_start = key_start + key_length;
+    Some(Record {
+        namespace: &buf[RECORD_HEADER_SIZE..key_start],
+        key: &buf[key_start..value_start],
+        value: &buf[value_start..length],
+        deleted: buf[4] & RECORD_DELETED != 0,
+        length: length,
+    })
+}
+
+/// Iterator over the valid records of a sector. Iteration stops at the first
+/// invalid record, which is where the next record would be written.
+struct Records<'a> {
+    sector: &'a [u8],
+    sequence: u32,
+    offset: usize,
+}
+
+impl<'a> Records<'a> {
+    fn new(sector: &'a [u8], sequence: u32, offset: usize) -> Records<'a> {
+        Records {
+            sector: sector,
+            sequence: sequence,
+            offset: cmp::min(offset, sector.len()),
+        }
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Record<'a>;
+
+    fn next(&mut self) -> Option<Record<'a>> {
+        parse_record(&self.sector[self.offset..], self.sequence).map(|record| {
+            self.offset = cmp::min(self.offset + align4(record.length), self.sector.len());
+            record
+        })
+    }
+}
+
+/// Remove the records for which `keep` returns false from the first `end`
+/// bytes of `sector`, moving the others to the front. Moved records are
+/// rewritten for a sector with `new_sequence`. `keep` is passed the sector,
+/// the record and the offset of the following record. Returns the end of the
+/// remaining records.
+fn compact<F>(sector: &mut [u8], end: usize, sequence: u32, new_sequence: u32, mut keep: F) -> usize
+where
+    F: FnMut(&[u8], &Record, usize) -> bool,
+{
+    let mut read = SECTOR_HEADER_SIZE;
+    let mut write = SECTOR_HEADER_SIZE;
+    loop {
+        let (length, keep_record) = match parse_record(&sector[read..end], sequence) {
+            Some(record) => {
+                let next = read + align4(record.length);
+                (record.length, keep(&sector[..end], &record, next))
+            }
+            None => break,
+        };
+
+        if keep_record {
+            // Records only move towards the front, so copying forwards never
+            // overwrites bytes that still have to be copied.
+            for i in 0..length {
+                sector[write + i] = sector[read + i];
+            }
+            for i in length..align4(length) {
+                sector[write + i] = 0;
+            }
+            if sequence != new_sequence {
+                let crc = record_crc(new_sequence, &sector[write..write + length]);
+                write_u32(sector, write + 8, crc);
+            }
+            write += align4(length);
+        }
+        read = cmp::min(read + align4(length), end);
+    }
+    write
+}
+
+#[derive(Default)]
+pub struct App {
+    callback: Option<Callback>,
+    key: Option<AppSlice<Shared, u8>>,
+    value: Option<AppSlice<Shared, u8>>,
+    pending: Option<Operation>,
+    set_length: usize,
+}
+
+pub struct KVStore<'a> {
+    // The underlying physical storage device.
+    driver: &'a hil::nonvolatile_storage::NonvolatileStorage,
+    // Per-app state.
+    apps: Grant<App>,
+
+    // Sector sized buffers for reading sectors and collecting garbage.
+    buffer: TakeCell<'static, [u8]>,
+    gc_buffer: TakeCell<'static, [u8]>,
+
+    // Where the store is in the physical storage.
+    start_address: usize,
+    sector_size: usize,
+    sectors: usize,
+
+    state: Cell<State>,
+
+    // Whether the log has been found after boot.
+    mounted: Cell<bool>,
+    // The newest sector, its sequence number and where its next record goes.
+    head: Cell<usize>,
+    head_sequence: Cell<u32>,
+    head_offset: Cell<usize>,
+    // How many sectors, ending with the newest, are part of the log.
+    used: Cell<usize>,
+    // End of the records in `gc_buffer` that survived so far.
+    gc_length: Cell<usize>,
+    // How many sectors have been collected for the current operation.
+    gc_rounds: Cell<usize>,
+
+    // The operation being executed and who issued it.
+    current_user: Cell<Option<KVUser>>,
+    operation: Cell<Operation>,
+    namespace: Cell<&'static [u8]>,
+    key: Cell<[u8; MAX_KEY_LENGTH]>,
+    key_length: Cell<usize>,
+    value_length: Cell<usize>,
+
+    // Optional client for the kernel.
+    kernel_client: Cell<Option<&'static hil::kv_store::KVStoreClient>>,
+    // An operation the kernel requested while the store was busy.
+    kernel_pending: Cell<Option<Operation>>,
+    kernel_key: Cell<[u8; MAX_KEY_LENGTH]>,
+    kernel_key_length: Cell<usize>,
+    kernel_value: TakeCell<'static, [u8]>,
+    kernel_value_length: Cell<usize>,
+}
+
+impl<'a> KVStore<'a> {
+    pub fn new(
+        driver: &'a hil::nonvolatile_storage::NonvolatileStorage,
+        grant: Grant<App>,
+        start_address: usize,
+        length: usize,
+        buffer: &'static mut [u8],
+        gc_buffer: &'static mut [u8],
+    ) -> KVStore<'a> {
+        let sector_size = cmp::min(buffer.len(), gc_buffer.len()) & !3;
+        KVStore {
+            driver: driver,
+            apps: grant,
+            buffer: TakeCell::new(buffer),
+            gc_buffer: TakeCell::new(gc_buffer),
+            start_address: start_address,
+            sector_size: sector_size,
+            sectors: if sector_size > 0 {
+                length / sector_size
+            } else {
+                0
+            },
+            state: Cell::new(State::Idle),
+            mounted: Cell::new(false),
+            head: Cell::new(0),
+            head_sequence: Cell::new(0),
+            head_offset: Cell::new(0),
+            used: Cell::new(0),
+            gc_length: Cell::new(0),
+            gc_rounds: Cell::new(0),
+            current_user: Cell::new(None),
+            operation: Cell::new(Operation::Get),
+            namespace: Cell::new(KERNEL_NAMESPACE),
+            key: Cell::new([0; MAX_KEY_LENGTH]),
+            key_length: Cell::new(0),
+            value_length: Cell::new(0),
+            kernel_client: Cell::new(None),
+            kernel_pending: Cell::new(None),
+            kernel_key: Cell::new([0; MAX_KEY_LENGTH]),
+            kernel_key_length: Cell::new(0),
+            kernel_value: TakeCell::empty(),
+            kernel_value_length: Cell::new(0),
+        }
+    }
+
+    /// How many bytes of namespace, key and value fit in one record.
+    fn record_capacity(&self) -> usize {
+        self.sector_size.saturating_sub(SECTOR_HEADER_SIZE + RECORD_HEADER_SIZE)
+    }
+
+    fn sector_address(&self, sector: usize) -> usize {
+        self.start_address + sector * self.sector_size
+    }
+
+    /// The sector `age` sectors older than the newest one.
+    fn sector_by_age(&self, age: usize) -> usize {
+        (self.head.get() + self.sectors - age % self.sectors) % self.sectors
+    }
+
+    /// Check that an operation can be executed at all.
+    fn check_operation(
+        &self,
+        operation: Operation,
+        namespace: &[u8],
+        key_length: usize,
+        value_length: usize,
+    ) -> ReturnCode {
+        if self.sectors < 2 {
+            return ReturnCode::ENODEVICE;
+        }
+        if key_length == 0 || key_length > MAX_KEY_LENGTH {
+            return ReturnCode::EINVAL;
+        }
+        let payload = match operation {
+            Operation::Set => namespace.len() + key_length + value_length,
+            _ => namespace.len() + key_length,
+        };
+        if namespace.len() > 255 || payload > self.record_capacity() {
+            return ReturnCode::ESIZE;
+        }
+        ReturnCode::SUCCESS
+    }
+
+    /// Make `user` the current user and start `operation`.
+    fn start(&self, user: KVUser, operation: Operation) -> ReturnCode {
+        let mut key = [0; MAX_KEY_LENGTH];
+        let (namespace, key_length, value_length) = match user {
+            KVUser::Kernel => {
+                key = self.kernel_key.get();
+                (
+                    KERNEL_NAMESPACE,
+                    self.kernel_key_length.get(),
+                    self.kernel_value_length.get(),
+                )
+            }
+            KVUser::App { app_id } => {
+                let namespace = app_id.get_package_name().unwrap_or("").as_bytes();
+                if namespace.len() == 0 {
+                    return ReturnCode::ENOSUPPORT;
+                }
+                let lengths = self.apps.enter(app_id, |app, _| {
+                    let key_length = app.key.as_ref().map_or(0, |app_key| {
+                        let length = cmp::min(app_key.len(), MAX_KEY_LENGTH);
+                        key[..length].copy_from_slice(&app_key.as_ref()[..length]);
+                        app_key.len()
+                    });
+                    let value_buffer_length = app.value.as_ref().map_or(0, |value| value.len());
+                    (key_length, cmp::min(app.set_length, value_buffer_length))
+                });
+                match lengths {
+                    Ok((key_length, value_length)) => (namespace, key_length, value_length),
+                    Err(err) => return err.into(),
+                }
+            }
+        };
+
+        let result = self.check_operation(operation, namespace, key_length, value_length);
+        if result != ReturnCode::SUCCESS {
+            return result;
+        }
+
+        // Nothing to find in an empty store.
+        if operation == Operation::Get && self.mounted.get() && self.used.get() == 0 {
+            return ReturnCode::ENOSUPPORT;
+        }
+
+        self.current_user.set(Some(user));
+        self.operation.set(operation);
+        self.namespace.set(namespace);
+        self.key.set(key);
+        self.key_length.set(key_length);
+        self.value_length.set(value_length);
+        self.run();
+        ReturnCode::SUCCESS
+    }
+
+    /// Execute the current operation, finding the log first if needed.
+    fn run(&self) {
+        if !self.mounted.get() {
+            self.used.set(0);
+            self.read_sector_header(0, State::MountFindHead { sector: 0 });
+            return;
+        }
+
+        match self.operation.get() {
+            Operation::Get => {
+                if self.used.get() == 0 {
+                    self.finish(ReturnCode::ENOSUPPORT, 0);
+                } else {
+                    self.read_sector(0, State::Lookup { age: 0 });
+                }
+            }
+            Operation::Set | Operation::Delete => {
+                self.gc_rounds.set(0);
+                self.append();
+            }
+        }
+    }
+
+    fn read_sector_header(&self, sector: usize, state: State) {
+        let result = self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
+            self.state.set(state);
+            let result = self.driver
+                .read(buffer, self.sector_address(sector), SECTOR_HEADER_SIZE);
+            put_back(&self.buffer, result)
+        });
+        self.check_started(result);
+    }
+
+    /// Read the sector `age` sectors older than the newest one.
+    fn read_sector(&self, age: usize, state: State) {
+        let sector = self.sector_by_age(age);
+        let result = self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
+            self.state.set(state);
+            let result = self.driver
+                .read(buffer, self.sector_address(sector), self.sector_size);
+            put_back(&self.buffer, result)
+        });
+        self.check_started(result);
+    }
+
+    /// Write an invalid header over the oldest sector so it is no longer part
+    /// of the log.
+    fn release_oldest(&self, state: State) {
+        let oldest = self.sector_by_age(self.used.get() - 1);
+        let result = self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
+            for b in buffer[..SECTOR_HEADER_SIZE].iter_mut() {
+                *b = 0;
+            }
+            self.state.set(state);
+            let result = self.driver
+                .write(buffer, self.sector_address(oldest), SECTOR_HEADER_SIZE);
+            put_back(&self.buffer, result)
+        });
+        self.check_started(result);
+    }
+
+    /// Abort the current operation if the storage did not accept a request.
+    fn check_started(&self, result: ReturnCode) {
+        if result != ReturnCode::SUCCESS {
+            self.finish(result, 0);
+        }
+    }
+
+    fn mount_find_tail(&self, age: usize) {
+        if age < self.sectors {
+            let sector = self.sector_by_age(age);
+            self.read_sector_header(sector, State::MountFindTail { age: age });
+        } else {
+            self.read_sector(0, State::MountReadHead);
+        }
+    }
+
+    fn mounted(&self) {
+        self.state.set(State::Idle);
+        self.mounted.set(true);
+        self.run();
+    }
+
+    /// Serialize the record for the current operation in to `buf`. Returns the
+    /// size of the record including padding, or `None` if the app that
+    /// started the operation is gone.
+    fn build_record(&self, buf: &mut [u8], sequence: u32) -> Option<usize> {
+        let namespace = self.namespace.get();
+        let key = self.key.get();
+        let key = &key[..self.key_length.get()];
+        let (value_length, flags) = match self.operation.get() {
+            Operation::Set => (self.value_length.get(), 0),
+            _ => (0, RECORD_DELETED),
+        };
+        let key_start = RECORD_HEADER_SIZE + namespace.len();
+        let value_start = key_start + key.len();
+        let length = value_start + value_length;
+
+        buf[0] = namespace.len() as u8;
+        buf[1] = key.len() as u8;
+        write_u16(buf, 2, value_length as u16);
+        buf[4] = flags;
+        for b in buf[5..RECORD_HEADER_SIZE].iter_mut() {
+            *b = 0;
+        }
+        buf[RECORD_HEADER_SIZE..key_start].copy_from_slice(namespace);
+        buf[key_start..value_start].copy_from_slice(key);
+
+        let value = &mut buf[value_start..length];
+        let copied = match self.current_user.get() {
+            Some(KVUser::Kernel) => self.kernel_value
+                .map(|kernel_value| value.copy_from_slice(&kernel_value[..value_length]))
+                .is_some(),
+            Some(KVUser::App { app_id }) => self.apps
+                .enter(app_id, |app, _| {
+                    app.value
+                        .as_ref()
+                        .map(|app_value| {
+                            value.copy_from_slice(&app_value.as_ref()[..value_length])
+                        })
+                        .is_some()
+                })
+                .unwrap_or(false),
+            None => false,
+        };
+        if !copied && value_length > 0 {
+            return None;
+        }
+
+        for b in buf[length..align4(length)].iter_mut() {
+            *b = 0;
+        }
+        let crc = record_crc(sequence, &buf[..length]);
+        write_u32(buf, 8, crc);
+        Some(align4(length))
+    }
+
+    /// Append the record of the current operation to the log, making room
+    /// first if needed.
+    fn append(&self) {
+        let size = align4(
+            RECORD_HEADER_SIZE + self.namespace.get().len() + self.key_length.get()
+                + self.value_length.get(),
+        );
+
+        if self.used.get() > 0 && self.head_offset.get() + size <= self.sector_size {
+            // There is room in the newest sector.
+            let address = self.sector_address(self.head.get()) + self.head_offset.get();
+            let result = self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
+                match self.build_record(buffer, self.head_sequence.get()) {
+                    Some(size) => {
+                        self.state.set(State::Append {
+                            new_sector: false,
+                            size: size,
+                        });
+                        put_back(&self.buffer, self.driver.write(buffer, address, size))
+                    }
+                    None => {
+                        self.buffer.replace(buffer);
+                        ReturnCode::FAIL
+                    }
+                }
+            });
+            self.check_started(result);
+        } else if self.used.get() < self.sectors - 1 {
+            // Start a new sector. The header and the first record are written
+            // together.
+            let sector = (self.head.get() + 1) % self.sectors;
+            let sequence = self.head_sequence.get().wrapping_add(1);
+            let result = self.buffer.take().map_or(ReturnCode::ERESERVE, |buffer| {
+                write_sector_header(buffer, sequence);
+                match self.build_record(&mut buffer[SECTOR_HEADER_SIZE..], sequence) {
+                    Some(size) => {
+                        self.state.set(State::Append {
+                            new_sector: true,
+                            size: size,
+                        });
+                        let length = SECTOR_HEADER_SIZE + size;
+                        let result = self.driver.write(buffer, self.sector_address(sector), length);
+                        put_back(&self.buffer, result)
+                    }
+                    None => {
+                        self.buffer.replace(buffer);
+                        ReturnCode::FAIL
+                    }
+                }
+            });
+            self.check_started(result);
+        } else if self.gc_rounds.get() < self.sectors {
+            // Only the spare sector is left, collect the oldest sector in to
+            // it.
+            self.gc_rounds.set(self.gc_rounds.get() + 1);
+            let oldest = self.sector_by_age(self.used.get() - 1);
+            let result = self.gc_buffer.take().map_or(ReturnCode::ERESERVE, |gc_buffer| {
+                self.state.set(State::GcReadOldest);
+                let result = self.driver
+                    .read(gc_buffer, self.sector_address(oldest), self.sector_size);
+                put_back(&self.gc_buffer, result)
+            });
+            self.check_started(result);
+        } else {
+            // Every sector has been collected and there still is no room.
+            self.finish(ReturnCode::ENOMEM, 0);
+        }
+    }
+
+    /// Read the next newer sector while collecting garbage, or write out what
+    /// survived once all have been checked.
+    fn gc_scan(&self, age: usize) {
+        if age < self.used.get() - 1 {
+            self.read_sector(age, State::GcScan { age: age });
+        } else if self.gc_length.get() == SECTOR_HEADER_SIZE {
+            // Nothing survived, so the oldest sector can simply be released.
+            self.release_oldest(State::GcRelease);
+        } else {
+            // Write the records with an invalid header first so that the
+            // sector only becomes part of the log once it is complete.
+            let sector = (self.head.get() + 1) % self.sectors;
+            let result = self.gc_buffer.take().map_or(ReturnCode::ERESERVE, |gc_buffer| {
+                for b in gc_buffer[..SECTOR_HEADER_SIZE].iter_mut() {
+                    *b = 0;
+                }
+                self.state.set(State::GcWrite);
+                let result = self.driver
+                    .write(gc_buffer, self.sector_address(sector), self.gc_length.get());
+                put_back(&self.gc_buffer, result)
+            });
+            self.check_started(result);
+        }
+    }
+
+    /// Copy the value of a record that was looked up to whoever asked for it.
+    /// Returns the result of the lookup and the full length of the value.
+    fn return_value(&self, record: &Record) -> (ReturnCode, usize) {
+        if record.deleted {
+            return (ReturnCode::ENOSUPPORT, 0);
+        }
+        match self.current_user.get() {
+            Some(KVUser::Kernel) => {
+                self.kernel_value.map(|value| {
+                    let length = cmp::min(value.len(), record.value.len());
+                    value[..length].copy_from_slice(&record.value[..length]);
+                });
+            }
+            Some(KVUser::App { app_id }) => {
+                let _ = self.apps.enter(app_id, |app, _| {
+                    app.value.as_mut().map(|value| {
+                        let length = cmp::min(value.len(), record.value.len());
+                        value.as_mut()[..length].copy_from_slice(&record.value[..length]);
+                    });
+                });
+            }
+            None => {}
+        }
+        (ReturnCode::SUCCESS, record.value.len())
+    }
+
+    /// End the current operation, tell whoever started it, and start the next
+    /// one.
+    fn finish(&self, result: ReturnCode, length: usize) {
+        self.state.set(State::Idle);
+        let operation = self.operation.get();
+        self.current_user.take().map(|user| match user {
+            KVUser::Kernel => self.kernel_client.get().map(|client| match operation {
+                Operation::Get => {
+                    self.kernel_value
+                        .take()
+                        .map(|value| client.get_done(value, length, result));
+                }
+                Operation::Set => {
+                    self.kernel_value
+                        .take()
+                        .map(|value| client.set_done(value, result));
+                }
+                Operation::Delete => client.delete_done(result),
+            }),
+            KVUser::App { app_id } => self.apps
+                .enter(app_id, |app, _| {
+                    app.callback.map(|mut cb| {
+                        cb.schedule(operation as usize, usize::from(result), length);
+                    })
+                })
+                .unwrap_or(None),
+        });
+
+        self.check_queue();
+    }
+
+    /// Start the next pending operation, if any.
+    fn check_queue(&self) {
+        if self.current_user.get().is_some() {
+            return;
+        }
+
+        if let Some(operation) = self.kernel_pending.take() {
+            let result = self.start(KVUser::Kernel, operation);
+            if result != ReturnCode::SUCCESS {
+                // Report the error as if the operation ran.
+                self.current_user.set(Some(KVUser::Kernel));
+                self.operation.set(operation);
+                self.finish(result, 0);
+            }
+            return;
+        }
+
+        for cntr in self.apps.iter() {
+            let (app_id, operation) = cntr.enter(|app, _| (app.appid(), app.pending.take()));
+            if let Some(operation) = operation {
+                let result = self.start(KVUser::App { app_id: app_id }, operation);
+                if result != ReturnCode::SUCCESS {
+                    self.current_user.set(Some(KVUser::App { app_id: app_id }));
+                    self.operation.set(operation);
+                    self.finish(result, 0);
+                }
+                break;
+            }
+        }
+    }
+
+    /// Start `operation` for an app, or queue it if the store is busy.
+    fn app_operation(&self, app_id: AppId, operation: Operation, set_length: usize) -> ReturnCode {
+        let queued = self.apps.enter(app_id, |app, _| {
+            if app.pending.is_some() {
+                return ReturnCode::EBUSY;
+            }
+            app.set_length = set_length;
+            if self.current_user.get().is_some() {
+                app.pending = Some(operation);
+            }
+            ReturnCode::SUCCESS
+        });
+        match queued {
+            Ok(ReturnCode::SUCCESS) => {
+                if self.current_user.get().is_none() {
+                    self.start(KVUser::App { app_id: app_id }, operation)
+                } else {
+                    ReturnCode::SUCCESS
+                }
+            }
+            Ok(err) => err,
+            Err(err) => err.into(),
+        }
+    }
+
+    /// Start `operation` for the kernel, or queue it if the store is busy.
+    fn kernel_operation(
+        &self,
+        operation: Operation,
+        key: &[u8],
+        value: Option<&'static mut [u8]>,
+        value_length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if self.kernel_pending.get().is_some() || self.current_user.get() == Some(KVUser::Kernel)
+        {
+            return (ReturnCode::EBUSY, value);
+        }
+        if key.len() == 0 || key.len() > MAX_KEY_LENGTH {
+            return (ReturnCode::EINVAL, value);
+        }
+
+        let mut kernel_key = [0; MAX_KEY_LENGTH];
+        kernel_key[..key.len()].copy_from_slice(key);
+        self.kernel_key.set(kernel_key);
+        self.kernel_key_length.set(key.len());
+        self.kernel_value_length.set(value.as_ref().map_or(0, |value| {
+            cmp::min(value_length, value.len())
+        }));
+        value.map(|value| self.kernel_value.replace(value));
+
+        if self.current_user.get().is_none() {
+            let result = self.start(KVUser::Kernel, operation);
+            if result != ReturnCode::SUCCESS {
+                return (result, self.kernel_value.take());
+            }
+        } else {
+            self.kernel_pending.set(Some(operation));
+        }
+        (ReturnCode::SUCCESS, None)
+    }
+}
+
+/// This is the callback client for the underlying physical storage driver.
+impl<'a> hil::nonvolatile_storage::NonvolatileStorageClient for KVStore<'a> {
+    fn read_done(&self, buffer: &'static mut [u8], _length: usize) {
+        match self.state.get() {
+            State::MountFindHead { sector } => {
+                if let Some(sequence) = parse_sector_header(buffer) {
+                    if self.used.get() == 0 || sequence > self.head_sequence.get() {
+                        self.head.set(sector);
+                        self.head_sequence.set(sequence);
+                        self.used.set(1);
+                    }
+                }
+                self.buffer.replace(buffer);
+
+                if sector + 1 < self.sectors {
+                    self.read_sector_header(
+                        sector + 1,
+                        State::MountFindHead { sector: sector + 1 },
+                    );
+                } else if self.used.get() == 0 {
+                    // The store is empty, the first sector used will be
+                    // sector 0.
+                    self.head.set(self.sectors - 1);
+                    self.head_sequence.set(0);
+                    self.head_offset.set(self.sector_size);
+                    self.mounted();
+                } else {
+                    self.mount_find_tail(1);
+                }
+            }
+
+            State::MountFindTail { age } => {
+                let expected = self.head_sequence.get().wrapping_sub(age as u32);
+                let in_log = parse_sector_header(buffer) == Some(expected);
+                self.buffer.replace(buffer);
+
+                if in_log {
+                    self.used.set(age + 1);
+                    self.mount_find_tail(age + 1);
+                } else {
+                    self.read_sector(0, State::MountReadHead);
+                }
+            }
+
+            State::MountReadHead => {
+                let end = {
+                    let mut records =
+                        Records::new(buffer, self.head_sequence.get(), SECTOR_HEADER_SIZE);
+                    while records.next().is_some() {}
+                    records.offset
+                };
+                self.head_offset.set(end);
+                self.buffer.replace(buffer);
+
+                if self.used.get() == self.sectors {
+                    // Power was lost after garbage collection wrote the new
+                    // sector but before it released the old one.
+                    self.release_oldest(State::MountCleanup);
+                } else {
+                    self.mounted();
+                }
+            }
+
+            State::Lookup { age } => {
+                let namespace = self.namespace.get();
+                let key = self.key.get();
+                let key = &key[..self.key_length.get()];
+                let sequence = self.head_sequence.get().wrapping_sub(age as u32);
+
+                // The last record for the key in the newest sector that has one
+                // is the current value.
+                let result = {
+                    let mut found = None;
+                    for record in Records::new(buffer, sequence, SECTOR_HEADER_SIZE) {
+                        if record.matches(namespace, key) {
+                            found = Some(record);
+                        }
+                    }
+                    found.map(|record| self.return_value(&record))
+                };
+                self.buffer.replace(buffer);
+
+                match result {
+                    Some((result, length)) => self.finish(result, length),
+                    None if age + 1 < self.used.get() => {
+                        self.read_sector(age + 1, State::Lookup { age: age + 1 })
+                    }
+                    None => self.finish(ReturnCode::ENOSUPPORT, 0),
+                }
+            }
+
+            State::GcReadOldest => {
+                // Drop deleted keys and values that were overwritten in the
+                // same sector. Nothing is older than this sector, so deletions
+                // are no longer needed either.
+                let sequence = self.head_sequence
+                    .get()
+                    .wrapping_sub(self.used.get() as u32 - 1);
+                let new_sequence = self.head_sequence.get().wrapping_add(1);
+                let length = compact(
+                    buffer,
+                    self.sector_size,
+                    sequence,
+                    new_sequence,
+                    |sector, record, next| {
+                        !record.deleted
+                            && !Records::new(sector, sequence, next)
+                                .any(|newer| newer.matches(record.namespace, record.key))
+                    },
+                );
+                self.gc_length.set(length);
+                self.gc_buffer.replace(buffer);
+                self.gc_scan(0);
+            }
+
+            State::GcScan { age } => {
+                // Drop the records that this newer sector overrides.
+                let sequence = self.head_sequence.get().wrapping_sub(age as u32);
+                let new_sequence = self.head_sequence.get().wrapping_add(1);
+                let gc_length = self.gc_length.get();
+                {
+                    let newer_sector: &[u8] = buffer;
+                    self.gc_buffer.map(|gc_buffer| {
+                        let length = compact(
+                            gc_buffer,
+                            gc_length,
+                            new_sequence,
+                            new_sequence,
+                            |_, record, _| {
+                                !Records::new(newer_sector, sequence, SECTOR_HEADER_SIZE)
+                                    .any(|newer| newer.matches(record.namespace, record.key))
+                            },
+                        );
+                        self.gc_length.set(length);
+                    });
+                }
+                self.buffer.replace(buffer);
+                self.gc_scan(age + 1);
+            }
+
+            _ => {
+                self.buffer.replace(buffer);
+            }
+        }
+    }
+
+    fn write_done(&self, buffer: &'static mut [u8], _length: usize) {
+        match self.state.get() {
+            State::MountCleanup => {
+                self.buffer.replace(buffer);
+                self.used.set(self.used.get() - 1);
+                self.mounted();
+            }
+
+            State::Append { new_sector, size } => {
+                self.buffer.replace(buffer);
+                if new_sector {
+                    self.head.set((self.head.get() + 1) % self.sectors);
+                    self.head_sequence
+                        .set(self.head_sequence.get().wrapping_add(1));
+                    self.head_offset.set(SECTOR_HEADER_SIZE + size);
+                    self.used.set(self.used.get() + 1);
+                } else {
+                    self.head_offset.set(self.head_offset.get() + size);
+                }
+                self.finish(ReturnCode::SUCCESS, 0);
+            }
+
+            State::GcWrite => {
+                // All records are in place, now make the sector valid.
+                let sector = (self.head.get() + 1) % self.sectors;
+                write_sector_header(buffer, self.head_sequence.get().wrapping_add(1));
+                self.state.set(State::GcCommit);
+                let result = self.driver
+                    .write(buffer, self.sector_address(sector), SECTOR_HEADER_SIZE);
+                self.check_started(put_back(&self.gc_buffer, result));
+            }
+
+            State::GcCommit => {
+                self.gc_buffer.replace(buffer);
+                self.head.set((self.head.get() + 1) % self.sectors);
+                self.head_sequence
+                    .set(self.head_sequence.get().wrapping_add(1));
+                self.head_offset.set(self.gc_length.get());
+                self.used.set(self.used.get() + 1);
+                self.release_oldest(State::GcRelease);
+            }
+
+            State::GcRelease => {
+                self.buffer.replace(buffer);
+                self.used.set(self.used.get() - 1);
+                self.append();
+            }
+
+            _ => {
+                self.buffer.replace(buffer);
+            }
+        }
+    }
+}
+
+/// Provide an interface for the kernel.
+impl<'a> hil::kv_store::KVStore for KVStore<'a> {
+    fn set_client(&self, client: &'static hil::kv_store::KVStoreClient) {
+        self.kernel_client.set(Some(client));
+    }
+
+    fn get(&self, key: &[u8], value: &'static mut [u8])
+        -> (ReturnCode, Option<&'static mut [u8]>) {
+        self.kernel_operation(Operation::Get, key, Some(value), 0)
+    }
+
+    fn set(
+        &self,
+        key: &[u8],
+        value: &'static mut [u8],
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        self.kernel_operation(Operation::Set, key, Some(value), length)
+    }
+
+    fn delete(&self, key: &[u8]) -> ReturnCode {
+        self.kernel_operation(Operation::Delete, key, None, 0).0
+    }
+}
+
+/// Provide an interface for userland.
+impl<'a> Driver for KVStore<'a> {
+    /// Setup shared buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: The key of the next operation.
+    /// - `1`: The buffer values are read in to and stored from.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.key = slice,
+                    1 => app.value = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Setup a callback for when an operation finishes.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        match subscribe_num {
+            0 => self.apps
+                .enter(app_id, |app, _| {
+                    app.callback = callback;
+                    ReturnCode::SUCCESS
+                })
+                .unwrap_or_else(|err| err.into()),
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return SUCCESS if this driver is included on the platform.
+    /// - `1`: Get the value of the key.
+    /// - `2`: Set the key to the first `arg1` bytes of the value buffer.
+    /// - `3`: Delete the key.
+    /// - `4`: Return the maximum combined length of a key and its value.
+    fn command(&self, command_num: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => /* This driver exists. */ ReturnCode::SUCCESS,
+
+            1 => self.app_operation(appid, Operation::Get, 0),
+
+            2 => self.app_operation(appid, Operation::Set, arg1),
+
+            3 => self.app_operation(appid, Operation::Delete, 0),
+
+            4 => {
+                let namespace_length = appid.get_package_name().map_or(0, |name| name.len());
+                ReturnCode::SuccessWithValue {
+                    value: self.record_capacity().saturating_sub(namespace_length),
+                }
+            }
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
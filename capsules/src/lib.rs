@@ -5,6 +5,7 @@
 #[allow(unused_imports)]
 #[macro_use(debug)]
 extern crate kernel;
+extern crate tbf;
 
 pub mod test;
 
@@ -13,6 +14,7 @@ pub mod ambient_light;
 pub mod ble_advertising_driver;
 pub mod button;
 pub mod console;
+pub mod energy;
 pub mod fm25cl;
 pub mod gpio;
 pub mod isl29035;
@@ -43,11 +45,20 @@ pub mod gpio_async;
 pub mod max17205;
 pub mod pca9544a;
 pub mod nonvolatile_to_pages;
+pub mod nonvolatile_to_sdcard;
 pub mod nonvolatile_storage_driver;
 pub mod app_flash_driver;
+pub mod kv_store;
+pub mod kernel_update;
+pub mod sha256;
+pub mod fat;
 pub mod usb;
 pub mod usb_user;
 pub mod usbc_client;
+pub mod usbc_client_ctrl;
+pub mod cdc;
+pub mod usb_hid;
+pub mod usb_dfu;
 #[macro_use]
 pub mod net;
 pub mod ieee802154;
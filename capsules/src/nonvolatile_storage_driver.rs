@@ -1,13 +1,20 @@
 //! This provides kernel and userspace access to nonvolatile memory.
 //!
-//! This is an initial implementation that does not provide safety for
-//! individual userland applications. Each application has full access to
-//! the entire memory space that has been provided to userland. Future revisions
-//! should update this to limit applications to only their allocated regions.
+//! The memory provided to userland is split between applications. Each app
+//! asks for the number of bytes it needs with the `TbfHeaderNonvolatileStorage`
+//! TLV in its TBF header, and when the kernel loads the apps it gives them
+//! consecutive regions in the order they are in flash. Apps address their own
+//! region starting at offset 0 and cannot access anything outside of it. Apps
+//! that do not ask for storage, or whose region does not fit in the memory
+//! provided to userland, cannot use this driver.
 //!
-//! However, the kernel accessible memory does not have to be the same range
-//! as the userspace accessible address space. The kernel memory can overlap
-//! if desired, or can be a completely separate range.
+//! Because regions are assigned in flash order, an app keeps its data across
+//! reboots and updates as long as the apps before it in flash, and the storage
+//! they ask for, do not change.
+//!
+//! The kernel accessible memory does not have to be the same range as the
+//! userspace accessible address space. The kernel memory can overlap if
+//! desired, or can be a completely separate range.
 //!
 //! Here is a diagram of the expected stack with this capsule:
 //! Boxes are components and between the boxes are the traits that are the
@@ -34,6 +41,9 @@
 //! +-----------------------------------------------------------------+
 //! ```
 //!
+//! The physical storage can be flash through `nonvolatile_to_pages`, the
+//! FM25CL FRAM, or an SD card through `nonvolatile_to_sdcard`.
+//!
 //! Example instantiation:
 //!
 //! ```rust
@@ -177,15 +187,19 @@ impl<'a> NonvolatileStorage<'a> {
         app_id: Option<AppId>,
     ) -> ReturnCode {
         // Do bounds check.
+        let mut offset = offset;
         match command {
             NonvolatileCommand::UserspaceRead | NonvolatileCommand::UserspaceWrite => {
-                // Userspace sees memory that starts at address 0 even if it
-                // is offset in the physical memory.
-                if offset >= self.userspace_length || length > self.userspace_length
-                    || offset + length > self.userspace_length
+                // Each app sees its own region that starts at address 0 even
+                // if it is offset in the physical memory.
+                let (region_start, region_length) =
+                    app_id.map_or((0, 0), |appid| self.app_region(appid));
+                if offset >= region_length || length > region_length
+                    || offset + length > region_length
                 {
                     return ReturnCode::EINVAL;
                 }
+                offset += region_start;
             }
             NonvolatileCommand::KernelRead | NonvolatileCommand::KernelWrite => {
                 // Because the kernel uses the NonvolatileStorage interface,
@@ -284,18 +298,10 @@ impl<'a> NonvolatileStorage<'a> {
                         if self.current_user.get().is_none() {
                             // Nothing is using this, lets go!
                             self.current_user.set(Some(NonvolatileUser::Kernel));
-
-                            match command {
-                                NonvolatileCommand::KernelRead => {
-                                    self.driver.read(kernel_buffer, offset, active_len)
-                                }
-                                NonvolatileCommand::KernelWrite => {
-                                    self.driver.write(kernel_buffer, offset, active_len)
-                                }
-                                _ => ReturnCode::FAIL,
-                            }
+                            self.call_driver(command, kernel_buffer, offset, active_len)
                         } else {
                             if self.kernel_pending_command.get() == true {
+                                self.kernel_buffer.replace(kernel_buffer);
                                 ReturnCode::ENOMEM
                             } else {
                                 self.kernel_pending_command.set(true);
@@ -311,6 +317,43 @@ impl<'a> NonvolatileStorage<'a> {
         }
     }
 
+    // The offset into the userspace memory and length of the region of an app.
+    // The region is empty if the app did not ask for storage or its region
+    // does not fit.
+    fn app_region(&self, appid: AppId) -> (usize, usize) {
+        match appid.get_nonvolatile_storage_region() {
+            Some((start, length)) if length <= self.userspace_length
+                && start <= self.userspace_length - length =>
+            {
+                (start, length)
+            }
+            _ => (0, 0),
+        }
+    }
+
+    // Start or queue a read or write for the kernel. The kernel buffer is
+    // handed back if the command is rejected.
+    fn kernel_call(
+        &self,
+        command: NonvolatileCommand,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if self.kernel_buffer.is_some() {
+            // A kernel command is already queued.
+            return (ReturnCode::EBUSY, Some(buffer));
+        }
+        self.kernel_buffer.replace(buffer);
+        let rc = self.enqueue_command(command, address, length, None);
+        if rc == ReturnCode::SUCCESS {
+            (rc, None)
+        } else {
+            (rc, self.kernel_buffer.take())
+        }
+    }
+
+    // `offset` is relative to the start of the userspace memory.
     fn userspace_call_driver(
         &self,
         command: NonvolatileCommand,
@@ -326,19 +369,39 @@ impl<'a> NonvolatileStorage<'a> {
             // allowed are long enough.
             let active_len = cmp::min(length, buffer.len());
 
-            // self.current_app.set(Some(appid));
-            match command {
-                NonvolatileCommand::UserspaceRead => {
-                    self.driver.read(buffer, physical_address, active_len)
-                }
-                NonvolatileCommand::UserspaceWrite => {
-                    self.driver.write(buffer, physical_address, active_len)
-                }
-                _ => ReturnCode::FAIL,
-            }
+            self.call_driver(command, buffer, physical_address, active_len)
         })
     }
 
+    // Start a read or write on the underlying storage. If it cannot be
+    // started, the buffer is put back and the storage is free again.
+    fn call_driver(
+        &self,
+        command: NonvolatileCommand,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> ReturnCode {
+        let (rc, buffer) = match command {
+            NonvolatileCommand::UserspaceRead | NonvolatileCommand::KernelRead => {
+                self.driver.read(buffer, address, length)
+            }
+            NonvolatileCommand::UserspaceWrite | NonvolatileCommand::KernelWrite => {
+                self.driver.write(buffer, address, length)
+            }
+        };
+        if rc != ReturnCode::SUCCESS {
+            self.current_user.set(None);
+            buffer.map(|buffer| match command {
+                NonvolatileCommand::KernelRead | NonvolatileCommand::KernelWrite => {
+                    self.kernel_buffer.replace(buffer)
+                }
+                _ => self.buffer.replace(buffer),
+            });
+        }
+        rc
+    }
+
     fn check_queue(&self) {
         // Check if there are any pending events.
         if self.kernel_pending_command.get() {
@@ -346,18 +409,25 @@ impl<'a> NonvolatileStorage<'a> {
                 self.kernel_pending_command.set(false);
                 self.current_user.set(Some(NonvolatileUser::Kernel));
 
-                match self.kernel_command.get() {
-                    NonvolatileCommand::KernelRead => self.driver.read(
-                        kernel_buffer,
-                        self.kernel_readwrite_address.get(),
-                        self.kernel_readwrite_length.get(),
-                    ),
-                    NonvolatileCommand::KernelWrite => self.driver.write(
-                        kernel_buffer,
-                        self.kernel_readwrite_address.get(),
-                        self.kernel_readwrite_length.get(),
-                    ),
-                    _ => ReturnCode::FAIL,
+                let command = self.kernel_command.get();
+                let rc = self.call_driver(
+                    command,
+                    kernel_buffer,
+                    self.kernel_readwrite_address.get(),
+                    self.kernel_readwrite_length.get(),
+                );
+                if rc != ReturnCode::SUCCESS {
+                    // The kernel was told the command was queued, so report
+                    // that nothing was read or written.
+                    self.kernel_buffer.take().map(|kernel_buffer| {
+                        self.kernel_client.get().map(move |client| {
+                            if command == NonvolatileCommand::KernelRead {
+                                client.read_done(kernel_buffer, 0);
+                            } else {
+                                client.write_done(kernel_buffer, 0);
+                            }
+                        });
+                    });
                 }
             });
         } else {
@@ -452,14 +522,22 @@ impl<'a> hil::nonvolatile_storage::NonvolatileStorage for NonvolatileStorage<'a>
         self.kernel_client.set(Some(client));
     }
 
-    fn read(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode {
-        self.kernel_buffer.replace(buffer);
-        self.enqueue_command(NonvolatileCommand::KernelRead, address, length, None)
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        self.kernel_call(NonvolatileCommand::KernelRead, buffer, address, length)
     }
 
-    fn write(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode {
-        self.kernel_buffer.replace(buffer);
-        self.enqueue_command(NonvolatileCommand::KernelWrite, address, length, None)
+    fn write(
+        &self,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        self.kernel_call(NonvolatileCommand::KernelWrite, buffer, address, length)
     }
 }
 
@@ -520,17 +598,20 @@ impl<'a> Driver for NonvolatileStorage<'a> {
     /// ### `command_num`
     ///
     /// - `0`: Return SUCCESS if this driver is included on the platform.
-    /// - `1`: Return the number of bytes available to userspace.
-    /// - `2`: Start a read from the nonvolatile storage.
-    /// - `3`: Start a write to the nonvolatile_storage.
+    /// - `1`: Return the number of bytes available to this app.
+    /// - `2`: Start a read from the app's region of the nonvolatile storage.
+    /// - `3`: Start a write to the app's region of the nonvolatile_storage.
+    ///
+    /// Reads and writes that are not entirely within the app's region fail
+    /// with `EINVAL`.
     fn command(&self, arg0: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
         let command_num = arg0 & 0xFF;
 
         match command_num {
             0 => /* This driver exists. */ ReturnCode::SUCCESS,
 
-            // How many bytes are accessible from userspace.
-            1 => ReturnCode::SuccessWithValue { value: self.userspace_length },
+            // How many bytes are accessible to this app.
+            1 => ReturnCode::SuccessWithValue { value: self.app_region(appid).1 },
 
             // Issue a read
             2 => {
@@ -7,6 +7,14 @@
 //! This module is designed to be used on top of any flash storage and below any
 //! user of `NonvolatileStorage`. This module handles different sized pages.
 //!
+//! If the flash supports partial page writes and a write only clears bits, for
+//! example because it goes to erased flash, only the words that are written
+//! are programmed instead of erasing and rewriting the whole page. Such a
+//! flash that can also erase several pages at once has all the whole pages of
+//! a write erased with one request, and then programs them one by one. If the
+//! flash reports an error, the operation stops and the client is told how
+//! many bytes were read or written.
+//!
 //! ```plain
 //! hil::nonvolatile_storage::NonvolatileStorage
 //!                ┌─────────────┐
@@ -63,8 +71,14 @@ pub struct NonvolatileToPages<'a, F: hil::flash::Flash + 'static> {
     length: Cell<usize>,
     /// How many bytes are left to read or write.
     remaining_length: Cell<usize>,
+    /// How many bytes the page write in progress covers. They only count as
+    /// written once the flash reports success.
+    write_length: Cell<usize>,
     /// Where we are in the user buffer.
     buffer_index: Cell<usize>,
+    /// How many of the next whole pages of the write were erased together and
+    /// only have to be programmed.
+    erased_pages: Cell<usize>,
 }
 
 impl<'a, F: hil::flash::Flash + 'a> NonvolatileToPages<'a, F> {
@@ -78,9 +92,83 @@ impl<'a, F: hil::flash::Flash + 'a> NonvolatileToPages<'a, F> {
             address: Cell::new(0),
             length: Cell::new(0),
             remaining_length: Cell::new(0),
+            write_length: Cell::new(0),
             buffer_index: Cell::new(0),
+            erased_pages: Cell::new(0),
+        }
+    }
+
+    /// Hand the buffer back with the error if the first page operation could
+    /// not be started.
+    fn started(
+        &self,
+        result: (ReturnCode, Option<&'static mut F::Page>),
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        let (rc, pagebuffer) = result;
+        pagebuffer.map(|pagebuffer| self.pagebuffer.replace(pagebuffer));
+        if rc == ReturnCode::SUCCESS {
+            (rc, None)
+        } else {
+            self.state.set(State::Idle);
+            (rc, self.buffer.take())
         }
     }
+
+    /// Stop and report what was done so far if the operation on the next
+    /// page could not be started.
+    fn continued(&self, result: (ReturnCode, Option<&'static mut F::Page>)) {
+        if let (rc, Some(pagebuffer)) = result {
+            if rc != ReturnCode::SUCCESS {
+                self.buffer
+                    .take()
+                    .map(move |buffer| self.finish(pagebuffer, buffer));
+            }
+        }
+    }
+
+    /// Copy the next whole page of the user buffer into the page buffer and
+    /// write it.
+    fn write_next_page(&self, pagebuffer: &'static mut F::Page, buffer: &'static mut [u8]) {
+        let page_size = pagebuffer.as_mut().len();
+        let buffer_index = self.buffer_index.get();
+        let page_number = self.address.get() / page_size;
+
+        // Copy data into page buffer.
+        for i in 0..page_size {
+            pagebuffer.as_mut()[i] = buffer[buffer_index + i];
+        }
+
+        self.buffer.replace(buffer);
+        self.address.set(self.address.get() + page_size);
+        self.write_length.set(page_size);
+        self.buffer_index.set(buffer_index + page_size);
+        let result = match self.erased_pages.get() {
+            0 => self.driver.write_page(page_number, pagebuffer),
+            erased_pages => {
+                // Already erased, so it only has to be programmed.
+                self.erased_pages.set(erased_pages - 1);
+                self.driver
+                    .write_partial(page_number, 0, page_size, pagebuffer)
+            }
+        };
+        self.continued(result);
+    }
+
+    /// Stop the operation and give the buffer back to the client, along with
+    /// how many bytes were actually read or written.
+    fn finish(&self, pagebuffer: &'static mut F::Page, buffer: &'static mut [u8]) {
+        self.pagebuffer.replace(pagebuffer);
+        let state = self.state.get();
+        self.state.set(State::Idle);
+        let length = self.length.get() - self.remaining_length.get();
+        self.client.get().map(move |client| {
+            if state == State::Write {
+                client.write_done(buffer, length);
+            } else {
+                client.read_done(buffer, length);
+            }
+        });
+    }
 }
 
 impl<'a, F: hil::flash::Flash + 'a> hil::nonvolatile_storage::NonvolatileStorage
@@ -90,69 +178,102 @@ impl<'a, F: hil::flash::Flash + 'a> hil::nonvolatile_storage::NonvolatileStorage
         self.client.set(Some(client));
     }
 
-    fn read(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode {
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
         if self.state.get() != State::Idle {
-            return ReturnCode::EBUSY;
+            return (ReturnCode::EBUSY, Some(buffer));
         }
+        let pagebuffer = match self.pagebuffer.take() {
+            Some(pagebuffer) => pagebuffer,
+            None => return (ReturnCode::ERESERVE, Some(buffer)),
+        };
+        let page_size = pagebuffer.as_mut().len();
 
-        self.pagebuffer
-            .take()
-            .map_or(ReturnCode::ERESERVE, move |pagebuffer| {
-                let page_size = pagebuffer.as_mut().len();
-
-                // Just start reading. We'll worry about how much of the page we
-                // want later.
-                self.state.set(State::Read);
-                self.buffer.replace(buffer);
-                self.address.set(address);
-                self.length.set(length);
-                self.remaining_length.set(length);
-                self.buffer_index.set(0);
-                self.driver.read_page(address / page_size, pagebuffer)
-            })
+        // Just start reading. We'll worry about how much of the page we want
+        // later.
+        self.state.set(State::Read);
+        self.buffer.replace(buffer);
+        self.address.set(address);
+        self.length.set(length);
+        self.remaining_length.set(length);
+        self.buffer_index.set(0);
+        let result = self.driver.read_page(address / page_size, pagebuffer);
+        self.started(result)
     }
 
-    fn write(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode {
+    fn write(
+        &self,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
         if self.state.get() != State::Idle {
-            return ReturnCode::EBUSY;
+            return (ReturnCode::EBUSY, Some(buffer));
         }
+        let pagebuffer = match self.pagebuffer.take() {
+            Some(pagebuffer) => pagebuffer,
+            None => return (ReturnCode::ERESERVE, Some(buffer)),
+        };
+        let page_size = pagebuffer.as_mut().len();
 
-        self.pagebuffer
-            .take()
-            .map_or(ReturnCode::ERESERVE, move |pagebuffer| {
-                let page_size = pagebuffer.as_mut().len();
+        self.state.set(State::Write);
+        self.length.set(length);
+        self.erased_pages.set(0);
 
-                self.state.set(State::Write);
-                self.length.set(length);
+        let result = if address % page_size == 0 && length >= page_size {
+            // This write is aligned to a page and we are writing an entire
+            // page or more.
+            let page_number = address / page_size;
+            let pages = length / page_size;
+            self.address.set(address);
+            self.remaining_length.set(length);
+            self.buffer_index.set(0);
 
-                if address % page_size == 0 && length >= page_size {
-                    // This write is aligned to a page and we are writing an entire
-                    // page or more.
+            if pages > 1 && self.driver.write_granularity().is_some()
+                && self.driver.erase_pages(page_number, pages) == ReturnCode::SUCCESS
+            {
+                // Write the pages once they are all erased.
+                self.erased_pages.set(pages);
+                self.pagebuffer.replace(pagebuffer);
+                self.buffer.replace(buffer);
+                return (ReturnCode::SUCCESS, None);
+            }
 
-                    // Copy data into page buffer.
-                    for i in 0..page_size {
-                        pagebuffer.as_mut()[i] = buffer[i];
-                    }
+            // Copy data into page buffer.
+            for i in 0..page_size {
+                pagebuffer.as_mut()[i] = buffer[i];
+            }
 
-                    self.buffer.replace(buffer);
-                    self.address.set(address + page_size);
-                    self.remaining_length.set(length - page_size);
-                    self.buffer_index.set(page_size);
-                    self.driver.write_page(address / page_size, pagebuffer)
-                } else {
-                    // Need to do a read first.
-                    self.buffer.replace(buffer);
-                    self.address.set(address);
-                    self.remaining_length.set(length);
-                    self.buffer_index.set(0);
-                    self.driver.read_page(address / page_size, pagebuffer)
-                }
-            })
+            self.buffer.replace(buffer);
+            self.address.set(address + page_size);
+            self.write_length.set(page_size);
+            self.buffer_index.set(page_size);
+            self.driver.write_page(page_number, pagebuffer)
+        } else {
+            // Need to do a read first.
+            self.buffer.replace(buffer);
+            self.address.set(address);
+            self.remaining_length.set(length);
+            self.buffer_index.set(0);
+            self.driver.read_page(address / page_size, pagebuffer)
+        };
+        self.started(result)
     }
 }
 
 impl<'a, F: hil::flash::Flash + 'a> hil::flash::Client<F> for NonvolatileToPages<'a, F> {
-    fn read_complete(&self, pagebuffer: &'static mut F::Page, _error: hil::flash::Error) {
+    fn read_complete(&self, pagebuffer: &'static mut F::Page, error: hil::flash::Error) {
+        if error != hil::flash::Error::CommandComplete {
+            self.buffer
+                .take()
+                .map(move |buffer| self.finish(pagebuffer, buffer));
+            return;
+        }
+
         match self.state.get() {
             State::Read => {
                 // OK we got a page from flash. Copy what we actually want from it
@@ -175,11 +296,8 @@ impl<'a, F: hil::flash::Flash + 'a> hil::flash::Client<F> for NonvolatileToPages
                     let new_len = self.remaining_length.get() - len;
                     if new_len == 0 {
                         // Nothing more to do. Put things back and issue callback.
-                        self.pagebuffer.replace(pagebuffer);
-                        self.state.set(State::Idle);
-                        self.client
-                            .get()
-                            .map(move |client| client.read_done(buffer, self.length.get()));
+                        self.remaining_length.set(0);
+                        self.finish(pagebuffer, buffer);
                     } else {
                         // More to do!
                         self.buffer.replace(buffer);
@@ -187,8 +305,9 @@ impl<'a, F: hil::flash::Flash + 'a> hil::flash::Client<F> for NonvolatileToPages
                         self.remaining_length.set(self.remaining_length.get() - len);
                         self.address.set(self.address.get() + len);
                         self.buffer_index.set(buffer_index + len);
-                        self.driver
+                        let result = self.driver
                             .read_page(self.address.get() / page_size, pagebuffer);
+                        self.continued(result);
                     }
                 });
             }
@@ -206,60 +325,89 @@ impl<'a, F: hil::flash::Flash + 'a> hil::flash::Client<F> for NonvolatileToPages
                     // Which page we read and which we are going to write back to.
                     let page_number = self.address.get() / page_size;
 
-                    // Copy what we read from the page buffer to the user buffer.
+                    // Flash can only clear bits without an erase. If that is all
+                    // this write does, the page does not have to be erased.
+                    let only_clears_bits = (0..len).all(|i| {
+                        let new = buffer[buffer_index + i];
+                        pagebuffer.as_mut()[page_index + i] & new == new
+                    });
+
+                    // Copy the user data into the page buffer.
                     for i in 0..len {
                         pagebuffer.as_mut()[page_index + i] = buffer[buffer_index + i];
                     }
 
                     // Do the write.
                     self.buffer.replace(buffer);
-                    self.remaining_length.set(self.remaining_length.get() - len);
                     self.address.set(self.address.get() + len);
+                    self.write_length.set(len);
                     self.buffer_index.set(buffer_index + len);
-                    self.driver.write_page(page_number, pagebuffer);
+                    let result = match self.driver.write_granularity() {
+                        Some(granularity) if only_clears_bits => {
+                            // Only program the words that contain the new data.
+                            let start = page_index - page_index % granularity;
+                            let end = cmp::min(
+                                (page_index + len + granularity - 1) / granularity * granularity,
+                                page_size,
+                            );
+                            self.driver
+                                .write_partial(page_number, start, end - start, pagebuffer)
+                        }
+                        _ => self.driver.write_page(page_number, pagebuffer),
+                    };
+                    self.continued(result);
                 });
             }
             _ => {}
         }
     }
 
-    fn write_complete(&self, pagebuffer: &'static mut F::Page, _error: hil::flash::Error) {
+    fn write_complete(&self, pagebuffer: &'static mut F::Page, error: hil::flash::Error) {
         // After a write we could be done, need to do another write, or need to
         // do a read.
         self.buffer.take().map(move |buffer| {
             let page_size = pagebuffer.as_mut().len();
 
+            if error != hil::flash::Error::CommandComplete {
+                // Only report the pages that were written successfully.
+                self.finish(pagebuffer, buffer);
+                return;
+            }
+            self.remaining_length
+                .set(self.remaining_length.get() - self.write_length.get());
+
             if self.remaining_length.get() == 0 {
                 // Done!
-                self.pagebuffer.replace(pagebuffer);
-                self.state.set(State::Idle);
-                self.client
-                    .get()
-                    .map(move |client| client.write_done(buffer, self.length.get()));
+                self.finish(pagebuffer, buffer);
             } else if self.remaining_length.get() >= page_size {
                 // Write an entire page!
-                let buffer_index = self.buffer_index.get();
-                let page_number = self.address.get() / page_size;
-
-                // Copy data into page buffer.
-                for i in 0..page_size {
-                    pagebuffer.as_mut()[i] = buffer[buffer_index + i];
-                }
-
-                self.buffer.replace(buffer);
-                self.remaining_length
-                    .set(self.remaining_length.get() - page_size);
-                self.address.set(self.address.get() + page_size);
-                self.buffer_index.set(buffer_index + page_size);
-                self.driver.write_page(page_number, pagebuffer);
+                self.write_next_page(pagebuffer, buffer);
             } else {
                 // Write a partial page!
                 self.buffer.replace(buffer);
-                self.driver
+                let result = self.driver
                     .read_page(self.address.get() / page_size, pagebuffer);
+                self.continued(result);
             }
         });
     }
 
-    fn erase_complete(&self, _error: hil::flash::Error) {}
+    fn erase_complete(&self, error: hil::flash::Error) {
+        // Only a write erases, all of its whole pages at once.
+        if let Some(pagebuffer) = self.pagebuffer.take() {
+            match self.buffer.take() {
+                Some(buffer) => {
+                    if error == hil::flash::Error::CommandComplete {
+                        self.write_next_page(pagebuffer, buffer);
+                    } else {
+                        self.erased_pages.set(0);
+                        self.finish(pagebuffer, buffer);
+                    }
+                }
+                None => {
+                    self.pagebuffer.replace(pagebuffer);
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,318 @@
+//! Map arbitrary nonvolatile reads and writes to SD card block operations.
+//!
+//! This splits reads and writes that are not aligned to the 512 byte blocks of
+//! an SD card into a series of block reads and writes, reading blocks that are
+//! only partially written first. While it is handling a read or write it
+//! returns `EBUSY` to all additional requests.
+//!
+//! With this module an SD card can back the `nonvolatile_storage_driver` or
+//! any other user of `NonvolatileStorage` instead of flash or FRAM. Addresses
+//! are byte offsets from the start of the card. If the card is not initialized
+//! yet, for example because it was just inserted, it is initialized before the
+//! first access.
+//!
+//! ```plain
+//! hil::nonvolatile_storage::NonvolatileStorage
+//!                ┌─────────────┐
+//!                │             │
+//!                │ This module │
+//!                │             │
+//!                └─────────────┘
+//!             capsules::sdcard::SDCard
+//! ```
+//!
+//! The SD card only supports a single client, so the card cannot be shared
+//! with other capsules like the FAT filesystem.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! let nv_to_sdcard = static_init!(
+//!     capsules::nonvolatile_to_sdcard::NonvolatileToSDCard<'static,
+//!         VirtualMuxAlarm<'static, sam4l::ast::Ast>>,
+//!     capsules::nonvolatile_to_sdcard::NonvolatileToSDCard::new(
+//!         sdcard,
+//!         &mut capsules::nonvolatile_to_sdcard::BUFFER));
+//! sdcard.set_client(nv_to_sdcard);
+//! ```
+
+use core::cell::Cell;
+use core::cmp;
+use kernel::ReturnCode;
+use kernel::common::take_cell::TakeCell;
+use kernel::hil;
+use sdcard::{SDCard, SDCardClient};
+
+/// Size of the blocks the SD card is accessed in.
+const BLOCK_SIZE: usize = 512;
+
+pub static mut BUFFER: [u8; 512] = [0; 512];
+
+/// This module is either waiting to do something, waiting for the card to be
+/// initialized, or handling a read/write.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Idle,
+    Initialize,
+    /// Reading a block to copy part of it to the user buffer.
+    Read,
+    /// Reading a block that is only partially overwritten.
+    ReadModify,
+    Write,
+}
+
+pub struct NonvolatileToSDCard<'a, A: hil::time::Alarm + 'a> {
+    /// The SD card the data is stored on.
+    sdcard: &'a SDCard<'a, A>,
+    /// Callback to the user of this capsule.
+    client: Cell<Option<&'static hil::nonvolatile_storage::NonvolatileStorageClient>>,
+    /// Buffer holding one block of the card.
+    blockbuffer: TakeCell<'static, [u8]>,
+    /// Current state of this capsule.
+    state: Cell<State>,
+    /// Whether the current operation is a write.
+    write: Cell<bool>,
+    /// Temporary holding place for the user's buffer.
+    buffer: TakeCell<'static, [u8]>,
+    /// Absolute address of where we are reading or writing. This gets updated
+    /// as each block is finished.
+    address: Cell<usize>,
+    /// Total length to read or write. We need to store this to return it to the
+    /// client.
+    length: Cell<usize>,
+    /// How many bytes are left to read or write.
+    remaining_length: Cell<usize>,
+    /// Where we are in the user buffer.
+    buffer_index: Cell<usize>,
+}
+
+impl<'a, A: hil::time::Alarm + 'a> NonvolatileToSDCard<'a, A> {
+    pub fn new(
+        sdcard: &'a SDCard<'a, A>,
+        buffer: &'static mut [u8; 512],
+    ) -> NonvolatileToSDCard<'a, A> {
+        NonvolatileToSDCard {
+            sdcard: sdcard,
+            client: Cell::new(None),
+            blockbuffer: TakeCell::new(buffer),
+            state: Cell::new(State::Idle),
+            write: Cell::new(false),
+            buffer: TakeCell::empty(),
+            address: Cell::new(0),
+            length: Cell::new(0),
+            remaining_length: Cell::new(0),
+            buffer_index: Cell::new(0),
+        }
+    }
+
+    /// Check that `length` bytes at `address` are on the card, if its size is
+    /// known yet.
+    fn in_range(&self, address: usize, length: usize) -> bool {
+        let size = self.sdcard.get_block_count() as u64 * BLOCK_SIZE as u64;
+        address.checked_add(length).map_or(false, |end| {
+            !self.sdcard.is_initialized() || end as u64 <= size
+        })
+    }
+
+    /// Save the user buffer and start a read or write. If it cannot be
+    /// started, the buffer is handed back with the error.
+    fn start(
+        &self,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+        write: bool,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        if self.state.get() != State::Idle {
+            return (ReturnCode::EBUSY, Some(buffer));
+        }
+        if length > buffer.len() || !self.in_range(address, length) {
+            return (ReturnCode::EINVAL, Some(buffer));
+        }
+        if !self.sdcard.is_installed() {
+            return (ReturnCode::EUNINSTALLED, Some(buffer));
+        }
+
+        self.buffer.replace(buffer);
+        self.write.set(write);
+        self.address.set(address);
+        self.length.set(length);
+        self.remaining_length.set(length);
+        self.buffer_index.set(0);
+
+        let rc = if self.sdcard.is_initialized() {
+            self.next_block()
+        } else {
+            // Initialize the card first, the access starts once it is done.
+            self.state.set(State::Initialize);
+            self.sdcard.initialize()
+        };
+        if rc != ReturnCode::SUCCESS {
+            self.state.set(State::Idle);
+            return (rc, self.buffer.take());
+        }
+        (rc, None)
+    }
+
+    /// Read or write the block at the current address.
+    fn next_block(&self) -> ReturnCode {
+        self.blockbuffer
+            .take()
+            .map_or(ReturnCode::ERESERVE, |blockbuffer| {
+                let block = (self.address.get() / BLOCK_SIZE) as u32;
+                let block_index = self.address.get() % BLOCK_SIZE;
+                let len = cmp::min(BLOCK_SIZE - block_index, self.remaining_length.get());
+
+                let (rc, blockbuffer) = if !self.write.get() {
+                    self.state.set(State::Read);
+                    self.sdcard.read_blocks(blockbuffer, block, 1)
+                } else if len == BLOCK_SIZE {
+                    // Writing an entire block, no need to read it first.
+                    self.buffer.map(|buffer| {
+                        let buffer_index = self.buffer_index.get();
+                        for i in 0..BLOCK_SIZE {
+                            blockbuffer[i] = buffer[buffer_index + i];
+                        }
+                    });
+                    self.state.set(State::Write);
+                    self.sdcard.write_blocks(blockbuffer, block, 1)
+                } else {
+                    // Need to do a read first.
+                    self.state.set(State::ReadModify);
+                    self.sdcard.read_blocks(blockbuffer, block, 1)
+                };
+
+                blockbuffer.map(|blockbuffer| self.blockbuffer.replace(blockbuffer));
+                rc
+            })
+    }
+
+    /// Advance past the part of the current block that was read or written
+    /// and continue with the next block, or finish if that was the last one.
+    fn block_done(&self) {
+        let len = cmp::min(
+            BLOCK_SIZE - self.address.get() % BLOCK_SIZE,
+            self.remaining_length.get(),
+        );
+        self.address.set(self.address.get() + len);
+        self.remaining_length.set(self.remaining_length.get() - len);
+        self.buffer_index.set(self.buffer_index.get() + len);
+
+        if self.remaining_length.get() == 0 || self.next_block() != ReturnCode::SUCCESS {
+            self.finish();
+        }
+    }
+
+    /// Give the buffer back to the client, along with how many bytes were
+    /// actually read or written.
+    fn finish(&self) {
+        self.state.set(State::Idle);
+        let length = self.length.get() - self.remaining_length.get();
+        self.buffer.take().map(|buffer| {
+            self.client.get().map(move |client| {
+                if self.write.get() {
+                    client.write_done(buffer, length);
+                } else {
+                    client.read_done(buffer, length);
+                }
+            });
+        });
+    }
+}
+
+impl<'a, A: hil::time::Alarm + 'a> hil::nonvolatile_storage::NonvolatileStorage
+    for NonvolatileToSDCard<'a, A>
+{
+    fn set_client(&self, client: &'static hil::nonvolatile_storage::NonvolatileStorageClient) {
+        self.client.set(Some(client));
+    }
+
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        self.start(buffer, address, length, false)
+    }
+
+    fn write(
+        &self,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        self.start(buffer, address, length, true)
+    }
+}
+
+impl<'a, A: hil::time::Alarm + 'a> SDCardClient for NonvolatileToSDCard<'a, A> {
+    fn card_detection_changed(&self, _installed: bool) {
+        // An access in progress is ended by the card with an error, and the
+        // next one initializes the new card.
+    }
+
+    fn init_done(&self, _block_size: u32, _total_size: u64) {
+        if self.state.get() == State::Initialize {
+            // Now that the size of the card is known, make sure the access
+            // fits on it.
+            let in_range = self.in_range(self.address.get(), self.length.get());
+            if !in_range || self.next_block() != ReturnCode::SUCCESS {
+                self.finish();
+            }
+        }
+    }
+
+    fn read_done(&self, blockbuffer: &'static mut [u8], _len: usize) {
+        let block_index = self.address.get() % BLOCK_SIZE;
+        let len = cmp::min(BLOCK_SIZE - block_index, self.remaining_length.get());
+        let buffer_index = self.buffer_index.get();
+
+        match self.state.get() {
+            State::Read => {
+                // Copy what we actually want from the block to the user buffer.
+                self.buffer.map(|buffer| {
+                    for i in 0..len {
+                        buffer[buffer_index + i] = blockbuffer[block_index + i];
+                    }
+                });
+                self.blockbuffer.replace(blockbuffer);
+                self.block_done();
+            }
+            State::ReadModify => {
+                // Copy the new data into the block and write it back.
+                self.buffer.map(|buffer| {
+                    for i in 0..len {
+                        blockbuffer[block_index + i] = buffer[buffer_index + i];
+                    }
+                });
+                self.state.set(State::Write);
+                let block = (self.address.get() / BLOCK_SIZE) as u32;
+                let (rc, blockbuffer) = self.sdcard.write_blocks(blockbuffer, block, 1);
+                blockbuffer.map(|blockbuffer| self.blockbuffer.replace(blockbuffer));
+                if rc != ReturnCode::SUCCESS {
+                    self.finish();
+                }
+            }
+            _ => {
+                self.blockbuffer.replace(blockbuffer);
+            }
+        }
+    }
+
+    fn write_done(&self, blockbuffer: &'static mut [u8]) {
+        self.blockbuffer.replace(blockbuffer);
+        if self.state.get() == State::Write {
+            self.block_done();
+        }
+    }
+
+    fn error(&self, _error: u32, blockbuffer: Option<&'static mut [u8]>) {
+        blockbuffer.map(|blockbuffer| self.blockbuffer.replace(blockbuffer));
+        if self.state.get() != State::Idle {
+            // Report how far we got.
+            self.finish();
+        }
+    }
+}
@@ -1,6 +1,11 @@
 //! Provides driver for accessing an SD Card and a userspace Driver.
 //!
 //! This allows initialization and block reads or writes on top of SPI.
+//! Several consecutive blocks can be transferred with a single command, which
+//! is much faster than transferring them one at a time. Data blocks are
+//! protected by CRCs in both directions, and the capacity of the card is read
+//! from its CSD register. If the board has a card detect pin, clients are told
+//! when a card is inserted or removed.
 //!
 //! Usage
 //! -----
@@ -67,6 +72,7 @@ pub struct SDCard<'a, A: hil::time::Alarm + 'a> {
 
     is_initialized: Cell<bool>,
     card_type: Cell<SDCardType>,
+    block_count: Cell<u32>,
 
     detect_pin: Cell<Option<&'static hil::gpio::Pin>>,
 
@@ -76,6 +82,10 @@ pub struct SDCard<'a, A: hil::time::Alarm + 'a> {
     client: Cell<Option<&'static SDCardClient>>,
     client_buffer: TakeCell<'static, [u8]>,
     client_offset: Cell<usize>,
+    multiple_blocks: Cell<bool>,
+    /// Error of a multiple block transfer, reported once the card has
+    /// stopped the transfer
+    transfer_error: Cell<Option<ErrorCode>>,
 }
 
 /// SD card command codes
@@ -94,6 +104,7 @@ enum SDCmd {
     CMD25_WriteMultiple = 25,             //        Write multiple blocks
     CMD55_ManufSpecificCommand = 55,      // Next command will be manufacturer specific
     CMD58_ReadOCR = 58,                   //              Read operation condition register (OCR)
+    CMD59_CrcOnOff = 59,                  //             Enable or disable CRC checking
     ACMD41_ManufSpecificInit = 0x80 + 41, // Manufacturer specific Init
 }
 
@@ -115,6 +126,7 @@ enum SpiState {
     SendManufSpecificCmd { cmd: SDCmd, arg: u32 },
 
     InitReset,
+    InitEnableCrc,
     InitCheckVersion,
     InitRepeatHCSInit,
     InitCheckCapacity,
@@ -123,6 +135,8 @@ enum SpiState {
     InitRepeatGenericInit,
     InitSetBlocksize,
     InitComplete,
+    InitWaitCSD,
+    InitReceivedCSD,
 
     StartReadBlocks { count: u32 },
     WaitReadBlock,
@@ -130,11 +144,14 @@ enum SpiState {
     WaitReadBlocks { count: u32 },
     ReceivedBlock { count: u32 },
     ReadBlocksComplete,
+    WaitReadStopBusy,
 
     StartWriteBlocks { count: u32 },
-    WriteBlockResponse,
-    WriteBlockBusy,
-    WaitWriteBlockBusy,
+    WriteBlockResponse { count: u32 },
+    WriteBlockBusy { count: u32 },
+    WaitWriteBlockBusy { count: u32 },
+    WriteStopToken,
+    WaitWriteStopBusy,
 }
 
 /// Alarm states
@@ -148,10 +165,13 @@ enum AlarmState {
     RepeatAppSpecificInit,
     RepeatGenericInit,
 
+    WaitForCSD,
     WaitForDataBlock,
     WaitForDataBlocks { count: u32 },
+    WaitForReadStopBusy,
 
-    WaitForWriteBusy,
+    WaitForWriteBusy { count: u32 },
+    WaitForWriteStopBusy,
 }
 
 /// Error codes returned if an SD card transaction fails
@@ -162,6 +182,7 @@ enum ErrorCode {
     ReadFailure = -3,
     WriteFailure = -4,
     TimeoutFailure = -5,
+    CRCFailure = -6,
 }
 
 /// SD card types, determined during initialization
@@ -178,6 +199,45 @@ enum SDCardType {
 const SUCCESS_STATUS: u8 = 0x00;
 const INITIALIZING_STATUS: u8 = 0x01;
 const DATA_TOKEN: u8 = 0xFE;
+const MULTIPLE_DATA_TOKEN: u8 = 0xFC;
+const STOP_TRANSMISSION_TOKEN: u8 = 0xFD;
+const DATA_ACCEPTED: u8 = 0x05;
+const DATA_CRC_ERROR: u8 = 0x0B;
+
+/// CRC7 of a command, shifted and with the end bit set
+fn crc7(data: &[u8]) -> u8 {
+    let mut crc: u8 = 0;
+    for &byte in data.iter() {
+        for bit in 0..8 {
+            let feedback = ((byte << bit) ^ crc) & 0x80;
+            crc <<= 1;
+            if feedback != 0 {
+                crc ^= 0x09 << 1;
+            }
+        }
+    }
+    crc | 0x01
+}
+
+/// CRC16-CCITT of a data block, computed four bits at a time
+fn crc16(data: &[u8]) -> u16 {
+    const TABLE: [u16; 16] = [
+        0x0000, 0x1021, 0x2042, 0x3063, 0x4084, 0x50A5, 0x60C6, 0x70E7, 0x8108, 0x9129, 0xA14A,
+        0xB16B, 0xC18C, 0xD1AD, 0xE1CE, 0xF1EF,
+    ];
+    let mut crc: u16 = 0;
+    for &byte in data.iter() {
+        crc = (crc << 4) ^ TABLE[((crc >> 12) ^ (byte as u16 >> 4)) as usize];
+        crc = (crc << 4) ^ TABLE[((crc >> 12) ^ (byte as u16 & 0x0F)) as usize];
+    }
+    crc
+}
+
+/// check the CRC at the end of a received data block
+fn block_crc_valid(data: &[u8]) -> bool {
+    let len = data.len() - 2;
+    crc16(&data[..len]) == (data[len] as u16) << 8 | data[len + 1] as u16
+}
 
 /// Callback functions from SDCard
 pub trait SDCardClient {
@@ -185,7 +245,9 @@ pub trait SDCardClient {
     fn init_done(&self, block_size: u32, total_size: u64);
     fn read_done(&self, data: &'static mut [u8], len: usize);
     fn write_done(&self, buffer: &'static mut [u8]);
-    fn error(&self, error: u32);
+    /// a transaction failed, the buffer of a failed read or write is handed
+    /// back
+    fn error(&self, error: u32, buffer: Option<&'static mut [u8]>);
 }
 
 /// Functions for initializing and accessing an SD card
@@ -231,12 +293,15 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
             alarm_count: Cell::new(0),
             is_initialized: Cell::new(false),
             card_type: Cell::new(SDCardType::Uninitialized),
+            block_count: Cell::new(0),
             detect_pin: Cell::new(pin),
             txbuffer: TakeCell::new(txbuffer),
             rxbuffer: TakeCell::new(rxbuffer),
             client: Cell::new(None),
             client_buffer: TakeCell::empty(),
             client_offset: Cell::new(0),
+            multiple_blocks: Cell::new(false),
+            transfer_error: Cell::new(None),
         }
     }
 
@@ -302,14 +367,9 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
         write_buffer[5] = ((arg >> 8) & 0xFF) as u8;
         write_buffer[6] = ((arg >> 0) & 0xFF) as u8;
 
-        // CRC is ignored except for CMD0 and maybe CMD8
-        // Established practice it to just always use the CMD0 CRC unless we
-        // are sending a CMD8
-        if cmd == SDCmd::CMD8_CheckVoltage {
-            write_buffer[7] = 0x87; // valid crc for CMD8(0x1AA)
-        } else {
-            write_buffer[7] = 0x95; // valid crc for CMD0
-        }
+        // CRC is checked for CMD0 and CMD8, and for all commands once CRC
+        //  checking is enabled
+        write_buffer[7] = crc7(&write_buffer[2..7]);
 
         // append dummy bytes to transmission after command bytes
         // Limit to minimum length between write_buffer and recv_len
@@ -401,6 +461,130 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
         (r1, r2, r3)
     }
 
+    /// stop the current transaction and send an error callback, handing back
+    /// the client buffer. The SPI buffers must already be replaced
+    fn report_error(&self, error: ErrorCode) {
+        self.state.set(SpiState::Idle);
+        self.alarm_state.set(AlarmState::Idle);
+        self.alarm_count.set(0);
+        let buffer = self.client_buffer.take();
+        self.client.get().map(move |client| {
+            client.error(error as u32, buffer);
+        });
+    }
+
+    /// parse the number of 512 byte blocks on the card from the CSD register,
+    /// followed by its CRC
+    fn parse_csd(&self, csd: &[u8]) -> Option<u32> {
+        if csd.len() < 18 || !block_crc_valid(&csd[..18]) {
+            return None;
+        }
+
+        if (csd[0] & 0xC0) == 0x00 || self.card_type.get() == SDCardType::MMC {
+            // CSD version 1.0
+            let c_size = (((csd[6] & 0x03) as u32) << 10) | ((csd[7] as u32) << 2)
+                | ((csd[8] as u32) >> 6);
+            let c_size_mult = (((csd[9] & 0x03) as u32) << 1) | ((csd[10] as u32) >> 7);
+            let read_bl_len = (csd[5] & 0x0F) as u32;
+            if read_bl_len < 9 || read_bl_len > 11 {
+                return None;
+            }
+
+            // capacity is given in blocks of 2^read_bl_len bytes
+            Some((c_size + 1) << (c_size_mult + 2 + read_bl_len - 9))
+        } else if (csd[0] & 0xC0) == 0x40 {
+            // CSD version 2.0, capacity is given in units of 512 kB
+            let c_size = (((csd[7] & 0x3F) as u32) << 16) | ((csd[8] as u32) << 8)
+                | (csd[9] as u32);
+            (c_size + 1).checked_mul(1024)
+        } else {
+            None
+        }
+    }
+
+    /// finish initialization with the capacity read from the CSD register
+    fn csd_received(
+        &self,
+        write_buffer: &'static mut [u8],
+        read_buffer: &'static mut [u8],
+        block_count: Option<u32>,
+    ) {
+        // replace buffers
+        self.txbuffer.replace(write_buffer);
+        self.rxbuffer.replace(read_buffer);
+
+        match block_count {
+            Some(block_count) => {
+                // initialization complete
+                self.state.set(SpiState::Idle);
+                self.alarm_count.set(0);
+                self.block_count.set(block_count);
+                self.is_initialized.set(true);
+
+                // perform callback
+                self.client.get().map(move |client| {
+                    client.init_done(512, block_count as u64 * 512);
+                });
+            }
+            None => {
+                // error, send callback and quit
+                self.report_error(ErrorCode::InitializationFailure);
+            }
+        }
+    }
+
+    /// send the next block of the client buffer to the card, filling up with
+    /// 0xFF if the client buffer is too short
+    fn write_data_block(
+        &self,
+        write_buffer: &'static mut [u8],
+        read_buffer: &'static mut [u8],
+        count: u32,
+    ) {
+        let offset = self.client_offset.get();
+        let bytes_written = self.client_buffer.map_or(0, |buffer| {
+            // copy over data from client buffer
+            // Limit to minimum length between write_buffer, buffer, and 512
+            // (block size)
+            for (write_byte, &client_byte) in write_buffer
+                .iter_mut()
+                .skip(1)
+                .zip(buffer.iter().skip(offset))
+                .take(512)
+            {
+                *write_byte = client_byte;
+            }
+
+            // calculate number of bytes written
+            cmp::min(buffer.len().saturating_sub(offset), 512)
+        });
+        self.client_offset.set(offset + 512);
+
+        // set a known value for remaining bytes
+        for write_byte in write_buffer
+            .iter_mut()
+            .skip(1)
+            .skip(bytes_written)
+            .take(512 - bytes_written)
+        {
+            *write_byte = 0xFF;
+        }
+
+        // set up remainder of data packet
+        if self.multiple_blocks.get() {
+            write_buffer[0] = MULTIPLE_DATA_TOKEN;
+        } else {
+            write_buffer[0] = DATA_TOKEN;
+        }
+        let crc = crc16(&write_buffer[1..513]);
+        write_buffer[513] = (crc >> 8) as u8;
+        write_buffer[514] = (crc & 0xFF) as u8;
+
+        // write data packet
+        self.state.set(SpiState::WriteBlockResponse { count: count });
+        self.write_bytes(write_buffer, read_buffer, 515);
+    }
+
     /// updates SD card state on SPI transaction returns
     fn process_spi_states(
         &self,
@@ -423,33 +607,38 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
 
                 // only continue if we are in idle state
                 if r1 == INITIALIZING_STATUS {
-                    // next send Check Voltage Range command that is only valid
-                    //  on SDv2 cards. This is used to check which SD card
-                    //  version is installed. Note that 0xAA is an arbitrary
-                    //  check pattern that will be duplicated in the response
-                    //  and 0x100 specifies that the card is running between
-                    //  2.7 and 3.6 volts
-                    self.state.set(SpiState::InitCheckVersion);
-                    self.send_command(
-                        SDCmd::CMD8_CheckVoltage,
-                        0x1AA,
-                        write_buffer,
-                        read_buffer,
-                        10,
-                    );
+                    // enable CRC checking so that corrupted commands and
+                    //  data blocks are rejected by the card
+                    self.state.set(SpiState::InitEnableCrc);
+                    self.send_command(SDCmd::CMD59_CrcOnOff, 0x1, write_buffer, read_buffer, 10);
                 } else {
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
                     self.rxbuffer.replace(read_buffer);
-                    self.state.set(SpiState::Idle);
-                    self.alarm_state.set(AlarmState::Idle);
-                    self.alarm_count.set(0);
-                    self.client.get().map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
-                    });
+                    self.report_error(ErrorCode::InitializationFailure);
                 }
             }
 
+            SpiState::InitEnableCrc => {
+                // cards that do not support CRC checking reject the command
+                //  but can still be used, so the response is ignored
+
+                // next send Check Voltage Range command that is only valid
+                //  on SDv2 cards. This is used to check which SD card
+                //  version is installed. Note that 0xAA is an arbitrary
+                //  check pattern that will be duplicated in the response
+                //  and 0x100 specifies that the card is running between
+                //  2.7 and 3.6 volts
+                self.state.set(SpiState::InitCheckVersion);
+                self.send_command(
+                    SDCmd::CMD8_CheckVoltage,
+                    0x1AA,
+                    write_buffer,
+                    read_buffer,
+                    10,
+                );
+            }
+
             SpiState::InitCheckVersion => {
                 // check response
                 let (r1, _, r7) = self.get_response(SDResponse::R7_CheckVoltage, read_buffer);
@@ -512,12 +701,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
                     self.rxbuffer.replace(read_buffer);
-                    self.state.set(SpiState::Idle);
-                    self.alarm_state.set(AlarmState::Idle);
-                    self.alarm_count.set(0);
-                    self.client.get().map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
-                    });
+                    self.report_error(ErrorCode::InitializationFailure);
                 }
             }
 
@@ -527,26 +711,34 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
 
                 if r1 == SUCCESS_STATUS {
                     if (r7 & 0x40000000) != 0x00000000 {
+                        // SDHC and SDXC cards are addressed by block and
+                        //  always use 512 byte blocks
                         self.card_type.set(SDCardType::SDv2BlockAddressable);
+
+                        // Read CSD register
+                        // Note that the receive length needs to be increased
+                        //  here to capture the 16-byte register (plus some
+                        //  slack)
+                        self.state.set(SpiState::InitComplete);
+                        self.send_command(SDCmd::CMD9_ReadCSD, 0x0, write_buffer, read_buffer, 36);
                     } else {
+                        // SDSC cards are addressed by byte, set blocksize to
+                        //  512
                         self.card_type.set(SDCardType::SDv2);
+                        self.state.set(SpiState::InitSetBlocksize);
+                        self.send_command(
+                            SDCmd::CMD16_SetBlockSize,
+                            512,
+                            write_buffer,
+                            read_buffer,
+                            10,
+                        );
                     }
-
-                    // Read CSD register
-                    // Note that the receive length needs to be increased here
-                    //  to capture the 16-byte register (plus some slack)
-                    self.state.set(SpiState::InitComplete);
-                    self.send_command(SDCmd::CMD9_ReadCSD, 0x0, write_buffer, read_buffer, 28);
                 } else {
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
                     self.rxbuffer.replace(read_buffer);
-                    self.state.set(SpiState::Idle);
-                    self.alarm_state.set(AlarmState::Idle);
-                    self.alarm_count.set(0);
-                    self.client.get().map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
-                    });
+                    self.report_error(ErrorCode::InitializationFailure);
                 }
             }
 
@@ -609,12 +801,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
                     self.rxbuffer.replace(read_buffer);
-                    self.state.set(SpiState::Idle);
-                    self.alarm_state.set(AlarmState::Idle);
-                    self.alarm_count.set(0);
-                    self.client.get().map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
-                    });
+                    self.report_error(ErrorCode::InitializationFailure);
                 }
             }
 
@@ -648,12 +835,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
                     self.rxbuffer.replace(read_buffer);
-                    self.state.set(SpiState::Idle);
-                    self.alarm_state.set(AlarmState::Idle);
-                    self.alarm_count.set(0);
-                    self.client.get().map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
-                    });
+                    self.report_error(ErrorCode::InitializationFailure);
                 }
             }
 
@@ -666,17 +848,12 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     // Note that the receive length needs to be increased here
                     //  to capture the 16-byte register (plus some slack)
                     self.state.set(SpiState::InitComplete);
-                    self.send_command(SDCmd::CMD9_ReadCSD, 0x0, write_buffer, read_buffer, 28);
+                    self.send_command(SDCmd::CMD9_ReadCSD, 0x0, write_buffer, read_buffer, 36);
                 } else {
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
                     self.rxbuffer.replace(read_buffer);
-                    self.state.set(SpiState::Idle);
-                    self.alarm_state.set(AlarmState::Idle);
-                    self.alarm_count.set(0);
-                    self.client.get().map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
-                    });
+                    self.report_error(ErrorCode::InitializationFailure);
                 }
             }
 
@@ -685,63 +862,73 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                 let (r1, _, _) = self.get_response(SDResponse::R1_Status, read_buffer);
 
                 if r1 == SUCCESS_STATUS {
-                    let mut total_size: u64 = 0;
-
-                    // find CSD register value
-                    // Slide through 12-byte windows searching for beginning of
-                    // the CSD register
-                    for buf in read_buffer.windows(12) {
-                        if buf[0] == DATA_TOKEN {
-                            // get total size from CSD
-                            if (buf[1] & 0xC0) == 0x00 {
-                                // CSD version 1.0
-                                let c_size = (((buf[7] & 0x03) as u32) << 10)
-                                    | (((buf[8] & 0xFF) as u32) << 2)
-                                    | (((buf[9] & 0xC0) as u32) >> 6);
-                                let c_size_mult = (((buf[10] & 0x03) as u32) << 1)
-                                    | (((buf[11] & 0x80) as u32) >> 7);
-                                let read_bl_len = (buf[6] & 0x0F) as u32;
-
-                                let block_count = (c_size + 1) * (1 << (c_size_mult + 2));
-                                let block_len = 1 << read_bl_len;
-                                total_size = block_count as u64 * block_len as u64;
+                    // the CSD register is sent as a data block. Search for
+                    //  its data token after the command bytes, only looking at
+                    //  bytes that were actually received. The card sends the
+                    //  token at most 8 bytes after the response, so 36 bytes
+                    //  hold the whole register
+                    let received = cmp::min(read_buffer.len(), 8 + 36);
+                    let token = read_buffer[8..received]
+                        .iter()
+                        .position(|&byte| byte == DATA_TOKEN)
+                        .map(|index| 8 + index + 1);
+
+                    match token {
+                        Some(start) => {
+                            // register and CRC follow the token, unless the
+                            //  card was too slow for them to fit
+                            let block_count = if start + 18 <= received {
+                                self.parse_csd(&read_buffer[start..start + 18])
                             } else {
-                                // CSD version 2.0
-                                let c_size = (((buf[8] & 0x3F) as u32) << 16)
-                                    | (((buf[9] & 0xFF) as u32) << 8)
-                                    | ((buf[10] & 0xFF) as u32);
-                                total_size = ((c_size as u64) + 1) * 512 * 1024;
-                            }
-
-                            break;
+                                None
+                            };
+                            self.csd_received(write_buffer, read_buffer, block_count);
+                        }
+                        None => {
+                            // check for the data token to be ready
+                            self.state.set(SpiState::InitWaitCSD);
+                            self.read_bytes(write_buffer, read_buffer, 1);
                         }
                     }
+                } else {
+                    // error, send callback and quit
+                    self.txbuffer.replace(write_buffer);
+                    self.rxbuffer.replace(read_buffer);
+                    self.report_error(ErrorCode::InitializationFailure);
+                }
+            }
+
+            SpiState::InitWaitCSD => {
+                if read_buffer[0] == DATA_TOKEN {
+                    // register ready to read. Read register plus CRC
+                    self.alarm_count.set(0);
+                    self.state.set(SpiState::InitReceivedCSD);
+                    self.read_bytes(write_buffer, read_buffer, 16 + 2);
+                } else if read_buffer[0] == 0xFF {
+                    // line is idling high, register is not ready
 
                     // replace buffers
                     self.txbuffer.replace(write_buffer);
                     self.rxbuffer.replace(read_buffer);
 
-                    // initialization complete
-                    self.state.set(SpiState::Idle);
-                    self.is_initialized.set(true);
-
-                    // perform callback
-                    self.client.get().map(move |client| {
-                        client.init_done(512, total_size);
-                    });
+                    // try again after 1 ms
+                    self.alarm_state.set(AlarmState::WaitForCSD);
+                    let interval = (1 as u32) * <A::Frequency>::frequency() / 1000;
+                    let tics = self.alarm.now().wrapping_add(interval);
+                    self.alarm.set_alarm(tics);
                 } else {
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
                     self.rxbuffer.replace(read_buffer);
-                    self.state.set(SpiState::Idle);
-                    self.alarm_state.set(AlarmState::Idle);
-                    self.alarm_count.set(0);
-                    self.client.get().map(move |client| {
-                        client.error(ErrorCode::InitializationFailure as u32);
-                    });
+                    self.report_error(ErrorCode::InitializationFailure);
                 }
             }
 
+            SpiState::InitReceivedCSD => {
+                let block_count = self.parse_csd(&read_buffer[..18]);
+                self.csd_received(write_buffer, read_buffer, block_count);
+            }
+
             SpiState::StartReadBlocks { count } => {
                 // check response
                 let (r1, _, _) = self.get_response(SDResponse::R1_Status, read_buffer);
@@ -760,12 +947,7 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
                     self.rxbuffer.replace(read_buffer);
-                    self.state.set(SpiState::Idle);
-                    self.alarm_state.set(AlarmState::Idle);
-                    self.alarm_count.set(0);
-                    self.client.get().map(move |client| {
-                        client.error(ErrorCode::ReadFailure as u32);
-                    });
+                    self.report_error(ErrorCode::ReadFailure);
                 }
             }
 
@@ -791,38 +973,42 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
                     self.rxbuffer.replace(read_buffer);
-                    self.state.set(SpiState::Idle);
-                    self.alarm_state.set(AlarmState::Idle);
-                    self.alarm_count.set(0);
-                    self.client.get().map(move |client| {
-                        client.error(ErrorCode::ReadFailure as u32);
-                    });
+                    self.report_error(ErrorCode::ReadFailure);
                 }
             }
 
             SpiState::ReadBlockComplete => {
+                // check the CRC of the data block
+                if !block_crc_valid(&read_buffer[..512 + 2]) {
+                    // error, send callback and quit
+                    self.txbuffer.replace(write_buffer);
+                    self.rxbuffer.replace(read_buffer);
+                    self.report_error(ErrorCode::CRCFailure);
+                    return;
+                }
+
+                // copy data to user buffer
+                // Limit to minimum length between buffer, read_buffer, and 512
+                // (block size)
+                let mut read_len = 0;
+                self.client_buffer.map(|buffer| {
+                    for (client_byte, &read_byte) in
+                        buffer.iter_mut().zip(read_buffer.iter()).take(512)
+                    {
+                        *client_byte = read_byte;
+                    }
+                    read_len = cmp::min(read_buffer.len(), cmp::min(buffer.len(), 512));
+                });
+
                 // replace buffers
                 self.txbuffer.replace(write_buffer);
                 self.rxbuffer.replace(read_buffer);
 
                 // read finished, perform callback
                 self.state.set(SpiState::Idle);
-                self.rxbuffer.map(|read_buffer| {
-                    self.client_buffer.take().map(move |buffer| {
-                        // copy data to user buffer
-                        // Limit to minimum length between buffer, read_buffer,
-                        // and 512 (block size)
-                        for (client_byte, &read_byte) in
-                            buffer.iter_mut().zip(read_buffer.iter()).take(512)
-                        {
-                            *client_byte = read_byte;
-                        }
-
-                        // callback
-                        let read_len = cmp::min(read_buffer.len(), cmp::min(buffer.len(), 512));
-                        self.client.get().map(move |client| {
-                            client.read_done(buffer, read_len);
-                        });
+                self.client_buffer.take().map(move |buffer| {
+                    self.client.get().map(move |client| {
+                        client.read_done(buffer, read_len);
                     });
                 });
             }
@@ -847,40 +1033,41 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                     let tics = self.alarm.now().wrapping_add(interval);
                     self.alarm.set_alarm(tics);
                 } else {
-                    // error, send callback and quit
-                    self.txbuffer.replace(write_buffer);
-                    self.rxbuffer.replace(read_buffer);
-                    self.state.set(SpiState::Idle);
-                    self.alarm_state.set(AlarmState::Idle);
-                    self.alarm_count.set(0);
-                    self.client.get().map(move |client| {
-                        client.error(ErrorCode::ReadFailure as u32);
-                    });
+                    // error token, the transmission still has to be stopped
+                    self.transfer_error.set(Some(ErrorCode::ReadFailure));
+                    self.state.set(SpiState::ReadBlocksComplete);
+                    self.send_command(SDCmd::CMD12_StopRead, 0x0, write_buffer, read_buffer, 10);
                 }
             }
 
             SpiState::ReceivedBlock { count } => {
-                // copy block over to client buffer
-                self.client_buffer.map(|buffer| {
-                    // copy block into client buffer
-                    // Limit to minimum length between buffer, read_buffer, and
-                    // 512 (block size)
-                    let offset = self.client_offset.get();
-                    for (client_byte, &read_byte) in buffer
-                        .iter_mut()
-                        .skip(offset)
-                        .zip(read_buffer.iter())
-                        .take(512)
-                    {
-                        *client_byte = read_byte;
-                    }
+                if block_crc_valid(&read_buffer[..512 + 2]) {
+                    // copy block over to client buffer
+                    self.client_buffer.map(|buffer| {
+                        // copy block into client buffer
+                        // Limit to minimum length between buffer, read_buffer,
+                        // and 512 (block size)
+                        let offset = self.client_offset.get();
+                        for (client_byte, &read_byte) in buffer
+                            .iter_mut()
+                            .skip(offset)
+                            .zip(read_buffer.iter())
+                            .take(512)
+                        {
+                            *client_byte = read_byte;
+                        }
 
-                    // update offset
-                    let read_len = cmp::min(read_buffer.len(), cmp::min(buffer.len(), 512));
-                    self.client_offset.set(offset + read_len);
-                });
+                        // update offset
+                        let read_len = cmp::min(buffer.len().saturating_sub(offset), 512);
+                        self.client_offset.set(offset + read_len);
+                    });
+                } else {
+                    // stop reading, the error is reported once the card
+                    //  stopped transmitting
+                    self.transfer_error.set(Some(ErrorCode::CRCFailure));
+                }
 
-                if count <= 1 {
+                if count <= 1 || self.transfer_error.get().is_some() {
                     // all blocks received. Terminate multiple read
                     self.state.set(SpiState::ReadBlocksComplete);
                     self.send_command(SDCmd::CMD12_StopRead, 0x0, write_buffer, read_buffer, 10);
@@ -894,30 +1081,48 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
 
             SpiState::ReadBlocksComplete => {
                 // check response
-                let (r1, _, _) = self.get_response(SDResponse::R1_Status, read_buffer);
+                // The byte following the command is a stuff byte that can
+                //  still contain data from the transmission and must be
+                //  skipped
+                let (r1, _, _) = self.get_response(SDResponse::R1_Status, &read_buffer[9..8 + 10]);
 
                 if r1 == SUCCESS_STATUS {
-                    // replace buffers
+                    // the card signals busy until the transmission stopped
+                    self.state.set(SpiState::WaitReadStopBusy);
+                    self.read_bytes(write_buffer, read_buffer, 1);
+                } else {
+                    // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
                     self.rxbuffer.replace(read_buffer);
-                    self.state.set(SpiState::Idle);
+                    self.report_error(ErrorCode::ReadFailure);
+                }
+            }
 
+            SpiState::WaitReadStopBusy => {
+                // replace buffers
+                self.txbuffer.replace(write_buffer);
+                self.rxbuffer.replace(read_buffer);
+
+                // check if line is still held low (busy state)
+                let busy = self.rxbuffer.map_or(false, |read_buffer| read_buffer[0] == 0x00);
+                if busy {
+                    // try again after 1 ms
+                    self.alarm_state.set(AlarmState::WaitForReadStopBusy);
+                    let interval = (1 as u32) * <A::Frequency>::frequency() / 1000;
+                    let tics = self.alarm.now().wrapping_add(interval);
+                    self.alarm.set_alarm(tics);
+                } else if let Some(error) = self.transfer_error.get() {
+                    // error, send callback and quit
+                    self.report_error(error);
+                } else {
                     // read finished, perform callback
+                    self.state.set(SpiState::Idle);
+                    self.alarm_count.set(0);
                     self.client_buffer.take().map(move |buffer| {
                         self.client.get().map(move |client| {
                             client.read_done(buffer, self.client_offset.get());
                         });
                     });
-                } else {
-                    // error, send callback and quit
-                    self.txbuffer.replace(write_buffer);
-                    self.rxbuffer.replace(read_buffer);
-                    self.state.set(SpiState::Idle);
-                    self.alarm_state.set(AlarmState::Idle);
-                    self.alarm_count.set(0);
-                    self.client.get().map(move |client| {
-                        client.error(ErrorCode::ReadFailure as u32);
-                    });
                 }
             }
 
@@ -926,114 +1131,123 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                 let (r1, _, _) = self.get_response(SDResponse::R1_Status, read_buffer);
 
                 if r1 == SUCCESS_STATUS {
-                    if count <= 1 {
-                        let bytes_written = self.client_buffer.map_or(0, |buffer| {
-                            // copy over data from client buffer
-                            // Limit to minimum length between write_buffer,
-                            // buffer, and 512 (block size)
-                            for (write_byte, &client_byte) in
-                                write_buffer.iter_mut().skip(1).zip(buffer.iter()).take(512)
-                            {
-                                *write_byte = client_byte;
-                            }
-
-                            // calculate number of bytes written
-                            cmp::min(write_buffer.len(), cmp::min(buffer.len(), 512))
-                        });
-
-                        // set a known value for remaining bytes
-                        for write_byte in write_buffer
-                            .iter_mut()
-                            .skip(1)
-                            .skip(bytes_written)
-                            .take(512)
-                        {
-                            *write_byte = 0xFF;
-                        }
-
-                        // set up remainder of data packet
-                        write_buffer[0] = DATA_TOKEN; // Data token
-                        write_buffer[513] = 0xFF; // dummy CRC
-                        write_buffer[514] = 0xFF; // dummy CRC
-
-                        // write data packet
-                        self.state.set(SpiState::WriteBlockResponse);
-                        self.write_bytes(write_buffer, read_buffer, 515);
-                    } else {
-                        // multi-block SD card writes are unimplemented
-                        // This should have returned an error already, but if
-                        // we got here somehow, return an error and quit now
-                        self.txbuffer.replace(write_buffer);
-                        self.rxbuffer.replace(read_buffer);
-                        self.state.set(SpiState::Idle);
-                        self.alarm_state.set(AlarmState::Idle);
-                        self.alarm_count.set(0);
-                        self.client.get().map(move |client| {
-                            client.error(ErrorCode::WriteFailure as u32);
-                        });
-                    }
+                    // send the first block
+                    self.write_data_block(write_buffer, read_buffer, count);
                 } else {
                     // error, send callback and quit
                     self.txbuffer.replace(write_buffer);
                     self.rxbuffer.replace(read_buffer);
-                    self.state.set(SpiState::Idle);
-                    self.alarm_state.set(AlarmState::Idle);
-                    self.alarm_count.set(0);
-                    self.client.get().map(move |client| {
-                        client.error(ErrorCode::WriteFailure as u32);
-                    });
+                    self.report_error(ErrorCode::WriteFailure);
                 }
             }
 
-            SpiState::WriteBlockResponse => {
+            SpiState::WriteBlockResponse { count } => {
                 // Get data packet
-                self.state.set(SpiState::WriteBlockBusy);
+                self.state.set(SpiState::WriteBlockBusy { count: count });
                 self.read_bytes(write_buffer, read_buffer, 1);
             }
 
-            SpiState::WriteBlockBusy => {
-                if (read_buffer[0] & 0x1F) == 0x05 {
+            SpiState::WriteBlockBusy { count } => {
+                if (read_buffer[0] & 0x1F) == DATA_ACCEPTED {
                     // check if sd card is busy
-                    self.state.set(SpiState::WaitWriteBlockBusy);
+                    self.state.set(SpiState::WaitWriteBlockBusy { count: count });
                     self.read_bytes(write_buffer, read_buffer, 1);
                 } else {
-                    // error, send callback and quit
-                    self.txbuffer.replace(write_buffer);
-                    self.rxbuffer.replace(read_buffer);
-                    self.state.set(SpiState::Idle);
-                    self.alarm_state.set(AlarmState::Idle);
-                    self.alarm_count.set(0);
-                    self.client.get().map(move |client| {
-                        client.error(ErrorCode::WriteFailure as u32);
-                    });
+                    // The block was rejected because of a CRC or write error
+                    let error = if (read_buffer[0] & 0x1F) == DATA_CRC_ERROR {
+                        ErrorCode::CRCFailure
+                    } else {
+                        ErrorCode::WriteFailure
+                    };
+                    if self.multiple_blocks.get() {
+                        // the card still waits for data, stop the
+                        //  transmission before reporting the error
+                        self.transfer_error.set(Some(error));
+                        write_buffer[0] = STOP_TRANSMISSION_TOKEN;
+                        write_buffer[1] = 0xFF;
+                        self.state.set(SpiState::WriteStopToken);
+                        self.write_bytes(write_buffer, read_buffer, 2);
+                    } else {
+                        // error, send callback and quit
+                        self.txbuffer.replace(write_buffer);
+                        self.rxbuffer.replace(read_buffer);
+                        self.report_error(error);
+                    }
                 }
             }
 
-            SpiState::WaitWriteBlockBusy => {
+            SpiState::WaitWriteBlockBusy { count } => {
                 // check if line is still held low (busy state)
                 if read_buffer[0] != 0x00 {
-                    // replace buffers
-                    self.txbuffer.replace(write_buffer);
-                    self.rxbuffer.replace(read_buffer);
-
-                    // read finished, perform callback
-                    self.state.set(SpiState::Idle);
                     self.alarm_count.set(0);
-                    self.client_buffer.take().map(move |buffer| {
-                        self.client.get().map(move |client| {
-                            client.write_done(buffer);
+                    if count > 1 {
+                        // send the next block
+                        self.write_data_block(write_buffer, read_buffer, count - 1);
+                    } else if self.multiple_blocks.get() {
+                        // all blocks written. Terminate multiple write, the
+                        //  card signals busy starting one byte after the token
+                        write_buffer[0] = STOP_TRANSMISSION_TOKEN;
+                        write_buffer[1] = 0xFF;
+                        self.state.set(SpiState::WriteStopToken);
+                        self.write_bytes(write_buffer, read_buffer, 2);
+                    } else {
+                        // replace buffers
+                        self.txbuffer.replace(write_buffer);
+                        self.rxbuffer.replace(read_buffer);
+
+                        // write finished, perform callback
+                        self.state.set(SpiState::Idle);
+                        self.client_buffer.take().map(move |buffer| {
+                            self.client.get().map(move |client| {
+                                client.write_done(buffer);
+                            });
                         });
-                    });
+                    }
                 } else {
                     // replace buffers
                     self.txbuffer.replace(write_buffer);
                     self.rxbuffer.replace(read_buffer);
 
                     // try again after 1 ms
-                    self.alarm_state.set(AlarmState::WaitForWriteBusy);
+                    self.alarm_state
+                        .set(AlarmState::WaitForWriteBusy { count: count });
+                    let interval = (1 as u32) * <A::Frequency>::frequency() / 1000;
+                    let tics = self.alarm.now().wrapping_add(interval);
+                    self.alarm.set_alarm(tics);
+                }
+            }
+
+            SpiState::WriteStopToken => {
+                // check if sd card is busy
+                self.state.set(SpiState::WaitWriteStopBusy);
+                self.read_bytes(write_buffer, read_buffer, 1);
+            }
+
+            SpiState::WaitWriteStopBusy => {
+                // replace buffers
+                self.txbuffer.replace(write_buffer);
+                self.rxbuffer.replace(read_buffer);
+
+                // check if line is still held low (busy state)
+                let busy = self.rxbuffer.map_or(false, |read_buffer| read_buffer[0] == 0x00);
+                if busy {
+                    // try again after 1 ms
+                    self.alarm_state.set(AlarmState::WaitForWriteStopBusy);
                     let interval = (1 as u32) * <A::Frequency>::frequency() / 1000;
                     let tics = self.alarm.now().wrapping_add(interval);
                     self.alarm.set_alarm(tics);
+                } else if let Some(error) = self.transfer_error.get() {
+                    // error, send callback and quit
+                    self.report_error(error);
+                } else {
+                    // write finished, perform callback
+                    self.state.set(SpiState::Idle);
+                    self.alarm_count.set(0);
+                    self.client_buffer.take().map(move |buffer| {
+                        self.client.get().map(move |client| {
+                            client.write_done(buffer);
+                        });
+                    });
                 }
             }
 
@@ -1053,27 +1267,25 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
         let repeats = self.alarm_count.get();
         if repeats > 100 {
             // error, send callback and quit
-            self.state.set(SpiState::Idle);
-            self.alarm_state.set(AlarmState::Idle);
-            self.alarm_count.set(0);
-            self.client.get().map(move |client| {
-                client.error(ErrorCode::TimeoutFailure as u32);
-            });
+            self.report_error(ErrorCode::TimeoutFailure);
         } else {
             self.alarm_count.set(repeats + 1);
         }
 
         match self.alarm_state.get() {
             AlarmState::DetectionChange => {
+                // the card has settled, the client may start initializing it
+                //  from the callback
+                self.alarm_count.set(0);
+                self.alarm_state.set(AlarmState::Idle);
+
+                // re-enable interrupts
+                self.detect_changes();
+
                 // perform callback
                 self.client.get().map(move |client| {
                     client.card_detection_changed(self.is_installed());
                 });
-
-                // re-enable interrupts
-                self.detect_changes();
-                self.alarm_count.set(0);
-                self.alarm_state.set(AlarmState::Idle);
             }
 
             AlarmState::RepeatHCSInit => {
@@ -1161,12 +1373,51 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
                 self.alarm_state.set(AlarmState::Idle);
             }
 
-            AlarmState::WaitForWriteBusy => {
+            AlarmState::WaitForCSD => {
+                // check CSD register again
+                self.txbuffer.take().map(|write_buffer| {
+                    self.rxbuffer.take().map(move |read_buffer| {
+                        // wait until ready and then read the register
+                        self.state.set(SpiState::InitWaitCSD);
+                        self.read_bytes(write_buffer, read_buffer, 1);
+                    });
+                });
+
+                self.alarm_state.set(AlarmState::Idle);
+            }
+
+            AlarmState::WaitForReadStopBusy => {
+                // check busy state again
+                self.txbuffer.take().map(|write_buffer| {
+                    self.rxbuffer.take().map(move |read_buffer| {
+                        // check if sd card is busy
+                        self.state.set(SpiState::WaitReadStopBusy);
+                        self.read_bytes(write_buffer, read_buffer, 1);
+                    });
+                });
+
+                self.alarm_state.set(AlarmState::Idle);
+            }
+
+            AlarmState::WaitForWriteBusy { count } => {
                 // check card initialization again
                 self.txbuffer.take().map(|write_buffer| {
                     self.rxbuffer.take().map(move |read_buffer| {
                         // check if sd card is busy
-                        self.state.set(SpiState::WaitWriteBlockBusy);
+                        self.state.set(SpiState::WaitWriteBlockBusy { count: count });
+                        self.read_bytes(write_buffer, read_buffer, 1);
+                    });
+                });
+
+                self.alarm_state.set(AlarmState::Idle);
+            }
+
+            AlarmState::WaitForWriteStopBusy => {
+                // check busy state again
+                self.txbuffer.take().map(|write_buffer| {
+                    self.rxbuffer.take().map(move |read_buffer| {
+                        // check if sd card is busy
+                        self.state.set(SpiState::WaitWriteStopBusy);
                         self.read_bytes(write_buffer, read_buffer, 1);
                     });
                 });
@@ -1183,6 +1434,9 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
 
     pub fn set_client<C: SDCardClient>(&self, client: &'static C) {
         self.client.set(Some(client));
+
+        // the client is told about cards being inserted or removed
+        self.detect_changes();
     }
 
     pub fn is_installed(&self) -> bool {
@@ -1197,6 +1451,11 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
         self.is_initialized.get()
     }
 
+    /// number of 512 byte blocks on the card, zero until it is initialized
+    pub fn get_block_count(&self) -> u32 {
+        self.block_count.get()
+    }
+
     /// watches SD card detect pin for changes, sends callback on change
     pub fn detect_changes(&self) {
         self.detect_pin.get().map(|pin| {
@@ -1205,8 +1464,15 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
     }
 
     pub fn initialize(&self) -> ReturnCode {
+        // only one transaction at a time
+        if self.state.get() != SpiState::Idle || self.alarm_state.get() != AlarmState::Idle {
+            return ReturnCode::EBUSY;
+        }
+
         // if not already, set card to uninitialized again
         self.is_initialized.set(false);
+        self.card_type.set(SDCardType::Uninitialized);
+        self.block_count.set(0);
 
         // no point in initializing if the card is not installed
         if self.is_installed() {
@@ -1228,102 +1494,116 @@ impl<'a, A: hil::time::Alarm + 'a> SDCard<'a, A> {
         }
     }
 
-    pub fn read_blocks(&self, buffer: &'static mut [u8], sector: u32, count: u32) -> ReturnCode {
+    /// check whether a transfer of `count` blocks starting at `sector` can be
+    /// started and return the address to send to the card
+    fn start_transfer(&self, sector: u32, count: u32) -> Result<u32, ReturnCode> {
         // only if initialized and installed
-        if self.is_installed() {
-            if self.is_initialized() {
-                self.txbuffer.take().map_or(ReturnCode::ENOMEM, |txbuffer| {
-                    self.rxbuffer
-                        .take()
-                        .map_or(ReturnCode::ENOMEM, move |rxbuffer| {
-                            // save the user buffer for later
-                            self.client_buffer.replace(buffer);
-                            self.client_offset.set(0);
-
-                            // convert block address to byte address for non-block
-                            //  access cards
-                            let mut address = sector;
-                            if self.card_type.get() != SDCardType::SDv2BlockAddressable {
-                                address *= 512;
-                            }
-
-                            self.state.set(SpiState::StartReadBlocks { count: count });
-                            if count == 1 {
-                                self.send_command(
-                                    SDCmd::CMD17_ReadSingle,
-                                    address,
-                                    txbuffer,
-                                    rxbuffer,
-                                    10,
-                                );
-                            } else {
-                                self.send_command(
-                                    SDCmd::CMD18_ReadMultiple,
-                                    address,
-                                    txbuffer,
-                                    rxbuffer,
-                                    10,
-                                );
-                            }
-
-                            // command started successfully
-                            ReturnCode::SUCCESS
-                        })
-                })
-            } else {
-                // sd card not initialized
-                ReturnCode::ERESERVE
-            }
-        } else {
+        if !self.is_installed() {
             // sd card not installed
-            ReturnCode::EUNINSTALLED
+            return Err(ReturnCode::EUNINSTALLED);
+        }
+        if !self.is_initialized() {
+            // sd card not initialized
+            return Err(ReturnCode::ERESERVE);
+        }
+
+        // only one transaction at a time
+        if self.state.get() != SpiState::Idle || self.alarm_state.get() != AlarmState::Idle {
+            return Err(ReturnCode::EBUSY);
+        }
+
+        // blocks must be on the card
+        let end = sector.checked_add(count);
+        let block_count = self.block_count.get();
+        if count == 0 || end.is_none() || (block_count != 0 && end.unwrap() > block_count) {
+            return Err(ReturnCode::EINVAL);
+        }
+
+        // convert block address to byte address for non-block access cards
+        if self.card_type.get() == SDCardType::SDv2BlockAddressable {
+            Ok(sector)
+        } else {
+            sector.checked_mul(512).ok_or(ReturnCode::EINVAL)
         }
     }
 
-    pub fn write_blocks(&self, buffer: &'static mut [u8], sector: u32, count: u32) -> ReturnCode {
-        // only if initialized and installed
-        if self.is_installed() {
-            if self.is_initialized() {
-                self.txbuffer.take().map_or(ReturnCode::ENOMEM, |txbuffer| {
-                    self.rxbuffer
-                        .take()
-                        .map_or(ReturnCode::ENOMEM, move |rxbuffer| {
-                            // save the user buffer for later
-                            self.client_buffer.replace(buffer);
-                            self.client_offset.set(0);
-
-                            // convert block address to byte address for non-block
-                            //  access cards
-                            let mut address = sector;
-                            if self.card_type.get() != SDCardType::SDv2BlockAddressable {
-                                address *= 512;
-                            }
-
-                            self.state.set(SpiState::StartWriteBlocks { count: count });
-                            if count == 1 {
-                                self.send_command(
-                                    SDCmd::CMD24_WriteSingle,
-                                    address,
-                                    txbuffer,
-                                    rxbuffer,
-                                    10,
-                                );
-
-                                // command started successfully
-                                ReturnCode::SUCCESS
-                            } else {
-                                // can't write multiple blocks yet
-                                ReturnCode::ENOSUPPORT
-                            }
-                        })
-                })
-            } else {
-                // sd card not initialized
-                ReturnCode::ERESERVE
+    /// read `count` consecutive blocks starting at `sector` into `buffer`
+    ///
+    /// The buffer is handed back if the read could not be started
+    pub fn read_blocks(
+        &self,
+        buffer: &'static mut [u8],
+        sector: u32,
+        count: u32,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        let address = match self.start_transfer(sector, count) {
+            Ok(address) => address,
+            Err(rc) => return (rc, Some(buffer)),
+        };
+
+        match (self.txbuffer.take(), self.rxbuffer.take()) {
+            (Some(txbuffer), Some(rxbuffer)) => {
+                // save the user buffer for later
+                self.client_buffer.replace(buffer);
+                self.client_offset.set(0);
+                self.multiple_blocks.set(count > 1);
+                self.transfer_error.set(None);
+
+                self.state.set(SpiState::StartReadBlocks { count: count });
+                if count == 1 {
+                    self.send_command(SDCmd::CMD17_ReadSingle, address, txbuffer, rxbuffer, 10);
+                } else {
+                    self.send_command(SDCmd::CMD18_ReadMultiple, address, txbuffer, rxbuffer, 10);
+                }
+
+                // command started successfully
+                (ReturnCode::SUCCESS, None)
+            }
+            (txbuffer, rxbuffer) => {
+                txbuffer.map(|txbuffer| self.txbuffer.replace(txbuffer));
+                rxbuffer.map(|rxbuffer| self.rxbuffer.replace(rxbuffer));
+                (ReturnCode::ENOMEM, Some(buffer))
+            }
+        }
+    }
+
+    /// write `count` consecutive blocks from `buffer` starting at `sector`
+    ///
+    /// The buffer is handed back if the write could not be started
+    pub fn write_blocks(
+        &self,
+        buffer: &'static mut [u8],
+        sector: u32,
+        count: u32,
+    ) -> (ReturnCode, Option<&'static mut [u8]>) {
+        let address = match self.start_transfer(sector, count) {
+            Ok(address) => address,
+            Err(rc) => return (rc, Some(buffer)),
+        };
+
+        match (self.txbuffer.take(), self.rxbuffer.take()) {
+            (Some(txbuffer), Some(rxbuffer)) => {
+                // save the user buffer for later
+                self.client_buffer.replace(buffer);
+                self.client_offset.set(0);
+                self.multiple_blocks.set(count > 1);
+                self.transfer_error.set(None);
+
+                self.state.set(SpiState::StartWriteBlocks { count: count });
+                if count == 1 {
+                    self.send_command(SDCmd::CMD24_WriteSingle, address, txbuffer, rxbuffer, 10);
+                } else {
+                    self.send_command(SDCmd::CMD25_WriteMultiple, address, txbuffer, rxbuffer, 10);
+                }
+
+                // command started successfully
+                (ReturnCode::SUCCESS, None)
+            }
+            (txbuffer, rxbuffer) => {
+                txbuffer.map(|txbuffer| self.txbuffer.replace(txbuffer));
+                rxbuffer.map(|rxbuffer| self.rxbuffer.replace(rxbuffer));
+                (ReturnCode::ENOMEM, Some(buffer))
             }
-        } else {
-            // sd card not installed
-            ReturnCode::EUNINSTALLED
         }
     }
 }
@@ -1357,15 +1637,13 @@ impl<'a, A: hil::time::Alarm + 'a> hil::gpio::Client for SDCard<'a, A> {
         if self.alarm_state.get() != AlarmState::Idle || self.state.get() != SpiState::Idle {
             // something was running when this occurred. Kill the transaction and
             //  send an error callback
-            self.state.set(SpiState::Idle);
-            self.alarm_state.set(AlarmState::Idle);
-            self.client.get().map(move |client| {
-                client.error(ErrorCode::CardStateChanged as u32);
-            });
+            self.report_error(ErrorCode::CardStateChanged);
         }
 
         // either the card is new or gone, in either case it isn't initialized
         self.is_initialized.set(false);
+        self.card_type.set(SDCardType::Uninitialized);
+        self.block_count.set(0);
 
         // disable additional interrupts
         self.detect_pin.get().map(|pin| {
@@ -1408,18 +1686,19 @@ impl Default for App {
 }
 
 /// Buffer for SD card driver, assigned in board `main.rs` files
-pub static mut KERNEL_BUFFER: [u8; 512] = [0; 512];
+/// Its length limits how many blocks an application can transfer at once
+pub static mut KERNEL_BUFFER: [u8; 2048] = [0; 2048];
 
 /// Functions for SDCardDriver
 impl<'a, A: hil::time::Alarm + 'a> SDCardDriver<'a, A> {
     /// Create new SD card userland interface
     ///
     /// sdcard - SDCard interface to provide application access to
-    /// kernel_buf - buffer used to hold SD card blocks, must be at least 512
-    ///     bytes in length
+    /// kernel_buf - buffer used to hold SD card blocks, must be a multiple of
+    ///     512 bytes in length
     pub fn new(
         sdcard: &'a SDCard<'a, A>,
-        kernel_buf: &'static mut [u8; 512],
+        kernel_buf: &'static mut [u8],
     ) -> SDCardDriver<'a, A> {
         // return new SDCardDriver
         SDCardDriver {
@@ -1485,7 +1764,9 @@ impl<'a, A: hil::time::Alarm + 'a> SDCardClient for SDCardDriver<'a, A> {
         });
     }
 
-    fn error(&self, error: u32) {
+    fn error(&self, error: u32, buffer: Option<&'static mut [u8]>) {
+        buffer.map(|buffer| self.kernel_buf.replace(buffer));
+
         self.app.map(|app| {
             app.callback.map(|mut cb| {
                 cb.schedule(4, error as usize, 0);
@@ -1536,7 +1817,10 @@ impl<'a, A: hil::time::Alarm + 'a> Driver for SDCardDriver<'a, A> {
         }
     }
 
-    fn command(&self, command_num: usize, data: usize, _: usize, _: AppId) -> ReturnCode {
+    fn command(&self, command_num: usize, data: usize, count: usize, _: AppId) -> ReturnCode {
+        // number of blocks to transfer, defaults to a single block
+        let count = cmp::max(count, 1);
+
         match command_num {
             // check if present
             0 => ReturnCode::SUCCESS,
@@ -1550,14 +1834,23 @@ impl<'a, A: hil::time::Alarm + 'a> Driver for SDCardDriver<'a, A> {
             // initialize
             2 => self.sdcard.initialize(),
 
-            // read_block
+            // read_blocks
             3 => self.kernel_buf
                 .take()
                 .map_or(ReturnCode::EBUSY, |kernel_buf| {
-                    self.sdcard.read_blocks(kernel_buf, data as u32, 1)
+                    if count > kernel_buf.len() / 512 {
+                        // transfer does not fit into the kernel buffer
+                        self.kernel_buf.replace(kernel_buf);
+                        return ReturnCode::ESIZE;
+                    }
+
+                    let (rc, buffer) = self.sdcard
+                        .read_blocks(kernel_buf, data as u32, count as u32);
+                    buffer.map(|buffer| self.kernel_buf.replace(buffer));
+                    rc
                 }),
 
-            // write_block
+            // write_blocks
             4 => {
                 self.app.map_or(ReturnCode::ENOMEM, |app| {
                     app.write_buffer
@@ -1566,22 +1859,53 @@ impl<'a, A: hil::time::Alarm + 'a> Driver for SDCardDriver<'a, A> {
                             self.kernel_buf
                                 .take()
                                 .map_or(ReturnCode::EBUSY, |kernel_buf| {
+                                    if count > kernel_buf.len() / 512 {
+                                        // transfer does not fit into the kernel buffer
+                                        self.kernel_buf.replace(kernel_buf);
+                                        return ReturnCode::ESIZE;
+                                    }
+
                                     // copy over write data from application
                                     // Limit to minimum length between kernel_buf,
-                                    // write_buffer, and 512 (block size)
+                                    // write_buffer, and the blocks to write.
+                                    // Blocks not covered by the application
+                                    // buffer are padded
+                                    let len = count * 512;
+                                    for byte in kernel_buf.iter_mut().take(len) {
+                                        *byte = 0xFF;
+                                    }
                                     for (kernel_byte, &write_byte) in
-                                        kernel_buf.iter_mut().zip(write_buffer.iter()).take(512)
+                                        kernel_buf.iter_mut().zip(write_buffer.iter()).take(len)
                                     {
                                         *kernel_byte = write_byte;
                                     }
 
                                     // begin writing
-                                    self.sdcard.write_blocks(kernel_buf, data as u32, 1)
+                                    let (rc, buffer) = self.sdcard
+                                        .write_blocks(kernel_buf, data as u32, count as u32);
+                                    buffer.map(|buffer| self.kernel_buf.replace(buffer));
+                                    rc
                                 })
                         })
                 })
             }
 
+            // block_count
+            5 => {
+                if self.sdcard.is_initialized() {
+                    let value = self.sdcard.get_block_count() as usize;
+                    if value > isize::max_value() as usize {
+                        // can't be represented in the return value
+                        ReturnCode::ESIZE
+                    } else {
+                        ReturnCode::SuccessWithValue { value: value }
+                    }
+                } else {
+                    // sd card not initialized
+                    ReturnCode::ERESERVE
+                }
+            }
+
             _ => ReturnCode::ENOSUPPORT,
         }
     }
@@ -0,0 +1,218 @@
+//! Software implementation of the SHA-256 hash function.
+//!
+//! This is used to check the integrity of data written to flash, for example
+//! kernel images received for an update. It processes data in 64 byte blocks
+//! as it is passed to `update()`, so large images can be hashed one flash page
+//! at a time without keeping them in memory.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! let mut sha = capsules::sha256::Sha256::new();
+//! sha.update(b"abc");
+//! let digest: [u8; capsules::sha256::DIGEST_LENGTH] = sha.finish();
+//! ```
+
+/// Length of a SHA-256 digest in bytes.
+pub const DIGEST_LENGTH: usize = 32;
+
+/// Length of the blocks the message is processed in.
+const BLOCK_LENGTH: usize = 64;
+
+/// Offset in the last block where the message length in bits is stored.
+const LENGTH_OFFSET: usize = 56;
+
+/// Round constants.
+const K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// Initial hash value.
+const H0: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+/// State of a SHA-256 computation. It is `Copy` so that it can be kept in a
+/// `Cell` while a capsule waits for the next piece of data.
+#[derive(Clone, Copy)]
+pub struct Sha256 {
+    /// Intermediate hash value.
+    state: [u32; 8],
+    /// Data that does not fill a whole block yet.
+    block: [u8; BLOCK_LENGTH],
+    /// Number of bytes in `block`.
+    block_length: usize,
+    /// Total number of bytes hashed so far.
+    length: u64,
+}
+
+impl Sha256 {
+    pub const fn new() -> Sha256 {
+        Sha256 {
+            state: H0,
+            block: [0; BLOCK_LENGTH],
+            block_length: 0,
+            length: 0,
+        }
+    }
+
+    /// Add `data` to the message being hashed.
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data.iter() {
+            self.block[self.block_length] = byte;
+            self.block_length += 1;
+            if self.block_length == BLOCK_LENGTH {
+                self.compress();
+                self.block_length = 0;
+            }
+        }
+        self.length = self.length.wrapping_add(data.len() as u64);
+    }
+
+    /// Pad the message and return its digest.
+    pub fn finish(mut self) -> [u8; DIGEST_LENGTH] {
+        let bit_length = self.length.wrapping_mul(8);
+
+        // Append a single one bit, then zeros up to the length field.
+        self.update(&[0x80]);
+        while self.block_length != LENGTH_OFFSET {
+            self.update(&[0]);
+        }
+        let mut length = [0; 8];
+        for i in 0..8 {
+            length[i] = (bit_length >> (56 - 8 * i)) as u8;
+        }
+        self.update(&length);
+
+        let mut digest = [0; DIGEST_LENGTH];
+        for i in 0..8 {
+            for j in 0..4 {
+                digest[i * 4 + j] = (self.state[i] >> (24 - 8 * j)) as u8;
+            }
+        }
+        digest
+    }
+
+    /// Process the data in `block`.
+    fn compress(&mut self) {
+        let mut w = [0u32; 64];
+        for i in 0..16 {
+            w[i] = (self.block[i * 4] as u32) << 24 | (self.block[i * 4 + 1] as u32) << 16
+                | (self.block[i * 4 + 2] as u32) << 8 | self.block[i * 4 + 3] as u32;
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let mut h = self.state;
+        for i in 0..64 {
+            let s1 = h[4].rotate_right(6) ^ h[4].rotate_right(11) ^ h[4].rotate_right(25);
+            let ch = (h[4] & h[5]) ^ (!h[4] & h[6]);
+            let t1 = h[7]
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(K[i])
+                .wrapping_add(w[i]);
+            let s0 = h[0].rotate_right(2) ^ h[0].rotate_right(13) ^ h[0].rotate_right(22);
+            let maj = (h[0] & h[1]) ^ (h[0] & h[2]) ^ (h[1] & h[2]);
+            let t2 = s0.wrapping_add(maj);
+
+            h[7] = h[6];
+            h[6] = h[5];
+            h[5] = h[4];
+            h[4] = h[3].wrapping_add(t1);
+            h[3] = h[2];
+            h[2] = h[1];
+            h[1] = h[0];
+            h[0] = t1.wrapping_add(t2);
+        }
+
+        for i in 0..8 {
+            self.state[i] = self.state[i].wrapping_add(h[i]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Sha256, DIGEST_LENGTH};
+
+    fn digest_from_hex(hex: &str) -> [u8; DIGEST_LENGTH] {
+        let hex = hex.as_bytes();
+        let nibble = |c: u8| if c <= b'9' { c - b'0' } else { c - b'a' + 10 };
+        let mut digest = [0; DIGEST_LENGTH];
+        for i in 0..DIGEST_LENGTH {
+            digest[i] = nibble(hex[2 * i]) << 4 | nibble(hex[2 * i + 1]);
+        }
+        digest
+    }
+
+    fn sha256(data: &[u8]) -> [u8; DIGEST_LENGTH] {
+        let mut sha = Sha256::new();
+        sha.update(data);
+        sha.finish()
+    }
+
+    // Test vectors from FIPS 180-2, appendix B.
+
+    #[test]
+    fn one_block() {
+        assert_eq!(
+            sha256(b"abc"),
+            digest_from_hex("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad")
+        );
+    }
+
+    #[test]
+    fn two_blocks() {
+        assert_eq!(
+            sha256(b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"),
+            digest_from_hex("248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1")
+        );
+    }
+
+    #[test]
+    fn million_a() {
+        let mut sha = Sha256::new();
+        for _ in 0..1000 {
+            sha.update(&[b'a'; 1000]);
+        }
+        assert_eq!(
+            sha.finish(),
+            digest_from_hex("cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0")
+        );
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(
+            sha256(b""),
+            digest_from_hex("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855")
+        );
+    }
+
+    #[test]
+    fn split_updates() {
+        // The digest must not depend on how the message is split up.
+        let message = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+        for split in 0..message.len() + 1 {
+            let mut sha = Sha256::new();
+            sha.update(&message[..split]);
+            sha.update(&message[split..]);
+            assert_eq!(sha.finish(), sha256(message));
+        }
+    }
+}
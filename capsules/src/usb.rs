@@ -1,9 +1,12 @@
 //! Platform-independent USB 2.0 protocol library
 
 use core::cell::Cell;
+use core::cmp::min;
 use core::convert::From;
 use core::fmt;
+use core::str;
 use kernel::common::VolatileCell;
+use kernel::hil::usb::TransferType;
 
 /// The datastructure sent in a SETUP handshake
 #[derive(Debug, Copy, Clone)]
@@ -131,6 +134,7 @@ pub enum DescriptorType {
     DeviceQualifier,
     OtherSpeedConfiguration,
     InterfacePower,
+    InterfaceAssociation = 11,
 }
 
 fn get_descriptor_type(byte: u8) -> Option<DescriptorType> {
@@ -143,6 +147,7 @@ fn get_descriptor_type(byte: u8) -> Option<DescriptorType> {
         6 => Some(DescriptorType::DeviceQualifier),
         7 => Some(DescriptorType::OtherSpeedConfiguration),
         8 => Some(DescriptorType::InterfacePower),
+        11 => Some(DescriptorType::InterfaceAssociation),
         _ => None,
     }
 }
@@ -261,6 +266,7 @@ pub trait Descriptor {
     fn write_to_unchecked(&self, buf: &[Cell<u8>]) -> usize;
 }
 
+#[derive(Copy, Clone)]
 pub struct DeviceDescriptor {
     /// Valid values include 0x0100 (USB1.0), 0x0110 (USB1.1) and 0x0200 (USB2.0)
     pub usb_release: u16,
@@ -448,6 +454,51 @@ impl Descriptor for InterfaceDescriptor {
     }
 }
 
+pub struct EndpointDescriptor {
+    pub endpoint_address: EndpointAddress,
+    pub transfer_type: TransferType,
+    /// Must be 8, 16, 32 or 64 for bulk endpoints
+    pub max_packet_size: u16,
+    /// Polling interval in frames, for interrupt endpoints
+    pub interval: u8,
+}
+
+impl Descriptor for EndpointDescriptor {
+    fn size(&self) -> usize {
+        7
+    }
+
+    fn write_to_unchecked(&self, buf: &[Cell<u8>]) -> usize {
+        buf[0].set(7); // Size of descriptor
+        buf[1].set(DescriptorType::Endpoint as u8);
+        buf[2].set(From::from(self.endpoint_address));
+        buf[3].set(self.transfer_type as u8);
+        put_u16(&buf[4..6], self.max_packet_size);
+        buf[6].set(self.interval);
+        7
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct EndpointAddress(u8);
+
+impl EndpointAddress {
+    pub fn new(endpoint: usize, direction: TransferDirection) -> Self {
+        EndpointAddress(
+            (endpoint as u8) & 0xf | match direction {
+                TransferDirection::HostToDevice => 0,
+                TransferDirection::DeviceToHost => 1 << 7,
+            },
+        )
+    }
+}
+
+impl From<EndpointAddress> for u8 {
+    fn from(ea: EndpointAddress) -> u8 {
+        ea.0
+    }
+}
+
 pub struct LanguagesDescriptor<'a> {
     pub langs: &'a [u16],
 }
@@ -497,6 +548,235 @@ impl<'a> Descriptor for StringDescriptor<'a> {
     }
 }
 
+/// Groups the interfaces of one function of a composite device
+pub struct InterfaceAssociationDescriptor {
+    pub first_interface: u8,
+    pub interface_count: u8,
+    pub function_class: u8,
+    pub function_subclass: u8,
+    pub function_protocol: u8,
+    pub string_index: u8,
+}
+
+impl Descriptor for InterfaceAssociationDescriptor {
+    fn size(&self) -> usize {
+        8
+    }
+
+    fn write_to_unchecked(&self, buf: &[Cell<u8>]) -> usize {
+        buf[0].set(8); // Size of descriptor
+        buf[1].set(DescriptorType::InterfaceAssociation as u8);
+        buf[2].set(self.first_interface);
+        buf[3].set(self.interface_count);
+        buf[4].set(self.function_class);
+        buf[5].set(self.function_subclass);
+        buf[6].set(self.function_protocol);
+        buf[7].set(self.string_index);
+        8
+    }
+}
+
+/// An interface a class capsule offers, as placed in the configuration by a
+/// `DeviceBuilder`
+pub struct Interface<'a> {
+    /// Alternate settings of an interface follow the interface with
+    /// alternate setting 0, and get its number
+    pub alternate_setting: u8,
+    pub interface_class: u8,
+    pub interface_subclass: u8,
+    pub interface_protocol: u8,
+    pub string_index: u8,
+
+    /// Class specific descriptors, which go between the interface descriptor
+    /// and the endpoint descriptors
+    pub class_descriptors: &'a [u8],
+
+    pub endpoints: &'a [EndpointDescriptor],
+}
+
+/// A function of the device: one or more interfaces that belong together,
+/// like the communication and data interfaces of a serial port
+pub struct Function<'a> {
+    pub function_class: u8,
+    pub function_subclass: u8,
+    pub function_protocol: u8,
+    pub string_index: u8,
+    pub interfaces: &'a [Interface<'a>],
+}
+
+/// Generates the descriptors of a device with a single configuration
+///
+/// The strings are the manufacturer, the product and the serial number, in
+/// this order, followed by any strings the functions and interfaces refer
+/// to; they are passed on to the host as string descriptors 1 and on.
+///
+/// Interfaces are numbered in order, starting at 0 with the first interface
+/// of the first function, and their alternate settings share their number.
+/// Class specific descriptors that refer to interface numbers have to agree
+/// with this. A composite device, which has more than one function, gets an
+/// interface association descriptor for each function.
+pub struct DeviceBuilder<'a> {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub device_release: u16,
+    pub strings: &'a [&'a str],
+    pub functions: &'a [Function<'a>],
+    pub attributes: ConfigurationAttributes,
+    pub max_power: u8, // in 2mA units
+}
+
+impl<'a> DeviceBuilder<'a> {
+    fn is_composite(&self) -> bool {
+        self.functions.len() > 1
+    }
+
+    fn num_interfaces(&self) -> usize {
+        self.functions.iter().map(|f| num_interfaces(f.interfaces)).sum()
+    }
+
+    pub fn device_descriptor(&self) -> DeviceDescriptor {
+        let (class, subclass, protocol) = if self.is_composite() {
+            // Miscellaneous device class, using interface association
+            // descriptors
+            (0xef, 0x02, 0x01)
+        } else {
+            // Each interface defines its own class
+            (0, 0, 0)
+        };
+        let string_index = |i: usize| if i <= self.strings.len() { i as u8 } else { 0 };
+
+        DeviceDescriptor {
+            class: class,
+            subclass: subclass,
+            protocol: protocol,
+            vendor_id: self.vendor_id,
+            product_id: self.product_id,
+            device_release: self.device_release,
+            manufacturer_string: string_index(1),
+            product_string: string_index(2),
+            serial_number_string: string_index(3),
+            ..Default::default()
+        }
+    }
+
+    /// Size of the configuration descriptor and all the descriptors that
+    /// follow it
+    pub fn configuration_size(&self) -> usize {
+        let mut size = 9;
+        for function in self.functions {
+            if self.is_composite() {
+                size += 8;
+            }
+            for interface in function.interfaces {
+                size += 9 + interface.class_descriptors.len() + 7 * interface.endpoints.len();
+            }
+        }
+        size
+    }
+
+    /// Write the configuration descriptor and all the descriptors that
+    /// follow it to `buf`, and return the bytes written.
+    ///
+    /// Panics if `buf` is shorter than `configuration_size()`. Class
+    /// capsules size their buffers for their own descriptors, so this can
+    /// only happen when a capsule and its buffer disagree, and the device
+    /// would be useless without its configuration.
+    pub fn write_configuration<'b>(&self, buf: &'b mut [u8]) -> &'b [u8] {
+        let size = self.configuration_size();
+        assert!(
+            size <= buf.len(),
+            "USB configuration needs {} bytes, buffer has {}",
+            size,
+            buf.len()
+        );
+
+        let dc = ConfigurationDescriptor {
+            num_interfaces: self.num_interfaces() as u8,
+            configuration_value: 1,
+            string_index: 0,
+            attributes: self.attributes,
+            max_power: self.max_power,
+            related_descriptor_length: size - 9,
+        };
+        let mut offset = put_descriptor(buf, 0, &dc);
+
+        // The number of interfaces placed so far
+        let mut interface_count: u8 = 0;
+        for function in self.functions {
+            if self.is_composite() {
+                let da = InterfaceAssociationDescriptor {
+                    first_interface: interface_count,
+                    interface_count: num_interfaces(function.interfaces) as u8,
+                    function_class: function.function_class,
+                    function_subclass: function.function_subclass,
+                    function_protocol: function.function_protocol,
+                    string_index: function.string_index,
+                };
+                offset = put_descriptor(buf, offset, &da);
+            }
+
+            for interface in function.interfaces {
+                if interface.alternate_setting == 0 {
+                    interface_count += 1;
+                }
+                let di = InterfaceDescriptor {
+                    interface_number: interface_count.saturating_sub(1),
+                    alternate_setting: interface.alternate_setting,
+                    num_endpoints: interface.endpoints.len() as u8,
+                    interface_class: interface.interface_class,
+                    interface_subclass: interface.interface_subclass,
+                    interface_protocol: interface.interface_protocol,
+                    string_index: interface.string_index,
+                };
+                offset = put_descriptor(buf, offset, &di);
+
+                let class_len = interface.class_descriptors.len();
+                buf[offset..offset + class_len].copy_from_slice(interface.class_descriptors);
+                offset += class_len;
+
+                for de in interface.endpoints {
+                    offset = put_descriptor(buf, offset, de);
+                }
+            }
+        }
+        &buf[..offset]
+    }
+}
+
+/// The number of interfaces, not counting alternate settings
+fn num_interfaces(interfaces: &[Interface]) -> usize {
+    interfaces
+        .iter()
+        .filter(|interface| interface.alternate_setting == 0)
+        .count()
+}
+
+/// Write `unique_id` to `buf` in hexadecimal, for use as the serial number
+/// string of a device. `buf` needs room for two digits per byte of
+/// `unique_id`, the rest of the ID is left out otherwise.
+pub fn serial_number<'b>(unique_id: &[u8], buf: &'b mut [u8]) -> &'b str {
+    const DIGITS: &'static [u8] = b"0123456789ABCDEF";
+
+    let len = min(unique_id.len(), buf.len() / 2);
+    for i in 0..len {
+        buf[2 * i] = DIGITS[(unique_id[i] >> 4) as usize];
+        buf[2 * i + 1] = DIGITS[(unique_id[i] & 0xf) as usize];
+    }
+    // Only ASCII digits were written
+    str::from_utf8(&buf[..2 * len]).unwrap_or("")
+}
+
+/// Serialize a descriptor into `buf` at `offset`, and return the offset
+/// following it
+fn put_descriptor<D: Descriptor>(buf: &mut [u8], offset: usize, descriptor: &D) -> usize {
+    let cells: [Cell<u8>; 18] = Default::default();
+    let len = descriptor.write_to(&cells);
+    for i in 0..len {
+        buf[offset + i] = cells[i].get();
+    }
+    offset + len
+}
+
 /// Parse a `u16` from two bytes as received on the bus
 fn get_u16(b0: u8, b1: u8) -> u16 {
     (b0 as u16) | ((b1 as u16) << 8)
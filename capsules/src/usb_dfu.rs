@@ -0,0 +1,677 @@
+//! USB Device Firmware Upgrade (DFU 1.1)
+//!
+//! This capsule lets `dfu-util` on the host write apps and kernels to the
+//! flash of the board over its USB port, without a serial bootloader or a
+//! JTAG probe.
+//!
+//! The device starts in DFU runtime mode, where it only offers the DFU
+//! interface to the host. When the host sends DFU_DETACH and resets the bus,
+//! the device comes back in DFU mode, with an alternate setting of its
+//! interface for each target the board set up. A target is a range of flash
+//! pages that holds either the apps or a kernel image. The host selects the
+//! target by the alternate setting, and downloads an image to it:
+//!
+//! ```text
+//! dfu-util -d 6667:abd0 -a app -D apps.tbf
+//! ```
+//!
+//! Images are written through `virtual_flash`, so the `FlashUser` of this
+//! capsule has to be allowed to modify the pages of all targets. The image
+//! for an app target has to start with a valid TBF header, which is checked
+//! before anything is written. After the image, the app list is ended, so the
+//! kernel does not load apps left over from before. Kernel images are not
+//! checked. As the running kernel cannot be overwritten in place, a kernel
+//! target should be a staging slot that a bootloader installs the kernel
+//! from.
+//!
+//! Once the download is complete, the host resets the bus again, and the
+//! capsule resets the chip to start the new image. Uploading images from the
+//! device is not supported.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! static DFU_TARGETS: [capsules::usb_dfu::Target; 2] = [
+//!     capsules::usb_dfu::Target {
+//!         kind: capsules::usb_dfu::TargetKind::App,
+//!         first_page: 128,
+//!         pages: 256,
+//!     },
+//!     capsules::usb_dfu::Target {
+//!         kind: capsules::usb_dfu::TargetKind::Kernel,
+//!         first_page: 384,
+//!         pages: 120,
+//!     },
+//! ];
+//!
+//! // Manufacturer, product, serial number and the names of the targets
+//! let strings = static_init!(
+//!     [&str; 5],
+//!     ["Tock", "imix", serial, "app", "kernel"]);
+//! let dfu = static_init!(
+//!     capsules::usb_dfu::Dfu<'static, sam4l::usbc::Usbc<'static>,
+//!         FlashUser<'static, sam4l::flashcalw::FLASHCALW>>,
+//!     capsules::usb_dfu::Dfu::new(
+//!         &sam4l::usbc::USBC,
+//!         0x6667,
+//!         0xabd0,
+//!         strings,
+//!         &mut capsules::usb_dfu::CONFIGURATION_BUF,
+//!         virtual_flash,
+//!         &mut PAGEBUFFER,
+//!         &DFU_TARGETS,
+//!         || unsafe { cortexm4::scb::reset() }));
+//! virtual_flash.set_allowed_pages(128, 504);
+//! hil::flash::HasClient::set_client(virtual_flash, dfu);
+//! sam4l::usbc::USBC.set_client(dfu);
+//!
+//! hil::usb::Client::enable(dfu);
+//! hil::usb::Client::attach(dfu);
+//! ```
+
+use core::cell::Cell;
+use core::cmp::min;
+use kernel::ReturnCode;
+use kernel::common::take_cell::TakeCell;
+use kernel::hil;
+use kernel::hil::usb::*;
+use tbf;
+use usb::*;
+use usbc_client_ctrl::ClientCtrl;
+
+static LANGUAGES: &'static [u16] = &[
+    0x0409 // English (United States)
+];
+
+/// The most targets the device can offer
+pub const MAX_TARGETS: usize = 4;
+
+/// Size of the configuration descriptors in runtime and in DFU mode, which
+/// consist of the configuration, interface and DFU functional descriptors
+const RUNTIME_CONFIGURATION_SIZE: usize = 27;
+const DFU_CONFIGURATION_SIZE: usize = 18 + 9 * MAX_TARGETS;
+
+pub static mut CONFIGURATION_BUF: [u8; RUNTIME_CONFIGURATION_SIZE + DFU_CONFIGURATION_SIZE] =
+    [0; RUNTIME_CONFIGURATION_SIZE + DFU_CONFIGURATION_SIZE];
+
+/// The largest block the host sends in a DFU_DNLOAD request
+const MAX_TRANSFER_SIZE: usize = 256;
+
+/// How long the host waits before asking for the status while flash is
+/// being written, in milliseconds
+const POLL_TIMEOUT: u32 = 20;
+
+/// Time the host has to reset the bus after DFU_DETACH, in milliseconds
+const DETACH_TIMEOUT: u16 = 1000;
+
+/// The interface class, and the subclass and protocols of DFU
+const INTERFACE_CLASS_APPLICATION: u8 = 0xfe;
+const INTERFACE_SUBCLASS_DFU: u8 = 0x01;
+const INTERFACE_PROTOCOL_RUNTIME: u8 = 0x01;
+const INTERFACE_PROTOCOL_DFU: u8 = 0x02;
+
+const DESCRIPTOR_TYPE_DFU_FUNCTIONAL: u8 = 0x21;
+
+/// Attributes of the DFU functional descriptor: the device can download,
+/// but it cannot upload, it needs a reset after the download, and it does
+/// not detach from the bus by itself
+const ATTRIBUTE_CAN_DNLOAD: u8 = 1 << 0;
+
+/// Class specific requests
+const DFU_DETACH: u8 = 0;
+const DFU_DNLOAD: u8 = 1;
+const DFU_GETSTATUS: u8 = 3;
+const DFU_CLRSTATUS: u8 = 4;
+const DFU_GETSTATE: u8 = 5;
+const DFU_ABORT: u8 = 6;
+
+/// Standard requests for the alternate setting, which select the target
+const GET_INTERFACE: u8 = 10;
+const SET_INTERFACE: u8 = 11;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum TargetKind {
+    /// Apps, each starting with a TBF header
+    App,
+
+    /// A kernel image
+    Kernel,
+}
+
+/// A range of flash pages the host can write an image to
+pub struct Target {
+    pub kind: TargetKind,
+    pub first_page: usize,
+    pub pages: usize,
+}
+
+/// The states of the DFU specification
+#[derive(Copy, Clone, PartialEq)]
+enum DfuState {
+    AppIdle = 0,
+    AppDetach = 1,
+    DfuIdle = 2,
+    DnloadSync = 3,
+    DnBusy = 4,
+    DnloadIdle = 5,
+    ManifestSync = 6,
+    Manifest = 7,
+    ManifestWaitReset = 8,
+    Error = 10,
+}
+
+/// The status codes of the DFU specification
+#[derive(Copy, Clone, PartialEq)]
+enum DfuStatus {
+    Ok = 0x00,
+    ErrFile = 0x02,
+    ErrWrite = 0x03,
+    ErrAddress = 0x08,
+    ErrNotDone = 0x09,
+    ErrStalledPacket = 0x0f,
+}
+
+pub struct Dfu<'a, C: 'a, F: hil::flash::Flash + 'static> {
+    client_ctrl: ClientCtrl<'a, 'static, C>,
+    dfu_device_descriptor: DeviceDescriptor,
+    dfu_configuration: &'static [u8],
+
+    flash: &'a F,
+    /// The page of the image being received, or being written
+    pagebuffer: TakeCell<'static, F::Page>,
+    page_size: usize,
+    transfer_size: usize,
+    targets: &'static [Target],
+    /// Resets the chip, to start the new image
+    reset: fn(),
+
+    state: Cell<DfuState>,
+    status: Cell<DfuStatus>,
+    /// The target selected by the alternate setting
+    target: Cell<usize>,
+    /// Bytes of the image received so far, including those in
+    /// `pagebuffer`, and the length of the block being received
+    received: Cell<usize>,
+    block_length: Cell<usize>,
+    /// Offset in an app image of the next TBF header to check
+    next_header: Cell<usize>,
+    /// Whether the data of the current control transfer is a block
+    receiving_block: Cell<bool>,
+    /// Whether a page is being written
+    busy: Cell<bool>,
+}
+
+impl<'a, C: UsbController, F: hil::flash::Flash + 'a> Dfu<'a, C, F> {
+    /// `strings` are the manufacturer, product and serial number of the
+    /// device, followed by the name of each target, and `configuration_buf`
+    /// holds the configuration descriptors of both modes.
+    ///
+    /// Panics if there are no targets.
+    pub fn new(
+        controller: &'a C,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str],
+        configuration_buf: &'static mut [u8],
+        flash: &'a F,
+        pagebuffer: &'static mut F::Page,
+        targets: &'static [Target],
+        reset: fn(),
+    ) -> Self {
+        let page_size = pagebuffer.as_mut().len();
+        let transfer_size = min(page_size, MAX_TRANSFER_SIZE);
+        assert!(!targets.is_empty(), "USB DFU needs at least one target");
+        let targets = &targets[..min(targets.len(), MAX_TARGETS)];
+
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let functional = [
+            9, DESCRIPTOR_TYPE_DFU_FUNCTIONAL, ATTRIBUTE_CAN_DNLOAD,
+            DETACH_TIMEOUT as u8, (DETACH_TIMEOUT >> 8) as u8,
+            transfer_size as u8, (transfer_size >> 8) as u8,
+            0x10, 0x01, // DFU 1.1
+        ];
+        let runtime_interfaces = [
+            Interface {
+                alternate_setting: 0,
+                interface_class: INTERFACE_CLASS_APPLICATION,
+                interface_subclass: INTERFACE_SUBCLASS_DFU,
+                interface_protocol: INTERFACE_PROTOCOL_RUNTIME,
+                string_index: 0,
+                class_descriptors: &functional,
+                endpoints: &[],
+            },
+        ];
+        // One alternate setting for each target, named by the strings after
+        // the serial number
+        let dfu_interface = |target: usize| Interface {
+            alternate_setting: target as u8,
+            interface_class: INTERFACE_CLASS_APPLICATION,
+            interface_subclass: INTERFACE_SUBCLASS_DFU,
+            interface_protocol: INTERFACE_PROTOCOL_DFU,
+            string_index: if 4 + target <= strings.len() {
+                4 + target as u8
+            } else {
+                0
+            },
+            class_descriptors: if target + 1 == targets.len() {
+                &functional
+            } else {
+                &[]
+            },
+            endpoints: &[],
+        };
+        let dfu_interfaces = [
+            dfu_interface(0),
+            dfu_interface(1),
+            dfu_interface(2),
+            dfu_interface(3),
+        ];
+
+        let function = |interfaces| Function {
+            function_class: INTERFACE_CLASS_APPLICATION,
+            function_subclass: INTERFACE_SUBCLASS_DFU,
+            function_protocol: 0,
+            string_index: 0,
+            interfaces: interfaces,
+        };
+        let runtime_functions = [function(&runtime_interfaces[..])];
+        let dfu_functions = [function(&dfu_interfaces[..targets.len()])];
+        let device = |functions| DeviceBuilder {
+            vendor_id: vendor_id,
+            product_id: product_id,
+            device_release: 0x0001,
+            strings: strings,
+            functions: functions,
+            attributes: ConfigurationAttributes::new(false, false),
+            max_power: 50, // 100 mA
+        };
+        let runtime_device = device(&runtime_functions);
+        let dfu_device = device(&dfu_functions);
+
+        let (runtime_buf, dfu_buf) = configuration_buf.split_at_mut(RUNTIME_CONFIGURATION_SIZE);
+
+        Dfu {
+            client_ctrl: ClientCtrl::new(
+                controller,
+                runtime_device.device_descriptor(),
+                runtime_device.write_configuration(runtime_buf),
+                LANGUAGES,
+                strings,
+            ),
+            dfu_device_descriptor: dfu_device.device_descriptor(),
+            dfu_configuration: dfu_device.write_configuration(dfu_buf),
+            flash: flash,
+            pagebuffer: TakeCell::new(pagebuffer),
+            page_size: page_size,
+            transfer_size: transfer_size,
+            targets: targets,
+            reset: reset,
+            state: Cell::new(DfuState::AppIdle),
+            status: Cell::new(DfuStatus::Ok),
+            target: Cell::new(0),
+            received: Cell::new(0),
+            block_length: Cell::new(0),
+            next_header: Cell::new(0),
+            receiving_block: Cell::new(false),
+            busy: Cell::new(false),
+        }
+    }
+
+    /// Handle a class specific request, or a request for the alternate
+    /// setting
+    fn dfu_request(&self, setup_data: SetupData) -> CtrlSetupResult {
+        let state = self.state.get();
+        let in_dfu_mode = state != DfuState::AppIdle && state != DfuState::AppDetach;
+
+        match (setup_data.request_type.request_type(), setup_data.request_code) {
+            (RequestType::Standard, GET_INTERFACE) => {
+                self.client_ctrl
+                    .send_in(&[self.target.get() as u8], setup_data.length)
+            }
+            (RequestType::Standard, SET_INTERFACE) => {
+                let target = setup_data.value as usize;
+                if !in_dfu_mode && target == 0 {
+                    CtrlSetupResult::Ok
+                } else if state == DfuState::DfuIdle && target < self.targets.len() {
+                    self.target.set(target);
+                    CtrlSetupResult::Ok
+                } else {
+                    CtrlSetupResult::ErrUnrecognizedRequestType
+                }
+            }
+            (RequestType::Class, DFU_GETSTATUS) => {
+                self.update_state();
+                let poll_timeout = if self.busy.get() { POLL_TIMEOUT } else { 0 };
+                let status = [
+                    self.status.get() as u8,
+                    poll_timeout as u8,
+                    (poll_timeout >> 8) as u8,
+                    (poll_timeout >> 16) as u8,
+                    self.state.get() as u8,
+                    0,
+                ];
+                self.client_ctrl.send_in(&status, setup_data.length)
+            }
+            (RequestType::Class, DFU_GETSTATE) => self.client_ctrl
+                .send_in(&[self.state.get() as u8], setup_data.length),
+            (RequestType::Class, DFU_DETACH) if state == DfuState::AppIdle => {
+                // Switch to DFU mode once the host resets the bus
+                self.state.set(DfuState::AppDetach);
+                CtrlSetupResult::Ok
+            }
+            (RequestType::Class, DFU_DNLOAD) if in_dfu_mode => {
+                self.download(setup_data.length as usize)
+            }
+            (RequestType::Class, DFU_CLRSTATUS) if state == DfuState::Error => {
+                self.status.set(DfuStatus::Ok);
+                self.abort_download();
+                CtrlSetupResult::Ok
+            }
+            (RequestType::Class, DFU_ABORT)
+                if state == DfuState::DfuIdle || state == DfuState::DnloadIdle =>
+            {
+                self.abort_download();
+                CtrlSetupResult::Ok
+            }
+            _ => {
+                if in_dfu_mode {
+                    self.fail(DfuStatus::ErrStalledPacket);
+                }
+                CtrlSetupResult::ErrUnrecognizedRequestType
+            }
+        }
+    }
+
+    /// Handle DFU_DNLOAD with a block of `length` bytes, or the end of the
+    /// image if it is empty
+    fn download(&self, length: usize) -> CtrlSetupResult {
+        match self.state.get() {
+            DfuState::DfuIdle | DfuState::DnloadIdle => {}
+            _ => {
+                self.fail(DfuStatus::ErrStalledPacket);
+                return CtrlSetupResult::ErrUnrecognizedRequestType;
+            }
+        }
+
+        if length == 0 {
+            if self.state.get() == DfuState::DfuIdle {
+                // There is no image to manifest
+                self.fail(DfuStatus::ErrNotDone);
+                return CtrlSetupResult::ErrUnrecognizedRequestType;
+            }
+            self.state.set(DfuState::ManifestSync);
+            return CtrlSetupResult::Ok;
+        }
+
+        // Blocks have to fit in the rest of the page being received
+        let target = &self.targets[self.target.get()];
+        let page_offset = self.received.get() % self.page_size;
+        if length > self.transfer_size || page_offset + length > self.page_size {
+            self.fail(DfuStatus::ErrStalledPacket);
+            return CtrlSetupResult::ErrBadLength;
+        }
+        if self.received.get() + length > target.pages * self.page_size {
+            self.fail(DfuStatus::ErrAddress);
+            return CtrlSetupResult::ErrBadLength;
+        }
+
+        self.block_length.set(length);
+        self.receiving_block.set(true);
+        self.client_ctrl.accept_out()
+    }
+
+    /// Copy a packet of the block being received into the page buffer
+    fn receive_packet(&self, packet_bytes: usize) -> bool {
+        let page_offset = self.received.get() % self.page_size;
+        let len = min(packet_bytes, self.block_length.get());
+        let ctrl_buf = self.client_ctrl.ctrl_buf();
+        self.pagebuffer.map_or(false, |pagebuffer| {
+            let page = pagebuffer.as_mut();
+            for i in 0..len {
+                page[page_offset + i] = ctrl_buf[i].get();
+            }
+            self.received.set(self.received.get() + len);
+            self.block_length.set(self.block_length.get() - len);
+            true
+        })
+    }
+
+    /// Move on from the states in which the device gets to work when the
+    /// host asks for the status
+    fn update_state(&self) {
+        if self.busy.get() {
+            return;
+        }
+        match self.state.get() {
+            DfuState::DnloadSync => {
+                let received = self.received.get();
+                if received % self.page_size == 0 {
+                    // The page is full
+                    self.write_page((received - 1) / self.page_size);
+                } else {
+                    self.state.set(DfuState::DnloadIdle);
+                }
+            }
+            DfuState::DnBusy => self.state.set(DfuState::DnloadIdle),
+            DfuState::ManifestSync => self.manifest(),
+            DfuState::Manifest => self.state.set(DfuState::ManifestWaitReset),
+            _ => {}
+        }
+    }
+
+    /// Write the rest of the image, and end the app list after it
+    fn manifest(&self) {
+        let target = &self.targets[self.target.get()];
+        let received = self.received.get();
+        let page_offset = received % self.page_size;
+        let last_page = received / self.page_size;
+
+        // The image has to end with the last app in it
+        if target.kind == TargetKind::App
+            && (!self.check_app_headers(target, last_page) || self.next_header.get() != received)
+        {
+            self.fail(DfuStatus::ErrFile);
+            return;
+        }
+
+        // Erased flash right after the image ends the app list. If the image
+        // fills its last page, the app list ends with the next page.
+        let ends_app_list = target.kind == TargetKind::App && last_page < target.pages;
+        if page_offset == 0 && !ends_app_list {
+            self.state.set(DfuState::ManifestWaitReset);
+            return;
+        }
+        self.pagebuffer.map(|pagebuffer| {
+            for b in pagebuffer.as_mut()[page_offset..].iter_mut() {
+                *b = 0xff;
+            }
+        });
+        self.write_page(last_page);
+        if self.state.get() == DfuState::DnBusy {
+            self.state.set(DfuState::Manifest);
+        }
+    }
+
+    /// Write page `page` of the target from the page buffer
+    fn write_page(&self, page: usize) {
+        let target = &self.targets[self.target.get()];
+        if target.kind == TargetKind::App && !self.check_app_headers(target, page) {
+            self.fail(DfuStatus::ErrFile);
+            return;
+        }
+
+        let rc = self.pagebuffer
+            .take()
+            .map_or(ReturnCode::EBUSY, |pagebuffer| {
+                let (rc, pagebuffer) = self.flash.write_page(target.first_page + page, pagebuffer);
+                pagebuffer.map(|pagebuffer| self.pagebuffer.replace(pagebuffer));
+                rc
+            });
+        if rc == ReturnCode::SUCCESS {
+            self.busy.set(true);
+            self.state.set(DfuState::DnBusy);
+        } else {
+            self.fail(DfuStatus::ErrWrite);
+        }
+    }
+
+    /// Check the TBF headers of an app image that start in page `page`,
+    /// which is in the page buffer. Each header is found from the one before
+    /// by its total size, has to lie within the page, and has to describe an
+    /// app or padding that ends within the target.
+    fn check_app_headers(&self, target: &Target, page: usize) -> bool {
+        let page_start = page * self.page_size;
+        let end = min(page_start + self.page_size, self.received.get());
+        self.pagebuffer.map_or(false, |pagebuffer| {
+            let page = pagebuffer.as_mut();
+            while self.next_header.get() < end {
+                let header = &page[self.next_header.get() - page_start..];
+                let header_length = match tbf::header_length(header) {
+                    Ok(length) if length <= header.len() => length,
+                    _ => return false,
+                };
+                // Version 1 headers do not check that the app is not empty
+                let total_size = match tbf::parse_tbf_header(&header[..header_length]) {
+                    Ok(header) if header.get_total_size() > 0 => header.get_total_size() as usize,
+                    _ => return false,
+                };
+                let next_header = self.next_header.get() + total_size;
+                if next_header > target.pages * self.page_size {
+                    return false;
+                }
+                self.next_header.set(next_header);
+            }
+            true
+        })
+    }
+
+    fn fail(&self, status: DfuStatus) {
+        self.status.set(status);
+        self.state.set(DfuState::Error);
+    }
+
+    fn abort_download(&self) {
+        self.received.set(0);
+        self.next_header.set(0);
+        self.state.set(DfuState::DfuIdle);
+    }
+}
+
+impl<'a, C: UsbController, F: hil::flash::Flash + 'a> hil::usb::Client for Dfu<'a, C, F> {
+    fn enable(&self) {
+        self.client_ctrl.enable();
+    }
+
+    fn attach(&self) {
+        self.client_ctrl.attach();
+    }
+
+    fn bus_reset(&self) {
+        self.client_ctrl.bus_reset();
+        self.receiving_block.set(false);
+
+        match self.state.get() {
+            DfuState::AppDetach => {
+                // The host asked for DFU mode, and reads the descriptors of
+                // it now
+                self.client_ctrl
+                    .set_descriptors(self.dfu_device_descriptor, self.dfu_configuration);
+                self.target.set(0);
+                self.abort_download();
+            }
+            DfuState::ManifestWaitReset => (self.reset)(),
+            _ => {}
+        }
+    }
+
+    fn ctrl_setup(&self) -> CtrlSetupResult {
+        self.receiving_block.set(false);
+
+        // The alternate setting selects the target, which ClientCtrl does
+        // not know about
+        let request = self.client_ctrl.setup_data().map(|setup_data| {
+            match (setup_data.request_type.request_type(), setup_data.request_code) {
+                (RequestType::Standard, GET_INTERFACE)
+                | (RequestType::Standard, SET_INTERFACE) => {
+                    Some(self.dfu_request(setup_data))
+                }
+                _ => None,
+            }
+        });
+        if let Some(Some(result)) = request {
+            return result;
+        }
+
+        match self.client_ctrl.ctrl_setup() {
+            CtrlSetupResult::ErrNonstandardRequest => self.client_ctrl
+                .setup_data()
+                .map_or(CtrlSetupResult::ErrNoParse, |setup_data| {
+                    self.dfu_request(setup_data)
+                }),
+            result => result,
+        }
+    }
+
+    fn ctrl_in(&self) -> CtrlInResult {
+        self.client_ctrl.ctrl_in()
+    }
+
+    fn ctrl_out(&self, packet_bytes: u32) -> CtrlOutResult {
+        if self.receiving_block.get() && !self.receive_packet(packet_bytes as usize) {
+            self.receiving_block.set(false);
+            self.fail(DfuStatus::ErrWrite);
+            return CtrlOutResult::Halted;
+        }
+        self.client_ctrl.ctrl_out(packet_bytes)
+    }
+
+    fn ctrl_status(&self) {
+        self.client_ctrl.ctrl_status()
+    }
+
+    fn ctrl_status_complete(&self) {
+        if self.receiving_block.get() {
+            self.receiving_block.set(false);
+            if self.block_length.get() == 0 {
+                self.state.set(DfuState::DnloadSync);
+            } else {
+                // The host sent less than it announced
+                self.fail(DfuStatus::ErrNotDone);
+            }
+        }
+        self.client_ctrl.ctrl_status_complete()
+    }
+
+    fn packet_in(&self, _transfer_type: TransferType, _endpoint: usize) -> InResult {
+        InResult::Error
+    }
+
+    fn packet_out(
+        &self,
+        _transfer_type: TransferType,
+        _endpoint: usize,
+        _packet_bytes: u32,
+    ) -> OutResult {
+        OutResult::Error
+    }
+
+    fn packet_transmitted(&self, _endpoint: usize) {}
+}
+
+impl<'a, C: UsbController, F: hil::flash::Flash + 'a> hil::flash::Client<F> for Dfu<'a, C, F> {
+    fn read_complete(&self, pagebuffer: &'static mut F::Page, _error: hil::flash::Error) {
+        self.pagebuffer.replace(pagebuffer);
+    }
+
+    fn write_complete(&self, pagebuffer: &'static mut F::Page, error: hil::flash::Error) {
+        self.pagebuffer.replace(pagebuffer);
+        self.busy.set(false);
+
+        if error != hil::flash::Error::CommandComplete {
+            self.fail(DfuStatus::ErrWrite);
+        }
+    }
+
+    fn erase_complete(&self, _error: hil::flash::Error) {}
+}
@@ -0,0 +1,684 @@
+//! USB HID device with a keyboard and vendor defined reports
+//!
+//! This capsule makes the USB device show up on the host as a keyboard and
+//! as a generic HID device, which every common operating system supports
+//! without installing a driver. Apps can type keystrokes, and exchange
+//! vendor defined reports with a program on the host, for example through
+//! hidapi.
+//!
+//! The report descriptor has two reports, which are told apart by the report
+//! ID in their first byte:
+//!
+//! - ID 1, keyboard: an input report with the modifier keys, a reserved byte
+//!   and up to six keys pressed, and an output report with the keyboard LEDs.
+//! - ID 2, vendor defined: an input and an output report of 63 bytes each.
+//!
+//! Input reports are sent on interrupt IN endpoint 1. Output reports arrive
+//! on interrupt OUT endpoint 2, or in SET_REPORT requests on the control
+//! endpoint.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! let hid = static_init!(
+//!     capsules::usb_hid::UsbHid<'static, sam4l::usbc::Usbc<'static>>,
+//!     capsules::usb_hid::UsbHid::new(
+//!         &sam4l::usbc::USBC,
+//!         0x6667,
+//!         0xabcf,
+//!         strings,
+//!         &mut capsules::usb_hid::CONFIGURATION_BUF,
+//!         kernel::Grant::create()));
+//! sam4l::usbc::USBC.set_client(hid);
+//!
+//! hil::usb::Client::enable(hid);
+//! hil::usb::Client::attach(hid);
+//! ```
+//!
+//! Syscall Interface
+//! -----------------
+//!
+//! - Allow 0: buffer for the vendor defined output reports the host sends.
+//! - Allow 1: data of the keystrokes or the vendor defined report to send.
+//! - Subscribe 0: called when the host sends a vendor defined output report,
+//!   with the number of bytes written to the allowed buffer.
+//! - Subscribe 1: called when keystrokes or a report were sent, with the
+//!   result.
+//! - Command 0: check if the driver exists.
+//! - Command 1: type `arg1` keystrokes. Each keystroke takes two bytes in the
+//!   allowed buffer: the modifier keys and the usage ID of the key. The key
+//!   is pressed and released before the next one.
+//! - Command 2: send a vendor defined report with the first `arg1` bytes of
+//!   the allowed buffer, padded with zeros.
+//! - Command 3: return the state of the keyboard LEDs set by the host.
+//! - Command 4: return 1 if the host configured the device, 0 otherwise.
+
+use core::cell::Cell;
+use core::cmp::min;
+use kernel::{AppId, AppSlice, Callback, Driver, Grant, ReturnCode, Shared};
+use kernel::common::VolatileCell;
+use kernel::hil;
+use kernel::hil::usb::*;
+use usb::*;
+use usbc_client_ctrl::ClientCtrl;
+
+/// Syscall number
+pub const DRIVER_NUM: usize = 0x20006;
+
+static LANGUAGES: &'static [u16] = &[
+    0x0409 // English (United States)
+];
+
+/// Endpoints of the input and output reports
+const IN_ENDPOINT: usize = 1;
+const OUT_ENDPOINT: usize = 2;
+
+/// Size of the endpoint buffers, which is the size of the largest report
+const REPORT_PACKET_SIZE: usize = 64;
+
+const KEYBOARD_REPORT_ID: u8 = 1;
+const VENDOR_REPORT_ID: u8 = 2;
+
+/// Sizes of the input reports without their report ID
+const KEYBOARD_REPORT_LENGTH: usize = 8;
+const VENDOR_REPORT_LENGTH: usize = REPORT_PACKET_SIZE - 1;
+
+const REPORT_DESCRIPTOR_LENGTH: usize = 95;
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+static REPORT_DESCRIPTOR: [u8; REPORT_DESCRIPTOR_LENGTH] = [
+    0x05, 0x01,       // Usage Page (Generic Desktop)
+    0x09, 0x06,       // Usage (Keyboard)
+    0xa1, 0x01,       // Collection (Application)
+    0x85, KEYBOARD_REPORT_ID, //   Report ID (1)
+    0x05, 0x07,       //   Usage Page (Keyboard/Keypad)
+    0x19, 0xe0,       //   Usage Minimum (Left Control)
+    0x29, 0xe7,       //   Usage Maximum (Right GUI)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x25, 0x01,       //   Logical Maximum (1)
+    0x75, 0x01,       //   Report Size (1)
+    0x95, 0x08,       //   Report Count (8)
+    0x81, 0x02,       //   Input (Data, Variable, Absolute): modifier keys
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x01,       //   Report Count (1)
+    0x81, 0x01,       //   Input (Constant): reserved
+    0x05, 0x08,       //   Usage Page (LEDs)
+    0x19, 0x01,       //   Usage Minimum (Num Lock)
+    0x29, 0x05,       //   Usage Maximum (Kana)
+    0x75, 0x01,       //   Report Size (1)
+    0x95, 0x05,       //   Report Count (5)
+    0x91, 0x02,       //   Output (Data, Variable, Absolute): LEDs
+    0x75, 0x03,       //   Report Size (3)
+    0x95, 0x01,       //   Report Count (1)
+    0x91, 0x01,       //   Output (Constant): padding
+    0x05, 0x07,       //   Usage Page (Keyboard/Keypad)
+    0x19, 0x00,       //   Usage Minimum (0)
+    0x29, 0xff,       //   Usage Maximum (255)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xff, 0x00, //   Logical Maximum (255)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, 0x06,       //   Report Count (6)
+    0x81, 0x00,       //   Input (Data, Array): keys
+    0xc0,             // End Collection
+    0x06, 0x00, 0xff, // Usage Page (Vendor Defined 0xFF00)
+    0x09, 0x01,       // Usage (1)
+    0xa1, 0x01,       // Collection (Application)
+    0x85, VENDOR_REPORT_ID,   //   Report ID (2)
+    0x15, 0x00,       //   Logical Minimum (0)
+    0x26, 0xff, 0x00, //   Logical Maximum (255)
+    0x75, 0x08,       //   Report Size (8)
+    0x95, VENDOR_REPORT_LENGTH as u8, //   Report Count (63)
+    0x09, 0x01,       //   Usage (1)
+    0x81, 0x02,       //   Input (Data, Variable, Absolute)
+    0x95, VENDOR_REPORT_LENGTH as u8, //   Report Count (63)
+    0x09, 0x01,       //   Usage (1)
+    0x91, 0x02,       //   Output (Data, Variable, Absolute)
+    0xc0,             // End Collection
+];
+
+/// The HID descriptor, which follows the interface descriptor
+#[cfg_attr(rustfmt, rustfmt_skip)]
+static HID_DESCRIPTOR: [u8; 9] = [
+    // HID 1.11, not localized, one report descriptor
+    9, DESCRIPTOR_TYPE_HID, 0x11, 0x01, 0, 1,
+    DESCRIPTOR_TYPE_REPORT, REPORT_DESCRIPTOR_LENGTH as u8, (REPORT_DESCRIPTOR_LENGTH >> 8) as u8,
+];
+
+const DESCRIPTOR_TYPE_HID: u8 = 0x21;
+const DESCRIPTOR_TYPE_REPORT: u8 = 0x22;
+
+/// GET_DESCRIPTOR, the standard request the HID class descriptors are read
+/// with
+const GET_DESCRIPTOR: u8 = 6;
+
+/// Class specific requests
+const GET_REPORT: u8 = 0x01;
+const GET_IDLE: u8 = 0x02;
+const SET_REPORT: u8 = 0x09;
+const SET_IDLE: u8 = 0x0a;
+
+/// Report types of GET_REPORT and SET_REPORT
+const REPORT_TYPE_INPUT: u16 = 1;
+const REPORT_TYPE_OUTPUT: u16 = 2;
+
+/// Size of the configuration descriptor with the interface, HID and endpoint
+/// descriptors
+const CONFIGURATION_SIZE: usize = 41;
+
+pub static mut CONFIGURATION_BUF: [u8; CONFIGURATION_SIZE] = [0; CONFIGURATION_SIZE];
+
+#[derive(Copy, Clone)]
+enum Request {
+    /// Type the given number of keystrokes
+    Keys(usize),
+
+    /// Send a vendor defined report with the given number of bytes
+    Report(usize),
+}
+
+#[derive(Default)]
+pub struct App {
+    output_callback: Option<Callback>,
+    sent_callback: Option<Callback>,
+    output_buffer: Option<AppSlice<Shared, u8>>,
+    input_buffer: Option<AppSlice<Shared, u8>>,
+    pending: Option<Request>,
+}
+
+pub struct UsbHid<'a, C: 'a> {
+    client_ctrl: ClientCtrl<'a, 'static, C>,
+    in_storage: [VolatileCell<u8>; REPORT_PACKET_SIZE],
+    out_storage: [VolatileCell<u8>; REPORT_PACKET_SIZE],
+    apps: Grant<App>,
+
+    /// The request being sent, and the index of the report in flight.
+    /// Keystrokes take two reports each, one to press and one to release
+    /// the key.
+    current: Cell<Option<(AppId, Request)>>,
+    report_index: Cell<usize>,
+    /// Whether the IN endpoint is waiting for a request
+    delayed_in: Cell<bool>,
+
+    /// The last keyboard report sent, for GET_REPORT
+    keyboard_report: Cell<[u8; KEYBOARD_REPORT_LENGTH]>,
+    leds: Cell<u8>,
+    idle_rate: Cell<u8>,
+
+    /// An output report arriving in a SET_REPORT request
+    receiving_report: Cell<bool>,
+    ctrl_report: Cell<[u8; REPORT_PACKET_SIZE]>,
+    ctrl_report_len: Cell<usize>,
+}
+
+impl<'a, C: UsbController> UsbHid<'a, C> {
+    /// `strings` are the manufacturer, product and serial number of the
+    /// device, and `configuration_buf` holds the generated configuration
+    /// descriptor
+    pub fn new(
+        controller: &'a C,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str],
+        configuration_buf: &'static mut [u8],
+        grant: Grant<App>,
+    ) -> Self {
+        let endpoints = [
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new(
+                    IN_ENDPOINT,
+                    TransferDirection::DeviceToHost,
+                ),
+                transfer_type: TransferType::Interrupt,
+                max_packet_size: REPORT_PACKET_SIZE as u16,
+                interval: 1,
+            },
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new(
+                    OUT_ENDPOINT,
+                    TransferDirection::HostToDevice,
+                ),
+                transfer_type: TransferType::Interrupt,
+                max_packet_size: REPORT_PACKET_SIZE as u16,
+                interval: 10,
+            },
+        ];
+        let interfaces = [
+            Interface {
+                alternate_setting: 0,
+                interface_class: 0x03, // HID
+                interface_subclass: 0, // No boot interface
+                interface_protocol: 0,
+                string_index: 0,
+                class_descriptors: &HID_DESCRIPTOR,
+                endpoints: &endpoints,
+            },
+        ];
+        let functions = [
+            Function {
+                function_class: 0x03,
+                function_subclass: 0,
+                function_protocol: 0,
+                string_index: 0,
+                interfaces: &interfaces,
+            },
+        ];
+        let device = DeviceBuilder {
+            vendor_id: vendor_id,
+            product_id: product_id,
+            device_release: 0x0001,
+            strings: strings,
+            functions: &functions,
+            attributes: ConfigurationAttributes::new(false, false),
+            max_power: 50, // 100 mA
+        };
+
+        UsbHid {
+            client_ctrl: ClientCtrl::new(
+                controller,
+                device.device_descriptor(),
+                device.write_configuration(configuration_buf),
+                LANGUAGES,
+                strings,
+            ),
+            in_storage: [VolatileCell::new(0); REPORT_PACKET_SIZE],
+            out_storage: [VolatileCell::new(0); REPORT_PACKET_SIZE],
+            apps: grant,
+            current: Cell::new(None),
+            report_index: Cell::new(0),
+            delayed_in: Cell::new(false),
+            keyboard_report: Cell::new([0; KEYBOARD_REPORT_LENGTH]),
+            leds: Cell::new(0),
+            idle_rate: Cell::new(0),
+            receiving_report: Cell::new(false),
+            ctrl_report: Cell::new([0; REPORT_PACKET_SIZE]),
+            ctrl_report_len: Cell::new(0),
+        }
+    }
+
+    #[inline]
+    fn controller(&self) -> &'a C {
+        self.client_ctrl.controller()
+    }
+
+    /// Handle a request for the HID class descriptors, or a class specific
+    /// request
+    fn hid_request(&self, setup_data: SetupData) -> CtrlSetupResult {
+        let report_type = setup_data.value >> 8;
+        let report_id = (setup_data.value & 0xff) as u8;
+
+        match (setup_data.request_type.request_type(), setup_data.request_code) {
+            (RequestType::Standard, GET_DESCRIPTOR) => match (setup_data.value >> 8) as u8 {
+                DESCRIPTOR_TYPE_HID => self.client_ctrl
+                    .send_in(&HID_DESCRIPTOR, setup_data.length),
+                DESCRIPTOR_TYPE_REPORT => self.client_ctrl
+                    .send_in_slice(&REPORT_DESCRIPTOR, setup_data.length),
+                _ => CtrlSetupResult::ErrUnrecognizedDescriptorType,
+            },
+            (RequestType::Class, GET_REPORT) => {
+                if report_type != REPORT_TYPE_INPUT || report_id != KEYBOARD_REPORT_ID {
+                    return CtrlSetupResult::ErrUnrecognizedRequestType;
+                }
+                let mut report = [0; KEYBOARD_REPORT_LENGTH + 1];
+                report[0] = KEYBOARD_REPORT_ID;
+                report[1..].copy_from_slice(&self.keyboard_report.get());
+                self.client_ctrl.send_in(&report, setup_data.length)
+            }
+            (RequestType::Class, SET_REPORT) => {
+                if report_type != REPORT_TYPE_OUTPUT {
+                    return CtrlSetupResult::ErrUnrecognizedRequestType;
+                }
+                if setup_data.length as usize > REPORT_PACKET_SIZE {
+                    return CtrlSetupResult::ErrBadLength;
+                }
+                self.receiving_report.set(true);
+                self.ctrl_report_len.set(0);
+                self.client_ctrl.accept_out()
+            }
+            (RequestType::Class, GET_IDLE) => self.client_ctrl
+                .send_in(&[self.idle_rate.get()], setup_data.length),
+            (RequestType::Class, SET_IDLE) => {
+                // Reports are only sent when apps ask for it, so the idle
+                // rate is only kept to report it back
+                self.idle_rate.set(report_type as u8);
+                CtrlSetupResult::Ok
+            }
+            _ => CtrlSetupResult::ErrNonstandardRequest,
+        }
+    }
+
+    /// Handle an output report from the host, starting with its report ID
+    fn receive_report(&self, report: &[u8]) {
+        match report.first() {
+            Some(&KEYBOARD_REPORT_ID) if report.len() > 1 => {
+                self.leds.set(report[1]);
+            }
+            Some(&VENDOR_REPORT_ID) => {
+                let data = &report[1..];
+                self.apps.each(|app| {
+                    let len = app.output_buffer.as_mut().map(|buffer| {
+                        let len = min(buffer.len(), data.len());
+                        buffer.as_mut()[..len].copy_from_slice(&data[..len]);
+                        len
+                    });
+                    len.map(|len| {
+                        app.output_callback.map(|mut cb| cb.schedule(len, 0, 0));
+                    });
+                });
+            }
+            _ => {}
+        }
+    }
+
+    /// Queue a request of an app, and start it if no other one is being sent
+    fn send(&self, appid: AppId, request: Request) -> ReturnCode {
+        if self.client_ctrl.configuration_value() == 0 {
+            return ReturnCode::EOFF;
+        }
+
+        let rc = self.apps
+            .enter(appid, |app, _| {
+                if app.pending.is_some() {
+                    return ReturnCode::EBUSY;
+                }
+                let len = match request {
+                    Request::Keys(0) => return ReturnCode::EINVAL,
+                    Request::Keys(keys) => match keys.checked_mul(2) {
+                        Some(len) => len,
+                        None => return ReturnCode::EINVAL,
+                    },
+                    Request::Report(len) if len > VENDOR_REPORT_LENGTH => {
+                        return ReturnCode::ESIZE
+                    }
+                    Request::Report(len) => len,
+                };
+                if app.input_buffer.as_ref().map_or(true, |buffer| buffer.len() < len) {
+                    return ReturnCode::EINVAL;
+                }
+                app.pending = Some(request);
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into());
+
+        if rc == ReturnCode::SUCCESS && self.select_request() && self.delayed_in.get() {
+            self.delayed_in.set(false);
+            self.controller().endpoint_resume_in(IN_ENDPOINT as u32);
+        }
+        rc
+    }
+
+    /// If no request is being sent, pick the next one any app has pending.
+    /// Returns whether there is a request to send.
+    fn select_request(&self) -> bool {
+        if self.current.get().is_none() {
+            self.apps.each(|app| {
+                if self.current.get().is_none() {
+                    app.pending.map(|request| {
+                        self.current.set(Some((app.appid(), request)));
+                        self.report_index.set(0);
+                    });
+                }
+            });
+        }
+        self.current.get().is_some()
+    }
+
+    /// Finish the request being sent, and tell the app
+    fn finish_request(&self, appid: AppId, rc: ReturnCode) {
+        self.current.set(None);
+        let _ = self.apps.enter(appid, |app, _| {
+            app.pending = None;
+            app.sent_callback
+                .map(|mut cb| cb.schedule(usize::from(rc), 0, 0));
+        });
+    }
+
+    /// Write the report at `index` of a request into the IN endpoint buffer,
+    /// and return its length, or `None` if the data is gone
+    fn write_report(&self, app: &App, request: Request, index: usize) -> Option<usize> {
+        app.input_buffer.as_ref().and_then(|buffer| {
+            let data = buffer.as_ref();
+            match request {
+                Request::Keys(keys) => {
+                    if keys.checked_mul(2).map_or(true, |len| data.len() < len) {
+                        return None;
+                    }
+                    // Press the key, then release all keys
+                    let mut report = [0; KEYBOARD_REPORT_LENGTH];
+                    if index % 2 == 0 {
+                        report[0] = data[index];
+                        report[2] = data[index + 1];
+                    }
+                    self.in_storage[0].set(KEYBOARD_REPORT_ID);
+                    for (i, b) in report.iter().enumerate() {
+                        self.in_storage[1 + i].set(*b);
+                    }
+                    self.keyboard_report.set(report);
+                    Some(KEYBOARD_REPORT_LENGTH + 1)
+                }
+                Request::Report(len) => {
+                    if data.len() < len {
+                        return None;
+                    }
+                    self.in_storage[0].set(VENDOR_REPORT_ID);
+                    for i in 0..VENDOR_REPORT_LENGTH {
+                        self.in_storage[1 + i].set(if i < len { data[i] } else { 0 });
+                    }
+                    Some(VENDOR_REPORT_LENGTH + 1)
+                }
+            }
+        })
+    }
+}
+
+impl<'a, C: UsbController> hil::usb::Client for UsbHid<'a, C> {
+    fn enable(&self) {
+        self.client_ctrl.enable();
+
+        self.controller()
+            .endpoint_set_buffer(IN_ENDPOINT as u32, &self.in_storage);
+        self.controller()
+            .endpoint_in_enable(TransferType::Interrupt, IN_ENDPOINT as u32);
+        self.controller()
+            .endpoint_set_buffer(OUT_ENDPOINT as u32, &self.out_storage);
+        self.controller()
+            .endpoint_out_enable(TransferType::Interrupt, OUT_ENDPOINT as u32);
+    }
+
+    fn attach(&self) {
+        self.client_ctrl.attach();
+    }
+
+    fn bus_reset(&self) {
+        self.client_ctrl.bus_reset();
+        // The host sets the LEDs again after it configured the device
+        self.leds.set(0);
+    }
+
+    fn ctrl_setup(&self) -> CtrlSetupResult {
+        self.receiving_report.set(false);
+        match self.client_ctrl.ctrl_setup() {
+            CtrlSetupResult::ErrNonstandardRequest => self.client_ctrl
+                .setup_data()
+                .map_or(CtrlSetupResult::ErrNoParse, |setup_data| {
+                    self.hid_request(setup_data)
+                }),
+            result => result,
+        }
+    }
+
+    fn ctrl_in(&self) -> CtrlInResult {
+        self.client_ctrl.ctrl_in()
+    }
+
+    fn ctrl_out(&self, packet_bytes: u32) -> CtrlOutResult {
+        if self.receiving_report.get() {
+            let offset = self.ctrl_report_len.get();
+            let len = min(packet_bytes as usize, REPORT_PACKET_SIZE - offset);
+            let mut report = self.ctrl_report.get();
+            for (i, b) in self.client_ctrl.ctrl_buf()[..len].iter().enumerate() {
+                report[offset + i] = b.get();
+            }
+            self.ctrl_report.set(report);
+            self.ctrl_report_len.set(offset + len);
+        }
+        self.client_ctrl.ctrl_out(packet_bytes)
+    }
+
+    fn ctrl_status(&self) {
+        self.client_ctrl.ctrl_status()
+    }
+
+    fn ctrl_status_complete(&self) {
+        if self.receiving_report.get() {
+            self.receiving_report.set(false);
+            let report = self.ctrl_report.get();
+            self.receive_report(&report[..self.ctrl_report_len.get()]);
+        }
+        self.client_ctrl.ctrl_status_complete()
+    }
+
+    fn packet_in(&self, transfer_type: TransferType, endpoint: usize) -> InResult {
+        match (transfer_type, endpoint) {
+            (TransferType::Interrupt, IN_ENDPOINT) => {
+                while self.select_request() {
+                    let (appid, request) = match self.current.get() {
+                        Some(current) => current,
+                        None => break,
+                    };
+                    let index = self.report_index.get();
+                    let len = self.apps
+                        .enter(appid, |app, _| self.write_report(app, request, index))
+                        .unwrap_or(None);
+                    match len {
+                        Some(len) => return InResult::Packet(len),
+                        None => self.finish_request(appid, ReturnCode::FAIL),
+                    }
+                }
+                self.delayed_in.set(true);
+                InResult::Delay
+            }
+            _ => InResult::Error,
+        }
+    }
+
+    fn packet_out(
+        &self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> OutResult {
+        match (transfer_type, endpoint) {
+            (TransferType::Interrupt, OUT_ENDPOINT) => {
+                let len = min(packet_bytes as usize, REPORT_PACKET_SIZE);
+                let mut report = [0; REPORT_PACKET_SIZE];
+                for i in 0..len {
+                    report[i] = self.out_storage[i].get();
+                }
+                self.receive_report(&report[..len]);
+                OutResult::Ok
+            }
+            _ => OutResult::Error,
+        }
+    }
+
+    fn packet_transmitted(&self, endpoint: usize) {
+        if endpoint != IN_ENDPOINT {
+            return;
+        }
+        self.current.get().map(|(appid, request)| {
+            let reports = match request {
+                // `send()` only accepts key counts whose reports can be
+                // counted
+                Request::Keys(keys) => keys.checked_mul(2).unwrap_or(0),
+                Request::Report(_) => 1,
+            };
+            let index = self.report_index.get() + 1;
+            if index < reports {
+                self.report_index.set(index);
+            } else {
+                // The controller asks for the next report right away
+                self.finish_request(appid, ReturnCode::SUCCESS);
+            }
+        });
+    }
+}
+
+impl<'a, C: UsbController> Driver for UsbHid<'a, C> {
+    /// Setup buffers.
+    ///
+    /// ### `allow_num`
+    ///
+    /// - `0`: Buffer for vendor defined output reports.
+    /// - `1`: Keystrokes or vendor defined report to send.
+    fn allow(
+        &self,
+        appid: AppId,
+        allow_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        self.apps
+            .enter(appid, |app, _| {
+                match allow_num {
+                    0 => app.output_buffer = slice,
+                    1 => app.input_buffer = slice,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Setup callbacks.
+    ///
+    /// ### `subscribe_num`
+    ///
+    /// - `0`: Setup a callback for vendor defined output reports.
+    /// - `1`: Setup a callback for when keystrokes or a report were sent.
+    fn subscribe(
+        &self,
+        subscribe_num: usize,
+        callback: Option<Callback>,
+        app_id: AppId,
+    ) -> ReturnCode {
+        self.apps
+            .enter(app_id, |app, _| {
+                match subscribe_num {
+                    0 => app.output_callback = callback,
+                    1 => app.sent_callback = callback,
+                    _ => return ReturnCode::ENOSUPPORT,
+                }
+                ReturnCode::SUCCESS
+            })
+            .unwrap_or_else(|err| err.into())
+    }
+
+    /// Command interface.
+    ///
+    /// ### `command_num`
+    ///
+    /// - `0`: Return SUCCESS if this driver is included on the platform.
+    /// - `1`: Type `arg1` keystrokes from the allowed buffer.
+    /// - `2`: Send a vendor defined report of `arg1` bytes.
+    /// - `3`: Return the state of the keyboard LEDs.
+    /// - `4`: Return whether the host configured the device.
+    fn command(&self, command_num: usize, arg1: usize, _: usize, appid: AppId) -> ReturnCode {
+        match command_num {
+            0 => /* This driver exists. */ ReturnCode::SUCCESS,
+
+            1 => self.send(appid, Request::Keys(arg1)),
+
+            2 => self.send(appid, Request::Report(arg1)),
+
+            3 => ReturnCode::SuccessWithValue {
+                value: self.leds.get() as usize,
+            },
+
+            4 => ReturnCode::SuccessWithValue {
+                value: (self.client_ctrl.configuration_value() != 0) as usize,
+            },
+
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
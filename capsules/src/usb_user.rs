@@ -13,7 +13,12 @@
 //! // Configure the USB controller
 //! let usb_client = static_init!(
 //!     capsules::usbc_client::Client<'static, sam4l::usbc::Usbc<'static>>,
-//!     capsules::usbc_client::Client::new(&sam4l::usbc::USBC));
+//!     capsules::usbc_client::Client::new(
+//!         &sam4l::usbc::USBC,
+//!         0x6667,
+//!         0xabcd,
+//!         strings,
+//!         &mut capsules::usbc_client::CONFIGURATION_BUF));
 //! sam4l::usbc::USBC.set_client(usb_client);
 //!
 //! // Configure the USB userspace driver
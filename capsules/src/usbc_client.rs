@@ -1,6 +1,26 @@
 //! A bare-bones client of the USB hardware interface
 //!
-//! It responds to standard device requests and can be enumerated.
+//! It responds to standard device requests and can be enumerated. Packets the
+//! host sends to bulk OUT endpoint 2 are echoed back on bulk IN endpoint 1.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! let serial = capsules::usb::serial_number(
+//!     &sam4l::serial_num::serial_number(),
+//!     static_init!([u8; 30], [0; 30]));
+//! let strings = static_init!([&str; 3], ["Tock", "imix", serial]);
+//! let usb_client = static_init!(
+//!     capsules::usbc_client::Client<'static, sam4l::usbc::Usbc<'static>>,
+//!     capsules::usbc_client::Client::new(
+//!         &sam4l::usbc::USBC,
+//!         0x6667,
+//!         0xabcd,
+//!         strings,
+//!         &mut capsules::usbc_client::CONFIGURATION_BUF));
+//! sam4l::usbc::USBC.set_client(usb_client);
+//! ```
 
 use core::cell::Cell;
 use core::cmp::min;
@@ -9,289 +29,249 @@ use kernel::common::VolatileCell;
 use kernel::hil;
 use kernel::hil::usb::*;
 use usb::*;
-
-const VENDOR_ID: u16 = 0x6667;
-const PRODUCT_ID: u16 = 0xabcd;
+use usbc_client_ctrl::ClientCtrl;
 
 static LANGUAGES: &'static [u16] = &[
     0x0409 // English (United States)
 ];
 
-static STRINGS: &'static [&'static str] = &[
-    "XYZ Corp.",      // Manufacturer
-    "The Zorpinator", // Product
-    "Serial No. 5",   // Serial number
-];
-
-const DESCRIPTOR_BUFLEN: usize = 30;
-
-pub struct Client<'a, C: 'a> {
-    controller: &'a C,
-    state: Cell<State>,
-    ep0_storage: [VolatileCell<u8>; 8],
-    descriptor_storage: [Cell<u8>; DESCRIPTOR_BUFLEN],
-}
+/// Endpoints of the bulk echo
+const BULK_IN_ENDPOINT: usize = 1;
+const BULK_OUT_ENDPOINT: usize = 2;
 
-#[derive(Copy, Clone)]
-enum State {
-    Init,
+/// Size of the bulk endpoint buffers
+const BULK_PACKET_SIZE: usize = 8;
 
-    /// We are doing a Control In transfer of some data
-    /// in self.descriptor_storage, with the given extent
-    /// remaining to send
-    CtrlIn(usize, usize),
+/// Size of the configuration descriptor with the interface and endpoint
+/// descriptors of the bulk echo
+const CONFIGURATION_SIZE: usize = 32;
 
-    /// We will accept data from the host
-    CtrlOut,
+pub static mut CONFIGURATION_BUF: [u8; CONFIGURATION_SIZE] = [0; CONFIGURATION_SIZE];
 
-    SetAddress,
+pub struct Client<'a, C: 'a> {
+    client_ctrl: ClientCtrl<'a, 'static, C>,
+    bulk_in_storage: [VolatileCell<u8>; BULK_PACKET_SIZE],
+    bulk_out_storage: [VolatileCell<u8>; BULK_PACKET_SIZE],
+
+    /// A packet received on the bulk OUT endpoint and not yet echoed
+    echo_buf: [Cell<u8>; BULK_PACKET_SIZE],
+    echo_len: Cell<usize>,
+    /// Whether the bulk endpoints are waiting for the echo buffer
+    delayed_in: Cell<bool>,
+    delayed_out: Cell<bool>,
 }
 
 impl<'a, C: UsbController> Client<'a, C> {
-    pub fn new(controller: &'a C) -> Self {
+    /// `strings` are the manufacturer, product and serial number of the
+    /// device, and `configuration_buf` holds the generated configuration
+    /// descriptor
+    pub fn new(
+        controller: &'a C,
+        vendor_id: u16,
+        product_id: u16,
+        strings: &'static [&'static str],
+        configuration_buf: &'static mut [u8],
+    ) -> Self {
+        let endpoints = [
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new(
+                    BULK_IN_ENDPOINT,
+                    TransferDirection::DeviceToHost,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: BULK_PACKET_SIZE as u16,
+                interval: 0,
+            },
+            EndpointDescriptor {
+                endpoint_address: EndpointAddress::new(
+                    BULK_OUT_ENDPOINT,
+                    TransferDirection::HostToDevice,
+                ),
+                transfer_type: TransferType::Bulk,
+                max_packet_size: BULK_PACKET_SIZE as u16,
+                interval: 0,
+            },
+        ];
+        let interfaces = [
+            Interface {
+                alternate_setting: 0,
+                interface_class: 0xff, // Vendor specific
+                interface_subclass: 0xab,
+                interface_protocol: 0,
+                string_index: 0,
+                class_descriptors: &[],
+                endpoints: &endpoints,
+            },
+        ];
+        let functions = [
+            Function {
+                function_class: 0xff,
+                function_subclass: 0xab,
+                function_protocol: 0,
+                string_index: 0,
+                interfaces: &interfaces,
+            },
+        ];
+        let device = DeviceBuilder {
+            vendor_id: vendor_id,
+            product_id: product_id,
+            device_release: 0x0001,
+            strings: strings,
+            functions: &functions,
+            attributes: ConfigurationAttributes::new(true, false),
+            max_power: 0,
+        };
+
         Client {
-            controller: controller,
-            state: Cell::new(State::Init),
-            ep0_storage: [VolatileCell::new(0); 8],
-            descriptor_storage: Default::default(),
+            client_ctrl: ClientCtrl::new(
+                controller,
+                device.device_descriptor(),
+                device.write_configuration(configuration_buf),
+                LANGUAGES,
+                strings,
+            ),
+            bulk_in_storage: [VolatileCell::new(0); BULK_PACKET_SIZE],
+            bulk_out_storage: [VolatileCell::new(0); BULK_PACKET_SIZE],
+            echo_buf: Default::default(),
+            echo_len: Cell::new(0),
+            delayed_in: Cell::new(false),
+            delayed_out: Cell::new(false),
         }
     }
 
     #[inline]
-    fn ep0_buf(&self) -> &[VolatileCell<u8>] {
-        &self.ep0_storage
-    }
-
-    #[inline]
-    fn descriptor_buf(&'a self) -> &'a [Cell<u8>] {
-        &self.descriptor_storage
+    fn controller(&self) -> &'a C {
+        self.client_ctrl.controller()
     }
 }
 
 impl<'a, C: UsbController> hil::usb::Client for Client<'a, C> {
     fn enable(&self) {
-        self.controller.endpoint_set_buffer(0, self.ep0_buf());
-        self.controller.enable_device(false);
-        self.controller.endpoint_ctrl_out_enable(0);
-
-        // XXX
-        // static es: C::EndpointState = Default::default();
-        // self.controller.endpoint_configure(&es, 0);
+        self.client_ctrl.enable();
+
+        self.controller()
+            .endpoint_set_buffer(BULK_IN_ENDPOINT as u32, &self.bulk_in_storage);
+        self.controller()
+            .endpoint_in_enable(TransferType::Bulk, BULK_IN_ENDPOINT as u32);
+        self.controller()
+            .endpoint_set_buffer(BULK_OUT_ENDPOINT as u32, &self.bulk_out_storage);
+        self.controller()
+            .endpoint_out_enable(TransferType::Bulk, BULK_OUT_ENDPOINT as u32);
     }
 
     fn attach(&self) {
-        self.controller.attach();
+        self.client_ctrl.attach();
     }
 
     fn bus_reset(&self) {
         // Should the client initiate reconfiguration here?
         // For now, the hardware layer does it.
+        self.client_ctrl.bus_reset();
     }
 
     /// Handle a Control Setup transaction
     fn ctrl_setup(&self) -> CtrlSetupResult {
-        SetupData::get(self.ep0_buf()).map_or(CtrlSetupResult::ErrNoParse, |setup_data| {
-            setup_data.get_standard_request().map_or_else(
-                || {
-                    // XX: CtrlSetupResult::ErrNonstandardRequest
-
-                    // For now, promiscuously accept vendor data and even supply
-                    // a few debugging bytes when host does a read
-
-                    match setup_data.request_type.transfer_direction() {
-                        TransferDirection::HostToDevice => {
-                            self.state.set(State::CtrlOut);
-                            CtrlSetupResult::Ok
-                        }
+        match self.client_ctrl.ctrl_setup() {
+            CtrlSetupResult::ErrNonstandardRequest => {
+                // For now, promiscuously accept vendor data and even supply
+                // a few debugging bytes when host does a read
+                match self.client_ctrl.setup_data() {
+                    Some(setup_data) => match setup_data.request_type.transfer_direction() {
+                        TransferDirection::HostToDevice => self.client_ctrl.accept_out(),
                         TransferDirection::DeviceToHost => {
-                            // Arrange to some crap back
-                            let buf = self.descriptor_buf();
-                            buf[0].set(0xa);
-                            buf[1].set(0xb);
-                            buf[2].set(0xc);
-                            self.state.set(State::CtrlIn(0, 3));
-                            CtrlSetupResult::Ok
-                        }
-                    }
-                },
-                |request| {
-                    match request {
-                        StandardDeviceRequest::GetDescriptor {
-                            descriptor_type,
-                            descriptor_index,
-                            lang_id,
-                            requested_length,
-                        } => {
-                            match descriptor_type {
-                                DescriptorType::Device => match descriptor_index {
-                                    0 => {
-                                        let buf = self.descriptor_buf();
-                                        let d = DeviceDescriptor {
-                                            vendor_id: VENDOR_ID,
-                                            product_id: PRODUCT_ID,
-                                            manufacturer_string: 1,
-                                            product_string: 2,
-                                            serial_number_string: 3,
-                                            ..Default::default()
-                                        };
-                                        let len = d.write_to(buf);
-                                        let end = min(len, requested_length as usize);
-                                        self.state.set(State::CtrlIn(0, end));
-                                        CtrlSetupResult::Ok
-                                    }
-                                    _ => CtrlSetupResult::ErrInvalidDeviceIndex,
-                                },
-                                DescriptorType::Configuration => {
-                                    match descriptor_index {
-                                        0 => {
-                                            // Place all the descriptors
-                                            // related to this configuration
-                                            // into a buffer contiguously,
-                                            // starting with the last
-
-                                            let buf = self.descriptor_buf();
-                                            let mut storage_avail = buf.len();
-
-                                            let di = InterfaceDescriptor::default();
-                                            storage_avail -=
-                                                di.write_to(&buf[storage_avail - di.size()..]);
-
-                                            let dc = ConfigurationDescriptor {
-                                                num_interfaces: 1,
-                                                related_descriptor_length: di.size(),
-                                                ..Default::default()
-                                            };
-                                            storage_avail -=
-                                                dc.write_to(&buf[storage_avail - dc.size()..]);
-
-                                            let request_start = storage_avail;
-                                            let request_end = min(
-                                                request_start + (requested_length as usize),
-                                                buf.len(),
-                                            );
-                                            self.state
-                                                .set(State::CtrlIn(request_start, request_end));
-                                            CtrlSetupResult::Ok
-                                        }
-                                        _ => CtrlSetupResult::ErrInvalidConfigurationIndex,
-                                    }
-                                }
-                                DescriptorType::String => {
-                                    if let Some(buf) = match descriptor_index {
-                                        0 => {
-                                            let buf = self.descriptor_buf();
-                                            let d = LanguagesDescriptor { langs: LANGUAGES };
-                                            let len = d.write_to(buf);
-                                            let end = min(len, requested_length as usize);
-                                            Some(&buf[..end])
-                                        }
-                                        i if i > 0 && (i as usize) <= STRINGS.len()
-                                            && lang_id == LANGUAGES[0] =>
-                                        {
-                                            let buf = self.descriptor_buf();
-                                            let d = StringDescriptor {
-                                                string: STRINGS[i as usize - 1],
-                                            };
-                                            let len = d.write_to(buf);
-                                            let end = min(len, requested_length as usize);
-                                            Some(&buf[..end])
-                                        }
-                                        _ => None,
-                                    } {
-                                        self.state.set(State::CtrlIn(0, buf.len()));
-                                        CtrlSetupResult::Ok
-                                    } else {
-                                        CtrlSetupResult::ErrInvalidStringIndex
-                                    }
-                                }
-                                DescriptorType::DeviceQualifier => {
-                                    // We are full-speed only, so we must
-                                    // respond with a request error
-                                    CtrlSetupResult::ErrNoDeviceQualifier
-                                }
-                                _ => CtrlSetupResult::ErrUnrecognizedDescriptorType,
-                            } // match descriptor_type
-                        }
-                        StandardDeviceRequest::SetAddress { device_address } => {
-                            // Load the address we've been assigned ...
-                            self.controller.set_address(device_address);
-
-                            // ... and when this request gets to the Status stage
-                            // we will actually enable the address.
-                            self.state.set(State::SetAddress);
-                            CtrlSetupResult::Ok
-                        }
-                        StandardDeviceRequest::SetConfiguration { .. } => {
-                            // We have been assigned a particular configuration: fine!
-                            CtrlSetupResult::Ok
+                            self.client_ctrl.send_in(&[0xa, 0xb, 0xc], setup_data.length)
                         }
-                        _ => CtrlSetupResult::ErrUnrecognizedRequestType,
-                    }
-                },
-            )
-        })
+                    },
+                    None => CtrlSetupResult::ErrNoParse,
+                }
+            }
+            result => result,
+        }
     }
 
     /// Handle a Control In transaction
     fn ctrl_in(&self) -> CtrlInResult {
-        match self.state.get() {
-            State::CtrlIn(start, end) => {
-                let len = end.saturating_sub(start);
-                if len > 0 {
-                    let packet_bytes = min(8, len);
-                    let packet = &self.descriptor_storage[start..start + packet_bytes];
-                    let ep0_buf = self.ep0_buf();
-
-                    // Copy a packet into the endpoint buffer
-                    for (i, b) in packet.iter().enumerate() {
-                        ep0_buf[i].set(b.get());
-                    }
-
-                    let start = start + packet_bytes;
-                    let len = end.saturating_sub(start);
-                    let transfer_complete = len == 0;
-
-                    self.state.set(State::CtrlIn(start, end));
-
-                    CtrlInResult::Packet(packet_bytes, transfer_complete)
-                } else {
-                    CtrlInResult::Packet(0, true)
-                }
-            }
-            _ => CtrlInResult::Error,
-        }
+        self.client_ctrl.ctrl_in()
     }
 
     /// Handle a Control Out transaction
     fn ctrl_out(&self, packet_bytes: u32) -> CtrlOutResult {
-        match self.state.get() {
-            State::CtrlOut => {
-                debug!("Received {} vendor control bytes", packet_bytes);
-                // &self.ep0_buf()[0 .. packet_bytes as usize]
-                CtrlOutResult::Ok
-            }
-            _ => {
-                // Bad state
-                CtrlOutResult::Halted
-            }
+        let result = self.client_ctrl.ctrl_out(packet_bytes);
+        if let CtrlOutResult::Ok = result {
+            debug!("Received {} vendor control bytes", packet_bytes);
         }
+        result
     }
 
     fn ctrl_status(&self) {
-        // Entered Status stage
+        self.client_ctrl.ctrl_status()
     }
 
     /// Handle the completion of a Control transfer
     fn ctrl_status_complete(&self) {
-        // Control Read: IN request acknowledged
-        // Control Write: status sent
+        self.client_ctrl.ctrl_status_complete()
+    }
+
+    /// Send the packet in the echo buffer, if there is one
+    fn packet_in(&self, transfer_type: TransferType, endpoint: usize) -> InResult {
+        match (transfer_type, endpoint) {
+            (TransferType::Bulk, BULK_IN_ENDPOINT) => {
+                let len = self.echo_len.get();
+                if len == 0 {
+                    // Send once the host gives us something to echo
+                    self.delayed_in.set(true);
+                    return InResult::Delay;
+                }
+
+                for i in 0..len {
+                    self.bulk_in_storage[i].set(self.echo_buf[i].get());
+                }
+                self.echo_len.set(0);
 
-        match self.state.get() {
-            State::SetAddress => {
-                self.controller.enable_address();
+                // There is room for the next packet from the host
+                if self.delayed_out.get() {
+                    self.delayed_out.set(false);
+                    self.controller()
+                        .endpoint_resume_out(BULK_OUT_ENDPOINT as u32);
+                }
+                InResult::Packet(len)
             }
-            _ => {}
-        };
-        self.state.set(State::Init);
+            _ => InResult::Error,
+        }
+    }
+
+    /// Keep a packet received on the bulk OUT endpoint for echoing
+    fn packet_out(
+        &self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> OutResult {
+        match (transfer_type, endpoint) {
+            (TransferType::Bulk, BULK_OUT_ENDPOINT) => {
+                if self.echo_len.get() != 0 {
+                    // The last packet was not echoed yet
+                    self.delayed_out.set(true);
+                    return OutResult::Delay;
+                }
+
+                let len = min(packet_bytes as usize, BULK_PACKET_SIZE);
+                for i in 0..len {
+                    self.echo_buf[i].set(self.bulk_out_storage[i].get());
+                }
+                self.echo_len.set(len);
+
+                if self.delayed_in.get() {
+                    self.delayed_in.set(false);
+                    self.controller().endpoint_resume_in(BULK_IN_ENDPOINT as u32);
+                }
+                OutResult::Ok
+            }
+            _ => OutResult::Error,
+        }
+    }
+
+    fn packet_transmitted(&self, _endpoint: usize) {
+        // Nothing to do, the echo buffer was freed when the packet was copied
     }
 }
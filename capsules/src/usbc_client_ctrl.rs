@@ -0,0 +1,379 @@
+//! Handling of control transfers for USB device class capsules
+//!
+//! `ClientCtrl` answers the standard device requests on endpoint 0 that every
+//! USB device has to support: it hands out the device, configuration and
+//! string descriptors, and takes care of SET_ADDRESS, SET_CONFIGURATION and
+//! halting endpoints. A class capsule implements `hil::usb::Client` by
+//! forwarding the control callbacks to a `ClientCtrl`, and handles the class
+//! requests it returns `ErrNonstandardRequest` for itself:
+//!
+//! ```
+//! fn ctrl_setup(&self) -> CtrlSetupResult {
+//!     match self.client_ctrl.ctrl_setup() {
+//!         CtrlSetupResult::ErrNonstandardRequest => {
+//!             // Look at self.client_ctrl.setup_data(), then answer the
+//!             // request with self.client_ctrl.send_in() or by accepting the
+//!             // data in ctrl_out()
+//!         }
+//!         result => result,
+//!     }
+//! }
+//! ```
+//!
+//! The configuration descriptor is passed in as bytes, together with all the
+//! interface, endpoint and class specific descriptors that follow it.
+
+use core::cell::Cell;
+use core::cmp::min;
+use core::default::Default;
+use kernel::common::VolatileCell;
+use kernel::hil::usb::*;
+use usb::*;
+
+/// Size of the buffer for the device descriptor and class data sent on
+/// endpoint 0
+const DESCRIPTOR_BUFLEN: usize = 32;
+
+pub struct ClientCtrl<'a, 'b, C: 'a> {
+    controller: &'a C,
+    state: Cell<State<'b>>,
+    ctrl_storage: [VolatileCell<u8>; 8],
+    descriptor_storage: [Cell<u8>; DESCRIPTOR_BUFLEN],
+
+    device_descriptor: Cell<DeviceDescriptor>,
+    /// The configuration descriptor followed by all its related descriptors
+    configuration: Cell<&'b [u8]>,
+    langs: &'b [u16],
+    /// String descriptors 1 and on, in the first language
+    strings: &'b [&'b str],
+
+    /// The configuration selected by the host, 0 if not configured
+    configuration_value: Cell<u8>,
+}
+
+#[derive(Copy, Clone)]
+enum State<'b> {
+    Init,
+
+    /// We are doing a Control In transfer of some data,
+    /// with the given extent remaining to send
+    CtrlIn(InData<'b>, usize, usize),
+
+    /// We will accept data from the host
+    CtrlOut,
+
+    SetAddress,
+}
+
+/// Where the data of a Control In transfer comes from
+#[derive(Copy, Clone)]
+enum InData<'b> {
+    /// Bytes in self.descriptor_storage
+    Storage,
+
+    /// Bytes that outlive the transfer, like the configuration descriptors
+    Slice(&'b [u8]),
+
+    /// A string descriptor, which is encoded as it is sent
+    String(&'b str),
+}
+
+impl<'a, 'b, C: UsbController> ClientCtrl<'a, 'b, C> {
+    pub fn new(
+        controller: &'a C,
+        device_descriptor: DeviceDescriptor,
+        configuration: &'b [u8],
+        langs: &'b [u16],
+        strings: &'b [&'b str],
+    ) -> Self {
+        ClientCtrl {
+            controller: controller,
+            state: Cell::new(State::Init),
+            ctrl_storage: [VolatileCell::new(0); 8],
+            descriptor_storage: Default::default(),
+            device_descriptor: Cell::new(device_descriptor),
+            configuration: Cell::new(configuration),
+            langs: langs,
+            strings: strings,
+            configuration_value: Cell::new(0),
+        }
+    }
+
+    #[inline]
+    pub fn controller(&self) -> &'a C {
+        self.controller
+    }
+
+    /// The buffer of endpoint 0, holding the last Setup or Out packet
+    #[inline]
+    pub fn ctrl_buf(&self) -> &[VolatileCell<u8>] {
+        &self.ctrl_storage
+    }
+
+    #[inline]
+    fn descriptor_buf(&self) -> &[Cell<u8>] {
+        &self.descriptor_storage
+    }
+
+    /// The Setup packet of the current request
+    pub fn setup_data(&self) -> Option<SetupData> {
+        SetupData::get(self.ctrl_buf())
+    }
+
+    /// The configuration selected by the host, 0 if the device is not
+    /// configured
+    pub fn configuration_value(&self) -> u8 {
+        self.configuration_value.get()
+    }
+
+    /// Answer the current request with a copy of `data`, sending at most
+    /// `requested_length` bytes
+    pub fn send_in(&self, data: &[u8], requested_length: u16) -> CtrlSetupResult {
+        if data.len() > DESCRIPTOR_BUFLEN {
+            return CtrlSetupResult::ErrBadLength;
+        }
+        let buf = self.descriptor_buf();
+        for (i, b) in data.iter().enumerate() {
+            buf[i].set(*b);
+        }
+        let end = min(data.len(), requested_length as usize);
+        self.state.set(State::CtrlIn(InData::Storage, 0, end));
+        CtrlSetupResult::Ok
+    }
+
+    /// Answer the current request with `data`, which can be larger than a
+    /// copy made by `send_in()`
+    pub fn send_in_slice(&self, data: &'b [u8], requested_length: u16) -> CtrlSetupResult {
+        let end = min(data.len(), requested_length as usize);
+        self.state.set(State::CtrlIn(InData::Slice(data), 0, end));
+        CtrlSetupResult::Ok
+    }
+
+    /// Accept the data of the current request, which the class capsule reads
+    /// from `ctrl_buf()` as it arrives
+    pub fn accept_out(&self) -> CtrlSetupResult {
+        self.state.set(State::CtrlOut);
+        CtrlSetupResult::Ok
+    }
+
+    /// Present the device differently to the host, which reads the
+    /// descriptors again after the next bus reset
+    pub fn set_descriptors(&self, device_descriptor: DeviceDescriptor, configuration: &'b [u8]) {
+        self.device_descriptor.set(device_descriptor);
+        self.configuration.set(configuration);
+    }
+
+    pub fn enable(&self) {
+        self.controller.endpoint_set_buffer(0, self.ctrl_buf());
+        self.controller.enable_device(false);
+        self.controller.endpoint_ctrl_out_enable(0);
+    }
+
+    pub fn attach(&self) {
+        self.controller.attach();
+    }
+
+    /// Forget the address and configuration, which the host assigns again
+    /// after a bus reset
+    pub fn bus_reset(&self) {
+        self.state.set(State::Init);
+        self.configuration_value.set(0);
+    }
+
+    /// Handle a Control Setup transaction for a standard request
+    pub fn ctrl_setup(&self) -> CtrlSetupResult {
+        self.state.set(State::Init);
+        SetupData::get(self.ctrl_buf()).map_or(CtrlSetupResult::ErrNoParse, |setup_data| {
+            setup_data
+                .get_standard_request()
+                .map_or(CtrlSetupResult::ErrNonstandardRequest, |request| {
+                    self.handle_standard_request(setup_data, request)
+                })
+        })
+    }
+
+    /// The endpoint an ENDPOINT_HALT request is for, or `None` if it is not
+    /// addressed to an endpoint of the controller
+    fn halt_endpoint(&self, setup_data: SetupData, recipient_index: u16) -> Option<u32> {
+        let e = (recipient_index & 0xf) as u32;
+        match setup_data.request_type.recipient() {
+            Recipient::Endpoint if e < self.controller.endpoint_count() => Some(e),
+            _ => None,
+        }
+    }
+
+    fn handle_standard_request(
+        &self,
+        setup_data: SetupData,
+        request: StandardDeviceRequest,
+    ) -> CtrlSetupResult {
+        match request {
+            StandardDeviceRequest::GetDescriptor {
+                descriptor_type,
+                descriptor_index,
+                lang_id,
+                requested_length,
+            } => match descriptor_type {
+                DescriptorType::Device => match descriptor_index {
+                    0 => {
+                        let buf = self.descriptor_buf();
+                        let len = self.device_descriptor.get().write_to(buf);
+                        let end = min(len, requested_length as usize);
+                        self.state.set(State::CtrlIn(InData::Storage, 0, end));
+                        CtrlSetupResult::Ok
+                    }
+                    _ => CtrlSetupResult::ErrInvalidDeviceIndex,
+                },
+                DescriptorType::Configuration => match descriptor_index {
+                    0 => self.send_in_slice(self.configuration.get(), requested_length),
+                    _ => CtrlSetupResult::ErrInvalidConfigurationIndex,
+                },
+                DescriptorType::String => match descriptor_index {
+                    0 => {
+                        let buf = self.descriptor_buf();
+                        let len = LanguagesDescriptor { langs: self.langs }.write_to(buf);
+                        let end = min(len, requested_length as usize);
+                        self.state.set(State::CtrlIn(InData::Storage, 0, end));
+                        CtrlSetupResult::Ok
+                    }
+                    i if i > 0 && (i as usize) <= self.strings.len()
+                        && self.langs.first() == Some(&lang_id) =>
+                    {
+                        let string = self.strings[i as usize - 1];
+                        let len = StringDescriptor { string: string }.size();
+                        let end = min(len, requested_length as usize);
+                        self.state.set(State::CtrlIn(InData::String(string), 0, end));
+                        CtrlSetupResult::Ok
+                    }
+                    _ => CtrlSetupResult::ErrInvalidStringIndex,
+                },
+                DescriptorType::DeviceQualifier => {
+                    // We are full-speed only, so we must
+                    // respond with a request error
+                    CtrlSetupResult::ErrNoDeviceQualifier
+                }
+                _ => CtrlSetupResult::ErrUnrecognizedDescriptorType,
+            },
+            StandardDeviceRequest::SetAddress { device_address } => {
+                // Load the address we've been assigned ...
+                self.controller.set_address(device_address);
+
+                // ... and when this request gets to the Status stage
+                // we will actually enable the address.
+                self.state.set(State::SetAddress);
+                CtrlSetupResult::Ok
+            }
+            StandardDeviceRequest::SetConfiguration {
+                configuration_value,
+            } => {
+                // There is a single configuration, and 0 deconfigures the
+                // device. bConfigurationValue is byte 5 of its descriptor.
+                let valid = configuration_value == 0
+                    || self.configuration.get().get(5) == Some(&configuration_value);
+                if valid {
+                    self.configuration_value.set(configuration_value);
+                    CtrlSetupResult::Ok
+                } else {
+                    CtrlSetupResult::ErrInvalidConfigurationIndex
+                }
+            }
+            StandardDeviceRequest::GetConfiguration => {
+                self.send_in(&[self.configuration_value.get()], 1)
+            }
+            StandardDeviceRequest::SetFeature {
+                feature: FeatureSelector::EndpointHalt,
+                recipient_index,
+                ..
+            } => self.halt_endpoint(setup_data, recipient_index).map_or(
+                CtrlSetupResult::ErrInvalidRequest,
+                |e| {
+                    self.controller.endpoint_set_stall(e);
+                    CtrlSetupResult::Ok
+                },
+            ),
+            StandardDeviceRequest::ClearFeature {
+                feature: FeatureSelector::EndpointHalt,
+                recipient_index,
+            } => self.halt_endpoint(setup_data, recipient_index).map_or(
+                CtrlSetupResult::ErrInvalidRequest,
+                |e| {
+                    self.controller.endpoint_clear_stall(e);
+                    CtrlSetupResult::Ok
+                },
+            ),
+            _ => CtrlSetupResult::ErrUnrecognizedRequestType,
+        }
+    }
+
+    /// Handle a Control In transaction
+    pub fn ctrl_in(&self) -> CtrlInResult {
+        match self.state.get() {
+            State::CtrlIn(data, start, end) => {
+                let len = end.saturating_sub(start);
+                if len == 0 {
+                    return CtrlInResult::Packet(0, true);
+                }
+
+                // Copy a packet into the endpoint buffer
+                let packet_bytes = min(8, len);
+                let ctrl_buf = self.ctrl_buf();
+                for i in 0..packet_bytes {
+                    ctrl_buf[i].set(self.in_byte(data, start + i));
+                }
+
+                let start = start + packet_bytes;
+                let transfer_complete = start >= end;
+                self.state.set(State::CtrlIn(data, start, end));
+
+                CtrlInResult::Packet(packet_bytes, transfer_complete)
+            }
+            _ => CtrlInResult::Error,
+        }
+    }
+
+    /// The byte at `index` of the data of a Control In transfer
+    fn in_byte(&self, data: InData, index: usize) -> u8 {
+        match data {
+            InData::Storage => self.descriptor_storage[index].get(),
+            InData::Slice(slice) => slice[index],
+            InData::String(string) => match index {
+                0 => StringDescriptor { string: string }.size() as u8,
+                1 => DescriptorType::String as u8,
+                _ => {
+                    // Encode as utf16-le
+                    let unit = string.encode_utf16().nth((index - 2) / 2).unwrap_or(0);
+                    (unit >> (8 * (index % 2))) as u8
+                }
+            },
+        }
+    }
+
+    /// Handle a Control Out transaction
+    pub fn ctrl_out(&self, _packet_bytes: u32) -> CtrlOutResult {
+        match self.state.get() {
+            State::CtrlOut => CtrlOutResult::Ok,
+            _ => {
+                // Bad state
+                CtrlOutResult::Halted
+            }
+        }
+    }
+
+    pub fn ctrl_status(&self) {
+        // Entered Status stage
+    }
+
+    /// Handle the completion of a Control transfer
+    pub fn ctrl_status_complete(&self) {
+        // Control Read: IN request acknowledged
+        // Control Write: status sent
+
+        match self.state.get() {
+            State::SetAddress => {
+                self.controller.enable_address();
+            }
+            _ => {}
+        };
+        self.state.set(State::Init);
+    }
+}
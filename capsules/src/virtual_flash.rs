@@ -8,6 +8,15 @@
 //! must use a `FlashUser` instance to contain the per-user state for the
 //! virtualization.
 //!
+//! Each `FlashUser` may only write and erase the pages the board allows it
+//! with `set_allowed_pages()`, so that a capsule cannot overwrite the kernel
+//! or the apps and their TBF headers. Requests outside of these pages return
+//! `EINVAL`. Reads are not restricted.
+//!
+//! A request that the flash rejects when it is issued right away returns the
+//! error, and the buffer if it had one, like any other `Flash`. A request
+//! that had to wait for another user gets the error in its callback.
+//!
 //! Usage
 //! -----
 //!
@@ -22,6 +31,8 @@
 //! let virtual_flash = static_init!(
 //!     capsules::virtual_flash::FlashUser<'static, sam4l::flashcalw::FLASHCALW>,
 //!     capsules::virtual_flash::FlashUser::new(mux_flash));
+//! // Only allow it to modify pages 448 to 511.
+//! virtual_flash.set_allowed_pages(448, 512);
 //! ```
 
 use core::cell::Cell;
@@ -46,6 +57,7 @@ impl<'a, F: hil::flash::Flash + 'a> hil::flash::Client<F> for MuxFlash<'a, F> {
             user.read_complete(pagebuffer, error);
         });
         self.do_next_op();
+        self.report_rejected();
     }
 
     fn write_complete(&self, pagebuffer: &'static mut F::Page, error: hil::flash::Error) {
@@ -54,6 +66,7 @@ impl<'a, F: hil::flash::Flash + 'a> hil::flash::Client<F> for MuxFlash<'a, F> {
             user.write_complete(pagebuffer, error);
         });
         self.do_next_op();
+        self.report_rejected();
     }
 
     fn erase_complete(&self, error: hil::flash::Error) {
@@ -62,6 +75,7 @@ impl<'a, F: hil::flash::Flash + 'a> hil::flash::Client<F> for MuxFlash<'a, F> {
             user.erase_complete(error);
         });
         self.do_next_op();
+        self.report_rejected();
     }
 }
 
@@ -76,39 +90,76 @@ impl<'a, F: hil::flash::Flash + 'a> MuxFlash<'a, F> {
 
     /// Scan the list of users and find the first user that has a pending
     /// request, then issue that request to the flash hardware.
+    ///
+    /// A request the flash does not start is marked as rejected on its user,
+    /// with the buffer kept there, and the next pending request is tried.
     fn do_next_op(&self) {
-        if self.inflight.get().is_none() {
+        while self.inflight.get().is_none() {
             let mnode = self.users
                 .iter()
                 .find(|node| node.operation.get() != Op::Idle);
-            mnode.map(|node| {
-                node.buffer.take().map_or_else(
-                    || {
-                        // Don't need a buffer for erase.
-                        match node.operation.get() {
-                            Op::Erase(page_number) => {
-                                self.flash.erase_page(page_number);
+            let node = match mnode {
+                Some(node) => node,
+                None => return,
+            };
+            let operation = node.operation.get();
+            node.operation.set(Op::Idle);
+            let rc = match operation {
+                // Don't need a buffer for erase.
+                Op::Erase(page_number) => self.flash.erase_page(page_number),
+                Op::EraseRange(page_number, count) => self.flash.erase_pages(page_number, count),
+                Op::Write(..) | Op::WritePartial(..) | Op::Read(..) => {
+                    node.buffer.take().map_or(ReturnCode::FAIL, |buf| {
+                        let (rc, buf) = match operation {
+                            Op::Write(page_number) => self.flash.write_page(page_number, buf),
+                            Op::WritePartial(page_number, offset, length) => {
+                                self.flash.write_partial(page_number, offset, length, buf)
                             }
-                            _ => {}
+                            Op::Read(page_number) => self.flash.read_page(page_number, buf),
+                            _ => (ReturnCode::FAIL, Some(buf)), // Can't get here...
                         };
-                    },
-                    |buf| {
-                        match node.operation.get() {
-                            Op::Write(page_number) => {
-                                self.flash.write_page(page_number, buf);
-                            }
-                            Op::Read(page_number) => {
-                                self.flash.read_page(page_number, buf);
-                            }
-                            Op::Erase(page_number) => {
-                                self.flash.erase_page(page_number);
-                            }
-                            Op::Idle => {} // Can't get here...
-                        }
-                    },
-                );
-                node.operation.set(Op::Idle);
+                        buf.map(|buf| node.buffer.replace(buf));
+                        rc
+                    })
+                }
+                Op::Idle => ReturnCode::FAIL, // Can't get here...
+            };
+
+            if rc == ReturnCode::SUCCESS {
                 self.inflight.set(Some(node));
+            } else {
+                node.rejected.set(Some((operation, rc)));
+            }
+        }
+    }
+
+    /// Tell the users whose queued requests were rejected by `do_next_op()`.
+    /// This is only called after a completion, so that a user is never called
+    /// back from inside its own request.
+    fn report_rejected(&self) {
+        while let Some(node) = self.users
+            .iter()
+            .find(|node| node.rejected.get().is_some())
+        {
+            node.rejected.take().map(|(operation, rc)| {
+                let error = if rc == ReturnCode::EBUSY {
+                    hil::flash::Error::Busy
+                } else {
+                    hil::flash::Error::FlashError
+                };
+                match operation {
+                    Op::Read(..) => {
+                        node.buffer.take().map(|buf| {
+                            hil::flash::Client::read_complete(node, buf, error);
+                        });
+                    }
+                    Op::Write(..) | Op::WritePartial(..) => {
+                        node.buffer.take().map(|buf| {
+                            hil::flash::Client::write_complete(node, buf, error);
+                        });
+                    }
+                    _ => hil::flash::Client::erase_complete(node, error),
+                }
             });
         }
     }
@@ -118,8 +169,10 @@ impl<'a, F: hil::flash::Flash + 'a> MuxFlash<'a, F> {
 enum Op {
     Idle,
     Write(usize),
+    WritePartial(usize, usize, usize),
     Read(usize),
     Erase(usize),
+    EraseRange(usize, usize),
 }
 
 /// Keep state for each flash user. All uses of the virtualized flash interface
@@ -132,6 +185,10 @@ pub struct FlashUser<'a, F: hil::flash::Flash + 'static> {
     operation: Cell<Op>,
     next: ListLink<'a, FlashUser<'a, F>>,
     client: Cell<Option<&'a hil::flash::Client<FlashUser<'a, F>>>>,
+    /// First page this user may write or erase, and the page after the last.
+    allowed_pages: Cell<(usize, usize)>,
+    /// The request the flash did not start, and why.
+    rejected: Cell<Option<(Op, ReturnCode)>>,
 }
 
 impl<'a, F: hil::flash::Flash + 'a> FlashUser<'a, F> {
@@ -142,6 +199,46 @@ impl<'a, F: hil::flash::Flash + 'a> FlashUser<'a, F> {
             operation: Cell::new(Op::Idle),
             next: ListLink::empty(),
             client: Cell::new(None),
+            allowed_pages: Cell::new((0, 0)),
+            rejected: Cell::new(None),
+        }
+    }
+
+    /// Allow this user to write and erase the pages from `first_page` up to,
+    /// but not including, `end_page`. Until this is called the user cannot
+    /// modify any page.
+    pub fn set_allowed_pages(&self, first_page: usize, end_page: usize) {
+        self.allowed_pages.set((first_page, end_page));
+    }
+
+    /// Whether `count` pages starting at `page_number` may be modified.
+    fn is_allowed(&self, page_number: usize, count: usize) -> bool {
+        let (first_page, end_page) = self.allowed_pages.get();
+        page_number >= first_page
+            && page_number
+                .checked_add(count)
+                .map_or(false, |end| end <= end_page)
+    }
+
+    /// Queue `operation` and start it if the flash is free. If the flash
+    /// rejects it right away the error is returned here, and there will be
+    /// no callback.
+    fn request(&self, operation: Op) -> ReturnCode {
+        self.operation.set(operation);
+        self.mux.do_next_op();
+        self.rejected.take().map_or(ReturnCode::SUCCESS, |(_, rc)| rc)
+    }
+
+    /// Like `request()`, and hand the buffer back if the request was rejected.
+    fn request_with_buffer(
+        &self,
+        operation: Op,
+        buf: &'static mut F::Page,
+    ) -> (ReturnCode, Option<&'static mut F::Page>) {
+        self.buffer.replace(buf);
+        match self.request(operation) {
+            ReturnCode::SUCCESS => (ReturnCode::SUCCESS, None),
+            rc => (rc, self.buffer.take()),
         }
     }
 }
@@ -184,23 +281,53 @@ impl<'a, F: hil::flash::Flash + 'a> ListNode<'a, FlashUser<'a, F>> for FlashUser
 impl<'a, F: hil::flash::Flash + 'a> hil::flash::Flash for FlashUser<'a, F> {
     type Page = F::Page;
 
-    fn read_page(&self, page_number: usize, buf: &'static mut Self::Page) -> ReturnCode {
-        self.buffer.replace(buf);
-        self.operation.set(Op::Read(page_number));
-        self.mux.do_next_op();
-        ReturnCode::SUCCESS
+    fn read_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> (ReturnCode, Option<&'static mut Self::Page>) {
+        self.request_with_buffer(Op::Read(page_number), buf)
     }
 
-    fn write_page(&self, page_number: usize, buf: &'static mut Self::Page) -> ReturnCode {
-        self.buffer.replace(buf);
-        self.operation.set(Op::Write(page_number));
-        self.mux.do_next_op();
-        ReturnCode::SUCCESS
+    fn write_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> (ReturnCode, Option<&'static mut Self::Page>) {
+        if !self.is_allowed(page_number, 1) {
+            return (ReturnCode::EINVAL, Some(buf));
+        }
+        self.request_with_buffer(Op::Write(page_number), buf)
     }
 
     fn erase_page(&self, page_number: usize) -> ReturnCode {
-        self.operation.set(Op::Erase(page_number));
-        self.mux.do_next_op();
-        ReturnCode::SUCCESS
+        if !self.is_allowed(page_number, 1) {
+            return ReturnCode::EINVAL;
+        }
+        self.request(Op::Erase(page_number))
+    }
+
+    fn write_granularity(&self) -> Option<usize> {
+        self.mux.flash.write_granularity()
+    }
+
+    fn write_partial(
+        &self,
+        page_number: usize,
+        offset: usize,
+        length: usize,
+        buf: &'static mut Self::Page,
+    ) -> (ReturnCode, Option<&'static mut Self::Page>) {
+        if !self.is_allowed(page_number, 1) {
+            return (ReturnCode::EINVAL, Some(buf));
+        }
+        self.request_with_buffer(Op::WritePartial(page_number, offset, length), buf)
+    }
+
+    fn erase_pages(&self, page_number: usize, count: usize) -> ReturnCode {
+        if !self.is_allowed(page_number, count) {
+            return ReturnCode::EINVAL;
+        }
+        self.request(Op::EraseRange(page_number, count))
     }
 }
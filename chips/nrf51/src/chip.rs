@@ -1,5 +1,7 @@
+use clock;
 use cortexm0::nvic;
 use kernel;
+use kernel::power::SleepMode;
 use nrf5x;
 use nrf5x::peripheral_interrupts::*;
 use radio;
@@ -51,4 +53,10 @@ impl kernel::Chip for NRF51 {
     fn has_pending_interrupts(&self) -> bool {
         unsafe { nvic::has_pending() }
     }
+
+    fn prepare_for_sleep(&self, mode: SleepMode) {
+        unsafe {
+            clock::CLOCK.prepare_for_sleep(mode);
+        }
+    }
 }
@@ -13,6 +13,7 @@
 
 use core::cell::Cell;
 use kernel::common::VolatileCell;
+use kernel::power::SleepMode;
 
 pub static mut CLOCK: Clock = Clock::new();
 
@@ -197,4 +198,34 @@ impl Clock {
         let regs = unsafe { &*self.registers };
         regs.lfclksrc.set(src as u32);
     }
+
+    /// Check if the high frequency clock is running from the crystal
+    fn high_running_xtal(&self) -> bool {
+        match self.high_source() {
+            HighClockSource::XTAL => self.high_running(),
+            HighClockSource::RC => false,
+        }
+    }
+
+    /// Start the high frequency crystal oscillator, unless it is running
+    /// already, and wait until it is stable
+    pub fn high_start_xtal(&self) {
+        if !self.high_running_xtal() {
+            self.high_start();
+            while !self.high_running_xtal() {}
+        }
+    }
+
+    /// Stop the high frequency crystal oscillator before deep sleep.
+    ///
+    /// Peripherals that need the high frequency clock then run from the
+    /// internal oscillator, which the chip starts and stops on demand. Only
+    /// the radio needs the accuracy of the crystal, and it does not permit
+    /// deep sleep while it is on. It restarts the crystal with
+    /// `high_start_xtal()`.
+    pub fn prepare_for_sleep(&self, mode: SleepMode) {
+        if mode == SleepMode::DeepSleep && self.high_running_xtal() {
+            self.high_stop();
+        }
+    }
 }
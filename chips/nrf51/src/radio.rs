@@ -10,6 +10,7 @@
 //! * Fredrik Nilsson <frednils@student.chalmers.se>
 //! * Date: June 22, 2017
 
+use clock;
 use core::cell::Cell;
 use core::convert::TryFrom;
 use kernel;
@@ -17,6 +18,7 @@ use kernel::ReturnCode;
 use kernel::common::VolatileCell;
 use kernel::hil::ble_advertising;
 use kernel::hil::ble_advertising::RadioChannel;
+use kernel::power::{PowerConstraint, SleepMode};
 use nrf5x;
 use nrf5x::constants::TxPower;
 
@@ -117,6 +119,7 @@ pub struct Radio {
     tx_power: Cell<TxPower>,
     rx_client: Cell<Option<&'static ble_advertising::RxClient>>,
     tx_client: Cell<Option<&'static ble_advertising::TxClient>>,
+    power_constraint: PowerConstraint,
 }
 
 impl Radio {
@@ -126,6 +129,7 @@ impl Radio {
             tx_power: Cell::new(TxPower::ZerodBm),
             rx_client: Cell::new(None),
             tx_client: Cell::new(None),
+            power_constraint: PowerConstraint::new(SleepMode::Sleep),
         }
     }
 
@@ -236,6 +240,11 @@ impl Radio {
 
     fn radio_on(&self) {
         let regs = unsafe { &*self.regs };
+        // The radio needs the crystal, which is stopped in deep sleep
+        self.power_constraint.set_active(true);
+        unsafe {
+            clock::CLOCK.high_start_xtal();
+        }
         // reset and enable power
         regs.power.set(0);
         regs.power.set(1);
@@ -244,6 +253,7 @@ impl Radio {
     fn radio_off(&self) {
         let regs = unsafe { &*self.regs };
         regs.power.set(0);
+        self.power_constraint.set_active(false);
     }
 
     // pre-condition validated before arriving here
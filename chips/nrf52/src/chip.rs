@@ -1,8 +1,11 @@
+use clock;
 use cortexm4::{self, nvic};
 use i2c;
 use kernel;
+use kernel::power::SleepMode;
 use nrf5x;
 use nrf5x::peripheral_interrupts::*;
+use nvmc;
 use radio;
 use spi;
 use uart;
@@ -14,6 +17,7 @@ pub struct NRF52 {
 
 impl NRF52 {
     pub unsafe fn new() -> NRF52 {
+        nvmc::NVMC.initialize_deferred_call();
         NRF52 {
             mpu: cortexm4::mpu::MPU::new(),
             // The NRF52's systick is uncalibrated, but is clocked from the
@@ -90,4 +94,17 @@ impl kernel::Chip for NRF52 {
     fn has_pending_interrupts(&self) -> bool {
         unsafe { nvic::has_pending() }
     }
+
+    fn prepare_for_sleep(&self, mode: SleepMode) {
+        // Both modes are System ON sleep, SLEEPDEEP lets the chip power down
+        // more of its clock tree when no active peripheral needs it.
+        unsafe {
+            clock::CLOCK.prepare_for_sleep(mode);
+            if mode == SleepMode::DeepSleep {
+                cortexm4::scb::set_sleepdeep();
+            } else {
+                cortexm4::scb::unset_sleepdeep();
+            }
+        }
+    }
 }
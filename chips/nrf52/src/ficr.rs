@@ -249,6 +249,25 @@ impl Ficr {
         }
     }
 
+    /// Number of pages of code memory.
+    pub fn code_pages(&self) -> usize {
+        let regs = unsafe { &*self.registers };
+        regs.codesize.get() as usize
+    }
+
+    /// The 64 bit unique device identifier, least significant byte first.
+    pub fn device_id(&self) -> [u8; 8] {
+        let regs = unsafe { &*self.registers };
+        let lsb = regs.deviceid0.get();
+        let msb = regs.deviceid1.get();
+        let mut id = [0; 8];
+        for i in 0..4 {
+            id[i] = (lsb >> (8 * i)) as u8;
+            id[4 + i] = (msb >> (8 * i)) as u8;
+        }
+        id
+    }
+
     fn part(&self) -> Part {
         let regs = unsafe { &*self.registers };
         match regs.info_part.get() {
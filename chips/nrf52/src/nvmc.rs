@@ -1,8 +1,37 @@
-// Non-Volatile Memory Controller
-// Used in order read and write to internal flash
-// Minimal implementation to support activation of the reset button
+//! Non-Volatile Memory Controller
+//!
+//! Used in order to read, write and erase the internal flash, and to program
+//! the UICR registers (for example to activate the reset button).
+//!
+//! Flash is accessed in 4 kB pages through `hil::flash::Flash`. Parts of a
+//! page can be programmed a word at a time without erasing it, and every write
+//! and erase is verified by reading the flash back. Writing or erasing the
+//! flash stalls the CPU while it executes from flash, so all operations run to
+//! completion synchronously and the completion callbacks are delivered
+//! afterwards from a deferred call.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! pub static mut PAGEBUFFER: nrf52::nvmc::NrfPage = nrf52::nvmc::NrfPage::new();
+//! let nv_to_page = static_init!(
+//!     capsules::nonvolatile_to_pages::NonvolatileToPages<'static, nrf52::nvmc::Nvmc>,
+//!     capsules::nonvolatile_to_pages::NonvolatileToPages::new(
+//!         &mut nrf52::nvmc::NVMC,
+//!         &mut PAGEBUFFER));
+//! hil::flash::HasClient::set_client(&nrf52::nvmc::NVMC, nv_to_page);
+//! ```
 
+use core::cell::Cell;
+use core::ops::{Index, IndexMut};
+use core::ptr;
+use ficr;
+use kernel::ReturnCode;
+use kernel::common::deferred_call::{DeferredCall, DeferredCallClient};
 use kernel::common::regs::{ReadOnly, ReadWrite};
+use kernel::common::take_cell::TakeCell;
+use kernel::hil;
 
 pub const NVMC_BASE: usize = 0x4001E400;
 #[repr(C)]
@@ -124,24 +153,350 @@ register_bitfields! [u32,
     ]
 ];
 
+/// Size of a flash page in bytes.
+pub const PAGE_SIZE: usize = 4096;
+
+/// This is a wrapper around a u8 array that is sized to a single page for the
+/// nrf52. Users of this module must pass an object of this type to use the
+/// `hil::flash::Flash` interface.
+///
+/// An example looks like:
+///
+/// ```
+/// static mut PAGEBUFFER: NrfPage = NrfPage::new();
+/// ```
+pub struct NrfPage(pub [u8; PAGE_SIZE]);
+
+impl NrfPage {
+    pub const fn new() -> NrfPage {
+        NrfPage([0; PAGE_SIZE])
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+impl Index<usize> for NrfPage {
+    type Output = u8;
+
+    fn index(&self, idx: usize) -> &u8 {
+        &self.0[idx]
+    }
+}
+
+impl IndexMut<usize> for NrfPage {
+    fn index_mut(&mut self, idx: usize) -> &mut u8 {
+        &mut self.0[idx]
+    }
+}
+
+impl AsMut<[u8]> for NrfPage {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+/// Which operation the deferred call has to report as complete.
+#[derive(Clone, Copy, PartialEq)]
+enum FlashState {
+    Ready,
+    Read,
+    Write,
+    Erase,
+}
+
 pub struct Nvmc {
     regs: *const NvmcRegisters,
+    client: Cell<Option<&'static hil::flash::Client<Nvmc>>>,
+    buffer: TakeCell<'static, NrfPage>,
+    state: Cell<FlashState>,
+    /// Result of the operation to report in the deferred call.
+    error: Cell<hil::flash::Error>,
+    deferred_call: DeferredCall,
 }
 
+// static instance for the board. Only one NVMC on chip.
+pub static mut NVMC: Nvmc = Nvmc::new();
+
 impl Nvmc {
     pub const fn new() -> Nvmc {
         Nvmc {
             regs: NVMC_BASE as *const NvmcRegisters,
+            client: Cell::new(None),
+            buffer: TakeCell::empty(),
+            state: Cell::new(FlashState::Ready),
+            error: Cell::new(hil::flash::Error::CommandComplete),
+            deferred_call: DeferredCall::new(),
         }
     }
 
+    /// Register for deferred calls, which are used to signal the completion
+    /// of all flash operations. Must be called before the flash is used.
+    pub fn initialize_deferred_call(&'static self) {
+        self.deferred_call.register(self);
+    }
+
+    pub fn configure_readonly(&self) {
+        let regs = unsafe { &*self.regs };
+        regs.config.write(Configuration::WEN::REN);
+    }
+
     pub fn configure_writeable(&self) {
         let regs = unsafe { &*self.regs };
-        regs.config.set(1);
+        regs.config.write(Configuration::WEN::WEN);
+    }
+
+    pub fn configure_eraseable(&self) {
+        let regs = unsafe { &*self.regs };
+        regs.config.write(Configuration::WEN::EEN);
     }
 
     pub fn is_ready(&self) -> bool {
         let regs = unsafe { &*self.regs };
         regs.ready.is_set(Ready::READY)
     }
+
+    /// Number of pages in the code area of the flash.
+    pub fn get_number_pages(&self) -> usize {
+        unsafe { ficr::FICR_INSTANCE.code_pages() }
+    }
+
+    /// Check that no operation is in progress and that `count` pages starting
+    /// at `page_number` exist.
+    fn check_request(&self, page_number: usize, count: usize) -> ReturnCode {
+        if self.state.get() != FlashState::Ready {
+            ReturnCode::EBUSY
+        } else if count == 0
+            || page_number
+                .checked_add(count)
+                .map_or(true, |end| end > self.get_number_pages())
+        {
+            ReturnCode::EINVAL
+        } else {
+            ReturnCode::SUCCESS
+        }
+    }
+
+    /// Erase a page and wait for the erase to finish. Returns whether the page
+    /// reads as erased afterwards.
+    fn erase_page_blocking(&self, page_number: usize) -> bool {
+        let regs = unsafe { &*self.regs };
+        self.configure_eraseable();
+        regs.erasepage.set((page_number * PAGE_SIZE) as u32);
+        while !self.is_ready() {}
+        self.configure_readonly();
+
+        let page = (page_number * PAGE_SIZE) as *const u32;
+        (0..PAGE_SIZE / 4)
+            .all(|i| unsafe { ptr::read_volatile(page.offset(i as isize)) == 0xFFFFFFFF })
+    }
+
+    /// Program the words of a page from `offset` to `offset + length` with
+    /// the same words of `data`. Returns whether the flash holds the expected
+    /// contents afterwards.
+    fn program_blocking(
+        &self,
+        page_number: usize,
+        offset: usize,
+        length: usize,
+        data: &NrfPage,
+    ) -> bool {
+        let mut verified = true;
+
+        self.configure_writeable();
+        for i in offset / 4..(offset + length) / 4 {
+            let word = (data[i * 4] as u32) | (data[i * 4 + 1] as u32) << 8
+                | (data[i * 4 + 2] as u32) << 16 | (data[i * 4 + 3] as u32) << 24;
+            let address = (page_number * PAGE_SIZE + i * 4) as *mut u32;
+            unsafe {
+                // Programming can only clear bits. Words that would not
+                // change, like all ones on erased flash, are skipped as each
+                // word may only be written a limited number of times.
+                let old = ptr::read_volatile(address);
+                if old & word != old {
+                    ptr::write_volatile(address, word);
+                    while !self.is_ready() {}
+                }
+                verified &= ptr::read_volatile(address) == old & word;
+            }
+        }
+        self.configure_readonly();
+
+        verified
+    }
+
+    /// Record the result of an operation and schedule its callback.
+    fn complete(&self, state: FlashState, success: bool) {
+        self.error.set(if success {
+            hil::flash::Error::CommandComplete
+        } else {
+            hil::flash::Error::VerificationFailed
+        });
+        self.state.set(state);
+        self.deferred_call.set();
+    }
+
+    pub fn read_page(
+        &self,
+        page_number: usize,
+        buffer: &'static mut NrfPage,
+    ) -> (ReturnCode, Option<&'static mut NrfPage>) {
+        let rc = self.check_request(page_number, 1);
+        if rc != ReturnCode::SUCCESS {
+            return (rc, Some(buffer));
+        }
+
+        // Flash is memory mapped, so this is just a copy.
+        let mut byte = (page_number * PAGE_SIZE) as *const u8;
+        unsafe {
+            for i in 0..buffer.len() {
+                buffer[i] = ptr::read_volatile(byte);
+                byte = byte.offset(1);
+            }
+        }
+
+        // Hold on to the buffer for the callback.
+        self.buffer.replace(buffer);
+        self.complete(FlashState::Read, true);
+        (ReturnCode::SUCCESS, None)
+    }
+
+    pub fn write_page(
+        &self,
+        page_number: usize,
+        data: &'static mut NrfPage,
+    ) -> (ReturnCode, Option<&'static mut NrfPage>) {
+        let rc = self.check_request(page_number, 1);
+        if rc != ReturnCode::SUCCESS {
+            return (rc, Some(data));
+        }
+
+        let success = self.erase_page_blocking(page_number)
+            && self.program_blocking(page_number, 0, PAGE_SIZE, data);
+
+        self.buffer.replace(data);
+        self.complete(FlashState::Write, success);
+        (ReturnCode::SUCCESS, None)
+    }
+
+    /// Program part of a page without erasing it. `offset` and `length` must
+    /// be word aligned.
+    pub fn write_partial(
+        &self,
+        page_number: usize,
+        offset: usize,
+        length: usize,
+        data: &'static mut NrfPage,
+    ) -> (ReturnCode, Option<&'static mut NrfPage>) {
+        let rc = self.check_request(page_number, 1);
+        if rc != ReturnCode::SUCCESS {
+            return (rc, Some(data));
+        }
+        if length == 0 || offset % 4 != 0 || length % 4 != 0
+            || offset + length > PAGE_SIZE
+        {
+            return (ReturnCode::EINVAL, Some(data));
+        }
+
+        let success = self.program_blocking(page_number, offset, length, data);
+
+        self.buffer.replace(data);
+        self.complete(FlashState::Write, success);
+        (ReturnCode::SUCCESS, None)
+    }
+
+    pub fn erase_page(&self, page_number: usize) -> ReturnCode {
+        self.erase_pages(page_number, 1)
+    }
+
+    pub fn erase_pages(&self, page_number: usize, count: usize) -> ReturnCode {
+        let rc = self.check_request(page_number, count);
+        if rc != ReturnCode::SUCCESS {
+            return rc;
+        }
+
+        let mut success = true;
+        for page in page_number..page_number + count {
+            success &= self.erase_page_blocking(page);
+        }
+
+        self.complete(FlashState::Erase, success);
+        ReturnCode::SUCCESS
+    }
+}
+
+impl DeferredCallClient for Nvmc {
+    fn handle_deferred_call(&self) {
+        let state = self.state.get();
+        let error = self.error.get();
+
+        // Reset state now that we are ready to do a new operation.
+        self.state.set(FlashState::Ready);
+
+        self.client.get().map(|client| match state {
+            FlashState::Read => {
+                self.buffer.take().map(|buffer| {
+                    client.read_complete(buffer, error);
+                });
+            }
+            FlashState::Write => {
+                self.buffer.take().map(|buffer| {
+                    client.write_complete(buffer, error);
+                });
+            }
+            FlashState::Erase => {
+                client.erase_complete(error);
+            }
+            FlashState::Ready => {}
+        });
+    }
+}
+
+impl<C: hil::flash::Client<Self>> hil::flash::HasClient<'static, C> for Nvmc {
+    fn set_client(&self, client: &'static C) {
+        self.client.set(Some(client));
+    }
+}
+
+impl hil::flash::Flash for Nvmc {
+    type Page = NrfPage;
+
+    fn read_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> (ReturnCode, Option<&'static mut Self::Page>) {
+        self.read_page(page_number, buf)
+    }
+
+    fn write_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> (ReturnCode, Option<&'static mut Self::Page>) {
+        self.write_page(page_number, buf)
+    }
+
+    fn erase_page(&self, page_number: usize) -> ReturnCode {
+        self.erase_page(page_number)
+    }
+
+    fn write_granularity(&self) -> Option<usize> {
+        Some(4)
+    }
+
+    fn write_partial(
+        &self,
+        page_number: usize,
+        offset: usize,
+        length: usize,
+        buf: &'static mut Self::Page,
+    ) -> (ReturnCode, Option<&'static mut Self::Page>) {
+        self.write_partial(page_number, offset, length, buf)
+    }
+
+    fn erase_pages(&self, page_number: usize, count: usize) -> ReturnCode {
+        self.erase_pages(page_number, count)
+    }
 }
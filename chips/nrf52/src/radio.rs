@@ -33,6 +33,7 @@
 //!
 //! * CRC - 3 bytes
 
+use clock;
 use core::cell::Cell;
 use core::convert::TryFrom;
 use kernel;
@@ -40,6 +41,7 @@ use kernel::ReturnCode;
 use kernel::common::regs::{ReadOnly, ReadWrite, WriteOnly};
 use kernel::hil::ble_advertising;
 use kernel::hil::ble_advertising::RadioChannel;
+use kernel::power::{PowerConstraint, SleepMode};
 use nrf5x;
 use nrf5x::constants::TxPower;
 
@@ -596,6 +598,7 @@ pub struct Radio {
     tx_power: Cell<TxPower>,
     rx_client: Cell<Option<&'static ble_advertising::RxClient>>,
     tx_client: Cell<Option<&'static ble_advertising::TxClient>>,
+    power_constraint: PowerConstraint,
 }
 
 pub static mut RADIO: Radio = Radio::new();
@@ -607,6 +610,7 @@ impl Radio {
             tx_power: Cell::new(TxPower::ZerodBm),
             rx_client: Cell::new(None),
             tx_client: Cell::new(None),
+            power_constraint: PowerConstraint::new(SleepMode::Sleep),
         }
     }
 
@@ -634,6 +638,11 @@ impl Radio {
 
     fn radio_on(&self) {
         let regs = unsafe { &*self.regs };
+        // The radio needs the crystal, which is stopped in deep sleep
+        self.power_constraint.set_active(true);
+        unsafe {
+            clock::CLOCK.high_start_xtal();
+        }
         // reset and enable power
         regs.power.write(Task::ENABLE::CLEAR);
         regs.power.write(Task::ENABLE::SET);
@@ -642,6 +651,7 @@ impl Radio {
     fn radio_off(&self) {
         let regs = unsafe { &*self.regs };
         regs.power.write(Task::ENABLE::CLEAR);
+        self.power_constraint.set_active(false);
     }
 
     fn set_tx_power(&self) {
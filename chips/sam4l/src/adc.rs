@@ -23,6 +23,7 @@ use kernel::common::math;
 use kernel::common::regs::{ReadOnly, ReadWrite, WriteOnly};
 use kernel::common::take_cell::TakeCell;
 use kernel::hil;
+use kernel::power::{PowerConstraint, SleepMode};
 use pm::{self, Clock, PBAClock};
 use scif;
 
@@ -126,6 +127,9 @@ pub struct Adc {
 
     // ADC client to send sample complete notifications to
     client: Cell<Option<&'static EverythingClient>>,
+
+    // Sampling needs GCLK10 or the CPU clock, which stop in deep sleep
+    power_constraint: PowerConstraint,
 }
 
 /// Memory mapped registers for the ADC.
@@ -375,9 +379,17 @@ impl Adc {
 
             // higher layer to send responses to
             client: Cell::new(None),
+
+            power_constraint: PowerConstraint::new(SleepMode::Sleep),
         }
     }
 
+    /// Track whether a sampling operation is in progress.
+    fn set_active(&self, active: bool) {
+        self.active.set(active);
+        self.power_constraint.set_active(active);
+    }
+
     /// Sets the client for this driver.
     ///
     /// - `client`: reference to capsule which handles responses
@@ -418,7 +430,7 @@ impl Adc {
                         self.timer_counts.set(0);
                     } else {
                         // single sampling, disable interrupt and set inactive
-                        self.active.set(false);
+                        self.set_active(false);
                         regs.idr.write(Interrupt::SEOC::SET);
                     }
                 } else {
@@ -616,7 +628,7 @@ impl hil::adc::Adc for Adc {
             // only one operation at a time
             ReturnCode::EBUSY
         } else {
-            self.active.set(true);
+            self.set_active(true);
             self.continuous.set(false);
             self.timer_repeats.set(0);
             self.timer_counts.set(0);
@@ -669,7 +681,7 @@ impl hil::adc::Adc for Adc {
             // limit sampling frequencies to a valid range
             ReturnCode::EINVAL
         } else {
-            self.active.set(true);
+            self.set_active(true);
             self.continuous.set(true);
 
             // adc sequencer configuration
@@ -757,7 +769,7 @@ impl hil::adc::Adc for Adc {
             ReturnCode::EINVAL
         } else {
             // clean up state
-            self.active.set(false);
+            self.set_active(false);
             self.continuous.set(false);
             self.dma_running.set(false);
 
@@ -844,7 +856,7 @@ impl hil::adc::AdcHighSpeed for Adc {
             // samples. Otherwise, what are we doing here?
             (ReturnCode::EINVAL, Some(buffer1), Some(buffer2))
         } else {
-            self.active.set(true);
+            self.set_active(true);
             self.continuous.set(true);
 
             // store the second buffer for later use
@@ -1,6 +1,7 @@
 //! Implementation of the BPM peripheral.
 
 use kernel::common::regs::{ReadOnly, ReadWrite, WriteOnly};
+use kernel::power::SleepMode;
 
 #[repr(C)]
 struct BpmRegisters {
@@ -172,6 +173,25 @@ pub unsafe fn set_ck32source(source: CK32Source) {
         .modify(PowerModeControl::CK32S.val(source as u32));
 }
 
+/// Select what `wfi` stops for a kernel sleep mode.
+///
+/// `Sleep` maps to the SLEEP mode that only stops the CPU clock, and
+/// `DeepSleep` to WAIT mode, which the core enters when SLEEPDEEP is set and
+/// neither retention nor backup mode is selected. Everything except the 32kHz
+/// clocks is stopped in WAIT mode, but RAM and registers keep their contents.
+pub unsafe fn set_sleep_mode(mode: SleepMode) {
+    unlock_register(0x1c); // Control
+    match mode {
+        SleepMode::Sleep => (*BPM).pmcon.modify(
+            PowerModeControl::SLEEP::CpuStopped + PowerModeControl::RET::NoPowerSave
+                + PowerModeControl::BKUP::NoPowerSave,
+        ),
+        SleepMode::DeepSleep => (*BPM)
+            .pmcon
+            .modify(PowerModeControl::RET::NoPowerSave + PowerModeControl::BKUP::NoPowerSave),
+    }
+}
+
 unsafe fn unlock_register(register_offset: u32) {
     (*BPM)
         .unlock
@@ -9,9 +9,9 @@ use dac;
 use dma;
 use flashcalw;
 use gpio;
-use helpers::{DeferredCall, Task};
 use i2c;
 use kernel::Chip;
+use kernel::power::SleepMode;
 use pm;
 use spi;
 use trng;
@@ -57,6 +57,8 @@ impl Sam4l {
         adc::ADC0.set_dma(&dma::DMA_CHANNELS[13]);
         dma::DMA_CHANNELS[13].initialize(&mut adc::ADC0, dma::DMAWidth::Width16Bit);
 
+        flashcalw::FLASH_CONTROLLER.initialize_deferred_call();
+
         Sam4l {
             mpu: cortexm4::mpu::MPU::new(),
             systick: cortexm4::systick::SysTick::new(),
@@ -73,11 +75,7 @@ impl Chip for Sam4l {
 
         unsafe {
             loop {
-                if let Some(task) = DeferredCall::next_pending() {
-                    match task {
-                        Task::Flashcalw => flashcalw::FLASH_CONTROLLER.handle_interrupt(),
-                    }
-                } else if let Some(interrupt) = cortexm4::nvic::next_pending() {
+                if let Some(interrupt) = cortexm4::nvic::next_pending() {
                     match interrupt {
                         ASTALARM => ast::AST.handle_interrupt(),
 
@@ -150,7 +148,7 @@ impl Chip for Sam4l {
     }
 
     fn has_pending_interrupts(&self) -> bool {
-        unsafe { cortexm4::nvic::has_pending() || DeferredCall::has_tasks() }
+        unsafe { cortexm4::nvic::has_pending() }
     }
 
     fn mpu(&self) -> &cortexm4::mpu::MPU {
@@ -161,8 +159,8 @@ impl Chip for Sam4l {
         &self.systick
     }
 
-    fn prepare_for_sleep(&self) {
-        if pm::deep_sleep_ready() {
+    fn prepare_for_sleep(&self, mode: SleepMode) {
+        if pm::prepare_for_sleep(mode) == SleepMode::DeepSleep {
             unsafe {
                 cortexm4::scb::set_sleepdeep();
             }
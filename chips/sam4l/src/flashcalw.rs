@@ -15,6 +15,20 @@
 //! The driver should be `configure()`'d before use, and a Client should be set
 //! to enable a callback after a command is completed.
 //!
+//! Boards can write protect flash with the hardware lock bits by calling
+//! `protect_pages()`, for example for the kernel image:
+//!
+//! ```
+//! extern "C" {
+//!     static _sapps: u8;
+//! }
+//! let kernel_pages = (&_sapps as *const u8 as u32) / 512;
+//! sam4l::flashcalw::FLASH_CONTROLLER.protect_pages(0, kernel_pages);
+//! sam4l::flashcalw::FLASH_CONTROLLER.configure();
+//! ```
+//!
+//! Writes and erases of protected pages fail with `WriteProtected`.
+//!
 //! Almost all of the flash controller functionality is implemented (except for
 //! general purpose fuse bits, and more granular control of the cache).
 //!
@@ -23,8 +37,8 @@
 
 use core::cell::Cell;
 use core::ops::{Index, IndexMut};
-use helpers::{DeferredCall, Task};
 use kernel::ReturnCode;
+use kernel::common::deferred_call::{DeferredCall, DeferredCallClient};
 use kernel::common::regs::{ReadOnly, ReadWrite, WriteOnly};
 use kernel::common::take_cell::TakeCell;
 use kernel::hil;
@@ -315,8 +329,6 @@ enum RegKey {
     GPFRLO,
 }
 
-static DEFERRED_CALL: DeferredCall = unsafe { DeferredCall::new(Task::Flashcalw) };
-
 /// There are 18 recognized commands for the flash. These are "bare-bones"
 /// commands and values that are written to the Flash's command register to
 /// inform the flash what to do. Table 14-5.
@@ -352,14 +364,28 @@ pub enum Speed {
 /// FlashState is used to track the current state and command of the flash.
 #[derive(Clone, Copy, PartialEq)]
 pub enum FlashState {
-    Unconfigured,                 //                 Flash is unconfigured, call configure().
-    Ready,                        //                        Flash is ready to complete a command.
-    Read,                         //                         Performing a read operation.
-    WriteUnlocking { page: i32 }, // Started a write operation.
-    WriteErasing { page: i32 },   //   Waiting on the page to erase.
-    WriteWriting,                 //                 Waiting on the page to actually be written.
-    EraseUnlocking { page: i32 }, // Started an erase operation.
-    EraseErasing,                 //                 Waiting on the erase to finish.
+    /// Flash is unconfigured, call configure().
+    Unconfigured,
+    /// Flash is ready to complete a command.
+    Ready,
+    /// Performing a read operation.
+    Read,
+    /// Started a write operation.
+    WriteUnlocking { page: i32 },
+    /// Started a write of part of a page, which does not erase the page.
+    PartialWriteUnlocking {
+        page: i32,
+        offset: usize,
+        length: usize,
+    },
+    /// Waiting on the page to erase.
+    WriteErasing { page: i32 },
+    /// Waiting on the page to actually be written.
+    WriteWriting,
+    /// Started erasing the pages from `page` to `last`.
+    EraseUnlocking { page: i32, last: i32 },
+    /// Waiting on the erase of `page` to finish.
+    EraseErasing { page: i32, last: i32 },
 }
 
 /// This is a wrapper around a u8 array that is sized to a single page for the
@@ -412,6 +438,9 @@ pub struct FLASHCALW {
     client: Cell<Option<&'static hil::flash::Client<FLASHCALW>>>,
     current_state: Cell<FlashState>,
     buffer: TakeCell<'static, Sam4lPage>,
+    /// Bitmask of lock regions that must stay locked.
+    protected_regions: Cell<u16>,
+    deferred_call: DeferredCall,
 }
 
 // static instance for the board. Only one FLASHCALW on chip.
@@ -458,9 +487,17 @@ impl FLASHCALW {
             client: Cell::new(None),
             current_state: Cell::new(FlashState::Unconfigured),
             buffer: TakeCell::empty(),
+            protected_regions: Cell::new(0),
+            deferred_call: DeferredCall::new(),
         }
     }
 
+    /// Register for deferred calls, which are used to signal the completion
+    /// of reads. Must be called before the flash is used.
+    pub fn initialize_deferred_call(&'static self) {
+        self.deferred_call.register(self);
+    }
+
     /// Cache controlling functionality.
 
     //  Flush the cache. Should be called after every write!
@@ -504,7 +541,7 @@ impl FLASHCALW {
         // either completed or failed at this point.
 
         // Check for errors and report to Client if there are any
-        if self.is_error() {
+        if let Some(error) = self.get_error() {
             let attempted_operation = self.current_state.get();
 
             // Reset state now that we are ready to do a new operation.
@@ -513,18 +550,19 @@ impl FLASHCALW {
             self.client.get().map(|client| match attempted_operation {
                 FlashState::Read => {
                     self.buffer.take().map(|buffer| {
-                        client.read_complete(buffer, hil::flash::Error::FlashError);
+                        client.read_complete(buffer, error);
                     });
                 }
                 FlashState::WriteUnlocking { .. }
+                | FlashState::PartialWriteUnlocking { .. }
                 | FlashState::WriteErasing { .. }
                 | FlashState::WriteWriting => {
                     self.buffer.take().map(|buffer| {
-                        client.write_complete(buffer, hil::flash::Error::FlashError);
+                        client.write_complete(buffer, error);
                     });
                 }
-                FlashState::EraseUnlocking { .. } | FlashState::EraseErasing => {
-                    client.erase_complete(hil::flash::Error::FlashError);
+                FlashState::EraseUnlocking { .. } | FlashState::EraseErasing { .. } => {
+                    client.erase_complete(error);
                 }
                 _ => {}
             });
@@ -552,7 +590,24 @@ impl FLASHCALW {
                 //  I'm combining these with an actual command, write_page,
                 //  which generates and interrupt and saves the page.
                 self.clear_page_buffer();
-                self.write_to_page_buffer(page as usize * PAGE_SIZE as usize);
+                self.write_to_page_buffer(
+                    page as usize * PAGE_SIZE as usize,
+                    0,
+                    PAGE_SIZE as usize,
+                );
+
+                self.current_state.set(FlashState::WriteWriting);
+                self.flashcalw_write_page(page);
+            }
+            FlashState::PartialWriteUnlocking {
+                page,
+                offset,
+                length,
+            } => {
+                // The cleared page buffer is all ones, so writing the page
+                // only clears bits in the range copied into it.
+                self.clear_page_buffer();
+                self.write_to_page_buffer(page as usize * PAGE_SIZE as usize, offset, length);
 
                 self.current_state.set(FlashState::WriteWriting);
                 self.flashcalw_write_page(page);
@@ -569,11 +624,20 @@ impl FLASHCALW {
                     });
                 });
             }
-            FlashState::EraseUnlocking { page } => {
-                self.current_state.set(FlashState::EraseErasing);
+            FlashState::EraseUnlocking { page, last } => {
+                self.current_state
+                    .set(FlashState::EraseErasing { page: page, last: last });
                 self.flashcalw_erase_page(page);
             }
-            FlashState::EraseErasing => {
+            FlashState::EraseErasing { page, last } if page < last => {
+                // Move on to the next page.
+                self.current_state.set(FlashState::EraseUnlocking {
+                    page: page + 1,
+                    last: last,
+                });
+                self.unlock_page_region(page + 1);
+            }
+            FlashState::EraseErasing { .. } => {
                 self.current_state.set(FlashState::Ready);
 
                 self.client.get().map(|client| {
@@ -709,10 +773,20 @@ impl FLASHCALW {
         regs.fsr.is_set(FlashStatus::FRDY)
     }
 
-    fn is_error(&self) -> bool {
+    /// The error to report to the client, if the last command failed. The
+    /// error flags are cleared when FSR is read, so it is only read once.
+    fn get_error(&self) -> Option<hil::flash::Error> {
         let regs: &FlashcalwRegisters = unsafe { &*self.registers };
         pm::enable_clock(self.pb_clock);
-        regs.fsr.is_set(FlashStatus::LOCKE) | regs.fsr.is_set(FlashStatus::PROGE)
+        let status = regs.fsr.get();
+        if status & FlashStatus::LOCKE::SET.mask() != 0 {
+            // Tried to modify a locked region.
+            Some(hil::flash::Error::WriteProtected)
+        } else if status & FlashStatus::PROGE::SET.mask() != 0 {
+            Some(hil::flash::Error::FlashError)
+        } else {
+            None
+        }
     }
 
     /// Flashcalw command control
@@ -783,6 +857,67 @@ impl FLASHCALW {
         }
     }
 
+    fn is_page_protected(&self, page_number: i32) -> bool {
+        self.protected_regions.get() & (1 << self.get_page_region(page_number)) != 0
+    }
+
+    /// Unlock the region of a page before it is written or erased. Protected
+    /// regions are locked again instead. That still generates the interrupt
+    /// that moves on to the next step, and the write or erase then fails with
+    /// a lock error.
+    fn unlock_page_region(&self, page_number: i32) {
+        let protected = self.is_page_protected(page_number);
+        self.lock_page_region(page_number, protected);
+    }
+
+    /// Set the lock bits of all protected regions. This spins until each
+    /// lock command is done, so the ready interrupt stays masked. Otherwise
+    /// it would fire later and look like the end of the next command.
+    fn lock_protected_regions(&self) {
+        let regs: &FlashcalwRegisters = unsafe { &*self.registers };
+        pm::enable_clock(self.pb_clock);
+        regs.fcr.modify(FlashControl::FRDY::CLEAR);
+        for region in 0..NB_OF_REGIONS {
+            if self.protected_regions.get() & (1 << region) != 0 {
+                regs.fcmd.write(
+                    FlashCommand::KEY.val(0xA5) + FlashCommand::CMD::LP
+                        + FlashCommand::PAGEN.val(self.get_region_first_page_number(region)),
+                );
+                while !regs.fsr.is_set(FlashStatus::FRDY) {}
+            }
+        }
+    }
+
+    /// Write protect the lock regions covering `count` pages starting at
+    /// `first_page`, for example the kernel image. The lock bits are set
+    /// as soon as the flash is configured, and this driver never unlocks
+    /// these regions again. Lock regions are larger than pages, so
+    /// neighbouring pages in the same region are protected as well.
+    pub fn protect_pages(&self, first_page: u32, count: u32) -> ReturnCode {
+        pm::enable_clock(self.pb_clock);
+        if count == 0
+            || first_page
+                .checked_add(count)
+                .map_or(true, |end| end > self.get_page_count())
+        {
+            return ReturnCode::EINVAL;
+        }
+
+        let first_region = self.get_page_region(first_page as i32);
+        let last_region = self.get_page_region((first_page + count - 1) as i32);
+        for region in first_region..last_region + 1 {
+            self.protected_regions
+                .set(self.protected_regions.get() | (1 << region));
+        }
+
+        // Locking generates an interrupt, so only do it now if no command is
+        // in progress. Otherwise `configure()` locks them.
+        if self.current_state.get() == FlashState::Ready {
+            self.lock_protected_regions();
+        }
+        ReturnCode::SUCCESS
+    }
+
     /// Flashcalw Access to Flash Pages
     fn clear_page_buffer(&self) {
         self.issue_command(FlashCMD::CPB, -1);
@@ -825,9 +960,10 @@ impl FLASHCALW {
     }
 
     // Instead of having several memset/memcpy functions as Atmel's ASF
-    // implementation will only have one to write to the page buffer.
-    fn write_to_page_buffer(&self, pg_buff_addr: usize) {
-        let mut page_buffer: *mut u8 = pg_buff_addr as *mut u8;
+    // implementation will only have one to write to the page buffer. Copies
+    // `length` bytes starting at `offset` into the page.
+    fn write_to_page_buffer(&self, pg_buff_addr: usize, offset: usize, length: usize) {
+        let mut page_buffer: *mut u8 = (pg_buff_addr + offset) as *mut u8;
 
         // Errata 45.1.7 - Need to write a 64-bit all one word for every write
         // to the page buffer.
@@ -838,9 +974,9 @@ impl FLASHCALW {
             unsafe {
                 use core::ptr;
 
-                let mut start_buffer: *const u8 = &buffer[0] as *const u8;
-                let mut data_transfered: u32 = 0;
-                while data_transfered < PAGE_SIZE {
+                let mut start_buffer: *const u8 = &buffer[offset] as *const u8;
+                let mut data_transfered: usize = 0;
+                while data_transfered < length {
                     // errata copy..
                     ptr::copy(clr_ptr, page_buffer, 8);
 
@@ -882,6 +1018,8 @@ impl FLASHCALW {
         // here. So if the bootloader changes, nothing breaks.
         self.enable_picocache(true);
 
+        self.lock_protected_regions();
+
         self.current_state.set(FlashState::Ready);
     }
 
@@ -901,7 +1039,7 @@ impl FLASHCALW {
         address: usize,
         size: usize,
         buffer: &'static mut Sam4lPage,
-    ) -> ReturnCode {
+    ) -> (ReturnCode, Option<&'static mut Sam4lPage>) {
         // Enable clock in case it's off.
         pm::enable_clock(self.ahb_clock);
 
@@ -911,7 +1049,7 @@ impl FLASHCALW {
             || buffer.len() < size
         {
             // invalid flash address
-            return ReturnCode::EINVAL;
+            return (ReturnCode::EINVAL, Some(buffer));
         }
 
         // Actually do a copy from flash into the buffer.
@@ -930,18 +1068,22 @@ impl FLASHCALW {
         // This is kind of strange, but because read() in this case is
         // synchronous, we still need to schedule as if we had an interrupt so
         // we can allow this function to return and then call the callback.
-        DEFERRED_CALL.set();
+        self.deferred_call.set();
 
-        ReturnCode::SUCCESS
+        (ReturnCode::SUCCESS, None)
     }
 
-    pub fn write_page(&self, page_num: i32, data: &'static mut Sam4lPage) -> ReturnCode {
+    pub fn write_page(
+        &self,
+        page_num: i32,
+        data: &'static mut Sam4lPage,
+    ) -> (ReturnCode, Option<&'static mut Sam4lPage>) {
         // Enable clock in case it's off.
         pm::enable_clock(self.ahb_clock);
 
         // If we're not ready don't take the command.
         if self.current_state.get() != FlashState::Ready {
-            return ReturnCode::EBUSY;
+            return (ReturnCode::EBUSY, Some(data));
         }
 
         // Save the buffer for the future write.
@@ -949,24 +1091,81 @@ impl FLASHCALW {
 
         self.current_state
             .set(FlashState::WriteUnlocking { page: page_num });
-        self.lock_page_region(page_num, false);
-        ReturnCode::SUCCESS
+        self.unlock_page_region(page_num);
+        (ReturnCode::SUCCESS, None)
+    }
+
+    /// Program `length` bytes at `offset` within the page without erasing
+    /// it. Both must be multiples of the 8 byte double words the page buffer
+    /// is written in.
+    pub fn write_partial(
+        &self,
+        page_num: i32,
+        offset: usize,
+        length: usize,
+        data: &'static mut Sam4lPage,
+    ) -> (ReturnCode, Option<&'static mut Sam4lPage>) {
+        // Enable clock in case it's off.
+        pm::enable_clock(self.ahb_clock);
+
+        // If we're not ready don't take the command.
+        if self.current_state.get() != FlashState::Ready {
+            return (ReturnCode::EBUSY, Some(data));
+        }
+        if length == 0 || offset % 8 != 0 || length % 8 != 0
+            || offset + length > PAGE_SIZE as usize
+        {
+            return (ReturnCode::EINVAL, Some(data));
+        }
+
+        // Save the buffer for the future write.
+        self.buffer.replace(data);
+
+        self.current_state.set(FlashState::PartialWriteUnlocking {
+            page: page_num,
+            offset: offset,
+            length: length,
+        });
+        self.unlock_page_region(page_num);
+        (ReturnCode::SUCCESS, None)
     }
 
     pub fn erase_page(&self, page_num: i32) -> ReturnCode {
+        self.erase_pages(page_num, 1)
+    }
+
+    /// Erase `count` pages starting at `page_num`, with one callback once all
+    /// of them are erased. Returns `EINVAL` if the range is empty or does not
+    /// fit in the flash.
+    pub fn erase_pages(&self, page_num: i32, count: i32) -> ReturnCode {
         // Enable AHB clock (in case it was off).
         pm::enable_clock(self.ahb_clock);
         if self.current_state.get() != FlashState::Ready {
             return ReturnCode::EBUSY;
         }
+        if page_num < 0 || count <= 0
+            || page_num
+                .checked_add(count)
+                .map_or(true, |end| end as u32 > self.get_number_pages())
+        {
+            return ReturnCode::EINVAL;
+        }
 
-        self.current_state
-            .set(FlashState::EraseUnlocking { page: page_num });
-        self.lock_page_region(page_num, false);
+        self.current_state.set(FlashState::EraseUnlocking {
+            page: page_num,
+            last: page_num + count - 1,
+        });
+        self.unlock_page_region(page_num);
         ReturnCode::SUCCESS
     }
 }
 
+impl DeferredCallClient for FLASHCALW {
+    fn handle_deferred_call(&self) {
+        self.handle_interrupt();
+    }
+}
+
 impl<C: hil::flash::Client<Self>> hil::flash::HasClient<'static, C> for FLASHCALW {
     fn set_client(&self, client: &'static C) {
         self.client.set(Some(client));
@@ -976,15 +1175,41 @@ impl<C: hil::flash::Client<Self>> hil::flash::HasClient<'static, C> for FLASHCAL
 impl hil::flash::Flash for FLASHCALW {
     type Page = Sam4lPage;
 
-    fn read_page(&self, page_number: usize, buf: &'static mut Self::Page) -> ReturnCode {
+    fn read_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> (ReturnCode, Option<&'static mut Self::Page>) {
         self.read_range(page_number * (PAGE_SIZE as usize), buf.len(), buf)
     }
 
-    fn write_page(&self, page_number: usize, buf: &'static mut Self::Page) -> ReturnCode {
+    fn write_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> (ReturnCode, Option<&'static mut Self::Page>) {
         self.write_page(page_number as i32, buf)
     }
 
     fn erase_page(&self, page_number: usize) -> ReturnCode {
         self.erase_page(page_number as i32)
     }
+
+    fn write_granularity(&self) -> Option<usize> {
+        Some(8)
+    }
+
+    fn write_partial(
+        &self,
+        page_number: usize,
+        offset: usize,
+        length: usize,
+        buf: &'static mut Self::Page,
+    ) -> (ReturnCode, Option<&'static mut Self::Page>) {
+        self.write_partial(page_number as i32, offset, length, buf)
+    }
+
+    fn erase_pages(&self, page_number: usize, count: usize) -> ReturnCode {
+        self.erase_pages(page_number as i32, count as i32)
+    }
 }
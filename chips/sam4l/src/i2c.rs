@@ -16,6 +16,7 @@ use kernel::common::VolatileCell;
 use kernel::common::peripherals::{PeripheralManagement, PeripheralManager};
 use kernel::common::take_cell::TakeCell;
 use kernel::hil;
+use kernel::power::{PowerConstraint, SleepMode};
 use pm;
 
 // Listing of all registers related to the TWIM peripheral.
@@ -171,6 +172,10 @@ pub struct I2CHw {
     slave_write_buffer: TakeCell<'static, [u8]>,
     slave_write_buffer_len: Cell<u8>,
     slave_write_buffer_index: Cell<u8>,
+
+    // Master transfers need the TWIM clock, which is stopped in deep sleep.
+    // The slave can be woken up by the bus, so it does not constrain sleep.
+    master_power_constraint: PowerConstraint,
 }
 
 impl PeripheralManagement<TWIMClock> for I2CHw {
@@ -196,6 +201,10 @@ impl PeripheralManagement<TWIMClock> for I2CHw {
             clock.disable();
         }
     }
+
+    fn get_power_constraint(&self) -> Option<&PowerConstraint> {
+        Some(&self.master_power_constraint)
+    }
 }
 type TWIMRegisterManager<'a> = PeripheralManager<'a, I2CHw, TWIMClock>;
 
@@ -302,6 +311,8 @@ impl I2CHw {
             slave_write_buffer: TakeCell::empty(),
             slave_write_buffer_len: Cell::new(0),
             slave_write_buffer_index: Cell::new(0),
+
+            master_power_constraint: PowerConstraint::new(SleepMode::Sleep),
         }
     }
 
@@ -5,7 +5,7 @@
 #![crate_name = "sam4l"]
 #![crate_type = "rlib"]
 #![feature(attr_literals, const_cell_new)]
-#![feature(const_atomic_usize_new, const_ptr_null_mut, integer_atomics, try_from)]
+#![feature(const_atomic_usize_new, const_ptr_null_mut, integer_atomics)]
 #![feature(asm, core_intrinsics, concat_idents, const_fn)]
 #![no_std]
 
@@ -14,9 +14,6 @@ extern crate cortexm4;
 #[macro_use(debug, debug_gpio, static_init, register_bitfields, register_bitmasks)]
 extern crate kernel;
 
-#[macro_use]
-mod helpers;
-
 pub mod chip;
 pub mod ast;
 pub mod bpm;
@@ -37,6 +34,7 @@ pub mod crccu;
 pub mod dac;
 pub mod aes;
 pub mod usbc;
+pub mod serial_num;
 
 use cortexm4::{generic_isr, svc_handler, systick_handler};
 
@@ -8,6 +8,7 @@ use flashcalw;
 use gpio;
 use kernel::{ClockInterface, StaticRef};
 use kernel::common::regs::{FieldValue, ReadOnly, ReadWrite, WriteOnly};
+use kernel::power::SleepMode;
 use scif;
 
 /// §10.7 PM::UserInterface from SAM4L Datasheet.
@@ -770,6 +771,24 @@ pub fn deep_sleep_ready() -> bool {
     hsb && pba && pbb && gpio
 }
 
+/// Configure the BPM for the sleep mode the kernel permits, and return the
+/// mode the chip will actually enter.
+///
+/// The kernel only knows about peripherals that report a `PowerConstraint`,
+/// so deep sleep is only entered if `deep_sleep_ready()` agrees as well. The
+/// caller sets SLEEPDEEP in the core if this returns `DeepSleep`.
+pub fn prepare_for_sleep(mode: SleepMode) -> SleepMode {
+    let mode = if mode == SleepMode::DeepSleep && deep_sleep_ready() {
+        SleepMode::DeepSleep
+    } else {
+        SleepMode::Sleep
+    };
+    unsafe {
+        bpm::set_sleep_mode(mode);
+    }
+    mode
+}
+
 impl ClockInterface for Clock {
     fn is_enabled(&self) -> bool {
         match self {
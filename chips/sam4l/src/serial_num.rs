@@ -0,0 +1,21 @@
+//! Unique serial number of the SAM4L
+//!
+//! Every SAM4L has a 120-bit serial number in the factory-programmed area of
+//! the flash, which can identify a board, for example as the serial number of
+//! its USB device.
+
+use core::ptr;
+
+/// Address of the serial number, following the user page
+const SERIAL_NUMBER_ADDRESS: usize = 0x0080020C;
+
+/// Length of the serial number in bytes
+pub const SERIAL_NUMBER_LENGTH: usize = 15;
+
+pub fn serial_number() -> [u8; SERIAL_NUMBER_LENGTH] {
+    let mut serial = [0; SERIAL_NUMBER_LENGTH];
+    for (i, byte) in serial.iter_mut().enumerate() {
+        *byte = unsafe { ptr::read_volatile((SERIAL_NUMBER_ADDRESS + i) as *const u8) };
+    }
+    serial
+}
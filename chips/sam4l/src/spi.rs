@@ -13,6 +13,7 @@ use dma::DMAClient;
 use dma::DMAPeripheral;
 use kernel::{ClockInterface, ReturnCode, StaticRef};
 use kernel::common::peripherals::{PeripheralManagement, PeripheralManager};
+use kernel::power::{PowerConstraint, SleepMode};
 use kernel::common::regs::{self, ReadOnly, ReadWrite, WriteOnly};
 use kernel::hil::spi;
 use kernel::hil::spi::ClockPhase;
@@ -191,6 +192,9 @@ pub struct SpiHw {
     // Slave client is distinct from master client
     slave_client: Cell<Option<&'static SpiSlaveClient>>,
     role: Cell<SpiRole>,
+
+    // The SPI clock is stopped in deep sleep, so transfers prevent it
+    power_constraint: PowerConstraint,
 }
 
 const SPI_BASE: StaticRef<SpiRegisters> =
@@ -216,6 +220,10 @@ impl PeripheralManagement<pm::Clock> for SpiHw {
             clock.disable();
         }
     }
+
+    fn get_power_constraint(&self) -> Option<&PowerConstraint> {
+        Some(&self.power_constraint)
+    }
 }
 
 type SpiRegisterManager<'a> = PeripheralManager<'a, SpiHw, pm::Clock>;
@@ -234,6 +242,8 @@ impl SpiHw {
 
             slave_client: Cell::new(None),
             role: Cell::new(SpiRole::SpiMaster),
+
+            power_constraint: PowerConstraint::new(SleepMode::Sleep),
         }
     }
 
@@ -10,6 +10,7 @@ use kernel::ReturnCode;
 use kernel::common::regs::{ReadOnly, ReadWrite, WriteOnly};
 // other modules
 use kernel::hil;
+use kernel::power::{PowerConstraint, SleepMode};
 // local modules
 use pm;
 
@@ -280,6 +281,7 @@ const USART_BASE_ADDRS: [*mut UsartRegisters; 4] = [
 pub struct USARTRegManager<'a> {
     registers: &'a UsartRegisters,
     clock: pm::Clock,
+    power_constraint: &'a PowerConstraint,
     rx_dma: Option<&'static dma::DMAChannel>,
     tx_dma: Option<&'static dma::DMAChannel>,
 }
@@ -295,6 +297,7 @@ impl<'a> USARTRegManager<'a> {
         USARTRegManager {
             registers: regs,
             clock: usart.clock,
+            power_constraint: &usart.power_constraint,
             rx_dma: usart.rx_dma.get(),
             tx_dma: usart.tx_dma.get(),
         }
@@ -330,6 +333,7 @@ impl<'a> Drop for USARTRegManager<'a> {
         if !(rx_active || tx_active || ints_active || is_panic) {
             pm::disable_clock(self.clock);
         }
+        self.power_constraint.set_active(pm::is_clock_enabled(self.clock));
     }
 }
 
@@ -380,6 +384,9 @@ pub struct USART {
     client: Cell<Option<UsartClient<'static>>>,
 
     spi_chip_select: Cell<Option<&'static hil::gpio::Pin>>,
+
+    // The USART clock is stopped in deep sleep, so transfers prevent it
+    power_constraint: PowerConstraint,
 }
 
 // USART hardware peripherals on SAM4L
@@ -437,6 +444,8 @@ impl USART {
 
             // This is only used if the USART is in SPI mode.
             spi_chip_select: Cell::new(None),
+
+            power_constraint: PowerConstraint::new(SleepMode::Sleep),
         }
     }
 
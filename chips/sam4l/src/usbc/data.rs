@@ -3,6 +3,7 @@
 use core::fmt;
 use core::ptr;
 use kernel::common::VolatileCell;
+use kernel::hil::usb::TransferType;
 use usbc::common::register::*;
 
 #[derive(Copy, Clone, PartialEq, Debug)]
@@ -47,6 +48,40 @@ impl ToWord for Mode {
     }
 }
 
+/// State of an endpoint other than the control endpoint
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum EndpointState {
+    Disabled,
+    In {
+        config: EndpointConfig,
+        transfer_type: TransferType,
+        state: InState,
+    },
+    Out {
+        config: EndpointConfig,
+        transfer_type: TransferType,
+        state: OutState,
+    },
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum InState {
+    /// Waiting for the bank to be free
+    Init,
+    /// A packet is in the bank, waiting for the host to take it
+    Sending,
+    /// The client has nothing to send
+    Delay,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum OutState {
+    /// Waiting for a packet from the host
+    Init,
+    /// The client cannot take the received packet yet
+    Delay,
+}
+
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum Speed {
     Full,
@@ -250,6 +285,23 @@ pub enum EndpointSize {
     Bytes1024,
 }
 
+impl EndpointSize {
+    /// The endpoint size for a buffer of `bytes` bytes, if there is one
+    pub fn from_bytes(bytes: usize) -> Option<EndpointSize> {
+        match bytes {
+            8 => Some(EndpointSize::Bytes8),
+            16 => Some(EndpointSize::Bytes16),
+            32 => Some(EndpointSize::Bytes32),
+            64 => Some(EndpointSize::Bytes64),
+            128 => Some(EndpointSize::Bytes128),
+            256 => Some(EndpointSize::Bytes256),
+            512 => Some(EndpointSize::Bytes512),
+            1024 => Some(EndpointSize::Bytes1024),
+            _ => None,
+        }
+    }
+}
+
 pub enum EndpointDirection {
     Out,
     In,
@@ -262,6 +314,17 @@ pub enum EndpointType {
     Interrupt,
 }
 
+impl From<TransferType> for EndpointType {
+    fn from(transfer_type: TransferType) -> EndpointType {
+        match transfer_type {
+            TransferType::Control => EndpointType::Control,
+            TransferType::Isochronous => EndpointType::Isochronous,
+            TransferType::Bulk => EndpointType::Bulk,
+            TransferType::Interrupt => EndpointType::Interrupt,
+        }
+    }
+}
+
 pub struct EndpointIndex(u32);
 
 impl EndpointIndex {
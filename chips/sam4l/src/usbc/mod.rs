@@ -16,6 +16,7 @@ use kernel::common::VolatileCell;
 use kernel::common::take_cell::MapCell;
 use kernel::hil;
 use kernel::hil::usb::*;
+use kernel::power::{PowerConstraint, SleepMode};
 use pm;
 use pm::{disable_clock, enable_clock, Clock, HSBClock, PBBClock};
 use scif;
@@ -26,15 +27,25 @@ macro_rules! client_err {
     };
 }
 
+/// Number of endpoints of the controller, including the control endpoint
+const N_ENDPOINTS: usize = 8;
+
 /// State for managing the USB controller
 // This ensures the `descriptors` field is laid out first
 #[repr(C)]
 // This provides the required alignment for the `descriptors` field
 #[repr(align(8))]
 pub struct Usbc<'a> {
-    descriptors: [Endpoint; 8],
+    descriptors: [Endpoint; N_ENDPOINTS],
     client: Option<&'a hil::usb::Client>,
     state: MapCell<State>,
+    /// Configuration and state of the bulk and interrupt endpoints
+    endpoints: [Cell<EndpointState>; N_ENDPOINTS],
+    /// Size of the buffer of each endpoint
+    buffer_sizes: [Cell<usize>; N_ENDPOINTS],
+    /// The generic clock of the USBC stops in deep sleep, so an enabled
+    /// controller prevents it
+    power_constraint: PowerConstraint,
 }
 
 #[derive(Default)]
@@ -58,10 +69,21 @@ impl<'a> UsbController for Usbc<'a> {
         e.set(Some(EP { index: index }));
     }
 
+    fn endpoint_count(&self) -> u32 {
+        N_ENDPOINTS as u32
+    }
+
     fn endpoint_set_buffer<'b>(&'b self, e: u32, buf: &[VolatileCell<u8>]) {
-        if buf.len() != 8 {
+        // The control endpoint only handles 8 byte packets
+        let valid = if e == 0 {
+            buf.len() == 8
+        } else {
+            EndpointSize::from_bytes(buf.len()).is_some()
+        };
+        if !valid || e as usize >= N_ENDPOINTS {
             panic!("Bad endpoint buffer size");
         }
+        self.buffer_sizes[e as usize].set(buf.len());
         self.endpoint_bank_set_buffer(EndpointIndex::new(e), BankIndex::Bank0, buf);
     }
 
@@ -76,6 +98,80 @@ impl<'a> UsbController for Usbc<'a> {
         self.endpoint_enable(e, cfg);
     }
 
+    fn endpoint_in_enable(&self, transfer_type: TransferType, e: u32) {
+        let cfg = self.bulk_endpoint_config(transfer_type, EndpointDirection::In, e);
+        self.endpoints[e as usize].set(EndpointState::In {
+            config: cfg,
+            transfer_type: transfer_type,
+            state: InState::Init,
+        });
+        self.endpoint_enable(e, cfg);
+    }
+
+    fn endpoint_out_enable(&self, transfer_type: TransferType, e: u32) {
+        let cfg = self.bulk_endpoint_config(transfer_type, EndpointDirection::Out, e);
+        self.endpoints[e as usize].set(EndpointState::Out {
+            config: cfg,
+            transfer_type: transfer_type,
+            state: OutState::Init,
+        });
+        self.endpoint_enable(e, cfg);
+    }
+
+    fn endpoint_resume_in(&self, e: u32) {
+        let endpoint = e as usize;
+        if let EndpointState::In {
+            config,
+            transfer_type,
+            state: InState::Delay,
+        } = self.endpoints[endpoint].get()
+        {
+            self.endpoints[endpoint].set(EndpointState::In {
+                config: config,
+                transfer_type: transfer_type,
+                state: InState::Init,
+            });
+
+            // Ask the client for the packet as soon as the bank is free
+            endpoint_enable_interrupts(endpoint, TXIN);
+        }
+    }
+
+    fn endpoint_resume_out(&self, e: u32) {
+        let endpoint = e as usize;
+        if let EndpointState::Out {
+            config,
+            transfer_type,
+            state: OutState::Delay,
+        } = self.endpoints[endpoint].get()
+        {
+            self.endpoints[endpoint].set(EndpointState::Out {
+                config: config,
+                transfer_type: transfer_type,
+                state: OutState::Init,
+            });
+
+            // The packet is still in the bank, so this interrupts right away
+            endpoint_enable_interrupts(endpoint, RXOUT);
+        }
+    }
+
+    fn endpoint_set_stall(&self, e: u32) {
+        UECONnSET[e as usize].write(STALLRQ);
+    }
+
+    fn endpoint_clear_stall(&self, e: u32) {
+        UECONnCLR[e as usize].write(STALLRQ);
+
+        // The next packet after a halt starts with DATA0
+        UECONnSET[e as usize].write(RSTDT);
+
+        // A client error leaves a stalled IN endpoint delayed with TXIN off.
+        // The host has cleared the halt, so resume the endpoint either way
+        self.endpoint_resume_in(e);
+        self.endpoint_resume_out(e);
+    }
+
     fn set_address(&self, addr: u16) {
         // The hardware can do only 7-bit addresses
         let addr = (addr as u8) & 0b1111111;
@@ -108,9 +204,49 @@ impl<'a> Usbc<'a> {
                 new_endpoint(),
                 new_endpoint(),
             ],
+            endpoints: [
+                Cell::new(EndpointState::Disabled),
+                Cell::new(EndpointState::Disabled),
+                Cell::new(EndpointState::Disabled),
+                Cell::new(EndpointState::Disabled),
+                Cell::new(EndpointState::Disabled),
+                Cell::new(EndpointState::Disabled),
+                Cell::new(EndpointState::Disabled),
+                Cell::new(EndpointState::Disabled),
+            ],
+            buffer_sizes: [
+                Cell::new(0),
+                Cell::new(0),
+                Cell::new(0),
+                Cell::new(0),
+                Cell::new(0),
+                Cell::new(0),
+                Cell::new(0),
+                Cell::new(0),
+            ],
+            power_constraint: PowerConstraint::new(SleepMode::Sleep),
         }
     }
 
+    /// Configuration of a bulk or interrupt endpoint, with packets as large
+    /// as its buffer
+    fn bulk_endpoint_config(
+        &self,
+        transfer_type: TransferType,
+        dir: EndpointDirection,
+        e: u32,
+    ) -> EndpointConfig {
+        let size = EndpointSize::from_bytes(self.buffer_sizes[e as usize].get())
+            .unwrap_or_else(|| panic!("No buffer for endpoint {}", e));
+        EndpointConfig::new(
+            BankCount::Single,
+            size,
+            dir,
+            EndpointType::from(transfer_type),
+            EndpointIndex::new(e),
+        )
+    }
+
     /// Attach to the USB bus after enabling USB clock
     fn _attach(&self) {
         self.state.map(|state| {
@@ -174,6 +310,7 @@ impl<'a> Usbc<'a> {
                     //   be activated" (17.6.2)
                     enable_clock(Clock::HSB(HSBClock::USBC));
                     enable_clock(Clock::PBB(PBBClock::USBC));
+                    self.power_constraint.set_active(true);
 
                     // If we got to this state via disable() instead of chip reset,
                     // the values USBCON.FRZCLK, USBCON.UIMOD, UDCON.LS have *not* been
@@ -229,6 +366,7 @@ impl<'a> Usbc<'a> {
 
                 disable_clock(Clock::PBB(PBBClock::USBC));
                 disable_clock(Clock::HSB(HSBClock::USBC));
+                self.power_constraint.set_active(false);
 
                 *state = State::Reset;
             }
@@ -255,16 +393,21 @@ impl<'a> Usbc<'a> {
     /// (XX: include addr and packetsize?)
     pub fn endpoint_enable(&self, endpoint: u32, cfg: EndpointConfig) {
         self.state.map(|state| {
-            // Record config in case of later reset
+            // Record config in case of later reset (the other endpoints keep
+            // theirs in `self.endpoints`)
             match *state {
                 State::Reset => {
                     client_err!("Not enabled");
                 }
                 State::Idle(Mode::Device { ref mut config, .. }) => {
-                    *config = Some(cfg);
+                    if endpoint == 0 {
+                        *config = Some(cfg);
+                    }
                 }
                 State::Active(Mode::Device { ref mut config, .. }) => {
-                    *config = Some(cfg);
+                    if endpoint == 0 {
+                        *config = Some(cfg);
+                    }
                 }
                 _ => {
                     client_err!("Not in Device mode");
@@ -290,7 +433,13 @@ impl<'a> Usbc<'a> {
         // Specify which endpoint interrupts we want, among:
         //      TXIN | RXOUT | RXSTP | NAKOUT | NAKIN |
         //      ERRORF | STALLED | CRCERR | RAMACERR
-        endpoint_enable_only_interrupts(endpoint, RXSTP | RAMACERR);
+        let interrupts = match self.endpoints[endpoint].get() {
+            // Ask the client for a packet as soon as the bank is free
+            EndpointState::In { .. } => TXIN | RAMACERR,
+            EndpointState::Out { .. } => RXOUT | RAMACERR,
+            EndpointState::Disabled => RXSTP | RAMACERR,
+        };
+        endpoint_enable_only_interrupts(endpoint, interrupts);
 
         // XX: Set endpoint state to Init
     }
@@ -339,6 +488,9 @@ impl<'a> Usbc<'a> {
             if let Some(ref config) = *config {
                 self.endpoint_configure(0, *config);
             }
+            for endpoint in 1..N_ENDPOINTS {
+                self.endpoint_reset(endpoint);
+            }
 
             // Re-initialize our record of the controller state
             *dstate = DeviceState::Init;
@@ -405,7 +557,7 @@ impl<'a> Usbc<'a> {
         }
 
         // Process per-endpoint interrupt flags
-        for endpoint in 0..1 {
+        for endpoint in 0..N_ENDPOINTS {
             if udint & (1 << (12 + endpoint)) == 0 {
                 // No interrupts for this endpoint
                 continue;
@@ -436,6 +588,11 @@ impl<'a> Usbc<'a> {
                     UESTAnCLR[endpoint].write(RAMACERR);
                 }
 
+                if endpoint != 0 {
+                    self.handle_endpoint_interrupt(endpoint, status);
+                    continue;
+                }
+
                 match *dstate {
                     DeviceState::Init => {
                         if status & RXSTP != 0 {
@@ -700,6 +857,128 @@ impl<'a> Usbc<'a> {
         } // for endpoint
     } // handle_device_interrupt
 
+    /// Enable a bulk or interrupt endpoint again after a bus reset
+    fn endpoint_reset(&self, endpoint: usize) {
+        let config = match self.endpoints[endpoint].get() {
+            EndpointState::Disabled => return,
+            EndpointState::In {
+                config,
+                transfer_type,
+                ..
+            } => {
+                self.endpoints[endpoint].set(EndpointState::In {
+                    config: config,
+                    transfer_type: transfer_type,
+                    state: InState::Init,
+                });
+                config
+            }
+            EndpointState::Out {
+                config,
+                transfer_type,
+                ..
+            } => {
+                self.endpoints[endpoint].set(EndpointState::Out {
+                    config: config,
+                    transfer_type: transfer_type,
+                    state: OutState::Init,
+                });
+                config
+            }
+        };
+
+        UERST.set_bit(endpoint as u32);
+        self.endpoint_configure(endpoint, config);
+        UDINTESET.set_bit(12 + endpoint as u32);
+    }
+
+    /// Handle the interrupt flags of a bulk or interrupt endpoint
+    fn handle_endpoint_interrupt(&self, endpoint: usize, status: u32) {
+        match self.endpoints[endpoint].get() {
+            EndpointState::In {
+                config,
+                transfer_type,
+                state,
+            } => {
+                if status & TXIN == 0 {
+                    return;
+                }
+
+                // The bank is free, so the last packet (if any) was sent
+                if state == InState::Sending {
+                    self.client.map(|c| c.packet_transmitted(endpoint));
+                }
+
+                let result = self.client.map(|c| c.packet_in(transfer_type, endpoint));
+                let state = match result {
+                    Some(InResult::Packet(packet_bytes)) => {
+                        self.descriptors[endpoint][0]
+                            .packet_size
+                            .set(PacketSize::single(packet_bytes as u32));
+
+                        // Hand the bank to the controller to send the packet
+                        UESTAnCLR[endpoint].write(TXIN);
+                        UECONnCLR[endpoint].write(FIFOCON);
+                        InState::Sending
+                    }
+                    Some(InResult::Delay) => {
+                        // The controller sends NAK until the client resumes
+                        endpoint_disable_interrupts(endpoint, TXIN);
+                        InState::Delay
+                    }
+                    _ => {
+                        UECONnSET[endpoint].write(STALLRQ);
+                        debug!("D({}) Client IN err => STALL", endpoint);
+                        endpoint_disable_interrupts(endpoint, TXIN);
+                        InState::Delay
+                    }
+                };
+                self.endpoints[endpoint].set(EndpointState::In {
+                    config: config,
+                    transfer_type: transfer_type,
+                    state: state,
+                });
+            }
+            EndpointState::Out {
+                config,
+                transfer_type,
+                ..
+            } => {
+                if status & RXOUT == 0 {
+                    return;
+                }
+
+                let packet_bytes = self.descriptors[endpoint][0].packet_size.get().byte_count();
+                let result = self.client
+                    .map(|c| c.packet_out(transfer_type, endpoint, packet_bytes));
+                let state = match result {
+                    Some(OutResult::Ok) => OutState::Init,
+                    Some(OutResult::Delay) => OutState::Delay,
+                    _ => {
+                        UECONnSET[endpoint].write(STALLRQ);
+                        debug!("D({}) Client OUT err => STALL", endpoint);
+                        OutState::Init
+                    }
+                };
+                if state == OutState::Delay {
+                    // Keep the packet in the bank, so the controller sends NAK
+                    // until the client resumes
+                    endpoint_disable_interrupts(endpoint, RXOUT);
+                } else {
+                    // Free the bank for the next packet
+                    UESTAnCLR[endpoint].write(RXOUT);
+                    UECONnCLR[endpoint].write(FIFOCON);
+                }
+                self.endpoints[endpoint].set(EndpointState::Out {
+                    config: config,
+                    transfer_type: transfer_type,
+                    state: state,
+                });
+            }
+            EndpointState::Disabled => {}
+        }
+    }
+
     #[allow(dead_code)]
     fn debug_show_d0(&self) {
         for bi in 0..1 {
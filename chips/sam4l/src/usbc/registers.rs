@@ -109,5 +109,9 @@ pub const CRCERR: u32 = 1 << 6;
 pub const RAMACERR: u32 = 1 << 11;
 pub const STALLRQ: u32 = 1 << 19;
 
+// Bitfields for UECONn, UECONnSET, UECONnCLR
+pub const FIFOCON: u32 = 1 << 14;
+pub const RSTDT: u32 = 1 << 18;
+
 // Bitfields for UESTAn
 pub const CTRLDIR: u32 = 1 << 17;
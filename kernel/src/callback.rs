@@ -37,6 +37,23 @@ impl AppId {
     pub fn get_editable_flash_range(&self) -> (usize, usize) {
         process::get_editable_flash_range(self.idx)
     }
+
+    /// Total time this app has spent running on the CPU, in microseconds, or
+    /// `None` if the app does not exist.
+    pub fn get_cpu_time_us(&self) -> Option<u64> {
+        process::get_cpu_time_us(self.idx)
+    }
+
+    /// The package name of this app, or `None` if the app does not exist.
+    pub fn get_package_name(&self) -> Option<&'static str> {
+        process::get_app_package_name(self.idx)
+    }
+
+    /// Offset and length of this app's nonvolatile storage region, relative to
+    /// the storage available to userspace, or `None` if the app does not exist.
+    pub fn get_nonvolatile_storage_region(&self) -> Option<(usize, usize)> {
+        process::get_nonvolatile_storage_region(self.idx)
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
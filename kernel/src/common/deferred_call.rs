@@ -0,0 +1,141 @@
+//! Deferred call mechanism.
+//!
+//! This is a tool to allow chip peripherals and capsules to schedule work to
+//! happen "later", from the main kernel loop, rather than from the call stack
+//! that requested it. This is useful when an operation completes
+//! synchronously (or is rejected) but the caller expects an asynchronous
+//! completion callback, or when hardware does not generate an interrupt where
+//! one is needed.
+//!
+//! There is a fixed set of deferred call flags. Each `DeferredCall` is
+//! assigned one of them when it is registered with its client. Calling
+//! `set()` marks the flag as pending, and the kernel main loop calls
+//! `handle_deferred_call()` on the client of every pending flag right after
+//! it services hardware interrupts.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! struct Driver {
+//!     deferred_call: DeferredCall,
+//! }
+//!
+//! impl Driver {
+//!     pub fn initialize_deferred_call(&'static self) {
+//!         self.deferred_call.register(self);
+//!     }
+//!
+//!     fn start(&self) -> ReturnCode {
+//!         // Operation completes immediately, deliver the callback later.
+//!         self.deferred_call.set();
+//!         ReturnCode::SUCCESS
+//!     }
+//! }
+//!
+//! impl DeferredCallClient for Driver {
+//!     fn handle_deferred_call(&self) {
+//!         // Issue the completion callback.
+//!     }
+//! }
+//! ```
+
+use common::VolatileCell;
+use core::cell::Cell;
+use support;
+
+/// Number of deferred calls that can be registered in the system. There is one
+/// bit per deferred call in the pending flags word.
+pub const NUM_DEFERRED_CALLS: usize = 32;
+
+/// Bitmask of pending deferred calls.
+static PENDING: VolatileCell<u32> = VolatileCell::new(0);
+
+/// Clients of the registered deferred calls, indexed by flag number.
+static mut CLIENTS: [Option<&'static DeferredCallClient>; NUM_DEFERRED_CALLS] =
+    [None; NUM_DEFERRED_CALLS];
+
+/// Number of flags that have been handed out so far.
+static mut NUM_REGISTERED: usize = 0;
+
+/// Implemented by users of a `DeferredCall` to be notified when it fires.
+pub trait DeferredCallClient {
+    /// Called from the kernel main loop after `DeferredCall::set()`.
+    fn handle_deferred_call(&self);
+}
+
+/// Represents a way to generate an asynchronous call without a hardware
+/// interrupt.
+pub struct DeferredCall {
+    flag: Cell<Option<usize>>,
+}
+
+impl DeferredCall {
+    /// Creates a new, unregistered `DeferredCall`.
+    pub const fn new() -> DeferredCall {
+        DeferredCall {
+            flag: Cell::new(None),
+        }
+    }
+
+    /// Assign a deferred call flag to this `DeferredCall` and set the client
+    /// that will be called when it fires. This should be called once, during
+    /// board initialization. Returns `false` if all flags are already in use.
+    pub fn register(&self, client: &'static DeferredCallClient) -> bool {
+        if self.flag.get().is_some() {
+            return false;
+        }
+        unsafe {
+            support::atomic(|| {
+                if NUM_REGISTERED >= NUM_DEFERRED_CALLS {
+                    return false;
+                }
+                let flag = NUM_REGISTERED;
+                NUM_REGISTERED += 1;
+                CLIENTS[flag] = Some(client);
+                self.flag.set(Some(flag));
+                true
+            })
+        }
+    }
+
+    /// Set the `DeferredCall` as pending. Does nothing if the `DeferredCall`
+    /// was never registered.
+    pub fn set(&self) {
+        self.flag.get().map(|flag| unsafe {
+            support::atomic(|| {
+                PENDING.set(PENDING.get() | (1 << flag));
+            });
+        });
+    }
+
+    /// Whether this `DeferredCall` is waiting to be serviced.
+    pub fn is_pending(&self) -> bool {
+        self.flag
+            .get()
+            .map_or(false, |flag| PENDING.get() & (1 << flag) != 0)
+    }
+}
+
+/// Are there any pending `DeferredCall`s.
+pub fn has_tasks() -> bool {
+    PENDING.get() != 0
+}
+
+/// Call the clients of all `DeferredCall`s that are pending at the time this
+/// is called. Deferred calls set by those clients are serviced on the next
+/// iteration of the main loop.
+pub unsafe fn service_pending() {
+    let pending = support::atomic(|| {
+        let pending = PENDING.get();
+        PENDING.set(0);
+        pending
+    });
+
+    let mut remaining = pending;
+    while remaining != 0 {
+        let flag = remaining.trailing_zeros() as usize;
+        remaining &= !(1 << flag);
+        CLIENTS[flag].map(|client| client.handle_deferred_call());
+    }
+}
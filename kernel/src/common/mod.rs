@@ -1,5 +1,6 @@
 //! Common operations in the Tock OS.
 
+pub mod deferred_call;
 pub mod ring_buffer;
 pub mod queue;
 pub mod utils;
@@ -85,8 +85,15 @@
 //!     }
 //! }
 //! ```
+//!
+//! Peripherals that limit how deeply the chip can sleep while they are
+//! running should also return a `PowerConstraint` from
+//! `get_power_constraint`. After every access, the peripheral is reported as
+//! active to the kernel power manager if its clock is still enabled, and as
+//! idle otherwise. See the `power` module for details.
 
 use ClockInterface;
+use platform::power::PowerConstraint;
 
 /// A structure encapsulating a peripheral should implement this trait.
 pub trait PeripheralManagement<C>
@@ -114,6 +121,14 @@ where
     /// Currently used primarily for power management to check whether the
     /// peripheral can be powered off.
     fn after_peripheral_access(&self, &C, &Self::RegisterType);
+
+    /// The power constraint of this peripheral, if it restricts sleep modes.
+    ///
+    /// Updated after every peripheral access based on whether the clock is
+    /// still enabled.
+    fn get_power_constraint(&self) -> Option<&PowerConstraint> {
+        None
+    }
 }
 
 /// Structures encapsulating periphal hardware (those implementing the
@@ -159,5 +174,8 @@ where
     fn drop(&mut self) {
         self.peripheral_hardware
             .after_peripheral_access(self.clock, self.registers);
+        self.peripheral_hardware
+            .get_power_constraint()
+            .map(|constraint| constraint.set_active(self.clock.is_enabled()));
     }
 }
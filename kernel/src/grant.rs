@@ -1,10 +1,22 @@
 //! Data structure to store a list of userspace applications.
+//!
+//! The closures passed to `Grant::enter()`, `Grant::each()` and
+//! `AppliedGrant::enter()` get the grant of the app as a `&mut Borrowed<T>`.
+//! They used to get a `&mut Owned<T>`, which freed the grant region of the
+//! app when it was dropped. A closure that names the type of its argument
+//! has to be changed to `Borrowed`; one that only dereferences it works as
+//! before.
+//!
+//! An `Owned<T>` from `Allocator::alloc()` is no longer dereferenced
+//! directly. `get()` and `get_mut()` return `None` once the process has freed
+//! its grant memory, so a capsule that keeps an allocation after the process
+//! restarts gets an error instead of memory that belongs to someone else.
 
 use callback::AppId;
 use core::marker::PhantomData;
-use core::mem::size_of;
+use core::mem::{size_of, size_of_val};
 use core::ops::{Deref, DerefMut};
-use core::ptr::{read_volatile, write_volatile, Unique};
+use core::ptr::{self, read_volatile, write_volatile, Unique};
 use debug;
 use process::{self, Error};
 
@@ -17,6 +29,7 @@ pub struct Grant<T: Default> {
 
 pub struct AppliedGrant<T> {
     appid: usize,
+    grant_num: usize,
     grant: *mut T,
     _phantom: PhantomData<T>,
 }
@@ -31,14 +44,15 @@ pub unsafe fn kernel_grant_for<T>(app_id: usize) -> *mut T {
 impl<T> AppliedGrant<T> {
     pub fn enter<F, R>(self, fun: F) -> R
     where
-        F: FnOnce(&mut Owned<T>, &mut Allocator) -> R,
+        F: FnOnce(&mut Borrowed<T>, &mut Allocator) -> R,
         R: Copy,
     {
         let mut allocator = Allocator {
             app: unsafe { process::PROCS[self.appid].as_mut() },
             app_id: self.appid,
+            grant_num: self.grant_num,
         };
-        let mut root = unsafe { Owned::new(self.grant, self.appid) };
+        let mut root = unsafe { Borrowed::new(&mut *self.grant, self.appid) };
         fun(&mut root, &mut allocator)
     }
 }
@@ -46,30 +60,86 @@ impl<T> AppliedGrant<T> {
 pub struct Allocator<'a> {
     app: Option<&'a mut process::Process<'a>>,
     app_id: usize,
+    grant_num: usize,
 }
 
+/// An allocation in the grant region of a process. The memory is returned to
+/// the process's grant region when the `Owned` is dropped.
+///
+/// All grant memory of a process is freed when it restarts, so an `Owned`
+/// kept outside the process's grants outlives its memory. Dropping it is then
+/// a no-op, and `get()` and `get_mut()` return `None` rather than memory that
+/// may have been handed out again.
 pub struct Owned<T: ?Sized> {
     data: Unique<T>,
     app_id: usize,
+    grant_num: usize,
+    generation: usize,
 }
 
 impl<T: ?Sized> Owned<T> {
-    pub unsafe fn new(data: *mut T, app_id: usize) -> Owned<T> {
+    pub unsafe fn new(
+        data: *mut T,
+        app_id: usize,
+        grant_num: usize,
+        generation: usize,
+    ) -> Owned<T> {
         Owned {
             data: Unique::new_unchecked(data),
             app_id: app_id,
+            grant_num: grant_num,
+            generation: generation,
         }
     }
 
     pub fn appid(&self) -> AppId {
         AppId::new(self.app_id)
     }
+
+    /// Whether the memory still belongs to this allocation, that is the
+    /// process has not freed its grant memory since.
+    pub fn is_valid(&self) -> bool {
+        if AppId::is_kernel_idx(self.app_id) {
+            return true;
+        }
+        unsafe {
+            match process::PROCS[self.app_id] {
+                Some(ref app) => app.grant_generation() == self.generation,
+                None => false,
+            }
+        }
+    }
+
+    /// The allocated value, or `None` if the process has freed its grant
+    /// memory since.
+    pub fn get(&self) -> Option<&T> {
+        if self.is_valid() {
+            Some(unsafe { self.data.as_ref() })
+        } else {
+            None
+        }
+    }
+
+    /// The allocated value, or `None` if the process has freed its grant
+    /// memory since.
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        if self.is_valid() {
+            Some(unsafe { self.data.as_mut() })
+        } else {
+            None
+        }
+    }
 }
 
 impl<T: ?Sized> Drop for Owned<T> {
     fn drop(&mut self) {
+        if !self.is_valid() {
+            return;
+        }
         unsafe {
             let app_id = self.app_id;
+            let size = size_of_val(self.data.as_ref());
+            ptr::drop_in_place(self.data.as_ptr());
             let data = self.data.as_ptr() as *mut u8;
             if AppId::is_kernel_idx(app_id) {
                 /* kernel free is nop */
@@ -78,7 +148,7 @@ impl<T: ?Sized> Drop for Owned<T> {
                 match process::PROCS[app_id] {
                     None => {}
                     Some(ref mut app) => {
-                        app.free(data);
+                        app.free(data, size, self.grant_num);
                     }
                 }
             }
@@ -86,30 +156,23 @@ impl<T: ?Sized> Drop for Owned<T> {
     }
 }
 
-impl<T: ?Sized> Deref for Owned<T> {
-    type Target = T;
-    fn deref(&self) -> &T {
-        unsafe { self.data.as_ref() }
-    }
-}
-
-impl<T: ?Sized> DerefMut for Owned<T> {
-    fn deref_mut(&mut self) -> &mut T {
-        unsafe { self.data.as_mut() }
-    }
-}
-
 impl<'a> Allocator<'a> {
     pub fn alloc<T>(&mut self, data: T) -> Result<Owned<T>, Error> {
         unsafe {
             let app_id = self.app_id;
+            let grant_num = self.grant_num;
             match self.app.as_mut() {
-                Some(app) => app.alloc(size_of::<T>())
-                    .map_or(Err(Error::OutOfMemory), |arr| {
-                        let mut owned = Owned::new(arr.as_mut_ptr() as *mut T, app_id);
-                        *owned = data;
-                        Ok(owned)
-                    }),
+                Some(app) => {
+                    let generation = app.grant_generation();
+                    app.alloc(size_of::<T>(), grant_num)
+                        .map_or(Err(Error::OutOfMemory), |arr| {
+                            let ptr = arr.as_mut_ptr() as *mut T;
+                            // The memory is uninitialized, don't drop its contents.
+                            ptr::write(ptr, data);
+                            let owned = Owned::new(ptr, app_id, grant_num, generation);
+                            Ok(owned)
+                        })
+                }
                 None => {
                     if !AppId::is_kernel_idx(app_id) {
                         panic!("No app for allocator for {}", app_id);
@@ -169,6 +232,7 @@ impl<T: Default> Grant<T> {
                 let cntr = kernel_grant_for::<T>(app_id);
                 Some(AppliedGrant {
                     appid: app_id,
+                    grant_num: self.grant_num,
                     grant: cntr,
                     _phantom: PhantomData,
                 })
@@ -181,6 +245,7 @@ impl<T: Default> Grant<T> {
                         } else {
                             Some(AppliedGrant {
                                 appid: app_id,
+                                grant_num: self.grant_num,
                                 grant: cntr,
                                 _phantom: PhantomData,
                             })
@@ -205,6 +270,7 @@ impl<T: Default> Grant<T> {
                 let mut allocator = Allocator {
                     app: None,
                     app_id: app_id,
+                    grant_num: self.grant_num,
                 };
                 let res = fun(&mut root, &mut allocator);
                 Ok(res)
@@ -217,6 +283,7 @@ impl<T: Default> Grant<T> {
                             let mut allocator = Allocator {
                                 app: Some(app),
                                 app_id: app_id,
+                                grant_num: self.grant_num,
                             };
                             let res = fun(&mut root, &mut allocator);
                             Ok(res)
@@ -230,14 +297,14 @@ impl<T: Default> Grant<T> {
 
     pub fn each<F>(&self, fun: F)
     where
-        F: Fn(&mut Owned<T>),
+        F: Fn(&mut Borrowed<T>),
     {
         unsafe {
             let itr = process::PROCS.iter_mut().filter_map(|p| p.as_mut());
             for (app_id, app) in itr.enumerate() {
                 let root_ptr = app.grant_for::<T>(self.grant_num);
                 if !root_ptr.is_null() {
-                    let mut root = Owned::new(root_ptr, app_id);
+                    let mut root = Borrowed::new(&mut *root_ptr, app_id);
                     fun(&mut root);
                 }
             }
@@ -40,6 +40,17 @@
 //! }
 //! ```
 //!
+//! Flash can only change bits from one to zero; setting them again requires
+//! erasing the whole page. `write_page` therefore erases the page before
+//! writing it. Chips that can program smaller units of a page without erasing
+//! it can additionally implement `write_granularity` and `write_partial`, and
+//! chips that can erase several pages with one request can implement
+//! `erase_pages`. Users should fall back to the page operations when these
+//! return `None` or `ENOSUPPORT`.
+//!
+//! If a read or write cannot be started, the page buffer is handed back with
+//! the error and there is no callback.
+//!
 //! Then a basic implementation of this trait should look like:
 //!
 //! ```rust
@@ -51,8 +62,16 @@
 //! impl hil::flash::Flash for NewChipStruct {
 //!     type Page = NewChipPage;
 //!
-//!     fn read_page(&self, page_number: usize, buf: &'static mut Self::Page) -> ReturnCode { }
-//!     fn write_page(&self, page_number: usize, buf: &'static mut Self::Page) -> ReturnCode { }
+//!     fn read_page(
+//!         &self,
+//!         page_number: usize,
+//!         buf: &'static mut Self::Page,
+//!     ) -> (ReturnCode, Option<&'static mut Self::Page>) { }
+//!     fn write_page(
+//!         &self,
+//!         page_number: usize,
+//!         buf: &'static mut Self::Page,
+//!     ) -> (ReturnCode, Option<&'static mut Self::Page>) { }
 //!     fn erase_page(&self, page_number: usize) -> ReturnCode { }
 //! }
 //! ```
@@ -91,6 +110,15 @@ pub enum Error {
 
     /// An error occurred during the flash operation.
     FlashError,
+
+    /// The page is write protected, for example by a lock bit.
+    WriteProtected,
+
+    /// The flash does not contain the written data after the operation.
+    VerificationFailed,
+
+    /// The flash was busy with another operation and did not start this one.
+    Busy,
 }
 
 pub trait HasClient<'a, C> {
@@ -105,13 +133,49 @@ pub trait Flash {
     type Page: AsMut<[u8]>;
 
     /// Read a page of flash into the buffer.
-    fn read_page(&self, page_number: usize, buf: &'static mut Self::Page) -> ReturnCode;
+    fn read_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> (ReturnCode, Option<&'static mut Self::Page>);
 
     /// Write a page of flash from the buffer.
-    fn write_page(&self, page_number: usize, buf: &'static mut Self::Page) -> ReturnCode;
+    fn write_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Self::Page,
+    ) -> (ReturnCode, Option<&'static mut Self::Page>);
 
     /// Erase a page of flash.
     fn erase_page(&self, page_number: usize) -> ReturnCode;
+
+    /// The number of bytes `write_partial` programs at a time, or `None` if
+    /// the flash can only be written a page at a time.
+    fn write_granularity(&self) -> Option<usize> {
+        None
+    }
+
+    /// Program `length` bytes at `offset` within the page from the same
+    /// bytes of the buffer, without erasing the page first. `offset` and
+    /// `length` must be multiples of `write_granularity()`. Because this can
+    /// only clear bits, the result is the bitwise AND of the old and new
+    /// contents. Completion is signaled with `write_complete`. Only call this
+    /// if `write_granularity()` is not `None`.
+    fn write_partial(
+        &self,
+        _page_number: usize,
+        _offset: usize,
+        _length: usize,
+        buf: &'static mut Self::Page,
+    ) -> (ReturnCode, Option<&'static mut Self::Page>) {
+        (ReturnCode::ENOSUPPORT, Some(buf))
+    }
+
+    /// Erase `count` consecutive pages of flash starting at `page_number`.
+    /// Completion is signaled with a single `erase_complete`.
+    fn erase_pages(&self, _page_number: usize, _count: usize) -> ReturnCode {
+        ReturnCode::ENOSUPPORT
+    }
 }
 
 /// Implement `Client` to receive callbacks from `Flash`.
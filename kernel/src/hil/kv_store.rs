@@ -0,0 +1,45 @@
+//! Interface for persistent key-value storage.
+
+use returncode::ReturnCode;
+
+/// Simple interface for storing values under keys in nonvolatile memory.
+///
+/// Only one operation can be outstanding at a time. Keys are copied when an
+/// operation is started, so they do not need to be `'static`. If an operation
+/// cannot be started, the value buffer is handed back with the error.
+pub trait KVStore {
+    fn set_client(&self, client: &'static KVStoreClient);
+
+    /// Look up `key` and read its value in to `value`. If the value is longer
+    /// than the buffer it is truncated.
+    fn get(&self, key: &[u8], value: &'static mut [u8])
+        -> (ReturnCode, Option<&'static mut [u8]>);
+
+    /// Store the first `length` bytes of `value` under `key`, replacing any
+    /// previous value.
+    fn set(
+        &self,
+        key: &[u8],
+        value: &'static mut [u8],
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>);
+
+    /// Remove `key` and its value.
+    fn delete(&self, key: &[u8]) -> ReturnCode;
+}
+
+/// Client interface for key-value storage.
+pub trait KVStoreClient {
+    /// `get_done` is called when a lookup finishes. On success, `length` is
+    /// the full length of the stored value, which may be larger than the
+    /// buffer. `ENOSUPPORT` means the key does not exist.
+    fn get_done(&self, value: &'static mut [u8], length: usize, result: ReturnCode);
+
+    /// `set_done` is called once the value is committed to storage, or the
+    /// operation failed.
+    fn set_done(&self, value: &'static mut [u8], result: ReturnCode);
+
+    /// `delete_done` is called once the deletion is committed to storage, or
+    /// the operation failed.
+    fn delete_done(&self, result: ReturnCode);
+}
@@ -17,6 +17,7 @@ pub mod symmetric_encryption;
 pub mod gpio_async;
 pub mod dac;
 pub mod nonvolatile_storage;
+pub mod kv_store;
 pub mod usb;
 pub mod ble_advertising;
 
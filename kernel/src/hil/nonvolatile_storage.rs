@@ -4,18 +4,31 @@ use returncode::ReturnCode;
 
 /// Simple interface for reading and writing nonvolatile memory. It is expected
 /// that drivers for nonvolatile memory would implement this trait.
+///
+/// If a read or write cannot be started, the buffer is handed back with the
+/// error and there is no callback.
 pub trait NonvolatileStorage {
     fn set_client(&self, client: &'static NonvolatileStorageClient);
 
     /// Read `length` bytes starting at address `address` in to the provided
     /// buffer. The buffer must be at least `length` bytes long. The address
     /// must be in the address space of the physical storage.
-    fn read(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode;
+    fn read(
+        &self,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>);
 
     /// Write `length` bytes starting at address `address` from the provided
     /// buffer. The buffer must be at least `length` bytes long. This address
     /// must be in the address space of the physical storage.
-    fn write(&self, buffer: &'static mut [u8], address: usize, length: usize) -> ReturnCode;
+    fn write(
+        &self,
+        buffer: &'static mut [u8],
+        address: usize,
+        length: usize,
+    ) -> (ReturnCode, Option<&'static mut [u8]>);
 }
 
 /// Client interface for nonvolatile storage.
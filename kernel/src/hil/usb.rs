@@ -1,4 +1,13 @@
 //! Interface to USB controller hardware
+//!
+//! Endpoint 0 is the default control endpoint, which the controller drives
+//! through the `ctrl_*` callbacks of the client. The other endpoints carry
+//! bulk or interrupt transfers in one direction each. The controller asks the
+//! client for the next packet of an IN endpoint with `packet_in()` whenever
+//! the endpoint buffer is free, and hands it each packet received on an OUT
+//! endpoint with `packet_out()`. If the client has nothing to send or no room
+//! for the packet it returns `Delay`, and the endpoint sends NAK to the host
+//! until the client calls `endpoint_resume_in()` or `endpoint_resume_out()`.
 
 use common::VolatileCell;
 use core::default::Default;
@@ -13,10 +22,38 @@ pub trait UsbController {
 
     fn endpoint_configure(&self, &'static Self::EndpointState, index: u32);
 
+    /// The number of endpoints of the controller, including endpoint 0.
+    fn endpoint_count(&self) -> u32;
+
+    /// Set the buffer packets of endpoint `e` are sent from and received
+    /// into. Its length is the maximum packet size of the endpoint.
     fn endpoint_set_buffer(&self, e: u32, buf: &[VolatileCell<u8>]);
 
     fn endpoint_ctrl_out_enable(&self, e: u32);
 
+    /// Enable endpoint `e` to send packets of the given transfer type to the
+    /// host.
+    fn endpoint_in_enable(&self, transfer_type: TransferType, e: u32);
+
+    /// Enable endpoint `e` to receive packets of the given transfer type from
+    /// the host.
+    fn endpoint_out_enable(&self, transfer_type: TransferType, e: u32);
+
+    /// The client has a packet to send on IN endpoint `e` after it returned
+    /// `InResult::Delay`.
+    fn endpoint_resume_in(&self, e: u32);
+
+    /// The client can take a packet from OUT endpoint `e` after it returned
+    /// `OutResult::Delay`.
+    fn endpoint_resume_out(&self, e: u32);
+
+    /// Halt endpoint `e`, so it answers all transactions with STALL.
+    fn endpoint_set_stall(&self, e: u32);
+
+    /// Stop halting endpoint `e` and reset its data toggle, for example when
+    /// the host clears the ENDPOINT_HALT feature.
+    fn endpoint_clear_stall(&self, e: u32);
+
     fn set_address(&self, addr: u16);
 
     fn enable_address(&self);
@@ -33,6 +70,31 @@ pub trait Client {
     fn ctrl_out(&self, packet_bytes: u32) -> CtrlOutResult;
     fn ctrl_status(&self);
     fn ctrl_status_complete(&self);
+
+    /// The buffer of IN endpoint `endpoint` is free to hold the next packet
+    /// to send
+    fn packet_in(&self, transfer_type: TransferType, endpoint: usize) -> InResult;
+
+    /// A packet of `packet_bytes` bytes was received into the buffer of OUT
+    /// endpoint `endpoint`
+    fn packet_out(
+        &self,
+        transfer_type: TransferType,
+        endpoint: usize,
+        packet_bytes: u32,
+    ) -> OutResult;
+
+    /// The last packet from `packet_in()` was received by the host
+    fn packet_transmitted(&self, endpoint: usize);
+}
+
+/// Types of USB transfers, as encoded in endpoint descriptors
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum TransferType {
+    Control = 0,
+    Isochronous,
+    Bulk,
+    Interrupt,
 }
 
 #[derive(Debug)]
@@ -50,6 +112,7 @@ pub enum CtrlSetupResult {
     ErrInvalidDeviceIndex,
     ErrInvalidConfigurationIndex,
     ErrInvalidStringIndex,
+    ErrInvalidRequest,
 }
 
 pub enum CtrlInResult {
@@ -76,3 +139,25 @@ pub enum CtrlOutResult {
     /// In halt state (send STALL)
     Halted,
 }
+
+pub enum InResult {
+    /// A packet of the given size was written into the endpoint buffer
+    Packet(usize),
+
+    /// The client has no data to send yet (send NAK)
+    Delay,
+
+    /// The client does not support the request (send STALL)
+    Error,
+}
+
+pub enum OutResult {
+    /// Data received (send ACK)
+    Ok,
+
+    /// Not ready yet (send NAK)
+    Delay,
+
+    /// The client cannot handle the data (send STALL)
+    Error,
+}
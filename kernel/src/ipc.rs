@@ -132,6 +132,12 @@ impl Driver for IPC {
     /// and notifying an IPC client is done by setting client_or_svc to 1.
     /// In either case, the target_id is the same number as provided in a notify
     /// callback or as returned by allow.
+    ///
+    /// Setting client_or_svc to 2 does not notify anyone but returns the
+    /// version of the target app, so that a client can check that a service
+    /// it discovered is recent enough. The version is packed as
+    /// `major << 16 | minor << 8 | patch`. ENOSUPPORT is returned if the app
+    /// does not specify a version in its TBF header.
     fn command(
         &self,
         target_id: usize,
@@ -144,6 +150,18 @@ impl Driver for IPC {
             return ReturnCode::EINVAL; /* Request to IPC to impossible process */
         }
 
+        if client_or_svc == 2 {
+            return procs[target_id - 1]
+                .as_ref()
+                .map(|target| match target.app_version() {
+                    Some(version) => ReturnCode::SuccessWithValue {
+                        value: version.as_u32() as usize,
+                    },
+                    None => ReturnCode::ENOSUPPORT,
+                })
+                .unwrap_or(ReturnCode::EINVAL); /* Request to IPC to unknown process */
+        }
+
         let cb_type = if client_or_svc == 0 {
             process::IPCType::Service
         } else {
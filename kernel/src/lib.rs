@@ -10,6 +10,8 @@
 #![feature(const_fn, const_cell_new, const_unsafe_cell_new, lang_items)]
 #![no_std]
 
+extern crate tbf;
+
 #[macro_use]
 pub mod common;
 
@@ -37,16 +39,19 @@ mod sched;
 mod syscall;
 mod platform;
 
+use common::deferred_call;
+
 pub use callback::{AppId, Callback};
 pub use common::StaticRef;
 pub use driver::Driver;
 pub use grant::Grant;
 pub use mem::{AppPtr, AppSlice, Private, Shared};
-pub use platform::{mpu, systick, Chip, Platform};
+pub use platform::{mpu, power, systick, Chip, Platform};
 pub use platform::{ClockInterface, NoClockControl, NO_CLOCK_CONTROL};
 pub use platform::systick::SysTick;
 pub use process::{Process, State};
 pub use returncode::ReturnCode;
+pub use syscall::SYSCALL_ABI_VERSION;
 
 /// Main loop.
 pub fn main<P: Platform, C: Chip>(
@@ -63,19 +68,22 @@ pub fn main<P: Platform, C: Chip>(
     loop {
         unsafe {
             chip.service_pending_interrupts();
+            deferred_call::service_pending();
 
             for (i, p) in processes.iter_mut().enumerate() {
                 p.as_mut().map(|process| {
                     sched::do_process(platform, chip, process, AppId::new(i), ipc);
                 });
-                if chip.has_pending_interrupts() {
+                if chip.has_pending_interrupts() || deferred_call::has_tasks() {
                     break;
                 }
             }
 
             support::atomic(|| {
-                if !chip.has_pending_interrupts() && process::processes_blocked() {
-                    chip.prepare_for_sleep();
+                if !chip.has_pending_interrupts() && !deferred_call::has_tasks()
+                    && process::processes_blocked()
+                {
+                    chip.prepare_for_sleep(power::deepest_sleep_mode());
                     support::wfi();
                 }
             });
@@ -42,19 +42,6 @@ impl<L, T> DerefMut for AppPtr<L, T> {
     }
 }
 
-impl<L, T> Drop for AppPtr<L, T> {
-    fn drop(&mut self) {
-        unsafe {
-            let ps = &mut process::PROCS;
-            if ps.len() > self.process.idx() {
-                ps[self.process.idx()]
-                    .as_mut()
-                    .map(|process| process.free(self.ptr.as_mut()));
-            }
-        }
-    }
-}
-
 pub struct AppSlice<L, T> {
     ptr: AppPtr<L, T>,
     len: usize,
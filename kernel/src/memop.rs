@@ -1,6 +1,6 @@
 //! Implementation of the MEMOP family of syscalls.
 
-use process::Process;
+use process::{MemoryRegion, Process};
 use returncode::ReturnCode;
 
 /// Handle the `memop` syscall.
@@ -36,6 +36,14 @@ use returncode::ReturnCode;
 ///   where the app has put the start of its heap. This is not strictly
 ///   necessary for correct operation, but allows for better debugging if the
 ///   app crashes.
+/// - `12`: Get the number of bytes currently used by the memory region
+///   selected by r1: `0` for the stack, `1` for the heap and `2` for the grant
+///   region. Stack and heap usage are only known if the kernel knows where
+///   they start (see `10` and `11`), otherwise `FAIL` is returned.
+/// - `13`: Get the maximum number of bytes ever used by the memory region
+///   selected by r1, with the same encoding as `12`.
+/// - `14`: Get the number of bytes the grant with number r1 has allocated for
+///   this app.
 pub fn memop(process: &mut Process) -> ReturnCode {
     let op_type = process.r0();
     let r1 = process.r1();
@@ -111,6 +119,29 @@ pub fn memop(process: &mut Process) -> ReturnCode {
             ReturnCode::SUCCESS
         }
 
+        // Op Type 12 and 13: Current and peak usage of a memory region.
+        12 | 13 => {
+            let region = match r1 {
+                0 => MemoryRegion::Stack,
+                1 => MemoryRegion::Heap,
+                2 => MemoryRegion::Grant,
+                _ => return ReturnCode::EINVAL,
+            };
+            process.memory_usage(region)
+                .map(|(current, peak)| {
+                    let value = if op_type == 12 { current } else { peak };
+                    ReturnCode::SuccessWithValue { value: value }
+                })
+                .unwrap_or(ReturnCode::FAIL)
+        }
+
+        // Op Type 14: Bytes allocated by the grant numbered r1.
+        14 => {
+            process.grant_bytes(r1)
+                .map(|bytes| ReturnCode::SuccessWithValue { value: bytes })
+                .unwrap_or(ReturnCode::EINVAL)
+        }
+
         _ => ReturnCode::ENOSUPPORT,
     }
 }
@@ -1,6 +1,7 @@
 use driver::Driver;
 
 pub mod mpu;
+pub mod power;
 pub mod systick;
 
 /// Interface for individual boards.
@@ -21,7 +22,11 @@ pub trait Chip {
     fn has_pending_interrupts(&self) -> bool;
     fn mpu(&self) -> &Self::MPU;
     fn systick(&self) -> &Self::SysTick;
-    fn prepare_for_sleep(&self) {}
+
+    /// Configure the chip for the upcoming `wfi`. `mode` is the deepest sleep
+    /// mode permitted by the active peripherals; chips may choose a lighter
+    /// one.
+    fn prepare_for_sleep(&self, _mode: power::SleepMode) {}
 }
 
 /// Generic operations that clock-like things are expected to support.
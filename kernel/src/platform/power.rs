@@ -0,0 +1,112 @@
+//! Kernel power management.
+//!
+//! Peripherals tell the kernel whether they are active, and how deeply the
+//! chip may sleep while they are, through a `PowerConstraint`. When all
+//! processes are blocked, the main loop asks for the deepest sleep mode that
+//! every active peripheral tolerates and passes it to
+//! `Chip::prepare_for_sleep()`, which configures the hardware accordingly.
+//!
+//! Peripherals that implement `PeripheralManagement` only need to return their
+//! constraint from `get_power_constraint()`. `PeripheralManager` then marks
+//! the peripheral active after each register access if its clock is still
+//! enabled, and inactive once the clock has been gated.
+//!
+//! ```rust
+//! pub struct SpiHw {
+//!     power_constraint: PowerConstraint,
+//! }
+//!
+//! impl PeripheralManagement<pm::Clock> for SpiHw {
+//!     fn get_power_constraint(&self) -> Option<&PowerConstraint> {
+//!         Some(&self.power_constraint)
+//!     }
+//! }
+//! ```
+//!
+//! Peripherals that do not use `PeripheralManager` can call
+//! `PowerConstraint::set_active()` directly.
+//!
+//! The chip backends decide what each mode means: the SAM4L selects the BPM
+//! sleep mode in `pm::prepare_for_sleep()` and only deep sleeps if no clock
+//! of a peripheral that stops in WAIT mode is enabled, and the nRF5x chips
+//! stop the high frequency crystal in deep sleep.
+
+use core::cell::Cell;
+
+/// Sleep modes, ordered from the lightest to the deepest.
+///
+/// What each mode means in terms of clocks and wakeup sources is chip
+/// specific, but every chip must be able to resume from a deeper mode on any
+/// interrupt that a lighter mode would have woken it up on, provided the
+/// peripherals generating that interrupt permit the deeper mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SleepMode {
+    /// The core is stopped, all enabled clocks keep running.
+    Sleep = 0,
+    /// The core and high frequency clocks are stopped. Only low frequency
+    /// clocks and asynchronous wakeup sources remain active.
+    DeepSleep = 1,
+}
+
+/// All sleep modes, from the lightest to the deepest.
+const SLEEP_MODES: [SleepMode; 2] = [SleepMode::Sleep, SleepMode::DeepSleep];
+
+/// Number of active constraints whose deepest tolerated mode is the sleep
+/// mode at that index.
+static mut ACTIVE_CONSTRAINTS: [usize; 2] = [0; 2];
+
+/// Tracks whether a peripheral is active and the deepest sleep mode it can
+/// tolerate while it is.
+pub struct PowerConstraint {
+    deepest: SleepMode,
+    active: Cell<bool>,
+}
+
+impl PowerConstraint {
+    /// Create an inactive constraint for a peripheral that can operate in
+    /// sleep modes down to and including `deepest`.
+    pub const fn new(deepest: SleepMode) -> PowerConstraint {
+        PowerConstraint {
+            deepest: deepest,
+            active: Cell::new(false),
+        }
+    }
+
+    /// Mark the peripheral as active or idle.
+    pub fn set_active(&self, active: bool) {
+        if self.active.get() == active {
+            return;
+        }
+        self.active.set(active);
+
+        let index = self.deepest as usize;
+        unsafe {
+            if active {
+                ACTIVE_CONSTRAINTS[index] += 1;
+            } else {
+                ACTIVE_CONSTRAINTS[index] -= 1;
+            }
+        }
+    }
+
+    /// Whether the peripheral is currently active, and so restricts the
+    /// sleep modes the chip may enter.
+    pub fn is_active(&self) -> bool {
+        self.active.get()
+    }
+
+    /// The deepest sleep mode tolerated while the peripheral is active.
+    pub fn deepest_sleep_mode(&self) -> SleepMode {
+        self.deepest
+    }
+}
+
+/// The deepest sleep mode permitted by all currently active peripherals.
+pub fn deepest_sleep_mode() -> SleepMode {
+    for mode in SLEEP_MODES.iter() {
+        if unsafe { ACTIVE_CONSTRAINTS[*mode as usize] } > 0 {
+            return *mode;
+        }
+    }
+    SleepMode::DeepSleep
+}
@@ -4,14 +4,15 @@ use callback::AppId;
 use common::{RingBuffer, Queue, VolatileCell};
 
 use grant;
-use core::{mem, ptr, slice, str};
+use core::{cmp, mem, ptr, slice, str};
 use core::cell::Cell;
 use core::fmt::Write;
 use core::ptr::{read_volatile, write_volatile, write};
 
 use platform::mpu;
 use returncode::ReturnCode;
-use syscall::Syscall;
+use syscall::{Syscall, SYSCALL_ABI_VERSION};
+use tbf::{self, TbfHeader, TbfHeaderV2AppVersion};
 use common::math;
 
 /// Takes a value and rounds it up to be aligned % 8
@@ -19,11 +20,6 @@ macro_rules! align8 {
     ( $e:expr ) => ( ($e) + ((8 - (($e) % 8)) % 8 ) );
 }
 
-/// Takes a value and rounds it up to be aligned % 4
-macro_rules! align4 {
-    ( $e:expr ) => ( ($e) + ((4 - (($e) % 4)) % 4 ) );
-}
-
 #[no_mangle]
 pub static mut SYSCALL_FIRED: usize = 0;
 
@@ -59,6 +55,12 @@ pub unsafe fn load_processes(start_of_flash: *const u8,
     let mut apps_in_flash_ptr = start_of_flash;
     let mut app_memory_ptr = app_memory.as_mut_ptr();
     let mut app_memory_size = app_memory.len();
+    // Apps get consecutive regions of nonvolatile storage in the order they
+    // are in flash. Once the regions add up to more than a usize, the app and
+    // every app after it get an empty region. The storage driver gives an
+    // empty region to an app whose region does not fit in its storage, and as
+    // offsets only grow, to every later app that asks for storage.
+    let mut storage_offset = Some(0usize);
     for i in 0..procs.len() {
         let (process, flash_offset, memory_offset) = Process::create(apps_in_flash_ptr,
                                                                      app_memory_ptr,
@@ -74,7 +76,22 @@ pub unsafe fn load_processes(start_of_flash: *const u8,
                 break;
             }
         } else {
-            procs[i] = process;
+            procs[i] = process.map(|mut p| {
+                let region = storage_offset.and_then(|offset| {
+                    offset.checked_add(p.storage_size).map(|end| (offset, end))
+                });
+                match region {
+                    Some((offset, end)) => {
+                        p.storage_offset = offset;
+                        storage_offset = Some(end);
+                    }
+                    None => {
+                        p.storage_size = 0;
+                        storage_offset = None;
+                    }
+                }
+                p
+            });
         }
 
         apps_in_flash_ptr = apps_in_flash_ptr.offset(flash_offset as isize);
@@ -103,6 +120,49 @@ pub fn schedule(callback: FunctionCall, appid: AppId) -> bool {
     }
 }
 
+/// Returns the total time, in microseconds, that the app has spent running on
+/// the CPU, or `None` if there is no such app.
+pub fn get_cpu_time_us(app_idx: usize) -> Option<u64> {
+    let procs = unsafe { &mut PROCS };
+    if app_idx >= procs.len() {
+        return None;
+    }
+
+    match procs[app_idx] {
+        None => None,
+        Some(ref p) => Some(p.cpu_time_us()),
+    }
+}
+
+/// Returns the package name of the app, or `None` if there is no such app. Apps
+/// without a name in their TBF header have an empty name.
+pub fn get_app_package_name(app_idx: usize) -> Option<&'static str> {
+    let procs = unsafe { &mut PROCS };
+    if app_idx >= procs.len() {
+        return None;
+    }
+
+    match procs[app_idx] {
+        None => None,
+        Some(ref p) => Some(p.package_name),
+    }
+}
+
+/// Returns the offset and length of the nonvolatile storage region of the app,
+/// relative to the start of the storage available to userspace, or `None` if
+/// there is no such app. Apps that did not ask for storage have an empty region.
+pub fn get_nonvolatile_storage_region(app_idx: usize) -> Option<(usize, usize)> {
+    let procs = unsafe { &mut PROCS };
+    if app_idx >= procs.len() {
+        return None;
+    }
+
+    match procs[app_idx] {
+        None => None,
+        Some(ref p) => Some((p.storage_offset, p.storage_size)),
+    }
+}
+
 /// Returns the full address of the start and end of the flash region that the
 /// app owns and can write to. This includes the app's code and data and any
 /// padding at the end of the app. It does not include the TBF header, or any
@@ -166,268 +226,42 @@ pub enum Task {
     IPC((AppId, IPCType)),
 }
 
-#[derive(Copy, Clone, Debug)]
-pub struct FunctionCall {
-    pub r0: usize,
-    pub r1: usize,
-    pub r2: usize,
-    pub r3: usize,
-    pub pc: usize,
-}
-
-/// Legacy Tock Binary Format header.
-///
-/// Version 1 of the header is deprecated but can still be parsed by the kernel
-/// to support any apps that were compiled with an older version of elf2tbf.
-#[repr(C)]
-#[derive(Clone, Copy, Debug)]
-struct TbfHeaderV1 {
-    version: u32,
-    total_size: u32,
-    entry_offset: u32,
-    rel_data_offset: u32,
-    rel_data_size: u32,
-    text_offset: u32,
-    text_size: u32,
-    got_offset: u32,
-    got_size: u32,
-    data_offset: u32,
-    data_size: u32,
-    bss_mem_offset: u32,
-    bss_size: u32,
-    min_stack_len: u32,
-    min_app_heap_len: u32,
-    min_kernel_heap_len: u32,
-    pkg_name_offset: u32,
-    pkg_name_size: u32,
-    checksum: u32,
-}
-
-/// TBF fields that must be present in all v2 headers.
-#[repr(C)]
-#[derive(Clone, Copy, Debug)]
-struct TbfHeaderV2Base {
-    version: u16,
-    header_size: u16,
-    total_size: u32,
-    flags: u32,
-    checksum: u32,
-}
-
-/// Types in TLV structures for each optional block of the header.
-#[repr(u16)]
-#[derive(Clone, Copy, Debug)]
-#[allow(dead_code)]
-enum TbfHeaderTypes {
-    TbfHeaderMain = 1,
-    TbfHeaderWriteableFlashRegions = 2,
-    TbfHeaderPackageName = 3,
-    Unused = 5,
-}
-
-/// The TLV header (T and L).
-#[repr(C)]
-#[derive(Clone, Copy, Debug)]
-struct TbfHeaderTlv {
-    tipe: TbfHeaderTypes,
-    length: u16,
-}
-
-/// The v2 main section for apps.
-///
-/// All apps must have a main section. Without it, the header is considered as
-/// only padding.
-#[repr(C)]
-#[derive(Clone, Copy, Debug)]
-struct TbfHeaderV2Main {
-    init_fn_offset: u32,
-    protected_size: u32,
-    minimum_ram_size: u32,
-}
-
-/// Writeable flash regions only need an offset and size.
-///
-/// There can be multiple (or zero) flash regions defined, so this is its own
-/// struct.
-#[repr(C)]
-#[derive(Clone, Copy, Debug)]
-struct TbfHeaderV2WriteableFlashRegion {
-    writeable_flash_region_offset: u32,
-    writeable_flash_region_size: u32,
+/// Regions of process RAM whose usage the kernel tracks.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MemoryRegion {
+    Stack,
+    Heap,
+    Grant,
 }
 
-/// PIC fields for kernel provided PIC fixup.
-///
-/// If an app wants the kernel to do the PIC fixup for it, it must pass this
-/// block so the kernel knows where sections are in the app binary.
-#[repr(C)]
-#[derive(Clone, Copy, Debug)]
-struct PicOption1Fields {
-    text_offset: u32,
-    data_offset: u32,
-    data_size: u32,
-    bss_memory_offset: u32,
-    bss_size: u32,
-    relocation_data_offset: u32,
-    relocation_data_size: u32,
-    got_offset: u32,
-    got_size: u32,
-    minimum_stack_length: u32,
+/// Header written into freed grant memory to link it into the free list.
+struct FreeBlock {
+    size: usize,
+    next: *mut FreeBlock,
 }
 
-/// Single header that can contain all parts of a v2 header.
-#[derive(Clone, Copy, Debug)]
-struct TbfHeaderV2 {
-    base: &'static TbfHeaderV2Base,
-    main: Option<&'static TbfHeaderV2Main>,
-    package_name: Option<&'static str>,
-    writeable_regions: Option<&'static [TbfHeaderV2WriteableFlashRegion]>,
+/// The number of bytes actually reserved in the grant region for an
+/// allocation of `size` bytes. Allocations are a multiple of the size of a
+/// `FreeBlock`, which keeps them 8-byte aligned and able to hold a `FreeBlock`
+/// once freed, and splitting a free block never leaves a piece too small for
+/// its header.
+fn grant_alloc_size(size: usize) -> usize {
+    let unit = mem::size_of::<FreeBlock>();
+    (cmp::max(size, 1) + unit - 1) / unit * unit
 }
 
-/// Type that represents the fields of the Tock Binary Format header.
-///
-/// This specifies the locations of the different code and memory sections
-/// in the tock binary, as well as other information about the application.
-/// The kernel can also use this header to keep persistent state about
-/// the application.
-#[derive(Debug)]
-enum TbfHeader {
-    TbfHeaderV1(&'static TbfHeaderV1),
-    TbfHeaderV2(TbfHeaderV2),
-    Padding(&'static TbfHeaderV2Base),
+/// The first byte after a free block.
+unsafe fn free_block_end(block: *mut FreeBlock) -> *mut u8 {
+    (block as *mut u8).offset((*block).size as isize)
 }
 
-impl TbfHeader {
-    /// Return whether this is an app or just padding between apps.
-    fn is_app(&self) -> bool {
-        match *self {
-            TbfHeader::TbfHeaderV1(_) => true,
-            TbfHeader::TbfHeaderV2(_) => true,
-            TbfHeader::Padding(_) => false,
-        }
-    }
-
-    /// Return whether the application is enabled or not.
-    /// Disabled applications are not started by the kernel.
-    fn enabled(&self) -> bool {
-        match *self {
-            // Header v1 has no flag for this, and therefore all apps are
-            // always enabled.
-            TbfHeader::TbfHeaderV1(_) => true,
-            TbfHeader::TbfHeaderV2(hd) => {
-                // Bit 1 of flags is the enable/disable bit.
-                hd.base.flags & 0x00000001 == 1
-            }
-            TbfHeader::Padding(_) => false,
-        }
-    }
-
-    /// Get the total size in flash of this app or padding.
-    fn get_total_size(&self) -> u32 {
-        match *self {
-            TbfHeader::TbfHeaderV1(hd) => hd.total_size,
-            TbfHeader::TbfHeaderV2(hd) => hd.base.total_size,
-            TbfHeader::Padding(hd) => hd.total_size,
-        }
-    }
-
-    /// Return whether we want the kernel to do PIC fixup for this app. If
-    /// we ever add more than one kernel PIC fixup method this would have to
-    /// get extended to support that.
-    fn needs_pic_fixup(&self) -> bool {
-        match *self {
-            TbfHeader::TbfHeaderV1(_) => true,
-            _ => false,
-        }
-    }
-
-    /// Add up all of the relevant fields in header version 1, or just used the
-    /// app provided value in version 2 to get the total amount of RAM that is
-    /// needed for this app.
-    fn get_minimum_app_ram_size(&self) -> u32 {
-        match *self {
-            TbfHeader::TbfHeaderV1(hd) => {
-                let heap_len = align8!(hd.min_app_heap_len) + align8!(hd.min_kernel_heap_len);
-                let data_len = hd.data_size + hd.got_size + hd.bss_size;
-                let stack_size = align8!(hd.min_stack_len);
-                align8!(data_len + stack_size) + heap_len
-            }
-            TbfHeader::TbfHeaderV2(hd) =>
-                hd.main.map_or(0, |m| m.minimum_ram_size),
-            _ => 0,
-        }
-    }
-
-    /// Get the number of bytes from the start of the app's region in flash that
-    /// is for kernel use only. The app cannot write this region.
-    fn get_protected_size(&self) -> u32 {
-        match *self {
-            TbfHeader::TbfHeaderV1(_) => mem::size_of::<TbfHeaderV1>() as u32,
-            TbfHeader::TbfHeaderV2(hd) =>
-                hd.main.map_or(0, |m| m.protected_size) + (hd.base.header_size as u32),
-            _ => 0,
-        }
-    }
-
-    /// Get the offset from the beginning of the app's flash region where the
-    /// app should start executing.
-    fn get_init_function_offset(&self) -> u32 {
-        match *self {
-            TbfHeader::TbfHeaderV1(hd) => hd.entry_offset,
-            TbfHeader::TbfHeaderV2(hd) =>
-                hd.main.map_or(0, |m| m.init_fn_offset) + (hd.base.header_size as u32),
-            _ => 0,
-        }
-    }
-
-    /// Get the name of the app.
-    fn get_package_name(&self, flash_start_addr: *const u8) -> &'static str {
-        match *self {
-            TbfHeader::TbfHeaderV1(hd) => {
-                unsafe {
-                    let package_name_byte_array =
-                        slice::from_raw_parts(flash_start_addr.offset(hd.pkg_name_offset as isize),
-                                              hd.pkg_name_size as usize);
-                    let mut app_name_str = "";
-                    let _ = str::from_utf8(package_name_byte_array).map(|name_str| {
-                        app_name_str = name_str;
-                    });
-                    app_name_str
-                }
-            }
-            TbfHeader::TbfHeaderV2(hd) => hd.package_name.unwrap_or(""),
-            _ => "",
-        }
-    }
-
-    /// Get the number of flash regions this app has specified in its header.
-    fn number_writeable_flash_regions(&self) -> usize {
-        match *self {
-            TbfHeader::TbfHeaderV1(_) => 0,
-            TbfHeader::TbfHeaderV2(hd) => {
-                hd.writeable_regions.map_or(0, |wr| wr.len())
-            }
-            _ => 0,
-        }
-    }
-
-    /// Get the offset and size of a given flash region.
-    fn get_writeable_flash_region(&self, index: usize) -> (u32, u32) {
-        match *self {
-            TbfHeader::TbfHeaderV1(_) => (0, 0),
-            TbfHeader::TbfHeaderV2(hd) => {
-                hd.writeable_regions.map_or((0, 0), |wr| {
-                    if wr.len() > index {
-                        (wr[index].writeable_flash_region_offset,
-                         wr[index].writeable_flash_region_size)
-                    } else {
-                        (0, 0)
-                    }
-                })
-            }
-            _ => (0, 0),
-        }
-    }
+#[derive(Copy, Clone, Debug)]
+pub struct FunctionCall {
+    pub r0: usize,
+    pub r1: usize,
+    pub r2: usize,
+    pub r3: usize,
+    pub pc: usize,
 }
 
 /// Converts a pointer to memory to a TbfHeader struct
@@ -435,144 +269,29 @@ impl TbfHeader {
 /// This function takes a pointer to arbitrary memory and optionally returns a
 /// TBF header struct. This function will validate the header checksum, but does
 /// not perform sanity or security checking on the structure.
-unsafe fn parse_and_validate_tbf_header(address: *const u8) -> Option<TbfHeader> {
-    let version = *(address as *const u16);
-
-    match version {
-        1 => {
-            let tbf_header = &*(address as *const TbfHeaderV1);
-
-            let checksum =
-                tbf_header.version             ^ tbf_header.total_size      ^ tbf_header.entry_offset ^
-                tbf_header.rel_data_offset     ^ tbf_header.rel_data_size   ^ tbf_header.text_offset ^
-                tbf_header.text_size           ^ tbf_header.got_offset      ^ tbf_header.got_size ^
-                tbf_header.data_offset         ^ tbf_header.data_size       ^ tbf_header.bss_mem_offset ^
-                tbf_header.bss_size            ^ tbf_header.min_stack_len   ^ tbf_header.min_app_heap_len ^
-                tbf_header.min_kernel_heap_len ^ tbf_header.pkg_name_offset ^ tbf_header.pkg_name_size;
-
-            if checksum != tbf_header.checksum {
-                None
-            } else {
-                Some(TbfHeader::TbfHeaderV1(tbf_header))
-            }
-        }
-
-        2 => {
-            let tbf_header_base = &*(address as *const TbfHeaderV2Base);
-
-            // Some sanity checking. Make sure the header isn't longer than the
-            // total app. Make sure the total app fits inside a reasonable size
-            // of flash.
-            if tbf_header_base.header_size as u32 >= tbf_header_base.total_size ||
-               tbf_header_base.total_size > 0x010000000 {
-                return None;
-            }
-
-            // Calculate checksum. The checksum is the XOR of each 4 byte word
-            // in the header.
-            let mut chunks = tbf_header_base.header_size as usize / 4;
-            let mut leftover_bytes = 0;
-            if chunks * 4 != tbf_header_base.header_size as usize {
-                chunks += 1;
-                leftover_bytes = tbf_header_base.header_size as usize - (chunks * 4);
-            }
-            let mut checksum: u32 = 0;
-            let header = slice::from_raw_parts(address as *const u32, chunks);
-            for (i, chunk) in header.iter().enumerate() {
-                if i == 3 {
-                    // Skip the checksum field.
-                } else if i == chunks - 1 && leftover_bytes != 0 {
-                    // In this case, we don't want to use the entire word.
-                    checksum ^= *chunk & (0xFFFFFFFF >> (4 - leftover_bytes));
-                } else {
-                    checksum ^= *chunk;
-                }
-            }
-
-            if checksum != tbf_header_base.checksum {
-                return None;
-            }
-
-            // Skip the base of the header.
-            let mut offset = mem::size_of::<TbfHeaderV2Base>() as isize;
-            let mut remaining_length = tbf_header_base.header_size as usize - offset as usize;
-
-            // Check if this is a real app or just padding. Padding apps are
-            // identified by not having any options.
-            if remaining_length == 0 {
-                // Just padding.
-                if checksum == tbf_header_base.checksum {
-                    Some(TbfHeader::Padding(tbf_header_base))
-                } else {
-                    None
-                }
-
-            } else {
-                // This is an actual app.
-
-                // Places to save fields that we parse out of the header
-                // options.
-                let mut main_pointer: Option<&TbfHeaderV2Main> = None;
-                let mut wfr_pointer: Option<&'static [TbfHeaderV2WriteableFlashRegion]> = None;
-                let mut app_name_str = "";
-
-                // Loop through the header looking for known options.
-                while remaining_length > mem::size_of::<TbfHeaderTlv>() {
-                    let tbf_tlv_header = &*(address.offset(offset) as *const TbfHeaderTlv);
-
-                    remaining_length -= mem::size_of::<TbfHeaderTlv>();
-                    offset += mem::size_of::<TbfHeaderTlv>() as isize;
-
-                    // Only parse known TLV blocks. There is no type 0.
-                    if (tbf_tlv_header.tipe as u16) < TbfHeaderTypes::Unused as u16 && (tbf_tlv_header.tipe as u16) > 0 {
-                        // This lets us skip unknown header types.
-
-                        match tbf_tlv_header.tipe {
-                            TbfHeaderTypes::TbfHeaderMain => /* Main */ {
-                                if remaining_length >= mem::size_of::<TbfHeaderV2Main>() &&
-                                   tbf_tlv_header.length as usize == mem::size_of::<TbfHeaderV2Main>() {
-                                    let tbf_main = &*(address.offset(offset) as *const TbfHeaderV2Main);
-                                    main_pointer = Some(tbf_main);
-                                }
-                            }
-                            TbfHeaderTypes::TbfHeaderWriteableFlashRegions => /* Writeable Flash Regions */ {
-                                // Length must be a multiple of the size of a region definition.
-                                if tbf_tlv_header.length as usize % mem::size_of::<TbfHeaderV2WriteableFlashRegion>() == 0 {
-                                    let number_regions = tbf_tlv_header.length as usize / mem::size_of::<TbfHeaderV2WriteableFlashRegion>();
-                                    let region_start = &*(address.offset(offset) as *const TbfHeaderV2WriteableFlashRegion);
-                                    let regions = slice::from_raw_parts(region_start, number_regions);
-                                    wfr_pointer = Some(regions);
-                                }
-                            }
-                            TbfHeaderTypes::TbfHeaderPackageName => /* Package Name */ {
-                                if remaining_length >= tbf_tlv_header.length as usize {
-                                    let package_name_byte_array =
-                                        slice::from_raw_parts(address.offset(offset), tbf_tlv_header.length as usize);
-                                    let _ = str::from_utf8(package_name_byte_array).map(|name_str| { app_name_str = name_str; });
-                                }
-                            }
-                            TbfHeaderTypes::Unused => {}
-                        }
-                    }
-
-                    // All TLV blocks are padded to 4 bytes, so we need to skip
-                    // more if the length is not a multiple of 4.
-                    remaining_length -= align4!(tbf_tlv_header.length) as usize;
-                    offset += align4!(tbf_tlv_header.length) as isize;
-                }
-
-                let tbf_header = TbfHeaderV2 {
-                    base: tbf_header_base,
-                    main: main_pointer,
-                    package_name: Some(app_name_str),
-                    writeable_regions: wfr_pointer,
-                };
+unsafe fn parse_and_validate_tbf_header(address: *const u8) -> Option<TbfHeader<'static>> {
+    // The first word of the header says how long the whole header is.
+    let header_length = match tbf::header_length(slice::from_raw_parts(address, 4)) {
+        Ok(length) => length,
+        Err(_) => return None,
+    };
+    let header = slice::from_raw_parts(address, header_length);
+    tbf::parse_tbf_header(header).ok()
+}
 
-                Some(TbfHeader::TbfHeaderV2(tbf_header))
-            }
+/// Get the name of the app whose header is at `flash_start_addr`.
+unsafe fn get_package_name(tbf_header: &TbfHeader<'static>,
+                           flash_start_addr: *const u8)
+                           -> &'static str {
+    match *tbf_header {
+        // Version 1 headers point to the name elsewhere in the app binary.
+        TbfHeader::TbfHeaderV1(hd) => {
+            let package_name_byte_array =
+                slice::from_raw_parts(flash_start_addr.offset(hd.pkg_name_offset as isize),
+                                      hd.pkg_name_size as usize);
+            str::from_utf8(package_name_byte_array).unwrap_or("")
         }
-
-        _ => None
+        _ => tbf_header.get_package_name().unwrap_or(""),
     }
 }
 
@@ -604,6 +323,13 @@ struct ProcessDebug {
     /// How low have we ever seen the stack pointer.
     min_stack_pointer: *const u8,
 
+    /// How high has the app break (end of the heap) ever been.
+    max_app_break: *const u8,
+
+    /// How low has the kernel memory break (start of the grant region) ever
+    /// been.
+    min_kernel_memory_break: *const u8,
+
     /// How many syscalls have occurred since the process started.
     syscall_count: Cell<usize>,
 
@@ -641,6 +367,24 @@ pub struct Process<'a> {
     /// Pointer to the end of the allocated (and MPU protected) grant region.
     kernel_memory_break: *const u8,
 
+    /// Pointer to the top of the part of the grant region used for grant
+    /// allocations. Above it are the grant pointers, grant usage counters and
+    /// the callback queue, which live as long as the process.
+    grant_allocations_start: *const u8,
+
+    /// Grant allocations that have been freed and can be reused, sorted by
+    /// address. Adjacent free blocks are always merged.
+    grant_free_list: *mut FreeBlock,
+
+    /// Incremented every time all grant memory is freed, so allocations made
+    /// before can be recognized.
+    grant_generation: usize,
+
+    /// Number of bytes allocated in the grant region for each grant, indexed
+    /// by grant number. This includes the grant's root structure and anything
+    /// allocated through its `Allocator`.
+    grant_bytes: &'a mut [usize],
+
     /// Pointer to the end of process RAM that has been sbrk'd to the process.
     app_break: *const u8,
 
@@ -651,7 +395,7 @@ pub struct Process<'a> {
     text: &'static [u8],
 
     /// Collection of pointers to the TBF header in flash.
-    header: TbfHeader,
+    header: TbfHeader<'static>,
 
     /// Saved each time the app switches to the kernel.
     stored_regs: StoredRegs,
@@ -688,6 +432,14 @@ pub struct Process<'a> {
     /// Name of the app. Public so that IPC can use it.
     pub package_name: &'static str,
 
+    /// Total time the process has spent running on the CPU, in microseconds.
+    cpu_time_us: u64,
+
+    /// Offset and length of the app's nonvolatile storage region, relative to
+    /// the storage available to userspace. Assigned by `load_processes()`.
+    storage_offset: usize,
+    storage_size: usize,
+
     /// Values kept so that we can print useful debug messages when apps fault.
     debug: ProcessDebug,
 }
@@ -744,6 +496,7 @@ impl<'a> Process<'a> {
 
     pub unsafe fn fault_state(&mut self) {
         write_volatile(&mut APP_FAULT, 0);
+        let was_running = self.state == State::Running;
         self.state = State::Fault;
 
         match self.fault_response {
@@ -752,14 +505,7 @@ impl<'a> Process<'a> {
                 panic!("Process {} had a fault", self.package_name);
             }
             FaultResponse::Restart => {
-                //XXX: unimplemented
-                panic!("Process {} had a fault and could not be restarted",
-                       self.package_name);
-                /*
-                // HAVE_WORK is really screwed up in this case
-                // the tasks ring buffer needs to be cleared
-                // need to re-load() the app
-                 */
+                self.restart(was_running);
             }
         }
     }
@@ -797,6 +543,39 @@ impl<'a> Process<'a> {
         self.kernel_memory_break
     }
 
+    /// Number of bytes grant `grant_num` has allocated for this process, or
+    /// `None` if there is no such grant.
+    pub fn grant_bytes(&self, grant_num: usize) -> Option<usize> {
+        self.grant_bytes.get(grant_num).map(|bytes| *bytes)
+    }
+
+    /// Current and peak number of bytes used by a region of process memory, or
+    /// `None` if the kernel does not know where the region starts.
+    pub fn memory_usage(&self, region: MemoryRegion) -> Option<(usize, usize)> {
+        match region {
+            MemoryRegion::Stack => self.debug.app_stack_start_pointer.map(|stack_start| {
+                let stack_start = stack_start as usize;
+                (stack_start.saturating_sub(self.current_stack_pointer as usize),
+                 stack_start.saturating_sub(self.debug.min_stack_pointer as usize))
+            }),
+            MemoryRegion::Heap => self.debug.app_heap_start_pointer.map(|heap_start| {
+                let heap_start = heap_start as usize;
+                ((self.app_break as usize).saturating_sub(heap_start),
+                 (self.debug.max_app_break as usize).saturating_sub(heap_start))
+            }),
+            MemoryRegion::Grant => {
+                let mem_end = self.mem_end() as usize;
+                Some((mem_end - self.kernel_memory_break as usize,
+                      mem_end - self.debug.min_kernel_memory_break as usize))
+            }
+        }
+    }
+
+    /// The version of the app, if its TBF header specifies one.
+    pub fn app_version(&self) -> Option<TbfHeaderV2AppVersion> {
+        self.header.get_app_version()
+    }
+
     pub fn number_writeable_flash_regions(&self) -> usize {
         self.header.number_writeable_flash_regions()
     }
@@ -923,9 +702,21 @@ impl<'a> Process<'a> {
                 return (None, app_flash_size, 0);
             }
 
+            // Apps built for a syscall ABI this kernel does not implement
+            // would misbehave, so skip them as well.
+            let package_name = get_package_name(&tbf_header, app_flash_address);
+            if !tbf_header.supports_kernel_abi(SYSCALL_ABI_VERSION) {
+                let abi = tbf_header.get_kernel_abi().unwrap_or_default();
+                debug!("Skipping app {:?}: needs kernel ABI {} to {}, kernel has {}",
+                       package_name,
+                       abi.minimum_abi_version,
+                       abi.maximum_abi_version,
+                       SYSCALL_ABI_VERSION);
+                return (None, app_flash_size, 0);
+            }
+
             // Otherwise, actually load the app.
             let mut min_app_ram_size = tbf_header.get_minimum_app_ram_size();
-            let package_name = tbf_header.get_package_name(app_flash_address);
             let init_fn = app_flash_address.offset(tbf_header.get_init_function_offset() as isize) as usize;
             let needs_pic_fixup = tbf_header.needs_pic_fixup();
 
@@ -942,6 +733,9 @@ impl<'a> Process<'a> {
                 let grant_ptrs_num = read_volatile(&grant::CONTAINER_COUNTER);
                 let grant_ptrs_offset = grant_ptrs_num * grant_ptr_size;
 
+                // Make room for the per grant usage counters.
+                let grant_bytes_offset = grant_ptrs_num * mem::size_of::<usize>();
+
                 // Allocate memory for callback ring buffer.
                 let callback_size = mem::size_of::<Task>();
                 let callback_len = 10;
@@ -949,8 +743,9 @@ impl<'a> Process<'a> {
 
                 // Need to make sure that the amount of memory we allocate for
                 // this process at least covers this state.
-                if min_app_ram_size < (grant_ptrs_offset + callbacks_offset) as u32 {
-                    min_app_ram_size = (grant_ptrs_offset + callbacks_offset) as u32;
+                let kernel_state_size = grant_ptrs_offset + grant_bytes_offset + callbacks_offset;
+                if min_app_ram_size < kernel_state_size as u32 {
+                    min_app_ram_size = kernel_state_size as u32;
                 }
 
                 // TODO round app_ram_size up to a closer MPU unit.
@@ -983,6 +778,14 @@ impl<'a> Process<'a> {
                     *opt = ptr::null()
                 }
 
+                // And the grant usage counters to zero.
+                kernel_memory_break = kernel_memory_break.offset(-(grant_bytes_offset as isize));
+                let grant_bytes = slice::from_raw_parts_mut(kernel_memory_break as *mut usize,
+                                                            grant_ptrs_num);
+                for bytes in grant_bytes.iter_mut() {
+                    *bytes = 0;
+                }
+
                 // Now that we know we have the space we can setup the memory
                 // for the callbacks.
                 kernel_memory_break = kernel_memory_break.offset(-(callbacks_offset as isize));
@@ -992,6 +795,9 @@ impl<'a> Process<'a> {
                                                              callback_len);
                 let tasks = RingBuffer::new(callback_buf);
 
+                // Grant allocations start below this, keep them aligned.
+                kernel_memory_break = ((kernel_memory_break as usize) & !0x7) as *mut u8;
+
                 // Determine the debug information to the best of our
                 // understanding. If the app is doing all of the PIC fixup and
                 // memory management we don't know much.
@@ -1008,6 +814,10 @@ impl<'a> Process<'a> {
                     header: load_result.header,
 
                     kernel_memory_break: kernel_memory_break,
+                    grant_allocations_start: kernel_memory_break,
+                    grant_free_list: ptr::null_mut(),
+                    grant_generation: 0,
+                    grant_bytes: grant_bytes,
                     app_break: load_result.initial_sbrk_pointer,
                     current_stack_pointer: load_result.initial_stack_pointer,
 
@@ -1029,10 +839,17 @@ impl<'a> Process<'a> {
                     tasks: tasks,
                     package_name: package_name,
 
+                    cpu_time_us: 0,
+
+                    storage_offset: 0,
+                    storage_size: tbf_header.get_nonvolatile_storage_size(),
+
                     debug: ProcessDebug {
                         app_heap_start_pointer: app_heap_start_pointer,
                         app_stack_start_pointer: app_stack_start_pointer,
                         min_stack_pointer: load_result.initial_stack_pointer,
+                        max_app_break: load_result.initial_sbrk_pointer,
+                        min_kernel_memory_break: kernel_memory_break,
                         syscall_count: Cell::new(0),
                         last_syscall: Cell::new(None),
                     }
@@ -1045,16 +862,8 @@ impl<'a> Process<'a> {
                            init_fn);
                 }
 
-                let flash_protected_size = process.header.get_protected_size() as usize;
-                let flash_app_start = app_flash_address as usize + flash_protected_size;
-
-                process.tasks.enqueue(Task::FunctionCall(FunctionCall {
-                    pc: init_fn,
-                    r0: flash_app_start,
-                    r1: process.memory.as_ptr() as usize,
-                    r2: process.memory.len() as usize,
-                    r3: process.app_break as usize,
-                }));
+                let init_call = process.init_function_call();
+                process.tasks.enqueue(Task::FunctionCall(init_call));
 
                 HAVE_WORK.set(HAVE_WORK.get() + 1);
 
@@ -1064,6 +873,56 @@ impl<'a> Process<'a> {
         (None, 0, 0)
     }
 
+    /// The call to the process entry point that starts the process.
+    fn init_function_call(&self) -> FunctionCall {
+        let flash_start = self.text.as_ptr() as usize;
+        let flash_protected_size = self.header.get_protected_size() as usize;
+
+        FunctionCall {
+            pc: flash_start + self.header.get_init_function_offset() as usize,
+            r0: flash_start + flash_protected_size,
+            r1: self.memory.as_ptr() as usize,
+            r2: self.memory.len() as usize,
+            r3: self.app_break as usize,
+        }
+    }
+
+    /// Restart the process from its entry point. Everything queued for the
+    /// old instance is dropped and all of its grant memory is freed.
+    unsafe fn restart(&mut self, was_running: bool) {
+        if was_running {
+            HAVE_WORK.set(HAVE_WORK.get() - 1);
+        }
+        while self.dequeue_task().is_some() {}
+
+        self.free_grants();
+
+        for region in self.mpu_regions.iter() {
+            region.set((ptr::null(), math::PowerOfTwo::zero()));
+        }
+
+        if let Some(load_result) = load(self.header, self.memory.as_mut_ptr()) {
+            self.app_break = load_result.initial_sbrk_pointer;
+            self.current_stack_pointer = load_result.initial_stack_pointer;
+            self.debug.min_stack_pointer = load_result.initial_stack_pointer;
+            self.debug.max_app_break = load_result.initial_sbrk_pointer;
+            if !self.header.needs_pic_fixup() {
+                self.debug.app_heap_start_pointer = None;
+                self.debug.app_stack_start_pointer = None;
+            }
+        }
+
+        let init_call = self.init_function_call();
+        self.stored_regs = Default::default();
+        self.yield_pc = init_call.pc;
+        // Set the Thumb bit and clear everything else
+        self.psr = 0x01000000;
+        self.state = State::Yielded;
+
+        self.tasks.enqueue(Task::FunctionCall(init_call));
+        HAVE_WORK.set(HAVE_WORK.get() + 1);
+    }
+
     pub fn sbrk(&mut self, increment: isize) -> Result<*const u8, Error> {
         let new_break = unsafe { self.app_break.offset(increment) };
         self.brk(new_break)
@@ -1077,6 +936,9 @@ impl<'a> Process<'a> {
         } else {
             let old_break = self.app_break;
             self.app_break = new_break;
+            if new_break > self.debug.max_app_break {
+                self.debug.max_app_break = new_break;
+            }
             Ok(old_break)
         }
     }
@@ -1088,17 +950,137 @@ impl<'a> Process<'a> {
         buf_start_addr >= self.mem_start() && buf_end_addr <= self.mem_end()
     }
 
-    pub unsafe fn alloc(&mut self, size: usize) -> Option<&mut [u8]> {
-        let new_break = self.kernel_memory_break.offset(-(size as isize));
-        if new_break < self.app_break {
-            None
+    /// Allocate `size` bytes in the grant region on behalf of grant
+    /// `grant_num`. Freed memory is reused if possible, otherwise the grant
+    /// region grows down towards the app break.
+    pub unsafe fn alloc(&mut self, size: usize, grant_num: usize) -> Option<&mut [u8]> {
+        let size = grant_alloc_size(size);
+        let block = match self.take_free_block(size) {
+            Some(block) => block,
+            None => {
+                let new_break = self.kernel_memory_break.offset(-(size as isize));
+                if new_break < self.app_break {
+                    return None;
+                }
+                self.kernel_memory_break = new_break;
+                if new_break < self.debug.min_kernel_memory_break {
+                    self.debug.min_kernel_memory_break = new_break;
+                }
+                new_break as *mut u8
+            }
+        };
+
+        if grant_num < self.grant_bytes.len() {
+            self.grant_bytes[grant_num] += size;
+        }
+        Some(slice::from_raw_parts_mut(block, size))
+    }
+
+    /// Return memory allocated with `alloc()` for grant `grant_num`. The
+    /// memory is zeroed so nothing leaks to its next user.
+    pub unsafe fn free(&mut self, data: *mut u8, size: usize, grant_num: usize) {
+        let size = grant_alloc_size(size);
+        let start = data as *const u8;
+        if start < self.kernel_memory_break
+            || start.offset(size as isize) > self.grant_allocations_start
+        {
+            // Not grant memory of this process.
+            return;
+        }
+
+        if grant_num < self.grant_bytes.len() {
+            self.grant_bytes[grant_num] = self.grant_bytes[grant_num].saturating_sub(size);
+        }
+
+        ptr::write_bytes(data, 0, size);
+        self.insert_free_block(data as *mut FreeBlock, size);
+        self.release_free_block_at_break();
+    }
+
+    /// Put a block of `size` bytes on the free list, and merge it with the
+    /// free blocks directly below and above it.
+    unsafe fn insert_free_block(&mut self, block: *mut FreeBlock, size: usize) {
+        let mut prev: *mut FreeBlock = ptr::null_mut();
+        let mut next = self.grant_free_list;
+        while !next.is_null() && next < block {
+            prev = next;
+            next = (*next).next;
+        }
+
+        (*block).size = size;
+        (*block).next = next;
+        if prev.is_null() {
+            self.grant_free_list = block;
         } else {
-            self.kernel_memory_break = new_break;
-            Some(slice::from_raw_parts_mut(new_break as *mut u8, size))
+            (*prev).next = block;
+        }
+
+        if !next.is_null() && free_block_end(block) == next as *mut u8 {
+            (*block).size += (*next).size;
+            (*block).next = (*next).next;
+            ptr::write_bytes(next as *mut u8, 0, mem::size_of::<FreeBlock>());
+        }
+        if !prev.is_null() && free_block_end(prev) == block as *mut u8 {
+            (*prev).size += (*block).size;
+            (*prev).next = (*block).next;
+            ptr::write_bytes(block as *mut u8, 0, mem::size_of::<FreeBlock>());
+        }
+    }
+
+    /// Find a free block of at least `size` bytes, first fit. Larger blocks
+    /// are split and the remainder stays on the free list.
+    unsafe fn take_free_block(&mut self, size: usize) -> Option<*mut u8> {
+        let mut link = &mut self.grant_free_list as *mut *mut FreeBlock;
+        while !(*link).is_null() {
+            let block = *link;
+            if (*block).size == size {
+                *link = (*block).next;
+                ptr::write_bytes(block as *mut u8, 0, mem::size_of::<FreeBlock>());
+                return Some(block as *mut u8);
+            } else if (*block).size > size {
+                // Hand out the top of the block, the header stays in place.
+                (*block).size -= size;
+                return Some((block as *mut u8).offset((*block).size as isize));
+            }
+            link = &mut (*block).next as *mut *mut FreeBlock;
+        }
+        None
+    }
+
+    /// Give a free block at the bottom of the grant region back to the
+    /// unallocated memory between the app break and the grant region. Free
+    /// blocks are merged, so there is at most one, and it is the first on the
+    /// list.
+    unsafe fn release_free_block_at_break(&mut self) {
+        let block = self.grant_free_list;
+        if !block.is_null() && block as *const u8 == self.kernel_memory_break {
+            self.grant_free_list = (*block).next;
+            self.kernel_memory_break = self.kernel_memory_break.offset((*block).size as isize);
+            ptr::write_bytes(block as *mut u8, 0, mem::size_of::<FreeBlock>());
         }
     }
 
-    pub unsafe fn free<T>(&mut self, _: *mut T) {}
+    /// Free and zero all grant memory of the process. Capsules get a freshly
+    /// initialized grant the next time they enter it for this process.
+    pub unsafe fn free_grants(&mut self) {
+        let start = self.kernel_memory_break as *mut u8;
+        let len = self.grant_allocations_start as usize - start as usize;
+        ptr::write_bytes(start, 0, len);
+
+        self.kernel_memory_break = self.grant_allocations_start;
+        self.grant_free_list = ptr::null_mut();
+        self.grant_generation = self.grant_generation.wrapping_add(1);
+        for grant_num in 0..self.grant_bytes.len() {
+            self.grant_bytes[grant_num] = 0;
+            write_volatile(self.grant_ptr::<u8>(grant_num), ptr::null_mut());
+        }
+    }
+
+    /// Identifies the grant allocations made since grant memory was last freed
+    /// as a whole. `Owned` values from before are no longer valid.
+    pub fn grant_generation(&self) -> usize {
+        self.grant_generation
+    }
 
     unsafe fn grant_ptr<T>(&self, grant_num: usize) -> *mut *mut T {
         let grant_num = grant_num as isize;
@@ -1114,7 +1096,7 @@ impl<'a> Process<'a> {
                                                      -> Option<*mut T> {
         let ctr_ptr = self.grant_ptr::<T>(grant_num);
         if (*ctr_ptr).is_null() {
-            self.alloc(mem::size_of::<T>()).map(|root_arr| {
+            self.alloc(mem::size_of::<T>(), grant_num).map(|root_arr| {
                 let root_ptr = root_arr.as_mut_ptr() as *mut T;
                 // Initialize the grant contents using ptr::write, to
                 // ensure that we don't try to drop the contents of
@@ -1209,6 +1191,15 @@ impl<'a> Process<'a> {
         self.debug.last_syscall.set(self.svc_number());
     }
 
+    /// Account for `us` microseconds the process just spent running.
+    pub fn incr_cpu_time(&mut self, us: u32) {
+        self.cpu_time_us += us as u64;
+    }
+
+    pub fn cpu_time_us(&self) -> u64 {
+        self.cpu_time_us
+    }
+
     pub fn sp(&self) -> usize {
         self.current_stack_pointer as usize
     }
@@ -1458,6 +1449,7 @@ impl<'a> Process<'a> {
         let events_queued = self.tasks.len();
         let syscall_count = self.debug.syscall_count.get();
         let last_syscall = self.debug.last_syscall.get();
+        let cpu_time_ms = self.cpu_time_us / 1000;
 
         // register values
         let (r0, r1, r2, r3, r12, sp, lr, pc, xpsr) = (self.r0(),
@@ -1470,11 +1462,16 @@ impl<'a> Process<'a> {
                                                        self.pc(),
                                                        self.xpsr());
 
-        let _ = writer.write_fmt(format_args!("\
-        App: {}   -   [{:?}]\
+        let _ = writer.write_fmt(format_args!("App: {}", self.package_name));
+        if let Some(version) = self.app_version() {
+            let _ = writer.write_fmt(format_args!(" v{}", version));
+        }
+
+        let _ = writer.write_fmt(format_args!("   -   [{:?}]\
+        \r\n CPU Time: {} ms\
         \r\n Events Queued: {}   Syscall Count: {}   ",
-                                              self.package_name,
                                               self.state,
+                                              cpu_time_ms,
                                               events_queued,
                                               syscall_count,
                                               ));
@@ -1548,6 +1545,13 @@ impl<'a> Process<'a> {
   pc,
   self.yield_pc,
   ));
+        let _ = writer.write_fmt(format_args!(" Grant Usage (bytes):"));
+        for (grant_num, bytes) in self.grant_bytes.iter().enumerate() {
+            if *bytes > 0 {
+                let _ = writer.write_fmt(format_args!(" [{}] {}", grant_num, bytes));
+            }
+        }
+        let _ = writer.write_fmt(format_args!("\r\n"));
         let _ = writer.write_fmt(format_args!("\
         \r\n APSR: N {} Z {} C {} V {} Q {}\
         \r\n       GE {} {} {} {}",
@@ -1589,7 +1593,7 @@ struct LoadResult {
     initial_sbrk_pointer: *const u8,
 
     // Pass the header back to the caller.
-    header: TbfHeader,
+    header: TbfHeader<'static>,
 }
 
 /// Loads the process into memory
@@ -1610,7 +1614,7 @@ struct LoadResult {
 ///
 /// The function returns a `LoadResult` containing metadata about the loaded
 /// process or None if loading failed.
-unsafe fn load(tbf_header: TbfHeader,
+unsafe fn load(tbf_header: TbfHeader<'static>,
                mem_base: *mut u8)
                -> Option<LoadResult> {
     if tbf_header.needs_pic_fixup() {
@@ -1,5 +1,6 @@
 //! Tock core scheduler.
 
+use common::deferred_call;
 use core::nonzero::NonZero;
 use core::ptr;
 use memop;
@@ -29,7 +30,7 @@ pub unsafe fn do_process<P: Platform, C: Chip>(
     systick.enable(true);
 
     loop {
-        if chip.has_pending_interrupts() || systick.overflowed()
+        if chip.has_pending_interrupts() || deferred_call::has_tasks() || systick.overflowed()
             || systick.value() <= MIN_QUANTA_THRESHOLD_US
         {
             break;
@@ -40,8 +41,10 @@ pub unsafe fn do_process<P: Platform, C: Chip>(
                 process.setup_mpu(chip.mpu());
                 chip.mpu().enable_mpu();
                 systick.enable(true);
+                let time_left = systick.value();
                 process.switch_to();
                 systick.enable(false);
+                process.incr_cpu_time(time_left.saturating_sub(systick.value()));
                 chip.mpu().disable_mpu();
             }
             process::State::Yielded => match process.dequeue_task() {
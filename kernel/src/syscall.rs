@@ -19,3 +19,11 @@ pub enum Syscall {
     /// Various memory operations.
     MEMOP = 4,
 }
+
+/// Version of the syscall ABI this kernel implements.
+///
+/// Apps can list the range of ABI versions they work with in their TBF
+/// header, and the kernel does not start apps that do not support this
+/// version. Bump this whenever the syscall interface changes in a way that
+/// breaks existing apps.
+pub const SYSCALL_ABI_VERSION: u32 = 1;
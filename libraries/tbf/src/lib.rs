@@ -0,0 +1,121 @@
+//! Tock Binary Format (TBF) headers.
+//!
+//! Every app in flash starts with a TBF header that tells the kernel how large
+//! the app is, where its entry point is and how much RAM it needs. Version 2
+//! headers consist of a fixed base followed by a list of type-length-value
+//! (TLV) entries. Version 1 headers are deprecated but can still be parsed.
+//!
+//! All multi-byte fields are little endian. All parsing and serialization is
+//! bounds checked, so arbitrary bytes can be passed to `parse_tbf_header()`.
+//!
+//! Usage
+//! -----
+//!
+//! ```rust
+//! # fn run() -> Result<(), tbf::Error> {
+//! // The start of an app in flash: a header with only a Main TLV.
+//! let flash: [u8; 32] = [
+//!     0x02, 0x00, 0x20, 0x00, // version, header_size
+//!     0x00, 0x04, 0x00, 0x00, // total_size
+//!     0x01, 0x00, 0x00, 0x00, // flags
+//!     0x02, 0x14, 0x2C, 0x00, // checksum
+//!     0x01, 0x00, 0x0C, 0x00, // Main TLV
+//!     0x00, 0x00, 0x00, 0x00, // init_fn_offset
+//!     0x00, 0x00, 0x00, 0x00, // protected_size
+//!     0x00, 0x10, 0x00, 0x00, // minimum_ram_size
+//! ];
+//!
+//! let length = tbf::header_length(&flash[..4])?;
+//! let header = tbf::parse_tbf_header(&flash[..length])?;
+//! assert!(header.is_app() && header.enabled());
+//! assert_eq!(header.get_init_function_offset(), 32);
+//! assert_eq!(header.get_minimum_app_ram_size(), 0x1000);
+//!
+//! // Serializing the header gives back the same bytes.
+//! let mut buf = [0; 32];
+//! assert_eq!(header.serialize(&mut buf)?, 32);
+//! assert_eq!(buf, flash);
+//! # Ok(())
+//! # }
+//! # fn main() {
+//! #     run().unwrap();
+//! # }
+//! ```
+
+#![forbid(unsafe_code)]
+#![no_std]
+
+/// Takes a value and rounds it up to be aligned % 4
+macro_rules! align4 {
+    ( $e:expr ) => ( ($e) + ((4 - (($e) % 4)) % 4 ) );
+}
+
+/// Takes a value and rounds it up to be aligned % 8
+macro_rules! align8 {
+    ( $e:expr ) => ( ($e) + ((8 - (($e) % 8)) % 8 ) );
+}
+
+mod parse;
+mod types;
+
+pub use parse::{checksum, header_length, parse_tbf_header, TbfHeader, TbfHeaderV2, Tlv, TlvIter,
+                WriteableFlashRegions};
+pub use types::{PicOption1Fields, TbfHeaderTlv, TbfHeaderTypes, TbfHeaderV1,
+                TbfHeaderV2AppVersion, TbfHeaderV2Base, TbfHeaderV2KernelAbi, TbfHeaderV2Main,
+                TbfHeaderV2NonvolatileStorage, TbfHeaderV2WriteableFlashRegion};
+
+/// Errors that can occur while parsing or serializing a header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The buffer is too small to parse or serialize the structure.
+    BufferTooSmall,
+    /// The header has a version this crate does not know about.
+    UnsupportedVersion(u16),
+    /// `header_size` is smaller than the header base or not smaller than
+    /// `total_size`.
+    InvalidHeaderSize,
+    /// `total_size` is larger than the kernel allows for an app.
+    InvalidTotalSize,
+    /// The checksum stored in the header does not match its contents.
+    ChecksumMismatch { stored: u32, computed: u32 },
+    /// A TLV extends past the end of the header.
+    TlvOutOfBounds,
+    /// The header is too large for its 16 bit `header_size`, or a TLV for
+    /// its 16 bit length.
+    HeaderTooLarge,
+}
+
+/// The largest `total_size` of an app.
+pub const MAX_TOTAL_SIZE: u32 = 0x10000000;
+
+fn read_u16(buf: &[u8], offset: usize) -> Result<u16, Error> {
+    buf.get(offset..offset + 2)
+        .map(|b| (b[0] as u16) | (b[1] as u16) << 8)
+        .ok_or(Error::BufferTooSmall)
+}
+
+fn read_u32(buf: &[u8], offset: usize) -> Result<u32, Error> {
+    buf.get(offset..offset + 4)
+        .map(|b| (b[0] as u32) | (b[1] as u32) << 8 | (b[2] as u32) << 16 | (b[3] as u32) << 24)
+        .ok_or(Error::BufferTooSmall)
+}
+
+fn write_u16(buf: &mut [u8], offset: usize, value: u16) -> Result<(), Error> {
+    buf.get_mut(offset..offset + 2)
+        .map(|b| {
+            b[0] = value as u8;
+            b[1] = (value >> 8) as u8;
+        })
+        .ok_or(Error::BufferTooSmall)
+}
+
+fn write_u32(buf: &mut [u8], offset: usize, value: u32) -> Result<(), Error> {
+    buf.get_mut(offset..offset + 4)
+        .map(|b| {
+            b[0] = value as u8;
+            b[1] = (value >> 8) as u8;
+            b[2] = (value >> 16) as u8;
+            b[3] = (value >> 24) as u8;
+        })
+        .ok_or(Error::BufferTooSmall)
+}
@@ -0,0 +1,551 @@
+//! Parsing and validation of complete TBF headers.
+
+use core::str;
+use types::{PicOption1Fields, TbfHeaderTlv, TbfHeaderTypes, TbfHeaderV1, TbfHeaderV2AppVersion,
+            TbfHeaderV2Base, TbfHeaderV2KernelAbi, TbfHeaderV2Main, TbfHeaderV2NonvolatileStorage,
+            TbfHeaderV2WriteableFlashRegion};
+use {read_u16, Error, MAX_TOTAL_SIZE};
+
+/// A single TLV entry of a version 2 header. `data` does not include the
+/// padding that aligns the next entry to four bytes.
+#[derive(Clone, Copy, Debug)]
+pub struct Tlv<'a> {
+    pub header: TbfHeaderTlv,
+    pub data: &'a [u8],
+}
+
+impl<'a> Tlv<'a> {
+    /// A TLV of type `tipe` holding `data`.
+    pub fn new(tipe: TbfHeaderTypes, data: &'a [u8]) -> Result<Tlv<'a>, Error> {
+        if data.len() > 0xFFFF {
+            return Err(Error::HeaderTooLarge);
+        }
+        Ok(Tlv {
+            header: TbfHeaderTlv::new(tipe, data.len() as u16),
+            data,
+        })
+    }
+
+    /// The number of bytes the entry occupies in a header, including padding.
+    pub fn serialized_len(&self) -> usize {
+        TbfHeaderTlv::SIZE + align4!(self.data.len())
+    }
+
+    /// Serialize the entry followed by the zeros that pad it to four bytes.
+    /// `header.length` must be the length of `data`.
+    pub fn serialize(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let size = self.serialized_len();
+        let buf = buf.get_mut(..size).ok_or(Error::BufferTooSmall)?;
+        self.header.serialize(buf)?;
+        let (data, padding) = buf[TbfHeaderTlv::SIZE..].split_at_mut(self.data.len());
+        data.copy_from_slice(self.data);
+        for byte in padding.iter_mut() {
+            *byte = 0;
+        }
+        Ok(size)
+    }
+}
+
+/// Iterator over the TLV entries of a version 2 header.
+///
+/// Iteration stops once four or fewer bytes are left, as those cannot hold a
+/// TLV with any content. An entry that extends past the end of the header is
+/// returned as `Error::TlvOutOfBounds` and ends the iteration.
+pub struct TlvIter<'a> {
+    remaining: &'a [u8],
+}
+
+impl<'a> TlvIter<'a> {
+    /// Iterate over the TLVs in `header`, which must be the complete header
+    /// including the base.
+    pub fn new(header: &'a [u8]) -> TlvIter<'a> {
+        TlvIter {
+            remaining: header.get(TbfHeaderV2Base::SIZE..).unwrap_or(&[]),
+        }
+    }
+
+    /// The bytes of the header that have not been parsed yet.
+    pub fn remainder(&self) -> &'a [u8] {
+        self.remaining
+    }
+}
+
+impl<'a> Iterator for TlvIter<'a> {
+    type Item = Result<Tlv<'a>, Error>;
+
+    fn next(&mut self) -> Option<Result<Tlv<'a>, Error>> {
+        if self.remaining.len() <= TbfHeaderTlv::SIZE {
+            return None;
+        }
+
+        let header = match TbfHeaderTlv::parse(self.remaining) {
+            Ok(header) => header,
+            Err(e) => return Some(Err(e)),
+        };
+        let rest = &self.remaining[TbfHeaderTlv::SIZE..];
+        let length = header.length as usize;
+
+        // All TLV blocks are padded to 4 bytes.
+        if align4!(length) > rest.len() {
+            self.remaining = &[];
+            return Some(Err(Error::TlvOutOfBounds));
+        }
+        self.remaining = &rest[align4!(length)..];
+
+        Some(Ok(Tlv {
+            header,
+            data: &rest[..length],
+        }))
+    }
+}
+
+/// The writeable flash regions listed in a header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WriteableFlashRegions<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> WriteableFlashRegions<'a> {
+    /// The regions serialized back to back in `data`, as they are stored in
+    /// the TLV. Returns `None` if `data` does not hold a whole number of
+    /// regions.
+    pub fn new(data: &'a [u8]) -> Option<WriteableFlashRegions<'a>> {
+        match data.len() % TbfHeaderV2WriteableFlashRegion::SIZE {
+            0 => Some(WriteableFlashRegions { data }),
+            _ => None,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len() / TbfHeaderV2WriteableFlashRegion::SIZE
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn get(&self, index: usize) -> Option<TbfHeaderV2WriteableFlashRegion> {
+        let offset = index * TbfHeaderV2WriteableFlashRegion::SIZE;
+        self.data
+            .get(offset..)
+            .and_then(|region| TbfHeaderV2WriteableFlashRegion::parse(region).ok())
+    }
+}
+
+/// Single header that can contain all parts of a v2 header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TbfHeaderV2<'a> {
+    pub base: TbfHeaderV2Base,
+    pub main: Option<TbfHeaderV2Main>,
+    pub package_name: Option<&'a str>,
+    pub writeable_regions: Option<WriteableFlashRegions<'a>>,
+    pub pic_option1: Option<PicOption1Fields>,
+    pub app_version: Option<TbfHeaderV2AppVersion>,
+    pub kernel_abi: Option<TbfHeaderV2KernelAbi>,
+    pub nonvolatile_storage: Option<TbfHeaderV2NonvolatileStorage>,
+}
+
+impl<'a> TbfHeaderV2<'a> {
+    /// Call `f` with each TLV of the header, in the order they are serialized.
+    fn for_each_tlv<F>(&self, mut f: F) -> Result<(), Error>
+    where
+        F: FnMut(Tlv) -> Result<(), Error>,
+    {
+        // Large enough for any of the fixed size TLVs.
+        let mut data = [0; PicOption1Fields::SIZE];
+
+        if let Some(main) = self.main {
+            let length = main.serialize(&mut data)?;
+            f(Tlv::new(TbfHeaderTypes::TbfHeaderMain, &data[..length])?)?;
+        }
+        // An empty name is left out. A TLV without data at the end of the
+        // header cannot be told apart from padding and would not be parsed.
+        if let Some(name) = self.package_name {
+            if !name.is_empty() {
+                f(Tlv::new(TbfHeaderTypes::TbfHeaderPackageName, name.as_bytes())?)?;
+            }
+        }
+        if let Some(regions) = self.writeable_regions {
+            f(Tlv::new(TbfHeaderTypes::TbfHeaderWriteableFlashRegions, regions.data)?)?;
+        }
+        if let Some(pic) = self.pic_option1 {
+            let length = pic.serialize(&mut data)?;
+            f(Tlv::new(TbfHeaderTypes::TbfHeaderPicOption1, &data[..length])?)?;
+        }
+        if let Some(version) = self.app_version {
+            let length = version.serialize(&mut data)?;
+            f(Tlv::new(TbfHeaderTypes::TbfHeaderAppVersion, &data[..length])?)?;
+        }
+        if let Some(abi) = self.kernel_abi {
+            let length = abi.serialize(&mut data)?;
+            f(Tlv::new(TbfHeaderTypes::TbfHeaderKernelAbi, &data[..length])?)?;
+        }
+        if let Some(storage) = self.nonvolatile_storage {
+            let length = storage.serialize(&mut data)?;
+            f(Tlv::new(TbfHeaderTypes::TbfHeaderNonvolatileStorage, &data[..length])?)?;
+        }
+        Ok(())
+    }
+
+    /// The number of bytes `serialize()` writes, which becomes `header_size`.
+    pub fn serialized_len(&self) -> Result<usize, Error> {
+        let mut length = TbfHeaderV2Base::SIZE;
+        self.for_each_tlv(|tlv| {
+            length += tlv.serialized_len();
+            Ok(())
+        })?;
+        if length > 0xFFFF {
+            return Err(Error::HeaderTooLarge);
+        }
+        Ok(length)
+    }
+
+    /// Serialize the base followed by a TLV for every part that is present,
+    /// except for an empty package name. `header_size` and `checksum` are
+    /// computed, the values in `base` are ignored. A header without any parts
+    /// is padding.
+    pub fn serialize(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let header_size = self.serialized_len()?;
+        let header = buf.get_mut(..header_size).ok_or(Error::BufferTooSmall)?;
+        let mut offset = TbfHeaderV2Base::SIZE;
+        self.for_each_tlv(|tlv| {
+            offset += tlv.serialize(&mut header[offset..])?;
+            Ok(())
+        })?;
+        serialize_v2_base(self.base, header)
+    }
+}
+
+/// Serialize `base` to the start of `header`, which must hold the complete
+/// header, with `header_size` and `checksum` computed from `header`.
+fn serialize_v2_base(mut base: TbfHeaderV2Base, header: &mut [u8]) -> Result<usize, Error> {
+    base.header_size = header.len() as u16;
+    base.serialize(header)?;
+    base.checksum = checksum(header);
+    base.serialize(header)?;
+    Ok(header.len())
+}
+
+/// Type that represents the fields of the Tock Binary Format header.
+///
+/// This specifies the locations of the different code and memory sections
+/// in the tock binary, as well as other information about the application.
+/// The kernel can also use this header to keep persistent state about
+/// the application.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TbfHeader<'a> {
+    TbfHeaderV1(TbfHeaderV1),
+    TbfHeaderV2(TbfHeaderV2<'a>),
+    Padding(TbfHeaderV2Base),
+}
+
+impl<'a> TbfHeader<'a> {
+    /// Return whether this is an app or just padding between apps.
+    pub fn is_app(&self) -> bool {
+        match *self {
+            TbfHeader::TbfHeaderV1(_) => true,
+            TbfHeader::TbfHeaderV2(_) => true,
+            TbfHeader::Padding(_) => false,
+        }
+    }
+
+    /// Serialize the complete header with a valid checksum and, for version 2
+    /// headers, `header_size`. Returns the number of bytes written.
+    pub fn serialize(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        match *self {
+            TbfHeader::TbfHeaderV1(mut hd) => {
+                hd.checksum = hd.compute_checksum();
+                hd.serialize(buf)
+            }
+            TbfHeader::TbfHeaderV2(hd) => hd.serialize(buf),
+            TbfHeader::Padding(base) => {
+                let header = buf.get_mut(..TbfHeaderV2Base::SIZE)
+                    .ok_or(Error::BufferTooSmall)?;
+                serialize_v2_base(base, header)
+            }
+        }
+    }
+
+    /// Return whether the application is enabled or not.
+    /// Disabled applications are not started by the kernel.
+    pub fn enabled(&self) -> bool {
+        match *self {
+            // Header v1 has no flag for this, and therefore all apps are
+            // always enabled.
+            TbfHeader::TbfHeaderV1(_) => true,
+            TbfHeader::TbfHeaderV2(hd) => {
+                hd.base.flags & TbfHeaderV2Base::FLAG_ENABLED == TbfHeaderV2Base::FLAG_ENABLED
+            }
+            TbfHeader::Padding(_) => false,
+        }
+    }
+
+    /// Get the total size in flash of this app or padding.
+    pub fn get_total_size(&self) -> u32 {
+        match *self {
+            TbfHeader::TbfHeaderV1(hd) => hd.total_size,
+            TbfHeader::TbfHeaderV2(hd) => hd.base.total_size,
+            TbfHeader::Padding(hd) => hd.total_size,
+        }
+    }
+
+    /// Return whether we want the kernel to do PIC fixup for this app. If
+    /// we ever add more than one kernel PIC fixup method this would have to
+    /// get extended to support that.
+    pub fn needs_pic_fixup(&self) -> bool {
+        match *self {
+            TbfHeader::TbfHeaderV1(_) => true,
+            TbfHeader::TbfHeaderV2(_) | TbfHeader::Padding(_) => false,
+        }
+    }
+
+    /// Add up all of the relevant fields in header version 1, or just used the
+    /// app provided value in version 2 to get the total amount of RAM that is
+    /// needed for this app.
+    pub fn get_minimum_app_ram_size(&self) -> u32 {
+        match *self {
+            TbfHeader::TbfHeaderV1(hd) => {
+                let heap_len = align8!(hd.min_app_heap_len) + align8!(hd.min_kernel_heap_len);
+                let data_len = hd.data_size + hd.got_size + hd.bss_size;
+                let stack_size = align8!(hd.min_stack_len);
+                align8!(data_len + stack_size) + heap_len
+            }
+            TbfHeader::TbfHeaderV2(hd) => hd.main.map_or(0, |m| m.minimum_ram_size),
+            _ => 0,
+        }
+    }
+
+    /// Get the number of bytes from the start of the app's region in flash that
+    /// is for kernel use only. The app cannot write this region.
+    pub fn get_protected_size(&self) -> u32 {
+        match *self {
+            TbfHeader::TbfHeaderV1(_) => TbfHeaderV1::SIZE as u32,
+            TbfHeader::TbfHeaderV2(hd) => {
+                hd.main.map_or(0, |m| m.protected_size) + (hd.base.header_size as u32)
+            }
+            _ => 0,
+        }
+    }
+
+    /// Get the offset from the beginning of the app's flash region where the
+    /// app should start executing.
+    pub fn get_init_function_offset(&self) -> u32 {
+        match *self {
+            TbfHeader::TbfHeaderV1(hd) => hd.entry_offset,
+            TbfHeader::TbfHeaderV2(hd) => {
+                hd.main.map_or(0, |m| m.init_fn_offset) + (hd.base.header_size as u32)
+            }
+            _ => 0,
+        }
+    }
+
+    /// Get the name of the app from a version 2 header. Version 1 headers
+    /// store the name outside of the header, see `pkg_name_offset`.
+    pub fn get_package_name(&self) -> Option<&'a str> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd.package_name,
+            _ => None,
+        }
+    }
+
+    /// Get the version of the app, if the header specifies one.
+    pub fn get_app_version(&self) -> Option<TbfHeaderV2AppVersion> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd.app_version,
+            _ => None,
+        }
+    }
+
+    /// Return whether the app can run on a kernel with syscall ABI `version`.
+    /// Apps that do not specify a range are assumed to work with any kernel.
+    pub fn supports_kernel_abi(&self, version: u32) -> bool {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => match hd.kernel_abi {
+                Some(abi) => abi.supports(version),
+                None => true,
+            },
+            _ => true,
+        }
+    }
+
+    /// Get the range of kernel syscall ABI versions the app supports, if the
+    /// header specifies one.
+    pub fn get_kernel_abi(&self) -> Option<TbfHeaderV2KernelAbi> {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd.kernel_abi,
+            _ => None,
+        }
+    }
+
+    /// Get how many bytes of nonvolatile storage the app asks for. Apps
+    /// without the TLV ask for none.
+    pub fn get_nonvolatile_storage_size(&self) -> usize {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd.nonvolatile_storage
+                .map_or(0, |storage| storage.storage_size as usize),
+            _ => 0,
+        }
+    }
+
+    /// Get the number of flash regions this app has specified in its header.
+    pub fn number_writeable_flash_regions(&self) -> usize {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd.writeable_regions.map_or(0, |wr| wr.len()),
+            _ => 0,
+        }
+    }
+
+    /// Get the offset and size of a given flash region.
+    pub fn get_writeable_flash_region(&self, index: usize) -> (u32, u32) {
+        match *self {
+            TbfHeader::TbfHeaderV2(hd) => hd.writeable_regions
+                .and_then(|wr| wr.get(index))
+                .map_or((0, 0), |region| {
+                    (
+                        region.writeable_flash_region_offset,
+                        region.writeable_flash_region_size,
+                    )
+                }),
+            _ => (0, 0),
+        }
+    }
+}
+
+/// Calculate the checksum of a version 2 header: the XOR of each 4 byte word
+/// in the header, skipping the checksum field. A partial last word is padded
+/// with zeros.
+pub fn checksum(header: &[u8]) -> u32 {
+    let mut checksum: u32 = 0;
+    for (i, chunk) in header.chunks(4).enumerate() {
+        if i == 3 {
+            // Skip the checksum field.
+            continue;
+        }
+        for (j, byte) in chunk.iter().enumerate() {
+            checksum ^= (*byte as u32) << (8 * j);
+        }
+    }
+    checksum
+}
+
+/// Determine how many bytes the header starting at `buf` occupies. Only the
+/// first four bytes of the header are needed.
+pub fn header_length(buf: &[u8]) -> Result<usize, Error> {
+    match read_u16(buf, 0)? {
+        1 => Ok(TbfHeaderV1::SIZE),
+        2 => Ok(read_u16(buf, 2)? as usize),
+        version => Err(Error::UnsupportedVersion(version)),
+    }
+}
+
+/// Parse and validate a complete header.
+///
+/// The header checksum is validated, as are the header and total sizes, but
+/// this does not perform sanity or security checking on the contents. TLVs of
+/// unknown types are skipped, as are known TLVs with a length their type does
+/// not allow or a package name that is not valid UTF-8.
+pub fn parse_tbf_header<'a>(header: &'a [u8]) -> Result<TbfHeader<'a>, Error> {
+    match read_u16(header, 0)? {
+        1 => {
+            let tbf_header = TbfHeaderV1::parse(header)?;
+            let computed = tbf_header.compute_checksum();
+            if computed != tbf_header.checksum {
+                return Err(Error::ChecksumMismatch {
+                    stored: tbf_header.checksum,
+                    computed,
+                });
+            }
+            Ok(TbfHeader::TbfHeaderV1(tbf_header))
+        }
+
+        2 => {
+            let base = TbfHeaderV2Base::parse(header)?;
+
+            // Some sanity checking. Make sure the header isn't longer than the
+            // total app. Make sure the total app fits inside a reasonable size
+            // of flash.
+            let header_size = base.header_size as usize;
+            if base.header_size as u32 >= base.total_size || header_size < TbfHeaderV2Base::SIZE {
+                return Err(Error::InvalidHeaderSize);
+            }
+            if base.total_size > MAX_TOTAL_SIZE {
+                return Err(Error::InvalidTotalSize);
+            }
+            let header = header.get(..header_size).ok_or(Error::BufferTooSmall)?;
+
+            let computed = checksum(header);
+            if computed != base.checksum {
+                return Err(Error::ChecksumMismatch {
+                    stored: base.checksum,
+                    computed,
+                });
+            }
+
+            // Padding is identified by not having any options.
+            if header_size == TbfHeaderV2Base::SIZE {
+                return Ok(TbfHeader::Padding(base));
+            }
+
+            let mut tbf_header = TbfHeaderV2 {
+                base,
+                main: None,
+                package_name: None,
+                writeable_regions: None,
+                pic_option1: None,
+                app_version: None,
+                kernel_abi: None,
+                nonvolatile_storage: None,
+            };
+
+            for tlv in TlvIter::new(header) {
+                let tlv = tlv?;
+                let length = tlv.data.len();
+                match tlv.header.header_type() {
+                    Some(TbfHeaderTypes::TbfHeaderMain) if length == TbfHeaderV2Main::SIZE => {
+                        tbf_header.main = Some(TbfHeaderV2Main::parse(tlv.data)?);
+                    }
+                    Some(TbfHeaderTypes::TbfHeaderWriteableFlashRegions) => {
+                        // Length must be a multiple of the size of a region
+                        // definition.
+                        if let Some(regions) = WriteableFlashRegions::new(tlv.data) {
+                            tbf_header.writeable_regions = Some(regions);
+                        }
+                    }
+                    Some(TbfHeaderTypes::TbfHeaderPackageName) => {
+                        if let Ok(name) = str::from_utf8(tlv.data) {
+                            tbf_header.package_name = Some(name);
+                        }
+                    }
+                    Some(TbfHeaderTypes::TbfHeaderPicOption1)
+                        if length == PicOption1Fields::SIZE =>
+                    {
+                        tbf_header.pic_option1 = Some(PicOption1Fields::parse(tlv.data)?);
+                    }
+                    Some(TbfHeaderTypes::TbfHeaderAppVersion)
+                        if length == TbfHeaderV2AppVersion::SIZE =>
+                    {
+                        tbf_header.app_version = Some(TbfHeaderV2AppVersion::parse(tlv.data)?);
+                    }
+                    Some(TbfHeaderTypes::TbfHeaderKernelAbi)
+                        if length == TbfHeaderV2KernelAbi::SIZE =>
+                    {
+                        tbf_header.kernel_abi = Some(TbfHeaderV2KernelAbi::parse(tlv.data)?);
+                    }
+                    Some(TbfHeaderTypes::TbfHeaderNonvolatileStorage)
+                        if length == TbfHeaderV2NonvolatileStorage::SIZE =>
+                    {
+                        tbf_header.nonvolatile_storage =
+                            Some(TbfHeaderV2NonvolatileStorage::parse(tlv.data)?);
+                    }
+                    // Unknown TLVs, and known ones with a length their type
+                    // does not allow, are skipped.
+                    _ => {}
+                }
+            }
+
+            Ok(TbfHeader::TbfHeaderV2(tbf_header))
+        }
+
+        version => Err(Error::UnsupportedVersion(version)),
+    }
+}
@@ -0,0 +1,502 @@
+//! The structures that make up a TBF header.
+//!
+//! Each structure knows its size in bytes and how to parse itself from, and
+//! serialize itself to, the start of a byte buffer.
+
+use core::fmt;
+use {read_u16, read_u32, write_u16, write_u32, Error};
+
+/// Legacy Tock Binary Format header.
+///
+/// Version 1 of the header is deprecated but can still be parsed to support
+/// any apps that were compiled with an older version of elf2tbf.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TbfHeaderV1 {
+    pub version: u32,
+    pub total_size: u32,
+    pub entry_offset: u32,
+    pub rel_data_offset: u32,
+    pub rel_data_size: u32,
+    pub text_offset: u32,
+    pub text_size: u32,
+    pub got_offset: u32,
+    pub got_size: u32,
+    pub data_offset: u32,
+    pub data_size: u32,
+    pub bss_mem_offset: u32,
+    pub bss_size: u32,
+    pub min_stack_len: u32,
+    pub min_app_heap_len: u32,
+    pub min_kernel_heap_len: u32,
+    pub pkg_name_offset: u32,
+    pub pkg_name_size: u32,
+    pub checksum: u32,
+}
+
+impl TbfHeaderV1 {
+    pub const SIZE: usize = 76;
+
+    pub fn parse(buf: &[u8]) -> Result<TbfHeaderV1, Error> {
+        let mut fields = [0u32; 19];
+        for (i, field) in fields.iter_mut().enumerate() {
+            *field = read_u32(buf, i * 4)?;
+        }
+        Ok(TbfHeaderV1 {
+            version: fields[0],
+            total_size: fields[1],
+            entry_offset: fields[2],
+            rel_data_offset: fields[3],
+            rel_data_size: fields[4],
+            text_offset: fields[5],
+            text_size: fields[6],
+            got_offset: fields[7],
+            got_size: fields[8],
+            data_offset: fields[9],
+            data_size: fields[10],
+            bss_mem_offset: fields[11],
+            bss_size: fields[12],
+            min_stack_len: fields[13],
+            min_app_heap_len: fields[14],
+            min_kernel_heap_len: fields[15],
+            pkg_name_offset: fields[16],
+            pkg_name_size: fields[17],
+            checksum: fields[18],
+        })
+    }
+
+    /// Serialize the header with the `checksum` field as it is. Use
+    /// `TbfHeader::serialize()` to store the correct checksum.
+    pub fn serialize(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let fields = [
+            self.version,
+            self.total_size,
+            self.entry_offset,
+            self.rel_data_offset,
+            self.rel_data_size,
+            self.text_offset,
+            self.text_size,
+            self.got_offset,
+            self.got_size,
+            self.data_offset,
+            self.data_size,
+            self.bss_mem_offset,
+            self.bss_size,
+            self.min_stack_len,
+            self.min_app_heap_len,
+            self.min_kernel_heap_len,
+            self.pkg_name_offset,
+            self.pkg_name_size,
+            self.checksum,
+        ];
+        for (i, field) in fields.iter().enumerate() {
+            write_u32(buf, i * 4, *field)?;
+        }
+        Ok(Self::SIZE)
+    }
+
+    /// The XOR of every field except the checksum.
+    pub fn compute_checksum(&self) -> u32 {
+        self.version ^ self.total_size ^ self.entry_offset ^ self.rel_data_offset
+            ^ self.rel_data_size ^ self.text_offset ^ self.text_size ^ self.got_offset
+            ^ self.got_size ^ self.data_offset ^ self.data_size ^ self.bss_mem_offset
+            ^ self.bss_size ^ self.min_stack_len ^ self.min_app_heap_len
+            ^ self.min_kernel_heap_len ^ self.pkg_name_offset ^ self.pkg_name_size
+    }
+}
+
+/// TBF fields that must be present in all v2 headers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TbfHeaderV2Base {
+    pub version: u16,
+    pub header_size: u16,
+    pub total_size: u32,
+    pub flags: u32,
+    pub checksum: u32,
+}
+
+impl TbfHeaderV2Base {
+    pub const SIZE: usize = 16;
+
+    /// Bit in `flags` that marks the app as enabled. Disabled apps are not
+    /// started by the kernel.
+    pub const FLAG_ENABLED: u32 = 0x00000001;
+
+    pub fn parse(buf: &[u8]) -> Result<TbfHeaderV2Base, Error> {
+        Ok(TbfHeaderV2Base {
+            version: read_u16(buf, 0)?,
+            header_size: read_u16(buf, 2)?,
+            total_size: read_u32(buf, 4)?,
+            flags: read_u32(buf, 8)?,
+            checksum: read_u32(buf, 12)?,
+        })
+    }
+
+    pub fn serialize(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        write_u16(buf, 0, self.version)?;
+        write_u16(buf, 2, self.header_size)?;
+        write_u32(buf, 4, self.total_size)?;
+        write_u32(buf, 8, self.flags)?;
+        write_u32(buf, 12, self.checksum)?;
+        Ok(Self::SIZE)
+    }
+}
+
+/// Types in TLV structures for each optional block of the header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TbfHeaderTypes {
+    TbfHeaderMain = 1,
+    TbfHeaderWriteableFlashRegions = 2,
+    TbfHeaderPackageName = 3,
+    TbfHeaderPicOption1 = 4,
+    TbfHeaderAppVersion = 5,
+    TbfHeaderKernelAbi = 6,
+    TbfHeaderNonvolatileStorage = 7,
+}
+
+impl TbfHeaderTypes {
+    /// The type with the given number, or `None` for unknown types.
+    pub fn from_u16(tipe: u16) -> Option<TbfHeaderTypes> {
+        match tipe {
+            1 => Some(TbfHeaderTypes::TbfHeaderMain),
+            2 => Some(TbfHeaderTypes::TbfHeaderWriteableFlashRegions),
+            3 => Some(TbfHeaderTypes::TbfHeaderPackageName),
+            4 => Some(TbfHeaderTypes::TbfHeaderPicOption1),
+            5 => Some(TbfHeaderTypes::TbfHeaderAppVersion),
+            6 => Some(TbfHeaderTypes::TbfHeaderKernelAbi),
+            7 => Some(TbfHeaderTypes::TbfHeaderNonvolatileStorage),
+            _ => None,
+        }
+    }
+}
+
+/// The TLV header (T and L).
+///
+/// The type is kept as a raw number so that headers with TLVs unknown to this
+/// version can still be parsed and skipped.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TbfHeaderTlv {
+    pub tipe: u16,
+    pub length: u16,
+}
+
+impl TbfHeaderTlv {
+    pub const SIZE: usize = 4;
+
+    pub fn new(tipe: TbfHeaderTypes, length: u16) -> TbfHeaderTlv {
+        TbfHeaderTlv {
+            tipe: tipe as u16,
+            length,
+        }
+    }
+
+    /// The type of the TLV, or `None` if it is unknown.
+    pub fn header_type(&self) -> Option<TbfHeaderTypes> {
+        TbfHeaderTypes::from_u16(self.tipe)
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<TbfHeaderTlv, Error> {
+        Ok(TbfHeaderTlv {
+            tipe: read_u16(buf, 0)?,
+            length: read_u16(buf, 2)?,
+        })
+    }
+
+    pub fn serialize(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        write_u16(buf, 0, self.tipe)?;
+        write_u16(buf, 2, self.length)?;
+        Ok(Self::SIZE)
+    }
+}
+
+/// The v2 main section for apps.
+///
+/// Apps need a main section to tell the kernel where to start executing and
+/// how much RAM they need. A header without one is still an app, which starts
+/// at the end of its header with no RAM. Only a header without any TLVs is
+/// padding.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TbfHeaderV2Main {
+    pub init_fn_offset: u32,
+    pub protected_size: u32,
+    pub minimum_ram_size: u32,
+}
+
+impl TbfHeaderV2Main {
+    pub const SIZE: usize = 12;
+
+    pub fn parse(buf: &[u8]) -> Result<TbfHeaderV2Main, Error> {
+        Ok(TbfHeaderV2Main {
+            init_fn_offset: read_u32(buf, 0)?,
+            protected_size: read_u32(buf, 4)?,
+            minimum_ram_size: read_u32(buf, 8)?,
+        })
+    }
+
+    pub fn serialize(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        write_u32(buf, 0, self.init_fn_offset)?;
+        write_u32(buf, 4, self.protected_size)?;
+        write_u32(buf, 8, self.minimum_ram_size)?;
+        Ok(Self::SIZE)
+    }
+}
+
+/// Writeable flash regions only need an offset and size.
+///
+/// There can be multiple (or zero) flash regions defined, so this is its own
+/// struct.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TbfHeaderV2WriteableFlashRegion {
+    pub writeable_flash_region_offset: u32,
+    pub writeable_flash_region_size: u32,
+}
+
+impl TbfHeaderV2WriteableFlashRegion {
+    pub const SIZE: usize = 8;
+
+    pub fn parse(buf: &[u8]) -> Result<TbfHeaderV2WriteableFlashRegion, Error> {
+        Ok(TbfHeaderV2WriteableFlashRegion {
+            writeable_flash_region_offset: read_u32(buf, 0)?,
+            writeable_flash_region_size: read_u32(buf, 4)?,
+        })
+    }
+
+    pub fn serialize(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        write_u32(buf, 0, self.writeable_flash_region_offset)?;
+        write_u32(buf, 4, self.writeable_flash_region_size)?;
+        Ok(Self::SIZE)
+    }
+}
+
+/// PIC fields for kernel provided PIC fixup.
+///
+/// If an app wants the kernel to do the PIC fixup for it, it must pass this
+/// block so the kernel knows where sections are in the app binary. The kernel
+/// no longer does PIC fixup, so it ignores this block.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PicOption1Fields {
+    pub text_offset: u32,
+    pub data_offset: u32,
+    pub data_size: u32,
+    pub bss_memory_offset: u32,
+    pub bss_size: u32,
+    pub relocation_data_offset: u32,
+    pub relocation_data_size: u32,
+    pub got_offset: u32,
+    pub got_size: u32,
+    pub minimum_stack_length: u32,
+}
+
+impl PicOption1Fields {
+    pub const SIZE: usize = 40;
+
+    pub fn parse(buf: &[u8]) -> Result<PicOption1Fields, Error> {
+        Ok(PicOption1Fields {
+            text_offset: read_u32(buf, 0)?,
+            data_offset: read_u32(buf, 4)?,
+            data_size: read_u32(buf, 8)?,
+            bss_memory_offset: read_u32(buf, 12)?,
+            bss_size: read_u32(buf, 16)?,
+            relocation_data_offset: read_u32(buf, 20)?,
+            relocation_data_size: read_u32(buf, 24)?,
+            got_offset: read_u32(buf, 28)?,
+            got_size: read_u32(buf, 32)?,
+            minimum_stack_length: read_u32(buf, 36)?,
+        })
+    }
+
+    pub fn serialize(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        write_u32(buf, 0, self.text_offset)?;
+        write_u32(buf, 4, self.data_offset)?;
+        write_u32(buf, 8, self.data_size)?;
+        write_u32(buf, 12, self.bss_memory_offset)?;
+        write_u32(buf, 16, self.bss_size)?;
+        write_u32(buf, 20, self.relocation_data_offset)?;
+        write_u32(buf, 24, self.relocation_data_size)?;
+        write_u32(buf, 28, self.got_offset)?;
+        write_u32(buf, 32, self.got_size)?;
+        write_u32(buf, 36, self.minimum_stack_length)?;
+        Ok(Self::SIZE)
+    }
+}
+
+/// The version of the app, as `major.minor.patch`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TbfHeaderV2AppVersion {
+    pub major: u16,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+impl TbfHeaderV2AppVersion {
+    pub const SIZE: usize = 4;
+
+    /// The version packed into a single word, `major` in the upper 16 bits
+    /// followed by `minor` and `patch`. Packed versions compare in the same
+    /// order as the versions themselves.
+    pub fn as_u32(&self) -> u32 {
+        (self.major as u32) << 16 | (self.minor as u32) << 8 | self.patch as u32
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<TbfHeaderV2AppVersion, Error> {
+        let version = read_u32(buf, 0)?;
+        Ok(TbfHeaderV2AppVersion {
+            major: (version >> 16) as u16,
+            minor: (version >> 8) as u8,
+            patch: version as u8,
+        })
+    }
+
+    pub fn serialize(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        write_u32(buf, 0, self.as_u32())?;
+        Ok(Self::SIZE)
+    }
+}
+
+/// The range of kernel syscall ABI versions an app works with.
+///
+/// Both bounds are inclusive. The kernel does not start apps that do not
+/// support its ABI version.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TbfHeaderV2KernelAbi {
+    pub minimum_abi_version: u32,
+    pub maximum_abi_version: u32,
+}
+
+impl TbfHeaderV2KernelAbi {
+    pub const SIZE: usize = 8;
+
+    /// Whether a kernel with syscall ABI `version` can run the app.
+    pub fn supports(&self, version: u32) -> bool {
+        self.minimum_abi_version <= version && version <= self.maximum_abi_version
+    }
+
+    pub fn parse(buf: &[u8]) -> Result<TbfHeaderV2KernelAbi, Error> {
+        Ok(TbfHeaderV2KernelAbi {
+            minimum_abi_version: read_u32(buf, 0)?,
+            maximum_abi_version: read_u32(buf, 4)?,
+        })
+    }
+
+    pub fn serialize(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        write_u32(buf, 0, self.minimum_abi_version)?;
+        write_u32(buf, 4, self.maximum_abi_version)?;
+        Ok(Self::SIZE)
+    }
+}
+
+/// How many bytes of nonvolatile storage the app needs.
+///
+/// The kernel gives each app that asks for storage its own region of the
+/// storage shared with userspace. Apps without this TLV get no storage.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TbfHeaderV2NonvolatileStorage {
+    pub storage_size: u32,
+}
+
+impl TbfHeaderV2NonvolatileStorage {
+    pub const SIZE: usize = 4;
+
+    pub fn parse(buf: &[u8]) -> Result<TbfHeaderV2NonvolatileStorage, Error> {
+        Ok(TbfHeaderV2NonvolatileStorage {
+            storage_size: read_u32(buf, 0)?,
+        })
+    }
+
+    pub fn serialize(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        write_u32(buf, 0, self.storage_size)?;
+        Ok(Self::SIZE)
+    }
+}
+
+impl fmt::Display for TbfHeaderV2AppVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl fmt::Display for TbfHeaderV2Base {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "
+               version: {:>8} {:>#10X}
+           header_size: {:>8} {:>#10X}
+            total_size: {:>8} {:>#10X}
+                 flags: {:>8} {:>#10X}
+",
+            self.version,
+            self.version,
+            self.header_size,
+            self.header_size,
+            self.total_size,
+            self.total_size,
+            self.flags,
+            self.flags,
+        )
+    }
+}
+
+impl fmt::Display for TbfHeaderV2Main {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "
+        init_fn_offset: {:>8} {:>#10X}
+        protected_size: {:>8} {:>#10X}
+      minimum_ram_size: {:>8} {:>#10X}
+",
+            self.init_fn_offset,
+            self.init_fn_offset,
+            self.protected_size,
+            self.protected_size,
+            self.minimum_ram_size,
+            self.minimum_ram_size,
+        )
+    }
+}
+
+impl fmt::Display for TbfHeaderV2WriteableFlashRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "
+    flash region:
+                offset: {:>8} {:>#10X}
+                  size: {:>8} {:>#10X}
+",
+            self.writeable_flash_region_offset,
+            self.writeable_flash_region_offset,
+            self.writeable_flash_region_size,
+            self.writeable_flash_region_size,
+        )
+    }
+}
+
+impl fmt::Display for TbfHeaderV2KernelAbi {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "
+   minimum_abi_version: {:>8} {:>#10X}
+   maximum_abi_version: {:>8} {:>#10X}
+",
+            self.minimum_abi_version,
+            self.minimum_abi_version,
+            self.maximum_abi_version,
+            self.maximum_abi_version,
+        )
+    }
+}
+
+impl fmt::Display for TbfHeaderV2NonvolatileStorage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "
+          storage_size: {:>8} {:>#10X}
+",
+            self.storage_size,
+            self.storage_size,
+        )
+    }
+}
@@ -0,0 +1,279 @@
+//! Truncated and corrupt headers are rejected without panicking.
+
+extern crate tbf;
+
+use tbf::{Error, TbfHeader, TbfHeaderTypes, TbfHeaderV1, TbfHeaderV2, TbfHeaderV2AppVersion,
+          TbfHeaderV2Base, TbfHeaderV2KernelAbi, TbfHeaderV2Main, TbfHeaderV2NonvolatileStorage,
+          TlvIter, WriteableFlashRegions};
+
+const REGIONS: [u8; 16] = [0, 1, 0, 0, 0, 1, 0, 0, 0, 4, 0, 0, 0, 2, 0, 0];
+
+fn app() -> TbfHeaderV2<'static> {
+    TbfHeaderV2 {
+        base: TbfHeaderV2Base {
+            version: 2,
+            header_size: 0,
+            total_size: 0x1000,
+            flags: TbfHeaderV2Base::FLAG_ENABLED,
+            checksum: 0,
+        },
+        main: Some(TbfHeaderV2Main {
+            init_fn_offset: 0x10,
+            protected_size: 0,
+            minimum_ram_size: 0x1000,
+        }),
+        package_name: Some("corrupt"),
+        writeable_regions: WriteableFlashRegions::new(&REGIONS),
+        pic_option1: None,
+        app_version: Some(TbfHeaderV2AppVersion {
+            major: 2,
+            minor: 0,
+            patch: 1,
+        }),
+        kernel_abi: Some(TbfHeaderV2KernelAbi {
+            minimum_abi_version: 1,
+            maximum_abi_version: 1,
+        }),
+        nonvolatile_storage: Some(TbfHeaderV2NonvolatileStorage { storage_size: 0x100 }),
+    }
+}
+
+fn serialize(header: TbfHeaderV2) -> Vec<u8> {
+    let mut buf = vec![0; header.serialized_len().unwrap()];
+    header.serialize(&mut buf).unwrap();
+    buf
+}
+
+/// Recompute the checksum of a version 2 header after modifying it, so that
+/// parsing gets past the checksum.
+fn fix_checksum(buf: &mut [u8]) {
+    if let Ok(mut base) = TbfHeaderV2Base::parse(buf) {
+        let end = buf.len().min(base.header_size as usize);
+        base.checksum = tbf::checksum(&buf[..end]);
+        base.serialize(buf).unwrap();
+    }
+}
+
+/// Parse `buf` the way the kernel does and check that whatever parses
+/// serializes to a header that parses to the same values.
+fn check(buf: &[u8]) {
+    for tlv in TlvIter::new(buf) {
+        if tlv.is_err() {
+            break;
+        }
+    }
+
+    let length = match tbf::header_length(buf) {
+        Ok(length) => length,
+        Err(_) => return,
+    };
+    let header = match tbf::parse_tbf_header(buf.get(..length).unwrap_or(buf)) {
+        Ok(header) => header,
+        Err(_) => return,
+    };
+
+    let mut out = vec![0; length];
+    let written = header.serialize(&mut out).unwrap();
+    assert!(written <= length);
+    let reparsed = tbf::parse_tbf_header(&out[..written]).unwrap();
+    match (header, reparsed) {
+        (TbfHeader::TbfHeaderV2(mut a), TbfHeader::TbfHeaderV2(b)) => {
+            if a.package_name == Some("") {
+                a.package_name = None;
+            }
+            a.base.header_size = b.base.header_size;
+            a.base.checksum = b.base.checksum;
+            assert_eq!(a, b);
+        }
+        (TbfHeader::TbfHeaderV2(a), TbfHeader::Padding(b)) => {
+            // Only TLVs that are skipped when parsing, nothing to keep.
+            assert_eq!(a.base.total_size, b.total_size);
+            assert_eq!(a.base.flags, b.flags);
+        }
+        (TbfHeader::Padding(a), TbfHeader::Padding(b)) => assert_eq!(a, b),
+        (TbfHeader::TbfHeaderV1(a), TbfHeader::TbfHeaderV1(b)) => assert_eq!(a, b),
+        (a, b) => panic!("{:?} parsed back as {:?}", a, b),
+    }
+}
+
+/// A small xorshift generator, so failures are reproducible.
+struct Rng(u32);
+
+impl Rng {
+    fn next(&mut self) -> u32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        self.next() as usize % n
+    }
+}
+
+#[test]
+fn truncated() {
+    let buf = serialize(app());
+    for length in 0..buf.len() {
+        let truncated = &buf[..length];
+        if length < 4 {
+            assert_eq!(tbf::header_length(truncated), Err(Error::BufferTooSmall));
+        } else {
+            assert_eq!(tbf::header_length(truncated), Ok(buf.len()));
+        }
+        assert_eq!(
+            tbf::parse_tbf_header(truncated).unwrap_err(),
+            Error::BufferTooSmall
+        );
+    }
+
+    let mut v1 = [0; TbfHeaderV1::SIZE];
+    TbfHeader::TbfHeaderV1(TbfHeaderV1 {
+        version: 1,
+        total_size: 0x400,
+        ..TbfHeaderV1::default()
+    }).serialize(&mut v1)
+        .unwrap();
+    assert!(tbf::parse_tbf_header(&v1).is_ok());
+    for length in 0..v1.len() {
+        assert_eq!(
+            tbf::parse_tbf_header(&v1[..length]).unwrap_err(),
+            Error::BufferTooSmall
+        );
+    }
+}
+
+#[test]
+fn every_bit_flip_is_rejected() {
+    let buf = serialize(app());
+    for i in 0..buf.len() * 8 {
+        let mut corrupt = buf.clone();
+        corrupt[i / 8] ^= 1 << (i % 8);
+        assert!(
+            tbf::parse_tbf_header(&corrupt).is_err(),
+            "flipping bit {} was not detected",
+            i
+        );
+    }
+}
+
+#[test]
+fn invalid_sizes() {
+    let mut buf = serialize(app());
+    let length = buf.len();
+
+    // header_size smaller than the base.
+    buf[2] = 8;
+    buf[3] = 0;
+    fix_checksum(&mut buf);
+    assert_eq!(tbf::parse_tbf_header(&buf).unwrap_err(), Error::InvalidHeaderSize);
+
+    // header_size not smaller than total_size.
+    let mut buf = serialize(app());
+    buf[4..8].copy_from_slice(&[length as u8, 0, 0, 0]);
+    fix_checksum(&mut buf);
+    assert_eq!(tbf::parse_tbf_header(&buf).unwrap_err(), Error::InvalidHeaderSize);
+
+    // total_size larger than any app can be.
+    let mut buf = serialize(app());
+    buf[4..8].copy_from_slice(&[1, 0, 0, 0x10]);
+    fix_checksum(&mut buf);
+    assert_eq!(tbf::parse_tbf_header(&buf).unwrap_err(), Error::InvalidTotalSize);
+
+    // Unknown versions.
+    for version in [0u16, 3, 0xFFFF].iter() {
+        let mut buf = serialize(app());
+        buf[0] = *version as u8;
+        buf[1] = (*version >> 8) as u8;
+        assert_eq!(tbf::header_length(&buf), Err(Error::UnsupportedVersion(*version)));
+        assert_eq!(
+            tbf::parse_tbf_header(&buf).unwrap_err(),
+            Error::UnsupportedVersion(*version)
+        );
+    }
+}
+
+#[test]
+fn tlv_out_of_bounds() {
+    let mut header = app();
+    header.package_name = None;
+    let mut buf = serialize(header);
+
+    // Make the Main TLV claim more bytes than the header has left.
+    assert_eq!(buf[16], TbfHeaderTypes::TbfHeaderMain as u8);
+    buf[18] = 0xFF;
+    fix_checksum(&mut buf);
+    assert_eq!(tbf::parse_tbf_header(&buf).unwrap_err(), Error::TlvOutOfBounds);
+}
+
+#[test]
+fn ignored_tlvs() {
+    let mut header = app();
+    header.writeable_regions = None;
+    header.app_version = None;
+    header.kernel_abi = None;
+    header.nonvolatile_storage = None;
+    let mut buf = serialize(header);
+
+    // A Main TLV with the wrong length is skipped, as is a name that is not
+    // valid UTF-8.
+    buf[18] = 8;
+    buf[32] = 0xFF;
+    fix_checksum(&mut buf);
+    match tbf::parse_tbf_header(&buf).unwrap() {
+        TbfHeader::TbfHeaderV2(hd) => {
+            assert_eq!(hd.main, None);
+            assert_eq!(hd.package_name, None);
+        }
+        other => panic!("parsed as {:?}", other),
+    }
+    check(&buf);
+}
+
+#[test]
+fn fuzz() {
+    let valid = serialize(app());
+    let mut rng = Rng(0x2545F491);
+    for _ in 0..20000 {
+        let mut buf = valid.clone();
+
+        // Overwrite a few random bytes, sometimes with values that are likely
+        // to hit the edge cases of lengths and types.
+        for _ in 0..rng.below(4) + 1 {
+            let i = rng.below(buf.len());
+            buf[i] = match rng.below(4) {
+                0 => 0,
+                1 => 0xFF,
+                2 => rng.below(8) as u8,
+                _ => rng.next() as u8,
+            };
+        }
+        if rng.below(4) == 0 {
+            let length = rng.below(buf.len());
+            buf.truncate(length);
+        }
+        if rng.below(4) != 0 {
+            fix_checksum(&mut buf);
+        }
+        check(&buf);
+    }
+}
+
+#[test]
+fn fuzz_random_bytes() {
+    let mut rng = Rng(0x9E3779B9);
+    for _ in 0..20000 {
+        let length = rng.below(128);
+        let mut buf: Vec<u8> = (0..length).map(|_| rng.next() as u8).collect();
+        if length >= 4 {
+            // Mostly version 2 headers that are not larger than the buffer.
+            buf[0] = 1 + (rng.below(8) != 0) as u8;
+            buf[1] = 0;
+            buf[2] = rng.below(length + 1) as u8;
+            buf[3] = 0;
+        }
+        fix_checksum(&mut buf);
+        check(&buf);
+    }
+}
@@ -0,0 +1,283 @@
+//! Headers serialized by the crate parse back to the same header.
+
+extern crate tbf;
+
+use tbf::{Error, PicOption1Fields, TbfHeader, TbfHeaderV1, TbfHeaderV2, TbfHeaderV2AppVersion,
+          TbfHeaderV2Base, TbfHeaderV2KernelAbi, TbfHeaderV2Main, TbfHeaderV2NonvolatileStorage,
+          TbfHeaderV2WriteableFlashRegion, WriteableFlashRegions};
+
+fn base() -> TbfHeaderV2Base {
+    TbfHeaderV2Base {
+        version: 2,
+        header_size: 0,
+        total_size: 0x1000,
+        flags: TbfHeaderV2Base::FLAG_ENABLED,
+        checksum: 0,
+    }
+}
+
+fn empty(base: TbfHeaderV2Base) -> TbfHeaderV2<'static> {
+    TbfHeaderV2 {
+        base,
+        main: None,
+        package_name: None,
+        writeable_regions: None,
+        pic_option1: None,
+        app_version: None,
+        kernel_abi: None,
+        nonvolatile_storage: None,
+    }
+}
+
+fn regions(regions: &[TbfHeaderV2WriteableFlashRegion]) -> Vec<u8> {
+    let mut data = vec![0; regions.len() * TbfHeaderV2WriteableFlashRegion::SIZE];
+    for (region, buf) in regions
+        .iter()
+        .zip(data.chunks_mut(TbfHeaderV2WriteableFlashRegion::SIZE))
+    {
+        region.serialize(buf).unwrap();
+    }
+    data
+}
+
+/// Serialize `header`, check that it parses back to the same header with
+/// `header_size` and `checksum` filled in, and that serializing the parsed
+/// header gives the same bytes. Returns the serialized header.
+fn round_trip(header: TbfHeaderV2) -> Vec<u8> {
+    let length = header.serialized_len().unwrap();
+    let mut buf = vec![0xAA; length];
+    assert_eq!(header.serialize(&mut buf), Ok(length));
+    assert_eq!(length % 4, 0);
+    assert_eq!(tbf::header_length(&buf), Ok(length));
+
+    let parsed = tbf::parse_tbf_header(&buf).unwrap();
+    let mut expected = header;
+    expected.base.header_size = length as u16;
+    expected.base.checksum = tbf::checksum(&buf);
+    assert_eq!(parsed, TbfHeader::TbfHeaderV2(expected));
+
+    let mut again = vec![0x55; length];
+    assert_eq!(parsed.serialize(&mut again), Ok(length));
+    assert_eq!(again, buf);
+    buf
+}
+
+#[test]
+fn v2_main_only() {
+    let mut header = empty(base());
+    header.main = Some(TbfHeaderV2Main {
+        init_fn_offset: 0x41,
+        protected_size: 0x20,
+        minimum_ram_size: 0x2000,
+    });
+    let buf = round_trip(header);
+    assert_eq!(buf.len(), 32);
+
+    let parsed = tbf::parse_tbf_header(&buf).unwrap();
+    assert!(parsed.is_app());
+    assert!(parsed.enabled());
+    assert_eq!(parsed.get_init_function_offset(), 0x41 + 32);
+    assert_eq!(parsed.get_protected_size(), 0x20 + 32);
+    assert_eq!(parsed.get_minimum_app_ram_size(), 0x2000);
+}
+
+#[test]
+fn v2_all_tlvs() {
+    let regions = regions(&[
+        TbfHeaderV2WriteableFlashRegion {
+            writeable_flash_region_offset: 0x100,
+            writeable_flash_region_size: 0x200,
+        },
+        TbfHeaderV2WriteableFlashRegion {
+            writeable_flash_region_offset: 0x800,
+            writeable_flash_region_size: 0x400,
+        },
+    ]);
+    let header = TbfHeaderV2 {
+        base: base(),
+        main: Some(TbfHeaderV2Main {
+            init_fn_offset: 1,
+            protected_size: 2,
+            minimum_ram_size: 3,
+        }),
+        package_name: Some("sensors"),
+        writeable_regions: WriteableFlashRegions::new(&regions),
+        pic_option1: Some(PicOption1Fields {
+            text_offset: 1,
+            data_offset: 2,
+            data_size: 3,
+            bss_memory_offset: 4,
+            bss_size: 5,
+            relocation_data_offset: 6,
+            relocation_data_size: 7,
+            got_offset: 8,
+            got_size: 9,
+            minimum_stack_length: 10,
+        }),
+        app_version: Some(TbfHeaderV2AppVersion {
+            major: 1,
+            minor: 2,
+            patch: 3,
+        }),
+        kernel_abi: Some(TbfHeaderV2KernelAbi {
+            minimum_abi_version: 1,
+            maximum_abi_version: 2,
+        }),
+        nonvolatile_storage: Some(TbfHeaderV2NonvolatileStorage { storage_size: 0x400 }),
+    };
+    let buf = round_trip(header);
+    let parsed = tbf::parse_tbf_header(&buf).unwrap();
+    assert_eq!(parsed.get_package_name(), Some("sensors"));
+    assert_eq!(parsed.number_writeable_flash_regions(), 2);
+    assert_eq!(parsed.get_writeable_flash_region(1), (0x800, 0x400));
+    assert_eq!(parsed.get_writeable_flash_region(2), (0, 0));
+    assert!(parsed.supports_kernel_abi(2));
+    assert!(!parsed.supports_kernel_abi(3));
+    assert_eq!(parsed.get_nonvolatile_storage_size(), 0x400);
+}
+
+#[test]
+fn v2_package_name_padding() {
+    let names = ["a", "ab", "abc", "abcd", "abcde", "\u{e9}t\u{e9}"];
+    for name in names.iter() {
+        let mut header = empty(base());
+        header.package_name = Some(name);
+        let buf = round_trip(header);
+        assert_eq!(buf.len(), 16 + 4 + name.len() + (4 - name.len() % 4) % 4);
+        // The padding after the name is zeroed.
+        assert!(buf[20 + name.len()..].iter().all(|b| *b == 0));
+    }
+}
+
+#[test]
+fn v2_empty_package_name() {
+    let mut header = empty(base());
+    header.main = Some(TbfHeaderV2Main::default());
+    header.package_name = Some("");
+    let mut buf = [0; 32];
+    assert_eq!(header.serialize(&mut buf), Ok(32));
+
+    header.package_name = None;
+    assert_eq!(round_trip(header), &buf[..]);
+}
+
+#[test]
+fn v2_disabled() {
+    let mut header = empty(TbfHeaderV2Base {
+        flags: 0,
+        ..base()
+    });
+    header.package_name = Some("off");
+    let buf = round_trip(header);
+    let parsed = tbf::parse_tbf_header(&buf).unwrap();
+    assert!(parsed.is_app());
+    assert!(!parsed.enabled());
+}
+
+#[test]
+fn padding() {
+    let padding = TbfHeader::Padding(TbfHeaderV2Base {
+        flags: 0,
+        ..base()
+    });
+    let mut buf = [0; TbfHeaderV2Base::SIZE];
+    assert_eq!(padding.serialize(&mut buf), Ok(TbfHeaderV2Base::SIZE));
+
+    let parsed = tbf::parse_tbf_header(&buf).unwrap();
+    assert!(!parsed.is_app());
+    assert_eq!(parsed.get_total_size(), 0x1000);
+
+    // A version 2 header without any parts is padding as well.
+    let mut other = [0; TbfHeaderV2Base::SIZE];
+    let header = TbfHeader::TbfHeaderV2(empty(TbfHeaderV2Base {
+        flags: 0,
+        ..base()
+    }));
+    assert_eq!(header.serialize(&mut other), Ok(TbfHeaderV2Base::SIZE));
+    assert_eq!(other, buf);
+}
+
+#[test]
+fn v1() {
+    let header = TbfHeaderV1 {
+        version: 1,
+        total_size: 0x800,
+        entry_offset: 0x80,
+        rel_data_offset: 0x4C,
+        rel_data_size: 4,
+        text_offset: 0x50,
+        text_size: 0x400,
+        got_offset: 0x450,
+        got_size: 0x10,
+        data_offset: 0x460,
+        data_size: 0x20,
+        bss_mem_offset: 0x30,
+        bss_size: 0x40,
+        min_stack_len: 0x400,
+        min_app_heap_len: 0x400,
+        min_kernel_heap_len: 0x400,
+        pkg_name_offset: 0x480,
+        pkg_name_size: 5,
+        checksum: 0,
+    };
+    let mut buf = [0; TbfHeaderV1::SIZE];
+    assert_eq!(
+        TbfHeader::TbfHeaderV1(header).serialize(&mut buf),
+        Ok(TbfHeaderV1::SIZE)
+    );
+    assert_eq!(tbf::header_length(&buf), Ok(TbfHeaderV1::SIZE));
+
+    let parsed = tbf::parse_tbf_header(&buf).unwrap();
+    let expected = TbfHeaderV1 {
+        checksum: header.compute_checksum(),
+        ..header
+    };
+    assert_eq!(parsed, TbfHeader::TbfHeaderV1(expected));
+    assert!(parsed.needs_pic_fixup());
+    assert_eq!(parsed.get_init_function_offset(), 0x80);
+
+    // Serializing the structure itself keeps the stored checksum.
+    let mut raw = [0; TbfHeaderV1::SIZE];
+    assert_eq!(header.serialize(&mut raw), Ok(TbfHeaderV1::SIZE));
+    assert_eq!(TbfHeaderV1::parse(&raw), Ok(header));
+    assert_eq!(
+        tbf::parse_tbf_header(&raw).unwrap_err(),
+        Error::ChecksumMismatch {
+            stored: 0,
+            computed: header.compute_checksum(),
+        }
+    );
+}
+
+#[test]
+fn buffer_too_small() {
+    let mut header = empty(base());
+    header.package_name = Some("too small");
+    let length = header.serialized_len().unwrap();
+    for size in 0..length {
+        let mut buf = vec![0; size];
+        assert_eq!(header.serialize(&mut buf), Err(Error::BufferTooSmall));
+    }
+
+    let mut buf = [0; TbfHeaderV1::SIZE - 1];
+    assert_eq!(
+        TbfHeader::TbfHeaderV1(TbfHeaderV1::default()).serialize(&mut buf),
+        Err(Error::BufferTooSmall)
+    );
+}
+
+#[test]
+fn header_too_large() {
+    let name = "x".repeat(0x10000);
+    let mut header = empty(base());
+    header.package_name = Some(&name);
+    assert_eq!(header.serialized_len(), Err(Error::HeaderTooLarge));
+
+    // Each TLV fits, but all of them together do not.
+    let name = "x".repeat(0xFFF0);
+    header.package_name = Some(&name);
+    header.main = Some(TbfHeaderV2Main::default());
+    assert_eq!(header.serialized_len(), Err(Error::HeaderTooLarge));
+    let mut buf = vec![0; 0x20000];
+    assert_eq!(header.serialize(&mut buf), Err(Error::HeaderTooLarge));
+}
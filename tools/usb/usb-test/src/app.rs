@@ -0,0 +1,149 @@
+//! Apps for testing capsules with a system call interface
+//!
+//! `Apps::load()` puts a process in the kernel's process table, so a capsule
+//! can enter its grant for the process and use the buffers it allows. The
+//! process never runs: the test makes the system calls on its behalf with
+//! the returned `AppId`, and callbacks the capsule schedules are only queued.
+//!
+//! The process table and the grants are global to the kernel, so only one
+//! test can use apps at a time, and the capsule has to create its grant
+//! before the apps are loaded.
+//!
+//! ```
+//! # extern crate kernel;
+//! # extern crate usb_test;
+//! # use usb_test::app::Apps;
+//! # fn main() {
+//! let mut apps = Apps::new();
+//! // Create the capsule here
+//! let appid = apps.load("hid");
+//! let buffer = apps.buffer(appid, &[0x02, 0x04]);
+//! // Pass `buffer.slice()` to the capsule's `allow()`
+//! # }
+//! ```
+
+use kernel::process::{self, FaultResponse, Process};
+use kernel::{AppId, AppSlice, Shared};
+use std::slice;
+use std::sync::{Mutex, MutexGuard, Once, ONCE_INIT};
+use tbf::{TbfHeaderV2, TbfHeaderV2Base, TbfHeaderV2Main};
+
+/// Memory of each process, enough for the kernel's state and the grants
+const APP_MEMORY_SIZE: usize = 4096;
+
+/// The most apps a test can load
+const MAX_APPS: usize = 4;
+
+static INIT: Once = ONCE_INIT;
+static mut LOCK: Option<Mutex<()>> = None;
+
+/// The process table, which belongs to the test as long as this exists
+pub struct Apps {
+    _guard: MutexGuard<'static, ()>,
+}
+
+impl Apps {
+    /// Wait for other tests to be done with the process table, and empty it
+    pub fn new() -> Apps {
+        let guard = unsafe {
+            INIT.call_once(|| LOCK = Some(Mutex::new(())));
+            match LOCK {
+                // A test that failed while holding the lock leaves nothing
+                // behind that the next one needs
+                Some(ref lock) => lock.lock().unwrap_or_else(|err| err.into_inner()),
+                None => unreachable!(),
+            }
+        };
+        let procs: &'static mut [Option<Process<'static>>; MAX_APPS] =
+            Box::leak(Box::new([None, None, None, None]));
+        unsafe {
+            process::PROCS = procs;
+        }
+        Apps { _guard: guard }
+    }
+
+    /// Load an app with the given package name, and return its ID
+    pub fn load(&mut self, name: &'static str) -> AppId {
+        let procs = unsafe { &mut process::PROCS };
+        let index = procs
+            .iter()
+            .position(|p| p.is_none())
+            .expect("too many apps");
+
+        let image = app_image(name);
+        let memory = unsafe {
+            let words: &'static mut [u64] =
+                Box::leak(vec![0; APP_MEMORY_SIZE / 8].into_boxed_slice());
+            slice::from_raw_parts_mut(words.as_mut_ptr() as *mut u8, APP_MEMORY_SIZE)
+        };
+        unsafe {
+            process::load_processes(
+                image.as_ptr(),
+                memory,
+                &mut procs[index..index + 1],
+                FaultResponse::Panic,
+            );
+        }
+        assert!(procs[index].is_some(), "the app did not load");
+        AppId::new(index)
+    }
+
+    /// A buffer of app `appid` that holds `data`
+    pub fn buffer(&self, appid: AppId, data: &[u8]) -> Buffer {
+        let data: &'static mut [u8] = Box::leak(data.to_vec().into_boxed_slice());
+        Buffer {
+            appid: appid,
+            ptr: data.as_mut_ptr(),
+            len: data.len(),
+        }
+    }
+}
+
+/// Memory of an app that it can allow a capsule to use
+pub struct Buffer {
+    appid: AppId,
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Buffer {
+    /// The buffer as the kernel passes it to `allow()`
+    pub fn slice(&self) -> AppSlice<Shared, u8> {
+        unsafe { AppSlice::new(self.ptr, self.len, self.appid) }
+    }
+
+    /// What the buffer holds now
+    pub fn contents(&self) -> Vec<u8> {
+        unsafe { slice::from_raw_parts(self.ptr, self.len).to_vec() }
+    }
+}
+
+/// A TBF image with only a header, and the entry point right after it
+fn app_image(name: &'static str) -> &'static [u8] {
+    let mut header = TbfHeaderV2 {
+        base: TbfHeaderV2Base {
+            version: 2,
+            flags: TbfHeaderV2Base::FLAG_ENABLED,
+            ..Default::default()
+        },
+        main: Some(TbfHeaderV2Main::default()),
+        package_name: Some(name),
+        writeable_regions: None,
+        pic_option1: None,
+        app_version: None,
+        kernel_abi: None,
+        nonvolatile_storage: None,
+    };
+    let header_size = header.serialized_len().unwrap();
+    header.base.total_size = header_size as u32 + 4;
+    header.main = Some(TbfHeaderV2Main {
+        // The entry point has to be Thumb code
+        init_fn_offset: header_size as u32 + 1,
+        protected_size: 0,
+        minimum_ram_size: APP_MEMORY_SIZE as u32,
+    });
+
+    let image = Box::leak(vec![0; header_size + 4].into_boxed_slice());
+    header.serialize(image).unwrap();
+    image
+}
@@ -0,0 +1,423 @@
+//! A USB controller that is driven by the test instead of by a host
+//!
+//! `SimulatedController` implements `hil::usb::UsbController` for the capsule
+//! under test, and calls back into the capsule the way the SAM4L driver does
+//! when the host sends a transaction. The transactions are started by the
+//! methods on the controller, which are usually called through `Host`.
+//!
+//! IN endpoints are refilled lazily: the capsule is asked for the next packet
+//! when the host polls the endpoint, or right after the host received the
+//! last one. A packet a capsule refuses on an OUT endpoint stays in the
+//! endpoint buffer, and is handed to the capsule again once it resumes the
+//! endpoint.
+
+use kernel::common::VolatileCell;
+use kernel::hil;
+use kernel::hil::usb::*;
+use std::cell::{Cell, RefCell};
+use std::slice;
+
+/// Number of endpoints, including endpoint 0
+pub const NUM_ENDPOINTS: usize = 8;
+
+/// Size of the Setup packet of a control transfer
+const SETUP_PACKET_SIZE: usize = 8;
+
+/// How the device answered a transaction, if it did not accept it
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// The endpoint is halted, or the capsule does not support the request
+    Stall,
+
+    /// The capsule is not ready, so the host has to try again later
+    Nak,
+
+    /// The endpoint is not enabled in this direction
+    NotEnabled,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum InState {
+    /// The buffer is free, and the capsule is asked for a packet when the
+    /// host polls the endpoint
+    Idle,
+
+    /// The buffer holds a packet of the given size
+    Sending(usize),
+
+    /// The capsule has nothing to send until it resumes the endpoint
+    Delay,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum OutState {
+    Idle,
+
+    /// The buffer holds a packet of the given size that the capsule has no
+    /// room for yet
+    Delay(usize),
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Endpoint {
+    Disabled,
+    Ctrl,
+    In(TransferType, InState),
+    Out(TransferType, OutState),
+}
+
+impl Default for Endpoint {
+    fn default() -> Endpoint {
+        Endpoint::Disabled
+    }
+}
+
+pub struct SimulatedController {
+    client: Cell<Option<&'static hil::usb::Client>>,
+    enabled: Cell<bool>,
+    attached: Cell<bool>,
+
+    /// The address the host assigned, and the one it is assigning
+    address: Cell<u16>,
+    pending_address: Cell<u16>,
+
+    endpoints: [Cell<Endpoint>; NUM_ENDPOINTS],
+    buffers: RefCell<[Option<(*const VolatileCell<u8>, usize)>; NUM_ENDPOINTS]>,
+    stalled: [Cell<bool>; NUM_ENDPOINTS],
+
+    /// Endpoints the capsule resumed, which are serviced before the next
+    /// transaction
+    resumed: [Cell<bool>; NUM_ENDPOINTS],
+    /// The endpoint whose `packet_in()` or `packet_out()` is running
+    servicing: Cell<Option<usize>>,
+}
+
+impl SimulatedController {
+    pub fn new() -> SimulatedController {
+        ::debug::init();
+        SimulatedController {
+            client: Cell::new(None),
+            enabled: Cell::new(false),
+            attached: Cell::new(false),
+            address: Cell::new(0),
+            pending_address: Cell::new(0),
+            endpoints: Default::default(),
+            buffers: RefCell::new([None; NUM_ENDPOINTS]),
+            stalled: Default::default(),
+            resumed: Default::default(),
+            servicing: Cell::new(None),
+        }
+    }
+
+    pub fn set_client(&self, client: &'static hil::usb::Client) {
+        self.client.set(Some(client));
+    }
+
+    fn client(&self) -> &'static hil::usb::Client {
+        self.client.get().expect("No USB client set")
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.get()
+    }
+
+    pub fn is_attached(&self) -> bool {
+        self.attached.get()
+    }
+
+    /// The address the device answers to
+    pub fn address(&self) -> u16 {
+        self.address.get()
+    }
+
+    /// Whether endpoint `e` answers all transactions with STALL
+    pub fn is_stalled(&self, e: usize) -> bool {
+        self.stalled[e].get()
+    }
+
+    /// The buffer the capsule set for endpoint `e`
+    fn buffer(&self, e: usize) -> &[VolatileCell<u8>] {
+        let (ptr, len) = self.buffers.borrow()[e].expect("Endpoint has no buffer");
+        // The capsules under test live for the rest of the test, and so do
+        // the endpoint buffers they own.
+        unsafe { slice::from_raw_parts(ptr, len) }
+    }
+
+    /// The largest packet endpoint `e` takes, which is the size of its buffer
+    pub fn max_packet_size(&self, e: usize) -> usize {
+        self.buffers.borrow()[e].map_or(0, |(_, len)| len)
+    }
+
+    /// Reset the bus, which clears the address of the device and drops the
+    /// packets in the endpoint buffers
+    pub fn reset(&self) {
+        self.address.set(0);
+        for e in 0..NUM_ENDPOINTS {
+            self.stalled[e].set(false);
+            self.resumed[e].set(false);
+            match self.endpoints[e].get() {
+                Endpoint::In(transfer_type, _) => {
+                    self.endpoints[e].set(Endpoint::In(transfer_type, InState::Idle));
+                }
+                Endpoint::Out(transfer_type, _) => {
+                    self.endpoints[e].set(Endpoint::Out(transfer_type, OutState::Idle));
+                }
+                _ => {}
+            }
+        }
+        self.client().bus_reset();
+    }
+
+    /// Perform a control transfer that reads up to `length` bytes from the
+    /// device. `setup` has to ask for the same length.
+    pub fn control_read(&self, setup: [u8; 8], length: usize) -> Result<Vec<u8>, Error> {
+        self.setup(setup)?;
+
+        let mut data = Vec::new();
+        loop {
+            match self.client().ctrl_in() {
+                CtrlInResult::Packet(packet_bytes, transfer_complete) => {
+                    let buf = self.buffer(0);
+                    data.extend(buf[..packet_bytes].iter().map(|b| b.get()));
+                    // The host ends the Data stage on a short packet, or
+                    // once it has all the data it asked for
+                    if transfer_complete || packet_bytes < buf.len() || data.len() >= length {
+                        break;
+                    }
+                }
+                CtrlInResult::Delay => return Err(Error::Nak),
+                CtrlInResult::Error => return Err(Error::Stall),
+            }
+        }
+        if data.len() > length {
+            panic!("Device sent {} bytes, but host asked for {}", data.len(), length);
+        }
+
+        self.client().ctrl_status();
+        self.client().ctrl_status_complete();
+        Ok(data)
+    }
+
+    /// Perform a control transfer that writes `data` to the device, or
+    /// one without a Data stage if `data` is empty
+    pub fn control_write(&self, setup: [u8; 8], data: &[u8]) -> Result<(), Error> {
+        self.setup(setup)?;
+
+        let max_packet_size = self.max_packet_size(0);
+        for packet in data.chunks(max_packet_size) {
+            let buf = self.buffer(0);
+            for (i, b) in packet.iter().enumerate() {
+                buf[i].set(*b);
+            }
+            match self.client().ctrl_out(packet.len() as u32) {
+                CtrlOutResult::Ok => {}
+                CtrlOutResult::Delay => return Err(Error::Nak),
+                CtrlOutResult::Halted => return Err(Error::Stall),
+            }
+        }
+
+        self.client().ctrl_status();
+        self.client().ctrl_status_complete();
+        Ok(())
+    }
+
+    /// Send the Setup packet of a control transfer
+    fn setup(&self, setup: [u8; 8]) -> Result<(), Error> {
+        self.service_resumed();
+        if self.endpoints[0].get() != Endpoint::Ctrl {
+            return Err(Error::NotEnabled);
+        }
+
+        // A Setup packet clears a halt of endpoint 0
+        self.stalled[0].set(false);
+        let buf = self.buffer(0);
+        if buf.len() < SETUP_PACKET_SIZE {
+            panic!("Buffer of endpoint 0 cannot hold a Setup packet");
+        }
+        for (i, b) in setup.iter().enumerate() {
+            buf[i].set(*b);
+        }
+
+        match self.client().ctrl_setup() {
+            CtrlSetupResult::Ok => Ok(()),
+            _ => Err(Error::Stall),
+        }
+    }
+
+    /// Poll IN endpoint `e` for a packet
+    pub fn in_packet(&self, e: usize) -> Result<Vec<u8>, Error> {
+        self.service_resumed();
+        let transfer_type = match self.endpoints[e].get() {
+            Endpoint::In(transfer_type, _) => transfer_type,
+            _ => return Err(Error::NotEnabled),
+        };
+        if self.stalled[e].get() {
+            return Err(Error::Stall);
+        }
+
+        self.fill_in(e);
+        match self.endpoints[e].get() {
+            Endpoint::In(_, InState::Sending(packet_bytes)) => {
+                let packet = self.buffer(e)[..packet_bytes]
+                    .iter()
+                    .map(|b| b.get())
+                    .collect();
+                self.endpoints[e].set(Endpoint::In(transfer_type, InState::Idle));
+
+                // The buffer is free again
+                self.servicing.set(Some(e));
+                self.client().packet_transmitted(e);
+                self.servicing.set(None);
+                self.fill_in(e);
+                Ok(packet)
+            }
+            _ if self.stalled[e].get() => Err(Error::Stall),
+            _ => Err(Error::Nak),
+        }
+    }
+
+    /// Ask the client for the next packet of IN endpoint `e` if its buffer
+    /// is free
+    fn fill_in(&self, e: usize) {
+        if let Endpoint::In(transfer_type, InState::Idle) = self.endpoints[e].get() {
+            self.servicing.set(Some(e));
+            let result = self.client().packet_in(transfer_type, e);
+            self.servicing.set(None);
+
+            let state = match result {
+                InResult::Packet(packet_bytes) => InState::Sending(packet_bytes),
+                InResult::Delay => InState::Delay,
+                InResult::Error => {
+                    self.stalled[e].set(true);
+                    InState::Delay
+                }
+            };
+            self.endpoints[e].set(Endpoint::In(transfer_type, state));
+        }
+    }
+
+    /// Send a packet to OUT endpoint `e`
+    pub fn out_packet(&self, e: usize, packet: &[u8]) -> Result<(), Error> {
+        self.service_resumed();
+        let transfer_type = match self.endpoints[e].get() {
+            Endpoint::Out(transfer_type, OutState::Idle) => transfer_type,
+            Endpoint::Out(_, OutState::Delay(_)) => return Err(Error::Nak),
+            _ => return Err(Error::NotEnabled),
+        };
+        if self.stalled[e].get() {
+            return Err(Error::Stall);
+        }
+
+        let buf = self.buffer(e);
+        if packet.len() > buf.len() {
+            panic!("Packet of {} bytes is too large for endpoint {}", packet.len(), e);
+        }
+        for (i, b) in packet.iter().enumerate() {
+            buf[i].set(*b);
+        }
+        self.deliver_out(e, transfer_type, packet.len());
+        Ok(())
+    }
+
+    /// Hand the packet in the buffer of OUT endpoint `e` to the client
+    fn deliver_out(&self, e: usize, transfer_type: TransferType, packet_bytes: usize) {
+        self.servicing.set(Some(e));
+        let result = self.client()
+            .packet_out(transfer_type, e, packet_bytes as u32);
+        self.servicing.set(None);
+
+        let state = match result {
+            OutResult::Ok => OutState::Idle,
+            OutResult::Delay => OutState::Delay(packet_bytes),
+            OutResult::Error => {
+                self.stalled[e].set(true);
+                OutState::Idle
+            }
+        };
+        self.endpoints[e].set(Endpoint::Out(transfer_type, state));
+    }
+
+    /// Continue the endpoints the client resumed, including those it resumes
+    /// while others are continued
+    pub fn service_resumed(&self) {
+        while let Some(e) = (0..NUM_ENDPOINTS).find(|&e| self.resumed[e].get()) {
+            self.resumed[e].set(false);
+
+            match self.endpoints[e].get() {
+                Endpoint::In(transfer_type, InState::Delay) => {
+                    self.endpoints[e].set(Endpoint::In(transfer_type, InState::Idle));
+                }
+                Endpoint::Out(transfer_type, OutState::Delay(packet_bytes)) => {
+                    self.deliver_out(e, transfer_type, packet_bytes);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn resume(&self, e: usize) {
+        // Like the hardware, ignore a resume from within the callback that
+        // is about to return Delay for the same endpoint
+        if self.servicing.get() != Some(e) {
+            self.resumed[e].set(true);
+        }
+    }
+}
+
+impl UsbController for SimulatedController {
+    type EndpointState = ();
+
+    fn enable_device(&self, _full_speed: bool) {
+        self.enabled.set(true);
+    }
+
+    fn attach(&self) {
+        self.attached.set(true);
+    }
+
+    fn endpoint_configure(&self, _: &'static (), _index: u32) {}
+
+    fn endpoint_count(&self) -> u32 {
+        NUM_ENDPOINTS as u32
+    }
+
+    fn endpoint_set_buffer(&self, e: u32, buf: &[VolatileCell<u8>]) {
+        self.buffers.borrow_mut()[e as usize] = Some((buf.as_ptr(), buf.len()));
+    }
+
+    fn endpoint_ctrl_out_enable(&self, e: u32) {
+        self.endpoints[e as usize].set(Endpoint::Ctrl);
+    }
+
+    fn endpoint_in_enable(&self, transfer_type: TransferType, e: u32) {
+        self.endpoints[e as usize].set(Endpoint::In(transfer_type, InState::Idle));
+    }
+
+    fn endpoint_out_enable(&self, transfer_type: TransferType, e: u32) {
+        self.endpoints[e as usize].set(Endpoint::Out(transfer_type, OutState::Idle));
+    }
+
+    fn endpoint_resume_in(&self, e: u32) {
+        self.resume(e as usize);
+    }
+
+    fn endpoint_resume_out(&self, e: u32) {
+        self.resume(e as usize);
+    }
+
+    fn endpoint_set_stall(&self, e: u32) {
+        self.stalled[e as usize].set(true);
+    }
+
+    fn endpoint_clear_stall(&self, e: u32) {
+        self.stalled[e as usize].set(false);
+    }
+
+    fn set_address(&self, addr: u16) {
+        self.pending_address.set(addr);
+    }
+
+    fn enable_address(&self) {
+        self.address.set(self.pending_address.get());
+    }
+}
@@ -0,0 +1,86 @@
+//! A console for the kernel's `debug!()`
+//!
+//! Capsules print with `debug!()`, which panics unless the board has given
+//! the kernel a console driver. `init()` gives it one that writes the
+//! messages to standard error, where the test harness captures them.
+//! `SimulatedController::new()` calls it, so every test has the console.
+//!
+//! The kernel's debug buffer is global, so messages of tests that print at
+//! the same time can be interleaved.
+
+use kernel::{AppId, AppSlice, Callback, Driver, ReturnCode, Shared};
+use std::cell::{Cell, RefCell};
+use std::cmp::min;
+use std::sync::{Once, ONCE_INIT};
+
+static INIT: Once = ONCE_INIT;
+
+/// Gives the kernel the debug console, if it does not have it yet
+pub fn init() {
+    INIT.call_once(|| {
+        let console: &'static Console = Box::leak(Box::new(Console {
+            buffer: RefCell::new(None),
+            callback: Cell::new(None),
+        }));
+        // The console keeps no state for the kernel in its grant
+        let grant: &'static mut u8 = Box::leak(Box::new(0));
+        unsafe {
+            ::kernel::debug::assign_console_driver(Some(console), grant);
+        }
+    });
+}
+
+/// The part of the console driver's system call interface `debug!()` uses:
+/// the buffer to print is allowed with 1, the callback for the end of the
+/// print is subscribed with 1, and command 1 prints
+struct Console {
+    buffer: RefCell<Option<AppSlice<Shared, u8>>>,
+    callback: Cell<Option<Callback>>,
+}
+
+impl Driver for Console {
+    fn allow(
+        &self,
+        _app: AppId,
+        minor_num: usize,
+        slice: Option<AppSlice<Shared, u8>>,
+    ) -> ReturnCode {
+        match minor_num {
+            1 => {
+                *self.buffer.borrow_mut() = slice;
+                ReturnCode::SUCCESS
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn subscribe(&self, minor_num: usize, callback: Option<Callback>, _app: AppId) -> ReturnCode {
+        match minor_num {
+            1 => {
+                self.callback.set(callback);
+                ReturnCode::SUCCESS
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+
+    fn command(&self, minor_num: usize, len: usize, _: usize, _app: AppId) -> ReturnCode {
+        match minor_num {
+            1 => {
+                let len = match *self.buffer.borrow() {
+                    Some(ref buffer) => {
+                        let len = min(len, buffer.len());
+                        eprint!("{}", String::from_utf8_lossy(&buffer.as_ref()[..len]));
+                        len
+                    }
+                    None => return ReturnCode::ERESERVE,
+                };
+                // The kernel's callback prints the rest of its buffer, so it
+                // runs once the buffer is no longer borrowed
+                self.callback.get().map(|mut callback| callback.schedule(len, 0, 0));
+                ReturnCode::SUCCESS
+            }
+            _ => ReturnCode::ENOSUPPORT,
+        }
+    }
+}
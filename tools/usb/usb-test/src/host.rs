@@ -0,0 +1,317 @@
+//! The host side of the bus
+//!
+//! `Host` sends standard, class and vendor requests to the device, and
+//! splits bulk and interrupt data into packets, so tests can be written in
+//! terms of whole transfers.
+
+use controller::{Error, SimulatedController};
+
+/// Direction of the Data stage of a control transfer
+pub const DEVICE_TO_HOST: u8 = 0x80;
+pub const HOST_TO_DEVICE: u8 = 0x00;
+
+/// Type of a request
+pub const STANDARD: u8 = 0x00;
+pub const CLASS: u8 = 0x20;
+pub const VENDOR: u8 = 0x40;
+
+/// Recipient of a request
+pub const DEVICE: u8 = 0x00;
+pub const INTERFACE: u8 = 0x01;
+pub const ENDPOINT: u8 = 0x02;
+pub const OTHER: u8 = 0x03;
+
+/// Standard requests
+pub const CLEAR_FEATURE: u8 = 1;
+pub const SET_FEATURE: u8 = 3;
+pub const SET_ADDRESS: u8 = 5;
+pub const GET_DESCRIPTOR: u8 = 6;
+pub const GET_CONFIGURATION: u8 = 8;
+pub const SET_CONFIGURATION: u8 = 9;
+
+/// Descriptor types
+pub const DEVICE_DESCRIPTOR: u8 = 1;
+pub const CONFIGURATION_DESCRIPTOR: u8 = 2;
+pub const STRING_DESCRIPTOR: u8 = 3;
+
+/// Feature selector of a halted endpoint
+const ENDPOINT_HALT: u16 = 0;
+
+/// The language the host asks for strings in
+const ENGLISH_US: u16 = 0x0409;
+
+/// The Setup packet of a control transfer
+#[derive(Copy, Clone, Debug)]
+pub struct Setup {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl Setup {
+    pub fn bytes(&self) -> [u8; 8] {
+        [
+            self.request_type,
+            self.request,
+            self.value as u8,
+            (self.value >> 8) as u8,
+            self.index as u8,
+            (self.index >> 8) as u8,
+            self.length as u8,
+            (self.length >> 8) as u8,
+        ]
+    }
+}
+
+/// What the host learns about the device when enumerating it
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    pub device_descriptor: Vec<u8>,
+    /// The configuration descriptor and all descriptors that follow it
+    pub configuration: Vec<u8>,
+    /// Manufacturer, product and serial number
+    pub strings: [Option<String>; 3],
+}
+
+impl DeviceInfo {
+    pub fn vendor_id(&self) -> u16 {
+        self.device_descriptor[8] as u16 | (self.device_descriptor[9] as u16) << 8
+    }
+
+    pub fn product_id(&self) -> u16 {
+        self.device_descriptor[10] as u16 | (self.device_descriptor[11] as u16) << 8
+    }
+
+    /// The descriptors in the configuration, each with its type
+    pub fn descriptors(&self) -> Vec<(u8, &[u8])> {
+        split_descriptors(&self.configuration)
+    }
+}
+
+/// Split concatenated descriptors into their types and bytes
+pub fn split_descriptors(bytes: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut descriptors = Vec::new();
+    let mut rest = bytes;
+    while rest.len() >= 2 {
+        let length = rest[0] as usize;
+        if length < 2 || length > rest.len() {
+            panic!("Malformed descriptor at {}", bytes.len() - rest.len());
+        }
+        descriptors.push((rest[1], &rest[..length]));
+        rest = &rest[length..];
+    }
+    if !rest.is_empty() {
+        panic!("Trailing byte after descriptors");
+    }
+    descriptors
+}
+
+pub struct Host<'a> {
+    controller: &'a SimulatedController,
+}
+
+impl<'a> Host<'a> {
+    pub fn new(controller: &'a SimulatedController) -> Host<'a> {
+        Host {
+            controller: controller,
+        }
+    }
+
+    pub fn controller(&self) -> &'a SimulatedController {
+        self.controller
+    }
+
+    pub fn reset(&self) {
+        self.controller.reset();
+    }
+
+    /// Read `setup.length` bytes at most with a control transfer
+    pub fn control_in(&self, setup: Setup) -> Result<Vec<u8>, Error> {
+        if setup.request_type & DEVICE_TO_HOST == 0 {
+            panic!("Control In transfer with a Host-to-Device request");
+        }
+        self.controller
+            .control_read(setup.bytes(), setup.length as usize)
+    }
+
+    /// Write `data` with a control transfer; `setup.length` is set to its
+    /// length
+    pub fn control_out(&self, setup: Setup, data: &[u8]) -> Result<(), Error> {
+        if setup.request_type & DEVICE_TO_HOST != 0 {
+            panic!("Control Out transfer with a Device-to-Host request");
+        }
+        let setup = Setup {
+            length: data.len() as u16,
+            ..setup
+        };
+        self.controller.control_write(setup.bytes(), data)
+    }
+
+    pub fn get_descriptor(
+        &self,
+        descriptor_type: u8,
+        index: u8,
+        lang_id: u16,
+        length: u16,
+    ) -> Result<Vec<u8>, Error> {
+        self.control_in(Setup {
+            request_type: DEVICE_TO_HOST | STANDARD | DEVICE,
+            request: GET_DESCRIPTOR,
+            value: (descriptor_type as u16) << 8 | index as u16,
+            index: lang_id,
+            length: length,
+        })
+    }
+
+    /// Read string descriptor `index` in US English
+    pub fn get_string(&self, index: u8) -> Result<String, Error> {
+        let descriptor = self.get_descriptor(STRING_DESCRIPTOR, index, ENGLISH_US, 255)?;
+        if descriptor.len() < 2 || descriptor[0] as usize != descriptor.len()
+            || descriptor[1] != STRING_DESCRIPTOR
+        {
+            panic!("Malformed string descriptor {:?}", descriptor);
+        }
+        let units: Vec<u16> = descriptor[2..]
+            .chunks(2)
+            .map(|unit| unit[0] as u16 | (unit[1] as u16) << 8)
+            .collect();
+        Ok(String::from_utf16(&units).expect("String descriptor is not UTF-16"))
+    }
+
+    pub fn set_address(&self, address: u16) -> Result<(), Error> {
+        self.control_out(
+            Setup {
+                request_type: HOST_TO_DEVICE | STANDARD | DEVICE,
+                request: SET_ADDRESS,
+                value: address,
+                index: 0,
+                length: 0,
+            },
+            &[],
+        )
+    }
+
+    pub fn get_configuration(&self) -> Result<u8, Error> {
+        let value = self.control_in(Setup {
+            request_type: DEVICE_TO_HOST | STANDARD | DEVICE,
+            request: GET_CONFIGURATION,
+            value: 0,
+            index: 0,
+            length: 1,
+        })?;
+        Ok(value[0])
+    }
+
+    pub fn set_configuration(&self, configuration_value: u8) -> Result<(), Error> {
+        self.control_out(
+            Setup {
+                request_type: HOST_TO_DEVICE | STANDARD | DEVICE,
+                request: SET_CONFIGURATION,
+                value: configuration_value as u16,
+                index: 0,
+                length: 0,
+            },
+            &[],
+        )
+    }
+
+    /// Halt endpoint `endpoint_address`, or stop halting it
+    pub fn set_halt(&self, endpoint_address: u8, halt: bool) -> Result<(), Error> {
+        self.control_out(
+            Setup {
+                request_type: HOST_TO_DEVICE | STANDARD | ENDPOINT,
+                request: if halt { SET_FEATURE } else { CLEAR_FEATURE },
+                value: ENDPOINT_HALT,
+                index: endpoint_address as u16,
+                length: 0,
+            },
+            &[],
+        )
+    }
+
+    /// Go through the requests a host makes when a device is plugged in:
+    /// learn the packet size of endpoint 0, assign `address`, read the
+    /// descriptors and the strings of the device, and select the first
+    /// configuration.
+    pub fn enumerate(&self, address: u16) -> Result<DeviceInfo, Error> {
+        self.reset();
+        let max_packet_size = self.get_descriptor(DEVICE_DESCRIPTOR, 0, 0, 64)?[7];
+        if self.controller.max_packet_size(0) != max_packet_size as usize {
+            panic!("Device descriptor gives a wrong packet size for endpoint 0");
+        }
+        self.reset();
+        self.set_address(address)?;
+        if self.controller.address() != address {
+            panic!("Device did not take address {}", address);
+        }
+
+        let device_descriptor = self.get_descriptor(DEVICE_DESCRIPTOR, 0, 0, 18)?;
+        let header = self.get_descriptor(CONFIGURATION_DESCRIPTOR, 0, 0, 9)?;
+        let total_length = header[2] as u16 | (header[3] as u16) << 8;
+        let configuration = self.get_descriptor(CONFIGURATION_DESCRIPTOR, 0, 0, total_length)?;
+        if configuration.len() != total_length as usize {
+            panic!("Configuration is not as long as it claims");
+        }
+
+        let mut strings = [None, None, None];
+        if device_descriptor[14..17].iter().any(|&index| index != 0) {
+            let languages = self.get_descriptor(STRING_DESCRIPTOR, 0, 0, 255)?;
+            if !languages[2..].chunks(2).any(|l| l == [0x09, 0x04]) {
+                panic!("Device does not offer US English strings");
+            }
+            for (i, string) in strings.iter_mut().enumerate() {
+                let index = device_descriptor[14 + i];
+                if index != 0 {
+                    *string = Some(self.get_string(index)?);
+                }
+            }
+        }
+
+        self.set_configuration(configuration[5])?;
+        Ok(DeviceInfo {
+            device_descriptor: device_descriptor,
+            configuration: configuration,
+            strings: strings,
+        })
+    }
+
+    /// Write `data` to OUT endpoint `endpoint` in packets as large as the
+    /// endpoint takes, ending with a short packet. Returns how many bytes
+    /// the device took before it was not ready for more.
+    pub fn write(&self, endpoint: usize, data: &[u8]) -> Result<usize, Error> {
+        let max_packet_size = self.controller.max_packet_size(endpoint);
+        let mut written = 0;
+        loop {
+            let packet = &data[written..(written + max_packet_size).min(data.len())];
+            match self.controller.out_packet(endpoint, packet) {
+                Ok(()) => written += packet.len(),
+                Err(Error::Nak) => return Ok(written),
+                Err(error) => return Err(error),
+            }
+            if packet.len() < max_packet_size {
+                return Ok(written);
+            }
+        }
+    }
+
+    /// Read from IN endpoint `endpoint` until the device sends a short
+    /// packet, or has nothing to send
+    pub fn read(&self, endpoint: usize) -> Result<Vec<u8>, Error> {
+        let max_packet_size = self.controller.max_packet_size(endpoint);
+        let mut data = Vec::new();
+        loop {
+            match self.controller.in_packet(endpoint) {
+                Ok(packet) => {
+                    data.extend_from_slice(&packet);
+                    if packet.len() < max_packet_size {
+                        return Ok(data);
+                    }
+                }
+                Err(Error::Nak) => return Ok(data),
+                Err(error) => return Err(error),
+            }
+        }
+    }
+}
@@ -0,0 +1,58 @@
+//! Host-side tests of the USB capsules
+//!
+//! The USB class capsules are tested on the host machine, without a board,
+//! by connecting them to a `SimulatedController` that plays the part of the
+//! USB hardware and of the bus. A `Host` drives the controller with the
+//! transactions a USB host would send: it enumerates the device, makes
+//! standard, class and vendor requests, and moves data through the bulk and
+//! interrupt endpoints. The tests in `tests/` check the exact bytes the
+//! capsules produce. Capsules that serve apps get them from `app::Apps`, and
+//! what capsules print with `debug!()` goes to standard error.
+//!
+//! Run the tests from the root of the repository with
+//!
+//! ```text
+//! make usbtest
+//! ```
+//!
+//! which also sets the `TOCK_KERNEL_VERSION` the kernel crate needs.
+//!
+//! Usage
+//! -----
+//!
+//! ```
+//! # extern crate capsules;
+//! # extern crate kernel;
+//! # extern crate usb_test;
+//! # use kernel::hil::usb::Client;
+//! # use usb_test::{Host, SimulatedController};
+//! # fn main() {
+//! let controller: &'static SimulatedController =
+//!     Box::leak(Box::new(SimulatedController::new()));
+//! let client = Box::leak(Box::new(capsules::usbc_client::Client::new(
+//!     controller,
+//!     0x6667,
+//!     0xabcd,
+//!     &["Tock", "imix", "0"],
+//!     Box::leak(Box::new([0; 32])),
+//! )));
+//! controller.set_client(client);
+//! client.enable();
+//! client.attach();
+//!
+//! let host = Host::new(controller);
+//! let device = host.enumerate(5).unwrap();
+//! assert_eq!(device.vendor_id(), 0x6667);
+//! # }
+//! ```
+
+extern crate kernel;
+extern crate tbf;
+
+pub mod app;
+pub mod controller;
+pub mod debug;
+pub mod host;
+
+pub use controller::{Error, SimulatedController};
+pub use host::{DeviceInfo, Host, Setup};
@@ -0,0 +1,208 @@
+//! Tests of the CDC-ACM serial port: enumeration, the class requests of the
+//! abstract control model, and data in both directions
+
+extern crate capsules;
+extern crate kernel;
+extern crate usb_test;
+
+use capsules::cdc::CdcAcm;
+use kernel::common::deferred_call::DeferredCallClient;
+use kernel::hil::uart::{self, UART};
+use kernel::hil::usb::Client as UsbClient;
+use std::cell::RefCell;
+use usb_test::host::*;
+use usb_test::{Host, SimulatedController};
+
+const BULK_IN: usize = 2;
+const BULK_OUT: usize = 3;
+
+const SET_LINE_CODING: u8 = 0x20;
+const GET_LINE_CODING: u8 = 0x21;
+const SET_CONTROL_LINE_STATE: u8 = 0x22;
+
+/// Records the buffers the serial port hands back, and whether transmissions
+/// succeeded
+struct UartClient {
+    transmitted: RefCell<Vec<(usize, bool)>>,
+    received: RefCell<Vec<Vec<u8>>>,
+}
+
+impl uart::Client for UartClient {
+    fn transmit_complete(&self, buffer: &'static mut [u8], error: uart::Error) {
+        self.transmitted
+            .borrow_mut()
+            .push((buffer.len(), error == uart::Error::CommandComplete));
+    }
+
+    fn receive_complete(&self, buffer: &'static mut [u8], rx_len: usize, _error: uart::Error) {
+        self.received.borrow_mut().push(buffer[..rx_len].to_vec());
+    }
+}
+
+type Cdc = CdcAcm<'static, SimulatedController>;
+
+fn device() -> (&'static SimulatedController, &'static Cdc, &'static UartClient) {
+    let controller: &'static SimulatedController = Box::leak(Box::new(SimulatedController::new()));
+    let cdc: &'static Cdc = Box::leak(Box::new(CdcAcm::new(
+        controller,
+        0x6667,
+        0xabce,
+        &["Tock", "Tock serial port", "0"],
+        Box::leak(Box::new([0; 67])),
+    )));
+    let uart_client: &'static UartClient = Box::leak(Box::new(UartClient {
+        transmitted: RefCell::new(Vec::new()),
+        received: RefCell::new(Vec::new()),
+    }));
+    controller.set_client(cdc);
+    UART::set_client(cdc, uart_client);
+    cdc.enable();
+    cdc.attach();
+    (controller, cdc, uart_client)
+}
+
+fn class_request(request_type: u8, request: u8, value: u16, length: u16) -> Setup {
+    Setup {
+        request_type: request_type | CLASS | INTERFACE,
+        request: request,
+        value: value,
+        index: 0,
+        length: length,
+    }
+}
+
+fn set_dtr(host: &Host, dtr: bool) {
+    let setup = class_request(HOST_TO_DEVICE, SET_CONTROL_LINE_STATE, dtr as u16, 0);
+    assert_eq!(host.control_out(setup, &[]), Ok(()));
+}
+
+fn buffer(data: &[u8]) -> &'static mut [u8] {
+    Box::leak(data.to_vec().into_boxed_slice())
+}
+
+#[test]
+fn enumeration() {
+    let (controller, _, _) = device();
+    let host = Host::new(controller);
+    let device = host.enumerate(3).unwrap();
+
+    assert_eq!(device.device_descriptor[4], 0x02);
+    assert_eq!(device.vendor_id(), 0x6667);
+    assert_eq!(device.product_id(), 0xabce);
+    assert_eq!(device.strings[1], Some("Tock serial port".to_string()));
+
+    assert_eq!(device.configuration.len(), 67);
+    assert_eq!(&device.configuration[..9], &[9, 2, 67, 0, 2, 1, 0, 0x80, 50]);
+    let types: Vec<u8> = device.descriptors().iter().map(|d| d.0).collect();
+    assert_eq!(types, vec![2, 4, 0x24, 0x24, 0x24, 0x24, 5, 4, 5, 5]);
+    let descriptors = device.descriptors();
+    assert_eq!(descriptors[1].1, &[9, 4, 0, 0, 1, 0x02, 0x02, 0x01, 0]);
+    assert_eq!(descriptors[5].1, &[5, 0x24, 0x06, 0, 1]);
+    assert_eq!(descriptors[6].1, &[7, 5, 0x81, 3, 16, 0, 16]);
+    assert_eq!(descriptors[7].1, &[9, 4, 1, 0, 2, 0x0a, 0, 0, 0]);
+    assert_eq!(descriptors[8].1, &[7, 5, 0x82, 2, 64, 0, 0]);
+    assert_eq!(descriptors[9].1, &[7, 5, 0x03, 2, 64, 0, 0]);
+}
+
+#[test]
+fn line_coding() {
+    let (controller, _, _) = device();
+    let host = Host::new(controller);
+    host.enumerate(3).unwrap();
+
+    // 115200 baud, 8N1
+    let get_line_coding = class_request(DEVICE_TO_HOST, GET_LINE_CODING, 0, 7);
+    assert_eq!(host.control_in(get_line_coding), Ok(vec![0x00, 0xc2, 0x01, 0, 0, 0, 8]));
+
+    // 9600 baud, 2 stop bits, even parity
+    let line_coding = [0x80, 0x25, 0, 0, 2, 2, 8];
+    let set_line_coding = class_request(HOST_TO_DEVICE, SET_LINE_CODING, 0, 0);
+    assert_eq!(host.control_out(set_line_coding, &line_coding), Ok(()));
+    assert_eq!(host.control_in(get_line_coding), Ok(line_coding.to_vec()));
+}
+
+#[test]
+fn transmit() {
+    let (controller, cdc, uart_client) = device();
+    let host = Host::new(controller);
+    host.enumerate(3).unwrap();
+
+    // Without a program on the host the data is dropped
+    cdc.transmit(buffer(&[1, 2, 3]), 3);
+    cdc.handle_deferred_call();
+    assert_eq!(host.read(BULK_IN), Ok(vec![]));
+    assert_eq!(uart_client.transmitted.borrow_mut().pop(), Some((3, true)));
+
+    // Two full packets, ended by a zero-length packet
+    set_dtr(&host, true);
+    let data: Vec<u8> = (0..128).map(|i| i as u8).collect();
+    cdc.transmit(buffer(&data), 128);
+    assert_eq!(host.read(BULK_IN), Ok(data));
+    assert_eq!(uart_client.transmitted.borrow_mut().pop(), Some((128, true)));
+
+    // Only `tx_len` bytes of the buffer are sent
+    cdc.transmit(buffer(&[9; 16]), 5);
+    assert_eq!(host.read(BULK_IN), Ok(vec![9; 5]));
+    assert_eq!(uart_client.transmitted.borrow_mut().pop(), Some((16, true)));
+}
+
+#[test]
+fn receive() {
+    let (controller, cdc, uart_client) = device();
+    let host = Host::new(controller);
+    host.enumerate(3).unwrap();
+    set_dtr(&host, true);
+
+    let data: Vec<u8> = (1..13).collect();
+    cdc.receive(buffer(&[0; 16]), 10);
+    cdc.handle_deferred_call();
+    assert_eq!(host.write(BULK_OUT, &data), Ok(12));
+    assert_eq!(uart_client.received.borrow_mut().pop(), Some(data[..10].to_vec()));
+
+    // The rest of the packet is kept for the next receive
+    cdc.receive(buffer(&[0; 16]), 2);
+    cdc.handle_deferred_call();
+    assert_eq!(uart_client.received.borrow_mut().pop(), Some(data[10..].to_vec()));
+
+    // Without a receive buffer, the device holds on to one packet, and
+    // refuses the ones after it. Here that is the zero-length packet after
+    // the full one.
+    assert_eq!(host.write(BULK_OUT, &[0x55; 64]), Ok(64));
+    assert_eq!(host.write(BULK_OUT, &[0x66; 4]), Ok(0));
+    cdc.receive(buffer(&[0; 128]), 68);
+    cdc.handle_deferred_call();
+    assert_eq!(host.write(BULK_OUT, &[0x66; 4]), Ok(4));
+    let mut expected = vec![0x55; 64];
+    expected.extend_from_slice(&[0x66; 4]);
+    assert_eq!(uart_client.received.borrow_mut().pop(), Some(expected));
+}
+
+#[test]
+fn repeated_calls() {
+    let (controller, cdc, uart_client) = device();
+    let host = Host::new(controller);
+    host.enumerate(3).unwrap();
+    set_dtr(&host, true);
+
+    // While a transmission waits for the host, one more buffer is handed back
+    // from the deferred call, and any further one right away
+    cdc.transmit(buffer(&[1; 4]), 4);
+    cdc.transmit(buffer(&[2; 5]), 5);
+    cdc.transmit(buffer(&[3; 6]), 6);
+    assert_eq!(*uart_client.transmitted.borrow(), vec![(6, false)]);
+    cdc.handle_deferred_call();
+    assert_eq!(*uart_client.transmitted.borrow(), vec![(6, false), (5, false)]);
+    assert_eq!(host.read(BULK_IN), Ok(vec![1; 4]));
+    assert_eq!(uart_client.transmitted.borrow_mut().pop(), Some((4, true)));
+
+    // A new receive gives back the old buffer with what it got so far
+    cdc.receive(buffer(&[0; 16]), 10);
+    cdc.handle_deferred_call();
+    assert_eq!(host.write(BULK_OUT, &[1, 2, 3, 4]), Ok(4));
+    assert_eq!(uart_client.received.borrow_mut().pop(), None);
+    cdc.receive(buffer(&[0; 16]), 4);
+    assert_eq!(uart_client.received.borrow_mut().pop(), Some(vec![1, 2, 3, 4]));
+    cdc.handle_deferred_call();
+    assert_eq!(host.write(BULK_OUT, &[5, 6, 7, 8]), Ok(4));
+    assert_eq!(uart_client.received.borrow_mut().pop(), Some(vec![5, 6, 7, 8]));
+}
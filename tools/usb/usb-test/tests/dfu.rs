@@ -0,0 +1,454 @@
+//! Tests of the DFU capsule, going through the requests `dfu-util` makes to
+//! download an image
+
+extern crate capsules;
+extern crate kernel;
+extern crate usb_test;
+
+use capsules::usb_dfu::{Dfu, Target, TargetKind};
+use kernel::ReturnCode;
+use kernel::common::take_cell::TakeCell;
+use kernel::hil;
+use kernel::hil::usb::Client as UsbClient;
+use std::cell::{Cell, RefCell};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+use usb_test::host::*;
+use usb_test::{Error, Host, SimulatedController};
+
+const PAGE_SIZE: usize = 128;
+
+const DFU_DETACH: u8 = 0;
+const DFU_DNLOAD: u8 = 1;
+const DFU_UPLOAD: u8 = 2;
+const DFU_GETSTATUS: u8 = 3;
+const DFU_CLRSTATUS: u8 = 4;
+const DFU_GETSTATE: u8 = 5;
+const SET_INTERFACE: u8 = 11;
+
+const APP_IDLE: u8 = 0;
+const DFU_IDLE: u8 = 2;
+const DFU_DNBUSY: u8 = 4;
+const DFU_DNLOAD_IDLE: u8 = 5;
+const DFU_MANIFEST: u8 = 7;
+const DFU_MANIFEST_WAIT_RESET: u8 = 8;
+const DFU_ERROR: u8 = 10;
+
+const OK: u8 = 0x00;
+const ERR_FILE: u8 = 0x02;
+const ERR_ADDRESS: u8 = 0x08;
+const ERR_STALLED_PACKET: u8 = 0x0f;
+
+static TARGETS: [Target; 2] = [
+    Target {
+        kind: TargetKind::App,
+        first_page: 2,
+        pages: 4,
+    },
+    Target {
+        kind: TargetKind::Kernel,
+        first_page: 8,
+        pages: 2,
+    },
+];
+
+static RESETS: AtomicUsize = ATOMIC_USIZE_INIT;
+
+pub struct Page([u8; PAGE_SIZE]);
+
+impl AsMut<[u8]> for Page {
+    fn as_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+/// Flash that completes a write when the test asks it to
+struct Flash {
+    memory: RefCell<Vec<u8>>,
+    writing: Cell<Option<usize>>,
+    buffer: TakeCell<'static, Page>,
+    client: Cell<Option<&'static hil::flash::Client<Flash>>>,
+}
+
+impl Flash {
+    fn finish_write(&self) {
+        let page = self.writing.take().expect("No page is being written");
+        let buffer = self.buffer.take().unwrap();
+        self.memory.borrow_mut()[page * PAGE_SIZE..(page + 1) * PAGE_SIZE]
+            .copy_from_slice(&buffer.0);
+        self.client
+            .get()
+            .unwrap()
+            .write_complete(buffer, hil::flash::Error::CommandComplete);
+    }
+
+    fn page(&self, page: usize) -> Vec<u8> {
+        self.memory.borrow()[page * PAGE_SIZE..(page + 1) * PAGE_SIZE].to_vec()
+    }
+}
+
+impl hil::flash::Flash for Flash {
+    type Page = Page;
+
+    fn read_page(
+        &self,
+        _page_number: usize,
+        buf: &'static mut Page,
+    ) -> (ReturnCode, Option<&'static mut Page>) {
+        (ReturnCode::ENOSUPPORT, Some(buf))
+    }
+
+    fn write_page(
+        &self,
+        page_number: usize,
+        buf: &'static mut Page,
+    ) -> (ReturnCode, Option<&'static mut Page>) {
+        self.buffer.replace(buf);
+        self.writing.set(Some(page_number));
+        (ReturnCode::SUCCESS, None)
+    }
+
+    fn erase_page(&self, _page_number: usize) -> ReturnCode {
+        ReturnCode::ENOSUPPORT
+    }
+}
+
+type UsbDfu = Dfu<'static, SimulatedController, Flash>;
+
+fn flash() -> &'static Flash {
+    Box::leak(Box::new(Flash {
+        memory: RefCell::new(vec![0; 12 * PAGE_SIZE]),
+        writing: Cell::new(None),
+        buffer: TakeCell::empty(),
+        client: Cell::new(None),
+    }))
+}
+
+fn device() -> (Host<'static>, &'static Flash) {
+    let controller: &'static SimulatedController = Box::leak(Box::new(SimulatedController::new()));
+    let flash = flash();
+    let dfu: &'static UsbDfu = Box::leak(Box::new(Dfu::new(
+        controller,
+        0x6667,
+        0xabd0,
+        &["Tock", "imix", "0", "app", "kernel"],
+        Box::leak(Box::new([0; 81])),
+        flash,
+        Box::leak(Box::new(Page([0; PAGE_SIZE]))),
+        &TARGETS,
+        || {
+            RESETS.fetch_add(1, Ordering::SeqCst);
+        },
+    )));
+    controller.set_client(dfu);
+    flash.client.set(Some(dfu));
+    dfu.enable();
+    dfu.attach();
+    (Host::new(controller), flash)
+}
+
+fn dfu_in(host: &Host, request: u8, length: u16) -> Result<Vec<u8>, Error> {
+    host.control_in(Setup {
+        request_type: DEVICE_TO_HOST | CLASS | INTERFACE,
+        request: request,
+        value: 0,
+        index: 0,
+        length: length,
+    })
+}
+
+fn dfu_out(host: &Host, request: u8, value: u16, data: &[u8]) -> Result<(), Error> {
+    host.control_out(
+        Setup {
+            request_type: HOST_TO_DEVICE | CLASS | INTERFACE,
+            request: request,
+            value: value,
+            index: 0,
+            length: 0,
+        },
+        data,
+    )
+}
+
+/// The status, the poll timeout and the state
+fn get_status(host: &Host) -> (u8, u32, u8) {
+    let status = dfu_in(host, DFU_GETSTATUS, 6).unwrap();
+    assert_eq!(status.len(), 6);
+    let poll_timeout = status[1] as u32 | (status[2] as u32) << 8 | (status[3] as u32) << 16;
+    (status[0], poll_timeout, status[4])
+}
+
+fn select_target(host: &Host, target: u16) -> Result<(), Error> {
+    host.control_out(
+        Setup {
+            request_type: HOST_TO_DEVICE | STANDARD | INTERFACE,
+            request: SET_INTERFACE,
+            value: target,
+            index: 0,
+            length: 0,
+        },
+        &[],
+    )
+}
+
+/// Switch from runtime mode to DFU mode
+fn detach(host: &Host) {
+    host.enumerate(1).unwrap();
+    assert_eq!(dfu_in(host, DFU_GETSTATE, 1), Ok(vec![APP_IDLE]));
+    assert_eq!(dfu_out(host, DFU_DETACH, 1000, &[]), Ok(()));
+    host.enumerate(1).unwrap();
+    assert_eq!(get_status(host), (OK, 0, DFU_IDLE));
+}
+
+/// Download a block, and wait for it to be written like `dfu-util` does
+fn download_block(host: &Host, flash: &Flash, block: u16, data: &[u8]) {
+    assert_eq!(dfu_out(host, DFU_DNLOAD, block, data), Ok(()));
+    let (status, poll_timeout, state) = get_status(host);
+    if state == DFU_DNBUSY {
+        assert!(poll_timeout > 0);
+        flash.finish_write();
+        assert_eq!(get_status(host), (OK, 0, DFU_DNLOAD_IDLE));
+    } else {
+        assert_eq!((status, state), (OK, DFU_DNLOAD_IDLE));
+    }
+}
+
+/// Download an image in blocks of a page, and manifest it
+fn download(host: &Host, flash: &Flash, image: &[u8]) {
+    for (block, data) in image.chunks(PAGE_SIZE).enumerate() {
+        download_block(host, flash, block as u16, data);
+    }
+    assert_eq!(dfu_out(host, DFU_DNLOAD, 0, &[]), Ok(()));
+    let (_, _, state) = get_status(host);
+    if state == DFU_MANIFEST {
+        flash.finish_write();
+        assert_eq!(get_status(host), (OK, 0, DFU_MANIFEST_WAIT_RESET));
+    } else {
+        assert_eq!(state, DFU_MANIFEST_WAIT_RESET);
+    }
+}
+
+/// An app with a TBF header that is `total_size` bytes long
+fn app(total_size: u32) -> Vec<u8> {
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    let header = [
+        // Version 2, 32 bytes of header, enabled
+        2, 0, 32, 0,
+        total_size as u8, (total_size >> 8) as u8, 0, 0,
+        1, 0, 0, 0,
+        0, 0, 0, 0,
+        // Main TLV without entry point or memory
+        1, 0, 12, 0,
+        0, 0, 0, 0,
+        0, 0, 0, 0,
+        0, 0, 0, 0,
+    ];
+    with_header(&header, total_size)
+}
+
+/// Padding with a TBF header that is `total_size` bytes long
+fn padding(total_size: u32) -> Vec<u8> {
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    let header = [
+        // Version 2, 16 bytes of header, no options
+        2, 0, 16, 0,
+        total_size as u8, (total_size >> 8) as u8, 0, 0,
+        0, 0, 0, 0,
+        0, 0, 0, 0,
+    ];
+    with_header(&header, total_size)
+}
+
+fn with_header(header: &[u8], total_size: u32) -> Vec<u8> {
+    let mut image = vec![0x42; total_size as usize];
+    image[..header.len()].copy_from_slice(header);
+    let mut checksum = [0; 4];
+    for (i, word) in header.chunks(4).enumerate() {
+        if i != 3 {
+            for j in 0..4 {
+                checksum[j] ^= word[j];
+            }
+        }
+    }
+    image[12..16].copy_from_slice(&checksum);
+    image
+}
+
+#[test]
+fn descriptors() {
+    let (host, _) = device();
+
+    let device = host.enumerate(1).unwrap();
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    let runtime = vec![
+        9, 2, 27, 0, 1, 1, 0, 0x80, 50,
+        // Interface with the DFU runtime protocol
+        9, 4, 0, 0, 0, 0xfe, 1, 1, 0,
+        // Can download, 1000 ms to detach, 128 byte blocks, DFU 1.1
+        9, 0x21, 0x01, 0xe8, 0x03, 128, 0, 0x10, 0x01,
+    ];
+    assert_eq!(device.configuration, runtime);
+
+    assert_eq!(dfu_out(&host, DFU_DETACH, 1000, &[]), Ok(()));
+    let device = host.enumerate(1).unwrap();
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    let dfu_mode = vec![
+        9, 2, 36, 0, 1, 1, 0, 0x80, 50,
+        // An alternate setting for each target, with the DFU mode protocol
+        9, 4, 0, 0, 0, 0xfe, 1, 2, 4,
+        9, 4, 0, 1, 0, 0xfe, 1, 2, 5,
+        9, 0x21, 0x01, 0xe8, 0x03, 128, 0, 0x10, 0x01,
+    ];
+    assert_eq!(device.configuration, dfu_mode);
+    assert_eq!(host.get_string(4), Ok("app".to_string()));
+    assert_eq!(host.get_string(5), Ok("kernel".to_string()));
+}
+
+#[test]
+fn app_download() {
+    let (host, flash) = device();
+    detach(&host);
+    assert_eq!(select_target(&host, 0), Ok(()));
+
+    // The image ends in the middle of its second page, and the rest of the
+    // page is erased to end the app list
+    let image = app(200);
+    download(&host, flash, &image);
+    assert_eq!(flash.page(2), &image[..128]);
+    assert_eq!(&flash.page(3)[..72], &image[128..]);
+    assert!(flash.page(3)[72..].iter().all(|&b| b == 0xff));
+
+    let resets = RESETS.load(Ordering::SeqCst);
+    host.reset();
+    assert!(RESETS.load(Ordering::SeqCst) > resets);
+}
+
+#[test]
+fn app_download_ending_on_page() {
+    let (host, flash) = device();
+    detach(&host);
+
+    // The page after the image is erased to end the app list
+    let image = app(256);
+    download(&host, flash, &image);
+    assert_eq!(flash.page(3), &image[128..]);
+    assert!(flash.page(4).iter().all(|&b| b == 0xff));
+}
+
+#[test]
+fn invalid_app() {
+    let (host, flash) = device();
+    detach(&host);
+
+    // A corrupted header is not written
+    let mut image = app(200);
+    image[20] ^= 1;
+    assert_eq!(dfu_out(&host, DFU_DNLOAD, 0, &image[..128]), Ok(()));
+    assert_eq!(get_status(&host), (ERR_FILE, 0, DFU_ERROR));
+    assert_eq!(flash.page(2), vec![0; PAGE_SIZE]);
+    assert_eq!(dfu_out(&host, DFU_CLRSTATUS, 0, &[]), Ok(()));
+    assert_eq!(get_status(&host), (OK, 0, DFU_IDLE));
+
+    // Neither is an app larger than the target
+    let image = app(1000);
+    assert_eq!(dfu_out(&host, DFU_DNLOAD, 0, &image[..128]), Ok(()));
+    assert_eq!(get_status(&host), (ERR_FILE, 0, DFU_ERROR));
+    assert_eq!(dfu_out(&host, DFU_CLRSTATUS, 0, &[]), Ok(()));
+
+    // Uploads are not supported
+    assert_eq!(dfu_in(&host, DFU_UPLOAD, 128), Err(Error::Stall));
+    assert_eq!(get_status(&host), (ERR_STALLED_PACKET, 0, DFU_ERROR));
+}
+
+#[test]
+fn several_apps() {
+    let (host, flash) = device();
+    detach(&host);
+
+    // Every header is checked, including those that start in later pages
+    let mut image = app(160);
+    image.extend(padding(96));
+    image.extend(app(100));
+    download(&host, flash, &image);
+    assert_eq!(flash.page(3), &image[128..256]);
+    assert_eq!(&flash.page(4)[..100], &image[256..]);
+    assert!(flash.page(4)[100..].iter().all(|&b| b == 0xff));
+}
+
+#[test]
+fn invalid_later_app() {
+    let (host, flash) = device();
+    detach(&host);
+
+    // The header of the second app is corrupted, so its page is not written
+    let mut image = app(160);
+    image.extend(app(200));
+    image[160 + 20] ^= 1;
+    download_block(&host, flash, 0, &image[..128]);
+    assert_eq!(dfu_out(&host, DFU_DNLOAD, 1, &image[128..256]), Ok(()));
+    assert_eq!(get_status(&host), (ERR_FILE, 0, DFU_ERROR));
+    assert_eq!(flash.page(3), vec![0; PAGE_SIZE]);
+    assert_eq!(dfu_out(&host, DFU_CLRSTATUS, 0, &[]), Ok(()));
+
+    // Neither is a second app that does not fit after the first
+    let mut image = app(160);
+    image.extend(app(400));
+    download_block(&host, flash, 0, &image[..128]);
+    assert_eq!(dfu_out(&host, DFU_DNLOAD, 1, &image[128..256]), Ok(()));
+    assert_eq!(get_status(&host), (ERR_FILE, 0, DFU_ERROR));
+    assert_eq!(dfu_out(&host, DFU_CLRSTATUS, 0, &[]), Ok(()));
+
+    // A header that crosses into the next page cannot be checked
+    let mut image = app(120);
+    image.extend(app(200));
+    assert_eq!(dfu_out(&host, DFU_DNLOAD, 0, &image[..128]), Ok(()));
+    assert_eq!(get_status(&host), (ERR_FILE, 0, DFU_ERROR));
+}
+
+#[test]
+fn truncated_app() {
+    let (host, flash) = device();
+    detach(&host);
+
+    // The image ends before the app does
+    let image = app(300);
+    download_block(&host, flash, 0, &image[..128]);
+    download_block(&host, flash, 1, &image[128..250]);
+    assert_eq!(dfu_out(&host, DFU_DNLOAD, 0, &[]), Ok(()));
+    assert_eq!(get_status(&host), (ERR_FILE, 0, DFU_ERROR));
+    assert_eq!(flash.page(3), vec![0; PAGE_SIZE]);
+}
+
+#[test]
+#[should_panic(expected = "USB DFU needs at least one target")]
+fn no_targets() {
+    let controller: &'static SimulatedController = Box::leak(Box::new(SimulatedController::new()));
+    let flash = flash();
+    Dfu::new(
+        controller,
+        0x6667,
+        0xabd0,
+        &["Tock", "imix", "0"],
+        Box::leak(Box::new([0; 81])),
+        flash,
+        Box::leak(Box::new(Page([0; PAGE_SIZE]))),
+        &[],
+        || {},
+    );
+}
+
+#[test]
+fn kernel_download() {
+    let (host, flash) = device();
+    detach(&host);
+    assert_eq!(select_target(&host, 1), Ok(()));
+    assert_eq!(select_target(&host, 2), Err(Error::Stall));
+
+    // Kernel images are not checked, and fill their target
+    let image = vec![7; 2 * PAGE_SIZE];
+    download_block(&host, flash, 0, &image[..128]);
+    download_block(&host, flash, 1, &image[128..]);
+    assert_eq!(dfu_out(&host, DFU_DNLOAD, 2, &[1]), Err(Error::Stall));
+    assert_eq!(get_status(&host), (ERR_ADDRESS, 0, DFU_ERROR));
+    assert_eq!(flash.page(8), &image[..128]);
+    assert_eq!(flash.page(9), &image[128..]);
+}
@@ -0,0 +1,292 @@
+//! Tests of the HID device: enumeration, the report descriptor, the class
+//! requests, and the reports apps send and receive
+
+extern crate capsules;
+extern crate kernel;
+extern crate usb_test;
+
+use capsules::usb_hid::UsbHid;
+use kernel::hil::usb::Client as UsbClient;
+use kernel::{AppId, Driver, Grant, ReturnCode};
+use usb_test::app::Apps;
+use usb_test::host::*;
+use usb_test::{Error, Host, SimulatedController};
+
+const IN: usize = 1;
+const OUT: usize = 2;
+
+const GET_REPORT: u8 = 0x01;
+const SET_REPORT: u8 = 0x09;
+
+const REPORT_TYPE_INPUT: u16 = 1;
+const REPORT_TYPE_OUTPUT: u16 = 2;
+
+const KEYBOARD: u8 = 1;
+const VENDOR: u8 = 2;
+
+type Hid = UsbHid<'static, SimulatedController>;
+
+/// An enumerated device and an app that uses it
+fn device() -> (Apps, Host<'static>, &'static Hid, AppId) {
+    let mut apps = Apps::new();
+    let controller: &'static SimulatedController = Box::leak(Box::new(SimulatedController::new()));
+    let hid: &'static Hid = Box::leak(Box::new(UsbHid::new(
+        controller,
+        0x6667,
+        0xabcf,
+        &["Tock", "Tock HID", "0"],
+        Box::leak(Box::new([0; 41])),
+        unsafe { Grant::create() },
+    )));
+    controller.set_client(hid);
+    hid.enable();
+    hid.attach();
+    let appid = apps.load("hid");
+
+    let host = Host::new(controller);
+    host.enumerate(3).unwrap();
+    (apps, host, hid, appid)
+}
+
+fn get_report_request(report_type: u16, report_id: u8, length: u16) -> Setup {
+    Setup {
+        request_type: DEVICE_TO_HOST | CLASS | INTERFACE,
+        request: GET_REPORT,
+        value: report_type << 8 | report_id as u16,
+        index: 0,
+        length: length,
+    }
+}
+
+fn set_report_request(report_type: u16, report_id: u8, length: u16) -> Setup {
+    Setup {
+        request_type: HOST_TO_DEVICE | CLASS | INTERFACE,
+        request: SET_REPORT,
+        value: report_type << 8 | report_id as u16,
+        index: 0,
+        length: length,
+    }
+}
+
+fn keyboard_report(modifiers: u8, key: u8) -> Vec<u8> {
+    vec![KEYBOARD, modifiers, 0, key, 0, 0, 0, 0, 0]
+}
+
+/// The size in bytes of each report in a report descriptor, by report ID and
+/// main item (Input or Output)
+fn report_sizes(descriptor: &[u8]) -> Vec<(u8, u8, usize)> {
+    let mut sizes: Vec<(u8, u8, usize)> = Vec::new();
+    let (mut report_id, mut report_size, mut report_count) = (0, 0, 0);
+    let mut depth = 0;
+    let mut i = 0;
+    while i < descriptor.len() {
+        let prefix = descriptor[i];
+        let len = [0, 1, 2, 4][(prefix & 0x3) as usize];
+        let data = descriptor[i + 1..i + 1 + len]
+            .iter()
+            .rev()
+            .fold(0, |value, &b| value << 8 | b as usize);
+        match prefix & !0x3 {
+            0x84 => report_id = data as u8,
+            0x74 => report_size = data,
+            0x94 => report_count = data,
+            0xa0 => depth += 1,
+            0xc0 => depth -= 1,
+            item @ 0x80 | item @ 0x90 => {
+                let bits = report_size * report_count;
+                match sizes
+                    .iter()
+                    .position(|&(id, main, _)| id == report_id && main == item)
+                {
+                    Some(j) => sizes[j].2 += bits,
+                    None => sizes.push((report_id, item, bits)),
+                }
+            }
+            _ => {}
+        }
+        i += 1 + len;
+    }
+    assert_eq!(depth, 0, "Collections are not closed");
+    sizes
+        .into_iter()
+        .map(|(id, main, bits)| {
+            assert_eq!(bits % 8, 0, "Report {} is not a whole number of bytes", id);
+            (id, main, bits / 8)
+        })
+        .collect()
+}
+
+#[test]
+fn enumeration() {
+    let (_apps, host, _, _) = device();
+    let device = host.enumerate(3).unwrap();
+
+    assert_eq!(device.vendor_id(), 0x6667);
+    assert_eq!(device.product_id(), 0xabcf);
+    assert_eq!(device.configuration.len(), 41);
+    let descriptors = device.descriptors();
+    let types: Vec<u8> = descriptors.iter().map(|d| d.0).collect();
+    assert_eq!(types, vec![2, 4, 0x21, 5, 5]);
+    assert_eq!(descriptors[1].1, &[9, 4, 0, 0, 2, 0x03, 0, 0, 0]);
+    assert_eq!(descriptors[2].1, &[9, 0x21, 0x11, 0x01, 0, 1, 0x22, 95, 0]);
+    assert_eq!(descriptors[3].1, &[7, 5, 0x81, 3, 64, 0, 1]);
+    assert_eq!(descriptors[4].1, &[7, 5, 0x02, 3, 64, 0, 10]);
+}
+
+#[test]
+fn report_descriptor() {
+    let (_apps, host, _, _) = device();
+
+    let get_report_descriptor = Setup {
+        request_type: DEVICE_TO_HOST | STANDARD | INTERFACE,
+        request: GET_DESCRIPTOR,
+        value: 0x2200,
+        index: 0,
+        length: 255,
+    };
+    let descriptor = host.control_in(get_report_descriptor).unwrap();
+    assert_eq!(descriptor.len(), 95);
+    assert_eq!(
+        report_sizes(&descriptor),
+        vec![
+            (KEYBOARD, 0x80, 8),
+            (KEYBOARD, 0x90, 1),
+            (VENDOR, 0x80, 63),
+            (VENDOR, 0x90, 63),
+        ]
+    );
+
+    // The HID descriptor can be read on its own as well
+    let get_hid_descriptor = Setup {
+        value: 0x2100,
+        ..get_report_descriptor
+    };
+    assert_eq!(host.control_in(get_hid_descriptor).unwrap().len(), 9);
+}
+
+#[test]
+fn set_report() {
+    let (apps, host, hid, appid) = device();
+
+    // Keyboard LEDs
+    let leds = set_report_request(REPORT_TYPE_OUTPUT, KEYBOARD, 2);
+    assert_eq!(host.control_out(leds, &[KEYBOARD, 0x03]), Ok(()));
+    assert_eq!(
+        hid.command(3, 0, 0, appid),
+        ReturnCode::SuccessWithValue { value: 0x03 }
+    );
+
+    // A vendor defined report goes to the app, as far as its buffer goes
+    let buffer = apps.buffer(appid, &[0; 4]);
+    assert_eq!(hid.allow(appid, 0, Some(buffer.slice())), ReturnCode::SUCCESS);
+    let report = set_report_request(REPORT_TYPE_OUTPUT, VENDOR, 6);
+    assert_eq!(host.control_out(report, &[VENDOR, 1, 2, 3, 4, 5]), Ok(()));
+    assert_eq!(buffer.contents(), vec![1, 2, 3, 4]);
+
+    // The interrupt OUT endpoint takes the same reports
+    assert_eq!(host.write(OUT, &[KEYBOARD, 0x01]), Ok(2));
+    assert_eq!(
+        hid.command(3, 0, 0, appid),
+        ReturnCode::SuccessWithValue { value: 0x01 }
+    );
+    assert_eq!(host.write(OUT, &[VENDOR, 9, 8]), Ok(3));
+    assert_eq!(buffer.contents(), vec![9, 8, 3, 4]);
+
+    // Only output reports can be set, and they fit in one packet
+    let input = set_report_request(REPORT_TYPE_INPUT, KEYBOARD, 2);
+    assert_eq!(host.control_out(input, &[KEYBOARD, 0]), Err(Error::Stall));
+    let too_long = set_report_request(REPORT_TYPE_OUTPUT, VENDOR, 65);
+    assert_eq!(host.control_out(too_long, &[VENDOR; 65]), Err(Error::Stall));
+
+    // The host sets the LEDs again after a reset
+    host.enumerate(3).unwrap();
+    assert_eq!(
+        hid.command(3, 0, 0, appid),
+        ReturnCode::SuccessWithValue { value: 0 }
+    );
+}
+
+#[test]
+fn get_report() {
+    let (apps, host, hid, appid) = device();
+
+    // No keys pressed yet
+    let keyboard = get_report_request(REPORT_TYPE_INPUT, KEYBOARD, 9);
+    assert_eq!(host.control_in(keyboard), Ok(keyboard_report(0, 0)));
+
+    // The keys of the report waiting in the interrupt endpoint, which is
+    // refilled as soon as the host took a report
+    let controller = host.controller();
+    let buffer = apps.buffer(appid, &[0x02, 0x04, 0x00, 0x05]);
+    assert_eq!(hid.allow(appid, 1, Some(buffer.slice())), ReturnCode::SUCCESS);
+    assert_eq!(hid.command(1, 2, 0, appid), ReturnCode::SUCCESS);
+    assert_eq!(controller.in_packet(IN), Ok(keyboard_report(0x02, 0x04)));
+    assert_eq!(host.control_in(keyboard), Ok(keyboard_report(0, 0)));
+    assert_eq!(controller.in_packet(IN), Ok(keyboard_report(0, 0)));
+    assert_eq!(host.control_in(keyboard), Ok(keyboard_report(0, 0x05)));
+
+    // Only the keyboard input report can be read
+    let vendor = get_report_request(REPORT_TYPE_INPUT, VENDOR, 64);
+    assert_eq!(host.control_in(vendor), Err(Error::Stall));
+    let output = get_report_request(REPORT_TYPE_OUTPUT, KEYBOARD, 2);
+    assert_eq!(host.control_in(output), Err(Error::Stall));
+}
+
+#[test]
+fn keystrokes() {
+    let (apps, host, hid, appid) = device();
+
+    // Shift+A, then B. Each key is released before the next one.
+    let buffer = apps.buffer(appid, &[0x02, 0x04, 0x00, 0x05]);
+    assert_eq!(hid.allow(appid, 1, Some(buffer.slice())), ReturnCode::SUCCESS);
+    assert_eq!(hid.command(1, 2, 0, appid), ReturnCode::SUCCESS);
+    assert_eq!(hid.command(1, 1, 0, appid), ReturnCode::EBUSY);
+    let controller = host.controller();
+    assert_eq!(controller.in_packet(IN), Ok(keyboard_report(0x02, 0x04)));
+    assert_eq!(controller.in_packet(IN), Ok(keyboard_report(0, 0)));
+    assert_eq!(controller.in_packet(IN), Ok(keyboard_report(0, 0x05)));
+    assert_eq!(controller.in_packet(IN), Ok(keyboard_report(0, 0)));
+    assert_eq!(controller.in_packet(IN), Err(Error::Nak));
+
+    // The app can type again once the keys were sent
+    assert_eq!(hid.command(1, 1, 0, appid), ReturnCode::SUCCESS);
+    assert_eq!(controller.in_packet(IN), Ok(keyboard_report(0x02, 0x04)));
+    assert_eq!(controller.in_packet(IN), Ok(keyboard_report(0, 0)));
+
+    // The keys have to be in the allowed buffer, and their number must fit
+    // the buffer size in bytes
+    assert_eq!(hid.command(1, 0, 0, appid), ReturnCode::EINVAL);
+    assert_eq!(hid.command(1, 3, 0, appid), ReturnCode::EINVAL);
+    assert_eq!(hid.command(1, usize::max_value() / 2 + 1, 0, appid), ReturnCode::EINVAL);
+    assert_eq!(controller.in_packet(IN), Err(Error::Nak));
+}
+
+#[test]
+fn vendor_report() {
+    let (apps, host, hid, appid) = device();
+
+    let buffer = apps.buffer(appid, &[1, 2, 3]);
+    assert_eq!(hid.allow(appid, 1, Some(buffer.slice())), ReturnCode::SUCCESS);
+    assert_eq!(hid.command(2, 3, 0, appid), ReturnCode::SUCCESS);
+    let report = host.controller().in_packet(IN).unwrap();
+    assert_eq!(report.len(), 64);
+    assert_eq!(&report[..4], &[VENDOR, 1, 2, 3]);
+    assert!(report[4..].iter().all(|&b| b == 0));
+
+    assert_eq!(hid.command(2, 4, 0, appid), ReturnCode::EINVAL);
+    assert_eq!(hid.command(2, 64, 0, appid), ReturnCode::ESIZE);
+}
+
+#[test]
+fn unconfigured() {
+    let (apps, host, hid, appid) = device();
+    host.reset();
+
+    let buffer = apps.buffer(appid, &[0x02, 0x04]);
+    assert_eq!(hid.allow(appid, 1, Some(buffer.slice())), ReturnCode::SUCCESS);
+    assert_eq!(
+        hid.command(4, 0, 0, appid),
+        ReturnCode::SuccessWithValue { value: 0 }
+    );
+    assert_eq!(hid.command(1, 1, 0, appid), ReturnCode::EOFF);
+}
@@ -0,0 +1,264 @@
+//! Tests of the bare-bones USB client: enumeration, standard requests,
+//! vendor requests and the bulk echo
+
+extern crate capsules;
+extern crate kernel;
+extern crate usb_test;
+
+use capsules::usbc_client::Client;
+use kernel::hil::usb::Client as UsbClient;
+use usb_test::host::*;
+use usb_test::{Error, Host, SimulatedController};
+
+const VENDOR_ID: u16 = 0x6667;
+const PRODUCT_ID: u16 = 0xabcd;
+
+const BULK_IN: usize = 1;
+const BULK_OUT: usize = 2;
+
+fn device() -> &'static SimulatedController {
+    let controller: &'static SimulatedController = Box::leak(Box::new(SimulatedController::new()));
+    let client: &'static Client<SimulatedController> = Box::leak(Box::new(Client::new(
+        controller,
+        VENDOR_ID,
+        PRODUCT_ID,
+        &["Tock", "imix", "0123"],
+        Box::leak(Box::new([0; 32])),
+    )));
+    controller.set_client(client);
+    client.enable();
+    client.attach();
+    controller
+}
+
+#[test]
+#[should_panic(expected = "USB configuration needs 32 bytes, buffer has 31")]
+fn configuration_buffer_too_short() {
+    let controller: &'static SimulatedController = Box::leak(Box::new(SimulatedController::new()));
+    Client::new(
+        controller,
+        VENDOR_ID,
+        PRODUCT_ID,
+        &["Tock", "imix", "0123"],
+        Box::leak(Box::new([0; 31])),
+    );
+}
+
+fn vendor_request(request_type: u8, length: u16) -> Setup {
+    Setup {
+        request_type: request_type | VENDOR,
+        request: 1,
+        value: 0,
+        index: 0,
+        length: length,
+    }
+}
+
+#[test]
+fn enumeration() {
+    let controller = device();
+    assert!(controller.is_enabled());
+    assert!(controller.is_attached());
+
+    let host = Host::new(controller);
+    let device = host.enumerate(5).unwrap();
+    assert_eq!(controller.address(), 5);
+
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    let device_descriptor = vec![
+        18, 1, 0x00, 0x02, 0, 0, 0, 8,
+        0x67, 0x66, 0xcd, 0xab, 0x01, 0x00,
+        1, 2, 3, 1,
+    ];
+    assert_eq!(device.device_descriptor, device_descriptor);
+    assert_eq!(device.vendor_id(), VENDOR_ID);
+    assert_eq!(device.product_id(), PRODUCT_ID);
+
+    #[cfg_attr(rustfmt, rustfmt_skip)]
+    let configuration = vec![
+        // Configuration 1 with one interface, self-powered
+        9, 2, 32, 0, 1, 1, 0, 0xc0, 0,
+        // Vendor specific interface with two endpoints
+        9, 4, 0, 0, 2, 0xff, 0xab, 0, 0,
+        // Bulk IN endpoint 1 and bulk OUT endpoint 2, 8 bytes
+        7, 5, 0x81, 2, 8, 0, 0,
+        7, 5, 0x02, 2, 8, 0, 0,
+    ];
+    assert_eq!(device.configuration, configuration);
+    let types: Vec<u8> = device.descriptors().iter().map(|d| d.0).collect();
+    assert_eq!(types, vec![2, 4, 5, 5]);
+
+    assert_eq!(
+        device.strings,
+        [
+            Some("Tock".to_string()),
+            Some("imix".to_string()),
+            Some("0123".to_string()),
+        ]
+    );
+    assert_eq!(host.get_configuration(), Ok(1));
+}
+
+#[test]
+fn get_descriptor() {
+    let host = Host::new(device());
+    host.reset();
+
+    // The device descriptor takes three packets, and the host gets no more
+    // than it asks for
+    assert_eq!(host.get_descriptor(DEVICE_DESCRIPTOR, 0, 0, 255).unwrap().len(), 18);
+    assert_eq!(
+        host.get_descriptor(DEVICE_DESCRIPTOR, 0, 0, 8).unwrap(),
+        vec![18, 1, 0x00, 0x02, 0, 0, 0, 8]
+    );
+    assert_eq!(host.get_descriptor(DEVICE_DESCRIPTOR, 0, 0, 11).unwrap().len(), 11);
+
+    // The configuration header tells how long the whole configuration is
+    assert_eq!(
+        host.get_descriptor(CONFIGURATION_DESCRIPTOR, 0, 0, 9).unwrap(),
+        vec![9, 2, 32, 0, 1, 1, 0, 0xc0, 0]
+    );
+    assert_eq!(host.get_descriptor(CONFIGURATION_DESCRIPTOR, 0, 0, 32).unwrap().len(), 32);
+
+    // Languages, then strings encoded as UTF-16
+    assert_eq!(
+        host.get_descriptor(STRING_DESCRIPTOR, 0, 0, 255).unwrap(),
+        vec![4, 3, 0x09, 0x04]
+    );
+    assert_eq!(
+        host.get_descriptor(STRING_DESCRIPTOR, 2, 0x0409, 255).unwrap(),
+        vec![10, 3, b'i', 0, b'm', 0, b'i', 0, b'x', 0]
+    );
+    assert_eq!(host.get_string(3), Ok("0123".to_string()));
+
+    // Descriptors the device does not have
+    assert_eq!(host.get_descriptor(DEVICE_DESCRIPTOR, 1, 0, 18), Err(Error::Stall));
+    assert_eq!(host.get_descriptor(CONFIGURATION_DESCRIPTOR, 1, 0, 9), Err(Error::Stall));
+    assert_eq!(host.get_descriptor(STRING_DESCRIPTOR, 4, 0x0409, 255), Err(Error::Stall));
+    assert_eq!(host.get_descriptor(STRING_DESCRIPTOR, 1, 0x0407, 255), Err(Error::Stall));
+    // Device qualifier, which only high-speed capable devices have
+    assert_eq!(host.get_descriptor(6, 0, 0, 10), Err(Error::Stall));
+
+    // A stalled request does not affect the next one
+    assert_eq!(host.get_descriptor(DEVICE_DESCRIPTOR, 0, 0, 18).unwrap().len(), 18);
+}
+
+#[test]
+fn set_address() {
+    let controller = device();
+    let host = Host::new(controller);
+    host.reset();
+    assert_eq!(controller.address(), 0);
+
+    assert_eq!(host.set_address(42), Ok(()));
+    assert_eq!(controller.address(), 42);
+    assert_eq!(host.get_descriptor(DEVICE_DESCRIPTOR, 0, 0, 18).unwrap().len(), 18);
+
+    // A bus reset clears the address and the configuration
+    assert_eq!(host.set_configuration(1), Ok(()));
+    assert_eq!(host.get_configuration(), Ok(1));
+    host.reset();
+    assert_eq!(controller.address(), 0);
+    assert_eq!(host.get_configuration(), Ok(0));
+}
+
+#[test]
+fn set_configuration() {
+    let host = Host::new(device());
+    host.enumerate(1).unwrap();
+    assert_eq!(host.get_configuration(), Ok(1));
+
+    // There is no second configuration
+    assert_eq!(host.set_configuration(2), Err(Error::Stall));
+    assert_eq!(host.get_configuration(), Ok(1));
+
+    assert_eq!(host.set_configuration(0), Ok(()));
+    assert_eq!(host.get_configuration(), Ok(0));
+}
+
+#[test]
+fn vendor_requests() {
+    let host = Host::new(device());
+    host.enumerate(1).unwrap();
+
+    assert_eq!(
+        host.control_in(vendor_request(DEVICE_TO_HOST | DEVICE, 8)),
+        Ok(vec![0xa, 0xb, 0xc])
+    );
+    assert_eq!(
+        host.control_in(vendor_request(DEVICE_TO_HOST | DEVICE, 2)),
+        Ok(vec![0xa, 0xb])
+    );
+    assert_eq!(
+        host.control_out(vendor_request(HOST_TO_DEVICE | OTHER, 0), &[0xd, 0xe, 0xf]),
+        Ok(())
+    );
+    // Longer than a packet of endpoint 0
+    assert_eq!(
+        host.control_out(vendor_request(HOST_TO_DEVICE | DEVICE, 0), &[0x5a; 20]),
+        Ok(())
+    );
+}
+
+#[test]
+fn bulk_echo() {
+    let controller = device();
+    let host = Host::new(controller);
+    host.enumerate(1).unwrap();
+
+    // Nothing to echo yet
+    assert_eq!(controller.in_packet(BULK_IN), Err(Error::Nak));
+
+    assert_eq!(host.write(BULK_OUT, &[1, 2, 3]), Ok(3));
+    assert_eq!(host.read(BULK_IN), Ok(vec![1, 2, 3]));
+    assert_eq!(host.read(BULK_IN), Ok(vec![]));
+
+    // The echo buffer holds one packet, and the endpoint buffer another, so
+    // the device refuses the third until the host reads the first
+    let data: Vec<u8> = (0..24).collect();
+    assert_eq!(host.write(BULK_OUT, &data), Ok(16));
+    assert_eq!(controller.out_packet(BULK_OUT, &data[16..]), Err(Error::Nak));
+    assert_eq!(host.read(BULK_IN), Ok(data[..16].to_vec()));
+    assert_eq!(host.write(BULK_OUT, &data[16..]), Ok(8));
+    assert_eq!(host.read(BULK_IN), Ok(data[16..].to_vec()));
+}
+
+#[test]
+fn endpoint_halt() {
+    let controller = device();
+    let host = Host::new(controller);
+    host.enumerate(1).unwrap();
+
+    assert_eq!(host.set_halt(0x80 | BULK_IN as u8, true), Ok(()));
+    assert!(controller.is_stalled(BULK_IN));
+    assert_eq!(host.write(BULK_OUT, &[7]), Ok(1));
+    assert_eq!(host.read(BULK_IN), Err(Error::Stall));
+
+    assert_eq!(host.set_halt(0x80 | BULK_IN as u8, false), Ok(()));
+    assert_eq!(host.read(BULK_IN), Ok(vec![7]));
+}
+
+#[test]
+fn endpoint_halt_invalid() {
+    let controller = device();
+    let host = Host::new(controller);
+    host.enumerate(1).unwrap();
+
+    // The controller has no endpoint 15
+    assert_eq!(host.set_halt(0x8f, true), Err(Error::Stall));
+    assert_eq!(host.set_halt(0x8f, false), Err(Error::Stall));
+
+    // ENDPOINT_HALT is only a feature of endpoints
+    let setup = Setup {
+        request_type: HOST_TO_DEVICE | STANDARD | DEVICE,
+        request: SET_FEATURE,
+        value: 0,
+        index: 0x80 | BULK_IN as u16,
+        length: 0,
+    };
+    assert_eq!(host.control_out(setup, &[]), Err(Error::Stall));
+    assert!(!controller.is_stalled(BULK_IN));
+
+    assert_eq!(host.write(BULK_OUT, &[7]), Ok(1));
+    assert_eq!(host.read(BULK_IN), Ok(vec![7]));
+}
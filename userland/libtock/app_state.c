@@ -3,11 +3,15 @@
 #include "app_state.h"
 #include "tock.h"
 
+// Result of the last synchronous save.
+static int save_sync_result;
+
 // Internal callback for synchronous interfaces
-static void app_state_sync_cb(__attribute__ ((unused)) int callback_type,
+static void app_state_sync_cb(int result,
                               __attribute__ ((unused)) int value,
                               __attribute__ ((unused)) int unused,
                               void* ud) {
+  save_sync_result = result;
   *((bool*) ud) = true;
 }
 
@@ -67,5 +71,5 @@ int app_state_save_sync(void) {
   // Wait for the callback.
   yield_for(&save_sync_flag);
 
-  return 0;
+  return save_sync_result;
 }
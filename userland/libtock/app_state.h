@@ -73,7 +73,8 @@ extern size_t _app_state_size;
 __attribute__ ((warn_unused_result))
 int app_state_load_sync(void);
 
-// Save application state to persistent storage
+// Save application state to persistent storage. The first argument of the
+// callback is TOCK_SUCCESS, or TOCK_FAIL if the flash could not be written.
 __attribute__ ((warn_unused_result))
 int app_state_save(subscribe_cb callback, void* callback_args);
 __attribute__ ((warn_unused_result))
@@ -0,0 +1,136 @@
+#include <fat.h>
+#include <string.h>
+#include <tock.h>
+
+struct fat_data {
+  bool fired;
+  int result;
+  int value;
+};
+
+static struct fat_data result = { .fired = false, .result = 0, .value = 0 };
+
+// Internal callback for faking synchronous operations
+static void fat_cb(__attribute__ ((unused)) int operation,
+                   int status,
+                   int value,
+                   void* ud) {
+  struct fat_data* data = (struct fat_data*) ud;
+  data->fired  = true;
+  data->result = status;
+  data->value  = value;
+}
+
+int fat_exists(void) {
+  return command(DRIVER_NUM_FAT, 0, 0, 0);
+}
+
+int fat_set_callback(subscribe_cb callback, void* callback_args) {
+  return subscribe(DRIVER_NUM_FAT, 0, callback, callback_args);
+}
+
+int fat_set_path(const char* path) {
+  return allow(DRIVER_NUM_FAT, 0, (void*) path, strlen(path));
+}
+
+int fat_set_buffer(uint8_t* buffer, uint32_t len) {
+  return allow(DRIVER_NUM_FAT, 1, (void*) buffer, len);
+}
+
+int fat_open(int flags) {
+  return command(DRIVER_NUM_FAT, 1, flags, 0);
+}
+
+int fat_read(int handle, uint32_t len) {
+  return command(DRIVER_NUM_FAT, 2, handle, len);
+}
+
+int fat_write(int handle, uint32_t len) {
+  return command(DRIVER_NUM_FAT, 3, handle, len);
+}
+
+int fat_seek(int handle, uint32_t position) {
+  return command(DRIVER_NUM_FAT, 4, handle, position);
+}
+
+int fat_close(int handle) {
+  return command(DRIVER_NUM_FAT, 5, handle, 0);
+}
+
+int fat_list(int index) {
+  return command(DRIVER_NUM_FAT, 6, index, 0);
+}
+
+int fat_delete(void) {
+  return command(DRIVER_NUM_FAT, 7, 0, 0);
+}
+
+int fat_size(int handle) {
+  return command(DRIVER_NUM_FAT, 8, handle, 0);
+}
+
+// Start an operation and wait for it to finish.
+static int fat_run(int (*operation)(int, uint32_t), int arg, uint32_t len) {
+  int err = fat_set_callback(fat_cb, (void*) &result);
+  if (err < 0) return err;
+
+  result.fired = false;
+  err = operation(arg, len);
+  if (err < 0) return err;
+
+  yield_for(&result.fired);
+
+  if (result.result < 0) return result.result;
+  return result.value;
+}
+
+static int fat_open_op(int flags, __attribute__ ((unused)) uint32_t len) {
+  return fat_open(flags);
+}
+
+static int fat_list_op(int index, __attribute__ ((unused)) uint32_t len) {
+  return fat_list(index);
+}
+
+static int fat_delete_op(__attribute__ ((unused)) int arg,
+                         __attribute__ ((unused)) uint32_t len) {
+  return fat_delete();
+}
+
+int fat_open_sync(const char* path, int flags) {
+  int err = fat_set_path(path);
+  if (err < 0) return err;
+
+  return fat_run(fat_open_op, flags, 0);
+}
+
+int fat_read_sync(int handle, uint8_t* buffer, uint32_t len) {
+  int err = fat_set_buffer(buffer, len);
+  if (err < 0) return err;
+
+  return fat_run(fat_read, handle, len);
+}
+
+int fat_write_sync(int handle, uint8_t* buffer, uint32_t len) {
+  int err = fat_set_buffer(buffer, len);
+  if (err < 0) return err;
+
+  return fat_run(fat_write, handle, len);
+}
+
+int fat_list_sync(const char* path, int index, fat_entry_t* entry) {
+  int err = fat_set_path(path);
+  if (err < 0) return err;
+
+  err = fat_set_buffer((uint8_t*) entry, sizeof(fat_entry_t));
+  if (err < 0) return err;
+
+  return fat_run(fat_list_op, index, 0);
+}
+
+int fat_delete_sync(const char* path) {
+  int err = fat_set_path(path);
+  if (err < 0) return err;
+
+  return fat_run(fat_delete_op, 0, 0);
+}
@@ -0,0 +1,119 @@
+#pragma once
+
+#include "tock.h"
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define DRIVER_NUM_FAT 0x50004
+
+// Flags for fat_open.
+#define FAT_OPEN_CREATE   1
+#define FAT_OPEN_TRUNCATE 2
+#define FAT_OPEN_APPEND   4
+
+// Attributes of directory entries.
+#define FAT_ATTR_READ_ONLY 0x01
+#define FAT_ATTR_DIRECTORY 0x10
+
+// An entry of a directory, as written by fat_list.
+typedef struct {
+  char name[13];
+  uint8_t attributes;
+  uint16_t reserved;
+  uint32_t size;
+} fat_entry_t;
+
+/*  fat_exists
+ *  Check if the FAT filesystem driver is present.
+ *  returns 0 if it exists, negative otherwise.
+ */
+int fat_exists(void);
+
+/*  fat_set_callback
+ *  Registers a callback function that is called when an operation finishes.
+ *    callback: user defined callback function of the form:
+ *      void user_callback(int operation, int result, int value, void* ud);
+ *      where operation is 1 for open, 2 for read, 3 for write, 6 for list
+ *      and 7 for delete, result is 0 or a negative error code and value is
+ *      the handle of an opened file, the number of bytes read or written or
+ *      the index of a listed entry.
+ *    callback_args: passed to the callback as ud.
+ *  returns 0 on success, negative on failure.
+ */
+int fat_set_callback(subscribe_cb callback, void* callback_args);
+
+/*  fat_set_path
+ *  Share the path of the next open, list or delete with the kernel. Paths
+ *  are absolute, such as "/LOGS/DATA.CSV", and use 8.3 names.
+ *  returns 0 on success, negative on failure.
+ */
+int fat_set_path(const char* path);
+
+/*  fat_set_buffer
+ *  Share the buffer data is read in to and written from with the kernel.
+ *  returns 0 on success, negative on failure.
+ */
+int fat_set_buffer(uint8_t* buffer, uint32_t len);
+
+/*  fat_open / fat_read / fat_write / fat_list / fat_delete
+ *  Start an operation. fat_open and fat_delete use the shared path, fat_list
+ *  writes the first entry at or after index in the directory at the shared
+ *  path in to the buffer.
+ *  returns 0 if the operation started, negative on failure.
+ */
+int fat_open(int flags);
+int fat_read(int handle, uint32_t len);
+int fat_write(int handle, uint32_t len);
+int fat_list(int index);
+int fat_delete(void);
+
+/*  fat_seek
+ *  Move the position of a file, which must not be past its end.
+ *  returns 0 on success, negative on failure.
+ */
+int fat_seek(int handle, uint32_t position);
+
+/*  fat_close
+ *  Close a file.
+ *  returns 0 on success, negative on failure.
+ */
+int fat_close(int handle);
+
+/*  fat_size
+ *  returns the size of a file, negative on failure.
+ */
+int fat_size(int handle);
+
+/*  fat_open_sync
+ *  Open the file at path.
+ *  returns the handle of the file, TOCK_ENOSUPPORT if it does not exist,
+ *  negative on failure.
+ */
+int fat_open_sync(const char* path, int flags);
+
+/*  fat_read_sync / fat_write_sync
+ *  Read or write up to len bytes at the position of the file.
+ *  returns the number of bytes read or written, negative on failure.
+ */
+int fat_read_sync(int handle, uint8_t* buffer, uint32_t len);
+int fat_write_sync(int handle, uint8_t* buffer, uint32_t len);
+
+/*  fat_list_sync
+ *  Find the first entry at or after index in the directory at path. Pass the
+ *  returned index plus one to get the next entry.
+ *  returns the index of the entry, TOCK_ENOSUPPORT at the end of the
+ *  directory, negative on failure.
+ */
+int fat_list_sync(const char* path, int index, fat_entry_t* entry);
+
+/*  fat_delete_sync
+ *  Delete the file at path.
+ *  returns 0 on success, negative on failure.
+ */
+int fat_delete_sync(const char* path);
+
+#ifdef __cplusplus
+}
+#endif
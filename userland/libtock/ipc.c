@@ -9,6 +9,13 @@ int ipc_discover(const char* pkg_name) {
   return res;
 }
 
+int ipc_app_version(int pid) {
+  if (pid <= 0) {
+    return -1;
+  }
+  return command(IPC_DRIVER_NUM, pid, 2, 0);
+}
+
 int ipc_register_svc(subscribe_cb callback, void *ud) {
   return subscribe(IPC_DRIVER_NUM, 0, callback, ud);
 }
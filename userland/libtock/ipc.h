@@ -17,6 +17,12 @@ extern "C" {
 // or a negative value on error.
 int ipc_discover(const char* pkg_name);
 
+// Get the version of the app with the given process id
+//
+// The version is packed as `major << 16 | minor << 8 | patch`. Returns a
+// negative value if there is no such process or it does not specify a version.
+int ipc_app_version(int pid);
+
 // Registers a service callback for this process.
 //
 // Service callbacks are called in response to `notify`s from clients and take
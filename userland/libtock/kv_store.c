@@ -0,0 +1,101 @@
+#include <kv_store.h>
+#include <tock.h>
+
+struct kv_store_data {
+  bool fired;
+  int result;
+  int length;
+};
+
+static struct kv_store_data result = { .fired = false, .result = 0, .length = 0 };
+
+// Internal callback for faking synchronous operations
+static void kv_store_cb(__attribute__ ((unused)) int operation,
+                        int status,
+                        int length,
+                        void* ud) {
+  struct kv_store_data* data = (struct kv_store_data*) ud;
+  data->fired  = true;
+  data->result = status;
+  data->length = length;
+}
+
+int kv_store_exists(void) {
+  return command(DRIVER_NUM_KV_STORE, 0, 0, 0);
+}
+
+int kv_store_set_callback(subscribe_cb callback, void* callback_args) {
+  return subscribe(DRIVER_NUM_KV_STORE, 0, callback, callback_args);
+}
+
+int kv_store_set_key(const uint8_t* key, uint32_t len) {
+  return allow(DRIVER_NUM_KV_STORE, 0, (void*) key, len);
+}
+
+int kv_store_set_value(uint8_t* value, uint32_t len) {
+  return allow(DRIVER_NUM_KV_STORE, 1, (void*) value, len);
+}
+
+int kv_store_get(void) {
+  return command(DRIVER_NUM_KV_STORE, 1, 0, 0);
+}
+
+int kv_store_set(uint32_t len) {
+  return command(DRIVER_NUM_KV_STORE, 2, len, 0);
+}
+
+int kv_store_delete(void) {
+  return command(DRIVER_NUM_KV_STORE, 3, 0, 0);
+}
+
+int kv_store_max_length(void) {
+  return command(DRIVER_NUM_KV_STORE, 4, 0, 0);
+}
+
+// Run an operation on `key` and wait for it to finish.
+static int kv_store_run(const uint8_t* key, uint32_t key_len, int (*operation)(uint32_t), uint32_t len) {
+  int err;
+
+  err = kv_store_set_key(key, key_len);
+  if (err < 0) return err;
+
+  err = kv_store_set_callback(kv_store_cb, (void*) &result);
+  if (err < 0) return err;
+
+  result.fired = false;
+  err = operation(len);
+  if (err < 0) return err;
+
+  yield_for(&result.fired);
+
+  return result.result;
+}
+
+static int kv_store_get_op(__attribute__ ((unused)) uint32_t len) {
+  return kv_store_get();
+}
+
+static int kv_store_delete_op(__attribute__ ((unused)) uint32_t len) {
+  return kv_store_delete();
+}
+
+int kv_store_get_sync(const uint8_t* key, uint32_t key_len, uint8_t* value, uint32_t len) {
+  int err = kv_store_set_value(value, len);
+  if (err < 0) return err;
+
+  err = kv_store_run(key, key_len, kv_store_get_op, 0);
+  if (err < 0) return err;
+
+  return result.length;
+}
+
+int kv_store_set_sync(const uint8_t* key, uint32_t key_len, uint8_t* value, uint32_t len) {
+  int err = kv_store_set_value(value, len);
+  if (err < 0) return err;
+
+  return kv_store_run(key, key_len, kv_store_set, len);
+}
+
+int kv_store_delete_sync(const uint8_t* key, uint32_t key_len) {
+  return kv_store_run(key, key_len, kv_store_delete_op, 0);
+}
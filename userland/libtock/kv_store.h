@@ -0,0 +1,77 @@
+#pragma once
+
+#include "tock.h"
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define DRIVER_NUM_KV_STORE 0x50003
+
+/*  kv_store_exists
+ *  Check if the key-value store driver is present.
+ *  returns 0 if it exists, negative otherwise.
+ */
+int kv_store_exists(void);
+
+/*  kv_store_set_callback
+ *  Registers a callback function that is called when an operation finishes.
+ *    callback: user defined callback function of the form:
+ *      void user_callback(int operation, int result, int length, void* ud);
+ *      where operation is 1 for get, 2 for set and 3 for delete, result is 0
+ *      or a negative error code and length is the full length of the value
+ *      read. A result of TOCK_ENOSUPPORT from a get means the key does not
+ *      exist.
+ *    callback_args: passed to the callback as ud.
+ *  returns 0 on success, negative on failure.
+ */
+int kv_store_set_callback(subscribe_cb callback, void* callback_args);
+
+/*  kv_store_set_key
+ *  Share the key of the next operation with the kernel.
+ *  returns 0 on success, negative on failure.
+ */
+int kv_store_set_key(const uint8_t* key, uint32_t len);
+
+/*  kv_store_set_value
+ *  Share the buffer values are read in to and stored from with the kernel.
+ *  returns 0 on success, negative on failure.
+ */
+int kv_store_set_value(uint8_t* value, uint32_t len);
+
+/*  kv_store_get / kv_store_set / kv_store_delete
+ *  Start an operation on the shared key. kv_store_set stores the first len
+ *  bytes of the value buffer.
+ *  returns 0 if the operation started, negative on failure.
+ */
+int kv_store_get(void);
+int kv_store_set(uint32_t len);
+int kv_store_delete(void);
+
+/*  kv_store_max_length
+ *  returns the maximum combined length of a key and its value.
+ */
+int kv_store_max_length(void);
+
+/*  kv_store_get_sync
+ *  Read the value of key in to value.
+ *  returns the full length of the value on success, which may be larger than
+ *  len, TOCK_ENOSUPPORT if the key does not exist, negative on failure.
+ */
+int kv_store_get_sync(const uint8_t* key, uint32_t key_len, uint8_t* value, uint32_t len);
+
+/*  kv_store_set_sync
+ *  Store len bytes of value under key.
+ *  returns 0 on success, negative on failure.
+ */
+int kv_store_set_sync(const uint8_t* key, uint32_t key_len, uint8_t* value, uint32_t len);
+
+/*  kv_store_delete_sync
+ *  Remove key and its value.
+ *  returns 0 on success, negative on failure.
+ */
+int kv_store_delete_sync(const uint8_t* key, uint32_t key_len);
+
+#ifdef __cplusplus
+}
+#endif
@@ -24,13 +24,13 @@ typedef struct {
 //
 // Possible callbacks
 // 0: card_detection_changed, SD card was either installed or removed
-//    arg1, arg2 - no meaning
+//    arg1 - installed, 1 if an SD card is now installed
 // 1: init_done, intialization completed successfully
 //    arg1 - block_size, block size of SD card in bytes
 //    arg2 - size_in_kB, total size of SD card in kilobytes
-// 2: read_done, read block completed successfully
+// 2: read_done, read blocks completed successfully
 //    arg1 - len, number of bytes read
-// 3: write_done, write block completed successfully
+// 3: write_done, write blocks completed successfully
 //    arg1 - len, number of bytes written
 // 4: error, an error occurred
 //    arg1 - error, number representing the error that occurred
@@ -116,10 +116,18 @@ int sdcard_initialize_sync (uint32_t* block_size, uint32_t* size_in_kB) {
 }
 
 int sdcard_read_block (uint32_t sector) {
-  return command(DRIVER_NUM_SDCARD, 3, sector, 0);
+  return sdcard_read_blocks(sector, 1);
 }
 
 int sdcard_read_block_sync (uint32_t sector) {
+  return sdcard_read_blocks_sync(sector, 1);
+}
+
+int sdcard_read_blocks (uint32_t sector, uint32_t count) {
+  return command(DRIVER_NUM_SDCARD, 3, sector, count);
+}
+
+int sdcard_read_blocks_sync (uint32_t sector, uint32_t count) {
   int err;
   sdcard_data_t result;
   result.fired = false;
@@ -128,7 +136,7 @@ int sdcard_read_block_sync (uint32_t sector) {
   err = sdcard_set_callback(sdcard_cb, (void*) &result);
   if (err < 0) return err;
 
-  err = sdcard_read_block(sector);
+  err = sdcard_read_blocks(sector, count);
   if (err < 0) return err;
 
   // wait for callback
@@ -138,10 +146,18 @@ int sdcard_read_block_sync (uint32_t sector) {
 }
 
 int sdcard_write_block (uint32_t sector) {
-  return command(DRIVER_NUM_SDCARD, 4, sector, 0);
+  return sdcard_write_blocks(sector, 1);
 }
 
 int sdcard_write_block_sync (uint32_t sector) {
+  return sdcard_write_blocks_sync(sector, 1);
+}
+
+int sdcard_write_blocks (uint32_t sector, uint32_t count) {
+  return command(DRIVER_NUM_SDCARD, 4, sector, count);
+}
+
+int sdcard_write_blocks_sync (uint32_t sector, uint32_t count) {
   int err;
   sdcard_data_t result;
   result.fired = false;
@@ -150,7 +166,7 @@ int sdcard_write_block_sync (uint32_t sector) {
   err = sdcard_set_callback(sdcard_cb, (void*) &result);
   if (err < 0) return err;
 
-  err = sdcard_write_block(sector);
+  err = sdcard_write_blocks(sector, count);
   if (err < 0) return err;
 
   // wait for callback
@@ -159,3 +175,7 @@ int sdcard_write_block_sync (uint32_t sector) {
   return result.error;
 }
 
+int sdcard_get_block_count (void) {
+  return command(DRIVER_NUM_SDCARD, 5, 0, 0);
+}
+
@@ -92,6 +92,54 @@ int sdcard_write_block (uint32_t sector);
 // returns 0 if the block has been written, < 0 if an error occurrs
 int sdcard_write_block_sync (uint32_t sector);
 
+// read consecutive blocks from an SD card asynchronously
+// Expects a read_buffer and a callback to already have been set up. Callback
+// will be called when either all blocks have been read or an error occurs.
+// When the callback is successful, data will be in the read_buffer
+//
+// sector - sector address of the first block to be read
+// count - number of blocks to read, limited by the kernel buffer (4 blocks)
+//
+// returns 0 if started successfully, < 0 if an error occurrs
+int sdcard_read_blocks (uint32_t sector, uint32_t count);
+
+// read consecutive blocks from an SD card synchronously
+// Expects a read_buffer to already have been set up. When the command
+// completes successfully, data will be in the read_buffer
+//
+// sector - sector address of the first block to be read
+// count - number of blocks to read
+//
+// returns 0 if the blocks have been read, < 0 if an error occurrs
+int sdcard_read_blocks_sync (uint32_t sector, uint32_t count);
+
+// write consecutive blocks to an SD card asynchronously
+// Expects a write_buffer and a callback to already have been set up. Data in
+// the write_buffer will be written to the SD card. Callback will be called
+// when either all blocks have been written or an error occurs
+//
+// sector - sector address of the first block to be written
+// count - number of blocks to write, limited by the kernel buffer (4 blocks)
+//
+// returns 0 if started successfully, < 0 if an error occurrs
+int sdcard_write_blocks (uint32_t sector, uint32_t count);
+
+// write consecutive blocks to an SD card synchronously
+// Expects a write_buffer to already have been set up. Data in the write_buffer
+// will be written to the SD card
+//
+// sector - sector address of the first block to be written
+// count - number of blocks to write
+//
+// returns 0 if the blocks have been written, < 0 if an error occurrs
+int sdcard_write_blocks_sync (uint32_t sector, uint32_t count);
+
+// get the number of blocks on an initialized SD card
+// Completes synchronously
+//
+// returns the number of 512 byte blocks, < 0 if an error occurrs
+int sdcard_get_block_count (void);
+
 #ifdef __cplusplus
 }
 #endif
@@ -0,0 +1,550 @@
+//! Inspect existing TBF files and flash images.
+//!
+//! The input is walked like the kernel does in `load_processes()`: headers are
+//! parsed one after another, advancing by `total_size`, until a header the
+//! kernel would reject is found. Headers are validated with the same `tbf`
+//! crate the kernel uses. Every field and TLV is printed. Anything the kernel
+//! rejects is reported as an error. Headers the kernel accepts but that are
+//! probably not what was intended, such as TLVs the kernel ignores or a
+//! missing Main TLV, are reported as warnings.
+
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::str;
+use tbf;
+use tbf::{TbfHeaderTypes, TbfHeaderV1, TbfHeaderV2AppVersion, TbfHeaderV2Base,
+          TbfHeaderV2KernelAbi, TbfHeaderV2Main, TbfHeaderV2NonvolatileStorage,
+          TbfHeaderV2WriteableFlashRegion, TlvIter};
+
+/// Names of the fields of a version 1 header, in order.
+const TBF_HEADER_V1_FIELDS: [&'static str; 19] = [
+    "version",
+    "total_size",
+    "entry_offset",
+    "rel_data_offset",
+    "rel_data_size",
+    "text_offset",
+    "text_size",
+    "got_offset",
+    "got_size",
+    "data_offset",
+    "data_size",
+    "bss_mem_offset",
+    "bss_size",
+    "min_stack_len",
+    "min_app_heap_len",
+    "min_kernel_heap_len",
+    "pkg_name_offset",
+    "pkg_name_size",
+    "checksum",
+];
+
+fn tlv_type_name(tipe: u16) -> &'static str {
+    match TbfHeaderTypes::from_u16(tipe) {
+        Some(TbfHeaderTypes::TbfHeaderMain) => "Main",
+        Some(TbfHeaderTypes::TbfHeaderWriteableFlashRegions) => "Writeable Flash Regions",
+        Some(TbfHeaderTypes::TbfHeaderPackageName) => "Package Name",
+        Some(TbfHeaderTypes::TbfHeaderPicOption1) => "PIC Option 1",
+        Some(TbfHeaderTypes::TbfHeaderAppVersion) => "App Version",
+        Some(TbfHeaderTypes::TbfHeaderKernelAbi) => "Kernel ABI",
+        Some(TbfHeaderTypes::TbfHeaderNonvolatileStorage) => "Nonvolatile Storage",
+        None => "Unknown",
+    }
+}
+
+/// The number of problems found in an image.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    /// Problems that make the kernel reject a header or stop loading apps.
+    pub errors: usize,
+    /// Problems the kernel accepts, but that are likely mistakes.
+    pub warnings: usize,
+}
+
+struct Inspector<'a> {
+    output: &'a mut Write,
+    summary: Summary,
+}
+
+impl<'a> Inspector<'a> {
+    fn error(&mut self, args: fmt::Arguments) -> io::Result<()> {
+        self.summary.errors += 1;
+        writeln!(self.output, "    error: {}", args)
+    }
+
+    fn warning(&mut self, args: fmt::Arguments) -> io::Result<()> {
+        self.summary.warnings += 1;
+        writeln!(self.output, "    warning: {}", args)
+    }
+
+    fn note(&mut self, args: fmt::Arguments) -> io::Result<()> {
+        writeln!(self.output, "    note: {}", args)
+    }
+
+    /// Report why the kernel rejects a header.
+    fn rejected(&mut self, error: tbf::Error) -> io::Result<()> {
+        match error {
+            tbf::Error::BufferTooSmall => self.error(format_args!("header is truncated")),
+            tbf::Error::UnsupportedVersion(version) => {
+                self.error(format_args!("unsupported header version {}", version))
+            }
+            tbf::Error::InvalidHeaderSize => self.error(format_args!(
+                "header_size is smaller than the header base or not smaller than total_size"
+            )),
+            tbf::Error::InvalidTotalSize => self.error(format_args!(
+                "total_size is larger than the kernel allows ({:#X})",
+                tbf::MAX_TOTAL_SIZE
+            )),
+            tbf::Error::ChecksumMismatch { computed, .. } => self.error(format_args!(
+                "checksum mismatch, computed {:#010X}",
+                computed
+            )),
+            tbf::Error::TlvOutOfBounds => {
+                self.error(format_args!("a TLV extends past the end of the header"))
+            }
+            tbf::Error::HeaderTooLarge => self.error(format_args!("header is too large")),
+        }
+    }
+
+    /// Inspect a version 1 header at the start of `buf`. Returns the number of
+    /// bytes to skip to the next header, or `None` if the kernel stops here.
+    fn inspect_v1(&mut self, buf: &[u8]) -> io::Result<Option<usize>> {
+        let header = match TbfHeaderV1::parse(buf) {
+            Ok(header) => header,
+            Err(e) => {
+                try!(self.rejected(e));
+                return Ok(None);
+            }
+        };
+
+        try!(writeln!(self.output, ""));
+        for (i, name) in TBF_HEADER_V1_FIELDS.iter().enumerate() {
+            let value = buf[i * 4] as u32 | (buf[i * 4 + 1] as u32) << 8
+                | (buf[i * 4 + 2] as u32) << 16 | (buf[i * 4 + 3] as u32) << 24;
+            try!(writeln!(
+                self.output,
+                "{:>22}: {:>8} {:>#10X}",
+                name,
+                value,
+                value
+            ));
+        }
+
+        if let Err(e) = tbf::parse_tbf_header(buf) {
+            try!(self.rejected(e));
+            return Ok(None);
+        }
+
+        // Version 1 headers do not check total_size, but the next header
+        // cannot start within this one
+        if (header.total_size as usize) < TbfHeaderV1::SIZE {
+            try!(self.error(format_args!(
+                "total_size is smaller than the {} byte header",
+                TbfHeaderV1::SIZE
+            )));
+            return Ok(None);
+        }
+
+        try!(self.note(format_args!(
+            "version 1 headers are deprecated, the kernel no longer does PIC fixup"
+        )));
+        Ok(Some(header.total_size as usize))
+    }
+
+    /// Inspect a version 2 header at the start of `buf`. Returns the number of
+    /// bytes to skip to the next header, or `None` if the kernel stops here.
+    fn inspect_v2(&mut self, buf: &[u8]) -> io::Result<Option<usize>> {
+        let base = match TbfHeaderV2Base::parse(buf) {
+            Ok(base) => base,
+            Err(e) => {
+                try!(self.rejected(e));
+                return Ok(None);
+            }
+        };
+        try!(write!(self.output, "{}", base));
+        try!(writeln!(
+            self.output,
+            "{:>22}: {:>8} {:>#10X}",
+            "checksum",
+            "",
+            base.checksum
+        ));
+
+        if let Err(e) = tbf::parse_tbf_header(buf) {
+            try!(self.rejected(e));
+            return Ok(None);
+        }
+
+        if buf.len() < base.total_size as usize {
+            try!(self.error(format_args!(
+                "total_size extends {} bytes past the end of the input",
+                base.total_size as usize - buf.len()
+            )));
+        }
+
+        let header_size = base.header_size as usize;
+        if header_size % 4 != 0 {
+            try!(self.warning(format_args!(
+                "header_size is not a multiple of 4, the kernel ignores the trailing bytes"
+            )));
+        }
+        if header_size == TbfHeaderV2Base::SIZE {
+            try!(writeln!(self.output, "    padding"));
+            return Ok(Some(base.total_size as usize));
+        }
+
+        // The header parsed, so all TLVs are within bounds. Go through them
+        // again to print them and to find the ones the kernel ignores.
+        let mut has_main = false;
+        let mut tlvs = TlvIter::new(&buf[..header_size]);
+        while let Some(Ok(tlv)) = tlvs.next() {
+            let tipe = tlv.header.tipe;
+            try!(writeln!(
+                self.output,
+                "\n    TLV {} ({}), length {}",
+                tipe,
+                tlv_type_name(tipe),
+                tlv.data.len()
+            ));
+
+            match tlv.header.header_type() {
+                Some(TbfHeaderTypes::TbfHeaderMain) => {
+                    if tlv.data.len() == TbfHeaderV2Main::SIZE {
+                        has_main = true;
+                        let main = TbfHeaderV2Main::parse(tlv.data).expect("Main within TLV");
+                        try!(write!(self.output, "{}", main));
+                    } else {
+                        try!(self.warning(format_args!(
+                            "Main TLV must be {} bytes long, the kernel ignores it",
+                            TbfHeaderV2Main::SIZE
+                        )));
+                    }
+                }
+                Some(TbfHeaderTypes::TbfHeaderWriteableFlashRegions) => {
+                    let region_size = TbfHeaderV2WriteableFlashRegion::SIZE;
+                    if tlv.data.len() % region_size != 0 {
+                        try!(self.warning(format_args!(
+                            "length is not a multiple of {}, the kernel ignores these regions",
+                            region_size
+                        )));
+                        continue;
+                    }
+                    for region in tlv.data.chunks(region_size) {
+                        let region = TbfHeaderV2WriteableFlashRegion::parse(region)
+                            .expect("region within TLV");
+                        try!(write!(self.output, "{}", region));
+                        if region.writeable_flash_region_offset as u64
+                            + region.writeable_flash_region_size as u64
+                            > base.total_size as u64
+                        {
+                            try!(self.warning(format_args!(
+                                "flash region extends past total_size"
+                            )));
+                        }
+                    }
+                }
+                Some(TbfHeaderTypes::TbfHeaderPackageName) => match str::from_utf8(tlv.data) {
+                    Ok(name) => try!(writeln!(self.output, "{:>22}: {}", "package_name", name)),
+                    Err(_) => try!(self.warning(format_args!(
+                        "package name is not valid UTF-8, the kernel uses an empty name"
+                    ))),
+                },
+                Some(TbfHeaderTypes::TbfHeaderPicOption1) => {
+                    try!(self.note(format_args!(
+                        "kernel PIC fixup has been removed, the kernel ignores this TLV"
+                    )));
+                }
+                Some(TbfHeaderTypes::TbfHeaderAppVersion) => {
+                    if tlv.data.len() == TbfHeaderV2AppVersion::SIZE {
+                        let version =
+                            TbfHeaderV2AppVersion::parse(tlv.data).expect("version within TLV");
+                        try!(writeln!(self.output, "{:>22}: {}", "app_version", version));
+                    } else {
+                        try!(self.warning(format_args!(
+                            "App Version TLV must be {} bytes long, the kernel ignores it",
+                            TbfHeaderV2AppVersion::SIZE
+                        )));
+                    }
+                }
+                Some(TbfHeaderTypes::TbfHeaderKernelAbi) => {
+                    if tlv.data.len() == TbfHeaderV2KernelAbi::SIZE {
+                        let abi = TbfHeaderV2KernelAbi::parse(tlv.data).expect("ABI within TLV");
+                        try!(write!(self.output, "{}", abi));
+                        if abi.minimum_abi_version > abi.maximum_abi_version {
+                            try!(self.warning(format_args!(
+                                "the ABI range is empty, no kernel will start the app"
+                            )));
+                        }
+                    } else {
+                        try!(self.warning(format_args!(
+                            "Kernel ABI TLV must be {} bytes long, the kernel ignores it",
+                            TbfHeaderV2KernelAbi::SIZE
+                        )));
+                    }
+                }
+                Some(TbfHeaderTypes::TbfHeaderNonvolatileStorage) => {
+                    if tlv.data.len() == TbfHeaderV2NonvolatileStorage::SIZE {
+                        let storage = TbfHeaderV2NonvolatileStorage::parse(tlv.data)
+                            .expect("storage within TLV");
+                        try!(write!(self.output, "{}", storage));
+                    } else {
+                        try!(self.warning(format_args!(
+                            "Nonvolatile Storage TLV must be {} bytes long, the kernel ignores it",
+                            TbfHeaderV2NonvolatileStorage::SIZE
+                        )));
+                    }
+                }
+                None => {
+                    try!(self.warning(format_args!("unknown TLV type, the kernel skips it")));
+                }
+            }
+        }
+
+        if tlvs.remainder().len() > 0 {
+            try!(self.note(format_args!(
+                "the kernel ignores the last {} bytes of the header",
+                tlvs.remainder().len()
+            )));
+        }
+
+        try!(writeln!(self.output, ""));
+        if !has_main {
+            try!(self.warning(format_args!(
+                "no valid Main TLV, the kernel starts the app at the end of the header with no RAM"
+            )));
+        }
+        if base.flags & TbfHeaderV2Base::FLAG_ENABLED == 0 {
+            try!(self.note(format_args!("app is disabled, the kernel skips it")));
+        }
+
+        Ok(Some(base.total_size as usize))
+    }
+}
+
+/// Print every header in `image`, which is either a single TBF or a flash image
+/// of several apps back to back. Returns the number of problems found.
+pub fn inspect(image: &[u8], output: &mut Write) -> io::Result<Summary> {
+    let mut inspector = Inspector {
+        output: output,
+        summary: Summary::default(),
+    };
+
+    let mut offset = 0;
+    while offset < image.len() {
+        let buf = &image[offset..];
+        let next = match tbf::header_length(buf) {
+            Ok(_) => {
+                try!(writeln!(
+                    inspector.output,
+                    "TBF header at offset {:#X}:",
+                    offset
+                ));
+                // header_length() only accepts versions 1 and 2.
+                if buf[0] == 1 {
+                    try!(inspector.inspect_v1(buf))
+                } else {
+                    try!(inspector.inspect_v2(buf))
+                }
+            }
+            Err(_) => {
+                // Erased flash is the usual way to end a flash image, anything
+                // else is suspicious.
+                if buf.iter().all(|b| *b == 0xFF) || buf.iter().all(|b| *b == 0) {
+                    try!(writeln!(
+                        inspector.output,
+                        "{} bytes of empty flash at offset {:#X}",
+                        buf.len(),
+                        offset
+                    ));
+                } else {
+                    try!(writeln!(
+                        inspector.output,
+                        "Data at offset {:#X}:",
+                        offset
+                    ));
+                    try!(inspector.error(format_args!(
+                        "not a TBF header, the kernel stops loading apps here"
+                    )));
+                }
+                None
+            }
+        };
+
+        match next {
+            Some(size) => offset += size,
+            None => break,
+        }
+    }
+
+    Ok(inspector.summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tbf::TbfHeaderTlv;
+
+    /// Build a version 2 header with the given TLVs, each given as a type and
+    /// its data, followed by `extra` bytes of header and zeroed app data up
+    /// to `total_size`.
+    fn app(total_size: u32, tlvs: &[(u16, &[u8])], extra: usize) -> Vec<u8> {
+        let mut header = vec![0; TbfHeaderV2Base::SIZE];
+        for &(tipe, data) in tlvs {
+            let mut tlv = [0; TbfHeaderTlv::SIZE];
+            TbfHeaderTlv {
+                tipe: tipe,
+                length: data.len() as u16,
+            }.serialize(&mut tlv)
+                .unwrap();
+            header.extend_from_slice(&tlv);
+            header.extend_from_slice(data);
+            while header.len() % 4 != 0 {
+                header.push(0);
+            }
+        }
+        header.extend(vec![0; extra]);
+
+        let mut base = TbfHeaderV2Base {
+            version: 2,
+            header_size: header.len() as u16,
+            total_size: total_size,
+            flags: TbfHeaderV2Base::FLAG_ENABLED,
+            checksum: 0,
+        };
+        base.serialize(&mut header).unwrap();
+        base.checksum = tbf::checksum(&header);
+        base.serialize(&mut header).unwrap();
+
+        header.resize(total_size as usize, 0);
+        header
+    }
+
+    fn main_tlv() -> Vec<u8> {
+        let mut main = vec![0; TbfHeaderV2Main::SIZE];
+        TbfHeaderV2Main {
+            init_fn_offset: 0x40,
+            protected_size: 0,
+            minimum_ram_size: 0x1000,
+        }.serialize(&mut main)
+            .unwrap();
+        main
+    }
+
+    fn region_tlv(offset: u32, size: u32) -> Vec<u8> {
+        let mut region = vec![0; TbfHeaderV2WriteableFlashRegion::SIZE];
+        TbfHeaderV2WriteableFlashRegion {
+            writeable_flash_region_offset: offset,
+            writeable_flash_region_size: size,
+        }.serialize(&mut region)
+            .unwrap();
+        region
+    }
+
+    fn run(image: &[u8]) -> Summary {
+        let mut output = Vec::new();
+        inspect(image, &mut output).unwrap()
+    }
+
+    fn summary(errors: usize, warnings: usize) -> Summary {
+        Summary {
+            errors: errors,
+            warnings: warnings,
+        }
+    }
+
+    const MAIN: u16 = TbfHeaderTypes::TbfHeaderMain as u16;
+    const REGIONS: u16 = TbfHeaderTypes::TbfHeaderWriteableFlashRegions as u16;
+    const NAME: u16 = TbfHeaderTypes::TbfHeaderPackageName as u16;
+
+    #[test]
+    fn valid_app() {
+        let image = app(0x400, &[(MAIN, &main_tlv()), (NAME, b"blink")], 0);
+        assert!(tbf::parse_tbf_header(&image).is_ok());
+        assert_eq!(run(&image), summary(0, 0));
+    }
+
+    #[test]
+    fn missing_main_is_a_warning() {
+        let image = app(0x400, &[(NAME, b"blink")], 0);
+        assert!(tbf::parse_tbf_header(&image).is_ok());
+        assert_eq!(run(&image), summary(0, 1));
+    }
+
+    #[test]
+    fn flash_region_past_total_size_is_a_warning() {
+        let image = app(0x400, &[(MAIN, &main_tlv()), (REGIONS, &region_tlv(0x300, 0x200))], 0);
+        assert!(tbf::parse_tbf_header(&image).is_ok());
+        assert_eq!(run(&image), summary(0, 1));
+    }
+
+    #[test]
+    fn unaligned_header_size_is_a_warning() {
+        let image = app(0x400, &[(MAIN, &main_tlv())], 2);
+        assert!(tbf::parse_tbf_header(&image).is_ok());
+        assert_eq!(run(&image), summary(0, 1));
+    }
+
+    #[test]
+    fn ignored_tlvs_are_warnings() {
+        let image = app(
+            0x400,
+            &[(MAIN, &main_tlv()), (MAIN, &[0; 4]), (NAME, &[0xFF]), (0x1234, &[0; 4])],
+            0,
+        );
+        assert!(tbf::parse_tbf_header(&image).is_ok());
+        assert_eq!(run(&image), summary(0, 3));
+    }
+
+    #[test]
+    fn bad_checksum_is_an_error() {
+        let mut image = app(0x400, &[(MAIN, &main_tlv())], 0);
+        image[12] ^= 1;
+        assert!(tbf::parse_tbf_header(&image).is_err());
+        assert_eq!(run(&image), summary(1, 0));
+    }
+
+    #[test]
+    fn truncated_app_is_an_error() {
+        let image = app(0x400, &[(MAIN, &main_tlv())], 0);
+        assert_eq!(run(&image[..0x200]), summary(1, 0));
+    }
+
+    #[test]
+    fn flash_image() {
+        let mut image = app(0x400, &[(MAIN, &main_tlv())], 0);
+        image.extend(app(0x100, &[], 0));
+        image.extend(app(0x800, &[(MAIN, &main_tlv()), (NAME, b"second")], 0));
+        image.extend(vec![0xFF; 0x200]);
+        assert_eq!(run(&image), summary(0, 0));
+
+        // Loading stops at the first header the kernel rejects, so problems
+        // in later apps are not reported.
+        image[0x400 + 4] = 0xFF;
+        image[0x500 + 0x100] = 0xFF;
+        assert_eq!(run(&image), summary(1, 0));
+    }
+
+    #[test]
+    fn v1_total_size_smaller_than_header_is_an_error() {
+        for &total_size in &[0, TbfHeaderV1::SIZE as u32 - 4] {
+            let mut header = TbfHeaderV1 {
+                version: 1,
+                total_size: total_size,
+                ..Default::default()
+            };
+            header.checksum = header.compute_checksum();
+            let mut image = vec![0; 0x100];
+            header.serialize(&mut image).unwrap();
+            assert!(tbf::parse_tbf_header(&image).is_ok());
+            assert_eq!(run(&image), summary(1, 0));
+        }
+    }
+
+    #[test]
+    fn garbage_after_apps_is_an_error() {
+        let mut image = app(0x400, &[(MAIN, &main_tlv())], 0);
+        image.extend_from_slice(&[0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(run(&image), summary(1, 0));
+    }
+}
@@ -1,5 +1,6 @@
 extern crate elf;
 extern crate getopts;
+extern crate tbf;
 
 use getopts::Options;
 use std::cmp;
@@ -7,111 +8,176 @@ use std::env;
 use std::fmt;
 use std::fs::File;
 use std::io;
-use std::io::{Cursor, Read, Seek, SeekFrom, Write};
-use std::mem;
+use std::io::{Read, Write};
 use std::path::Path;
-use std::slice;
+use std::process;
+use tbf::{TbfHeaderV2, TbfHeaderV2AppVersion, TbfHeaderV2Base, TbfHeaderV2KernelAbi,
+          TbfHeaderV2Main, TbfHeaderV2NonvolatileStorage, TbfHeaderV2WriteableFlashRegion,
+          WriteableFlashRegions};
 
 /// Takes a value and rounds it up to be aligned % 4
 macro_rules! align4 {
     ( $e:expr ) => ( ($e) + ((4 - (($e) % 4)) % 4 ) );
 }
 
-#[repr(u16)]
-#[derive(Clone, Copy, Debug)]
-#[allow(dead_code)]
-enum TbfHeaderTypes {
-    TbfHeaderMain = 1,
-    TbfHeaderWriteableFlashRegions = 2,
-    TbfHeaderPackageName = 3,
-    TbfHeaderPicOption1 = 4,
+mod inspect;
+mod tab;
+
+/// Memory sizes given on the command line, which take precedence over the
+/// section sizes in the ELF.
+#[derive(Default)]
+struct SizeOverrides {
+    stack: Option<u32>,
+    app_heap: Option<u32>,
+    kernel_heap: Option<u32>,
+    minimum_ram_size: Option<u32>,
 }
 
-#[repr(C)]
-#[derive(Clone, Copy, Debug)]
-struct TbfHeaderTlv {
-    tipe: TbfHeaderTypes,
-    length: u16,
+/// Optional information about the app that goes into its header.
+#[derive(Default)]
+struct AppInfo {
+    app_version: Option<TbfHeaderV2AppVersion>,
+    kernel_abi: Option<TbfHeaderV2KernelAbi>,
+    nonvolatile_storage: Option<TbfHeaderV2NonvolatileStorage>,
 }
 
-#[repr(C)]
-#[derive(Clone, Copy, Debug)]
-struct TbfHeaderBase {
-    version: u16,
-    header_size: u16,
-    total_size: u32,
-    flags: u32,
-    checksum: u32,
-}
-
-#[repr(C)]
-#[derive(Clone, Copy, Debug)]
-struct TbfHeaderMain {
-    base: TbfHeaderTlv,
-    init_fn_offset: u32,
-    protected_size: u32,
+/// Sizes of the parts of an app, in bytes.
+struct AppSizes {
+    header: u32,
+    text: u32,
+    got: u32,
+    data: u32,
+    bss: u32,
+    relocation: u32,
+    stack: u32,
+    app_heap: u32,
+    kernel_heap: u32,
     minimum_ram_size: u32,
+    total_size: u32,
 }
 
-#[repr(C)]
-#[derive(Clone, Copy, Debug)]
-struct TbfHeaderWriteableFlashRegion {
-    offset: u32,
-    size: u32,
-}
+impl AppSizes {
+    fn to_json(&self, package_name: &str) -> String {
+        let mut name = String::new();
+        for c in package_name.chars() {
+            match c {
+                '"' => name.push_str("\\\""),
+                '\\' => name.push_str("\\\\"),
+                c if (c as u32) < 0x20 => name.push_str(&format!("\\u{:04x}", c as u32)),
+                c => name.push(c),
+            }
+        }
 
-impl fmt::Display for TbfHeaderBase {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "
-               version: {:>8} {:>#10X}
-           header_size: {:>8} {:>#10X}
-            total_size: {:>8} {:>#10X}
-                 flags: {:>8} {:>#10X}
-",
-            self.version,
-            self.version,
-            self.header_size,
-            self.header_size,
-            self.total_size,
+        format!(
+            "{{\"package_name\": \"{}\", \"header\": {}, \"text\": {}, \"got\": {}, \
+             \"data\": {}, \"bss\": {}, \"relocation\": {}, \"stack\": {}, \
+             \"app_heap\": {}, \"kernel_heap\": {}, \"minimum_ram_size\": {}, \
+             \"total_size\": {}}}",
+            name,
+            self.header,
+            self.text,
+            self.got,
+            self.data,
+            self.bss,
+            self.relocation,
+            self.stack,
+            self.app_heap,
+            self.kernel_heap,
+            self.minimum_ram_size,
             self.total_size,
-            self.flags,
-            self.flags,
         )
     }
 }
 
-impl fmt::Display for TbfHeaderMain {
+impl fmt::Display for AppSizes {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
             "
-        init_fn_offset: {:>8} {:>#10X}
-        protected_size: {:>8} {:>#10X}
+    flash:
+                header: {:>8} {:>#10X}
+                  text: {:>8} {:>#10X}
+                   got: {:>8} {:>#10X}
+                  data: {:>8} {:>#10X}
+            relocation: {:>8} {:>#10X}
+            total_size: {:>8} {:>#10X}
+    ram:
+                   got: {:>8} {:>#10X}
+                  data: {:>8} {:>#10X}
+                   bss: {:>8} {:>#10X}
+                 stack: {:>8} {:>#10X}
+              app_heap: {:>8} {:>#10X}
+           kernel_heap: {:>8} {:>#10X}
       minimum_ram_size: {:>8} {:>#10X}
 ",
-            self.init_fn_offset,
-            self.init_fn_offset,
-            self.protected_size,
-            self.protected_size,
+            self.header,
+            self.header,
+            self.text,
+            self.text,
+            self.got,
+            self.got,
+            self.data,
+            self.data,
+            self.relocation,
+            self.relocation,
+            self.total_size,
+            self.total_size,
+            self.got,
+            self.got,
+            self.data,
+            self.data,
+            self.bss,
+            self.bss,
+            self.stack,
+            self.stack,
+            self.app_heap,
+            self.app_heap,
+            self.kernel_heap,
+            self.kernel_heap,
             self.minimum_ram_size,
             self.minimum_ram_size,
         )
     }
 }
 
-impl fmt::Display for TbfHeaderWriteableFlashRegion {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "
-    flash region:
-                offset: {:>8} {:>#10X}
-                  size: {:>8} {:>#10X}
-",
-            self.offset, self.offset, self.size, self.size,
-        )
+/// Parse a size given on the command line, in decimal or with a `0x` prefix
+/// in hex.
+fn parse_size(option: &str, value: &str) -> u32 {
+    let parsed = if value.starts_with("0x") || value.starts_with("0X") {
+        u32::from_str_radix(&value[2..], 16)
+    } else {
+        value.parse::<u32>()
+    };
+    match parsed {
+        Ok(size) => size,
+        Err(_) => panic!("Invalid size for --{}: {}", option, value),
+    }
+}
+
+/// Parse an app version given as `MAJOR.MINOR.PATCH`.
+fn parse_app_version(value: &str) -> TbfHeaderV2AppVersion {
+    let parts: Vec<&str> = value.split('.').collect();
+    let version = match parts.len() {
+        3 => match (
+            parts[0].parse::<u16>(),
+            parts[1].parse::<u8>(),
+            parts[2].parse::<u8>(),
+        ) {
+            (Ok(major), Ok(minor), Ok(patch)) => Some(TbfHeaderV2AppVersion {
+                major: major,
+                minor: minor,
+                patch: patch,
+            }),
+            _ => None,
+        },
+        _ => None,
+    };
+    match version {
+        Some(version) => version,
+        None => panic!(
+            "Invalid app version {}, expected MAJOR.MINOR.PATCH with MINOR and PATCH below 256",
+            value
+        ),
     }
 }
 
@@ -122,6 +188,65 @@ fn main() {
     opts.optopt("o", "", "set output file name", "OUTFILE");
     opts.optopt("n", "", "set package name", "PACKAGE_NAME");
     opts.optflag("v", "verbose", "be verbose");
+    opts.optopt("", "stack", "override the stack size", "SIZE");
+    opts.optopt("", "app-heap", "override the app heap size", "SIZE");
+    opts.optopt("", "kernel-heap", "override the kernel heap size", "SIZE");
+    opts.optopt(
+        "",
+        "minimum-ram-size",
+        "override the total amount of RAM requested for the app",
+        "SIZE",
+    );
+    opts.optflag("s", "sizes", "print the sizes of the app to stderr");
+    opts.optopt("", "json", "write the sizes of the app as JSON", "JSONFILE");
+    opts.optflag(
+        "",
+        "inspect",
+        "print the headers of a TBF or flash image instead of creating one",
+    );
+    opts.optflag(
+        "",
+        "tab",
+        "create a Tock Application Bundle from TAG=ELF inputs, TAG being an \
+         architecture or board",
+    );
+    opts.optflag("", "list", "list the contents of a Tock Application Bundle");
+    opts.optopt(
+        "",
+        "extract",
+        "extract the TBF for TAG from a Tock Application Bundle",
+        "TAG",
+    );
+    opts.optopt(
+        "",
+        "app-version",
+        "set the app version, as MAJOR.MINOR.PATCH",
+        "VERSION",
+    );
+    opts.optopt(
+        "",
+        "min-kernel-abi",
+        "set the oldest kernel syscall ABI version the app works with",
+        "ABI",
+    );
+    opts.optopt(
+        "",
+        "max-kernel-abi",
+        "set the newest kernel syscall ABI version the app works with",
+        "ABI",
+    );
+    opts.optopt(
+        "",
+        "storage-size",
+        "request SIZE bytes of the nonvolatile storage shared with userspace",
+        "SIZE",
+    );
+    opts.optopt(
+        "",
+        "kernel-version",
+        "set the minimum kernel version the app needs",
+        "VERSION",
+    );
 
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -130,6 +255,37 @@ fn main() {
     let output = matches.opt_str("o");
     let package_name = matches.opt_str("n");
     let verbose = matches.opt_present("v");
+    let print_sizes = matches.opt_present("s");
+    let json = matches.opt_str("json");
+    let size = |option: &str| matches.opt_str(option).map(|v| parse_size(option, &v));
+    let overrides = SizeOverrides {
+        stack: size("stack"),
+        app_heap: size("app-heap"),
+        kernel_heap: size("kernel-heap"),
+        minimum_ram_size: size("minimum-ram-size"),
+    };
+    let minimum_abi = size("min-kernel-abi");
+    let maximum_abi = size("max-kernel-abi");
+    let info = AppInfo {
+        app_version: matches
+            .opt_str("app-version")
+            .map(|version| parse_app_version(&version)),
+        kernel_abi: if minimum_abi.is_some() || maximum_abi.is_some() {
+            Some(TbfHeaderV2KernelAbi {
+                minimum_abi_version: minimum_abi.unwrap_or(0),
+                maximum_abi_version: maximum_abi.unwrap_or(u32::max_value()),
+            })
+        } else {
+            None
+        },
+        nonvolatile_storage: size("storage-size")
+            .map(|size| TbfHeaderV2NonvolatileStorage { storage_size: size }),
+    };
+    if let Some(abi) = info.kernel_abi {
+        if abi.minimum_abi_version > abi.maximum_abi_version {
+            panic!("--min-kernel-abi is larger than --max-kernel-abi");
+        }
+    }
     let input = if !matches.free.is_empty() {
         matches.free[0].clone()
     } else {
@@ -138,28 +294,160 @@ fn main() {
     };
 
     let path = Path::new(&input);
+
+    if matches.opt_present("inspect") {
+        let image = read_file(&path);
+        let summary = inspect::inspect(&image, &mut io::stdout()).expect("Failed to write output");
+        if summary.errors > 0 || summary.warnings > 0 {
+            println!("{} error(s), {} warning(s)", summary.errors, summary.warnings);
+        }
+        if summary.errors > 0 {
+            process::exit(1);
+        }
+        return;
+    }
+
+    if matches.opt_present("list") || matches.opt_present("extract") {
+        let entries = tab::read_tab(&read_file(&path)).expect("Failed to read TAB");
+        match matches.opt_str("extract") {
+            None => {
+                for entry in entries.iter() {
+                    println!("{:>8} {}", entry.data.len(), entry.name);
+                }
+                entries
+                    .iter()
+                    .find(|entry| entry.name == tab::METADATA_FILE)
+                    .map(|metadata| print!("\n{}", String::from_utf8_lossy(&metadata.data)));
+            }
+            Some(tag) => {
+                let name = format!("{}.bin", tag);
+                let tbf = match entries.iter().find(|entry| entry.name == name) {
+                    Some(entry) => &entry.data,
+                    None => panic!("No TBF for {} in {}", tag, input),
+                };
+                match output {
+                    None => io::stdout().write_all(tbf),
+                    Some(name) => match File::create(Path::new(&name)) {
+                        Ok(mut f) => f.write_all(tbf),
+                        Err(e) => panic!("Error: {:?}", e),
+                    },
+                }.expect("Failed to write output");
+            }
+        }
+        return;
+    }
+
+    let package_name = package_name.unwrap_or(String::new());
+
+    if matches.opt_present("tab") {
+        let output = match output {
+            Some(name) => name,
+            None => panic!("--tab requires -o OUTFILE"),
+        };
+
+        let mut entries = Vec::new();
+        let mut metadata = tab::Metadata {
+            name: package_name.clone(),
+            app_version: matches.opt_str("app-version"),
+            minimum_kernel_version: matches.opt_str("kernel-version"),
+            tbfs: Vec::new(),
+        };
+        let mut json_sizes = Vec::new();
+
+        for input in matches.free.iter() {
+            let mut split = input.splitn(2, '=');
+            let (tag, elf_path) = match (split.next(), split.next()) {
+                (Some(tag), Some(elf_path)) => (tag, elf_path),
+                _ => panic!("TAB inputs must be given as TAG=ELF: {}", input),
+            };
+            let file = match elf::File::open_path(Path::new(elf_path)) {
+                Ok(f) => f,
+                Err(e) => panic!("Error: {:?}", e),
+            };
+
+            let mut tbf = Vec::new();
+            let sizes = do_work(&file, &mut tbf, &package_name, verbose, &overrides, &info)
+                .expect("Failed to create TBF");
+            if print_sizes {
+                eprint!("\n{}:{}", tag, sizes);
+            }
+            json_sizes.push(sizes.to_json(&package_name));
+
+            let name = format!("{}.bin", tag);
+            metadata.tbfs.push((tag.to_string(), name.clone(), tbf.len()));
+            entries.push(tab::TabEntry {
+                name: name,
+                data: tbf,
+            });
+        }
+
+        entries.push(tab::TabEntry {
+            name: tab::METADATA_FILE.to_string(),
+            data: metadata.to_toml().into_bytes(),
+        });
+
+        match File::create(Path::new(&output)) {
+            Ok(mut f) => tab::write_tab(&mut f, &entries),
+            Err(e) => panic!("Error: {:?}", e),
+        }.expect("Failed to write output");
+
+        if let Some(name) = json {
+            match File::create(Path::new(&name)) {
+                Ok(mut f) => write!(f, "[{}]\n", json_sizes.join(", ")),
+                Err(e) => panic!("Error: {:?}", e),
+            }.expect("Failed to write JSON output");
+        }
+        return;
+    }
+
     let file = match elf::File::open_path(&path) {
         Ok(f) => f,
         Err(e) => panic!("Error: {:?}", e),
     };
 
-    match output {
+    let sizes = match output {
         None => {
             let mut out = io::stdout();
-            do_work(&file, &mut out, package_name, verbose)
+            do_work(&file, &mut out, &package_name, verbose, &overrides, &info)
         }
         Some(name) => match File::create(Path::new(&name)) {
-            Ok(mut f) => do_work(&file, &mut f, package_name, verbose),
+            Ok(mut f) => do_work(&file, &mut f, &package_name, verbose, &overrides, &info),
             Err(e) => panic!("Error: {:?}", e),
         },
     }.expect("Failed to write output");
+
+    if print_sizes {
+        eprint!("{}", sizes);
+    }
+    if let Some(name) = json {
+        match File::create(Path::new(&name)) {
+            Ok(mut f) => write!(f, "{}\n", sizes.to_json(&package_name)),
+            Err(e) => panic!("Error: {:?}", e),
+        }.expect("Failed to write JSON output");
+    }
 }
 
 fn print_usage(program: &str, opts: Options) {
-    let brief = format!("Usage: {} [-o OUTFILE] FILE", program);
+    let brief = format!(
+        "Usage: {0} [-o OUTFILE] FILE\n       \
+         {0} --tab -o OUTFILE TAG=FILE [TAG=FILE ...]\n       \
+         {0} --list FILE\n       \
+         {0} --extract TAG [-o OUTFILE] FILE\n       \
+         {0} --inspect FILE",
+        program
+    );
     print!("{}", opts.usage(&brief));
 }
 
+fn read_file(path: &Path) -> Vec<u8> {
+    let mut contents = Vec::new();
+    match File::open(path) {
+        Ok(mut f) => f.read_to_end(&mut contents),
+        Err(e) => panic!("Error: {:?}", e),
+    }.expect("Failed to read input");
+    contents
+}
+
 fn get_section<'a>(input: &'a elf::File, name: &str) -> elf::Section {
     match input.get_section(name) {
         Some(section) => elf::Section {
@@ -184,17 +472,14 @@ fn get_section<'a>(input: &'a elf::File, name: &str) -> elf::Section {
     }
 }
 
-unsafe fn as_byte_slice<'a, T: Copy>(input: &'a T) -> &'a [u8] {
-    slice::from_raw_parts(input as *const T as *const u8, mem::size_of::<T>())
-}
-
 fn do_work(
     input: &elf::File,
     output: &mut Write,
-    package_name: Option<String>,
+    package_name: &str,
     verbose: bool,
-) -> io::Result<()> {
-    let package_name = package_name.unwrap_or(String::new());
+    overrides: &SizeOverrides,
+    info: &AppInfo,
+) -> io::Result<AppSizes> {
     let rel_data = input
         .sections
         .iter()
@@ -208,31 +493,75 @@ fn do_work(
     let appstate = get_section(input, ".app_state");
 
     // For these, we only care about the length
-    let stack_len = get_section(input, ".stack").data.len() as u32;
-    let app_heap_len = get_section(input, ".app_heap").data.len() as u32;
-    let kernel_heap_len = get_section(input, ".kernel_heap").data.len() as u32;
-
-    // Need to calculate lengths ahead of time.
-    // Need the base and the main section.
-    let mut header_length = mem::size_of::<TbfHeaderBase>() + mem::size_of::<TbfHeaderMain>();
-
-    // If we have a package name, add that section.
-    let mut post_name_pad = 0;
-    if package_name.len() > 0 {
-        let name_total_size = align4!(mem::size_of::<TbfHeaderTlv>() + package_name.len());
-        header_length += name_total_size;
-
-        // Calculate the padding required after the package name TLV. All blocks
-        // must be four byte aligned, so we may need to add some padding.
-        post_name_pad = name_total_size - (mem::size_of::<TbfHeaderTlv>() + package_name.len());
-    }
+    let stack_len = overrides
+        .stack
+        .unwrap_or(get_section(input, ".stack").data.len() as u32);
+    let app_heap_len = overrides
+        .app_heap
+        .unwrap_or(get_section(input, ".app_heap").data.len() as u32);
+    let kernel_heap_len = overrides
+        .kernel_heap
+        .unwrap_or(get_section(input, ".kernel_heap").data.len() as u32);
 
-    // We have one app flash region, add that.
-    if appstate.data.len() > 0 {
-        header_length +=
-            mem::size_of::<TbfHeaderTlv>() + mem::size_of::<TbfHeaderWriteableFlashRegion>();
+    // Flags default to app is enabled.
+    let flags = 0x00000001;
+
+    let tbf_header_version = 2;
+
+    // To start we just restrict the app from writing all of the space before
+    // its actual code and whatnot.
+    let protected_size = 0;
+
+    let init_fn_offset = (input.ehdr.entry - text.shdr.addr) as u32;
+    let got_size = got.shdr.size as u32;
+    let data_size = data.shdr.size as u32;
+    let bss_size = bss.shdr.size as u32;
+    let required_ram_size =
+        stack_len + app_heap_len + kernel_heap_len + got_size + data_size + bss_size;
+    let minimum_ram_size = overrides.minimum_ram_size.unwrap_or(required_ram_size);
+    if minimum_ram_size < required_ram_size {
+        eprintln!(
+            "Warning: minimum RAM size {} is smaller than the {} bytes the app's sections need",
+            minimum_ram_size, required_ram_size
+        );
     }
 
+    let tbf_main = TbfHeaderV2Main {
+        init_fn_offset: init_fn_offset,
+        protected_size: protected_size as u32,
+        minimum_ram_size: minimum_ram_size,
+    };
+
+    // The total size and the flash region depend on the length of the header,
+    // so placeholders are used until that is known.
+    let placeholder_region = [0u8; TbfHeaderV2WriteableFlashRegion::SIZE];
+    let tbf_header = TbfHeaderV2 {
+        base: TbfHeaderV2Base {
+            version: tbf_header_version,
+            header_size: 0,
+            total_size: 0,
+            flags: flags,
+            checksum: 0,
+        },
+        main: Some(tbf_main),
+        package_name: if package_name.len() > 0 {
+            Some(&package_name[..])
+        } else {
+            None
+        },
+        // Only put these in the header if the app_state section is nonzero.
+        writeable_regions: if appstate.data.len() > 0 {
+            WriteableFlashRegions::new(&placeholder_region)
+        } else {
+            None
+        },
+        pic_option1: None,
+        app_version: info.app_version,
+        kernel_abi: info.kernel_abi,
+        nonvolatile_storage: info.nonvolatile_storage,
+    };
+    let header_length = tbf_header.serialized_len().expect("Header too large");
+
     // Calculate the offset between the start of the flash region and the actual
     // app code. Also need to get the padding size.
     let app_start_offset = align4!(header_length);
@@ -251,10 +580,6 @@ fn do_work(
     };
     total_size += ending_pad;
 
-    // To start we just restrict the app from writing all of the space before
-    // its actual code and whatnot.
-    let protected_size = 0;
-
     // First up is the app writeable app_state section. If this is not used or
     // non-existent, it will just be zero and won't matter. But we put it first
     // so that changes to the app won't move it.
@@ -263,105 +588,47 @@ fn do_work(
     // Make sure we pad back to a multiple of 4.
     let post_appstate_pad =
         align4!(appstate_offset + appstate_size) - (appstate_offset + appstate_size);
-    let init_fn_offset = (input.ehdr.entry - text.shdr.addr) as u32;
-    let got_size = got.shdr.size as u32;
-    let data_size = data.shdr.size as u32;
-    let bss_size = bss.shdr.size as u32;
-    let minimum_ram_size =
-        stack_len + app_heap_len + kernel_heap_len + got_size + data_size + bss_size;
-
-    // Flags default to app is enabled.
-    let flags = 0x00000001;
-
-    let tbf_header_version = 2;
 
-    let tbf_header = TbfHeaderBase {
-        version: tbf_header_version,
-        header_size: header_length as u16,
-        total_size: total_size,
-        flags: flags,
-        checksum: 0,
+    let tbf_flash_region = TbfHeaderV2WriteableFlashRegion {
+        writeable_flash_region_offset: appstate_offset,
+        writeable_flash_region_size: appstate_size,
     };
-
-    let tbf_main = TbfHeaderMain {
-        base: TbfHeaderTlv {
-            tipe: TbfHeaderTypes::TbfHeaderMain,
-            length: (mem::size_of::<TbfHeaderMain>() - mem::size_of::<TbfHeaderTlv>()) as u16,
+    let mut tbf_flash_region_buf = [0u8; TbfHeaderV2WriteableFlashRegion::SIZE];
+    tbf_flash_region
+        .serialize(&mut tbf_flash_region_buf)
+        .expect("Header buffer too small");
+
+    let tbf_header = TbfHeaderV2 {
+        base: TbfHeaderV2Base {
+            total_size: total_size,
+            ..tbf_header.base
         },
-        init_fn_offset: init_fn_offset,
-        protected_size: protected_size as u32,
-        minimum_ram_size: minimum_ram_size,
-    };
-
-    let tbf_package_name_tlv = TbfHeaderTlv {
-        tipe: TbfHeaderTypes::TbfHeaderPackageName,
-        length: package_name.len() as u16,
-    };
-
-    let tbf_flash_regions_tlv = TbfHeaderTlv {
-        tipe: TbfHeaderTypes::TbfHeaderWriteableFlashRegions,
-        length: 8,
-    };
-
-    let tbf_flash_region = TbfHeaderWriteableFlashRegion {
-        offset: appstate_offset,
-        size: appstate_size,
+        writeable_regions: tbf_header
+            .writeable_regions
+            .and(WriteableFlashRegions::new(&tbf_flash_region_buf)),
+        ..tbf_header
     };
 
     if verbose {
-        print!("{}", tbf_header);
+        print!("{}", tbf_header.base);
         print!("{}", tbf_main);
         print!("{}", tbf_flash_region);
-    }
-
-    // Calculate the header checksum.
-    let mut header_buf = Cursor::new(Vec::new());
-
-    // Write all bytes to an in-memory file for the header.
-    try!(header_buf.write_all(unsafe { as_byte_slice(&tbf_header) }));
-    try!(header_buf.write_all(unsafe { as_byte_slice(&tbf_main) }));
-    try!(header_buf.write_all(unsafe { as_byte_slice(&tbf_package_name_tlv) }));
-    try!(header_buf.write_all(package_name.as_ref()));
-    try!(do_pad(&mut header_buf, post_name_pad));
-
-    // Only put these in the header if the app_state section is nonzero.
-    if appstate.data.len() > 0 {
-        try!(header_buf.write_all(unsafe { as_byte_slice(&tbf_flash_regions_tlv) }));
-        try!(header_buf.write_all(unsafe { as_byte_slice(&tbf_flash_region) }));
-    }
-
-    // Start from the beginning and iterate through the buffer as words.
-    try!(header_buf.seek(SeekFrom::Start(0)));
-    let mut wordbuf = [0u8; 4];
-    let mut checksum: u32 = 0;
-    loop {
-        let ret = header_buf.read(&mut wordbuf);
-        match ret {
-            Ok(count) => {
-                // Combine the bytes back into a word, handling if we don't
-                // get a full word.
-                let mut word = 0;
-                for i in 0..count {
-                    word |= (wordbuf[i] as u32) << (8 * i);
-                }
-                checksum ^= word;
-                if count != 4 {
-                    break;
-                }
-            }
-            Err(_) => println!("Error calculating checksum."),
+        if let Some(version) = info.app_version {
+            print!("\n           app_version: {}\n", version);
+        }
+        if let Some(abi) = info.kernel_abi {
+            print!("{}", abi);
+        }
+        if let Some(storage) = info.nonvolatile_storage {
+            print!("{}", storage);
         }
     }
 
-    // Now we need to insert the checksum into the correct position in the
-    // header.
-    try!(header_buf.seek(SeekFrom::Start(12)));
-    wordbuf[0] = ((checksum >> 0) & 0xFF) as u8;
-    wordbuf[1] = ((checksum >> 8) & 0xFF) as u8;
-    wordbuf[2] = ((checksum >> 16) & 0xFF) as u8;
-    wordbuf[3] = ((checksum >> 24) & 0xFF) as u8;
-    try!(header_buf.write(&wordbuf));
-    try!(header_buf.seek(SeekFrom::Start(0)));
+    // Serialize the header, which also computes the header size and checksum.
+    let mut header_buf = vec![0u8; header_length];
+    tbf_header
+        .serialize(&mut header_buf)
+        .expect("Header buffer too small");
 
     fn do_pad(output: &mut Write, length: usize) -> io::Result<()> {
         let mut pad = length;
@@ -374,7 +641,7 @@ fn do_work(
     }
 
     // Write the header and actual app to a binary file.
-    try!(output.write_all(header_buf.get_ref()));
+    try!(output.write_all(&header_buf));
     try!(do_pad(output, post_header_pad as usize));
     try!(output.write_all(appstate.data.as_ref()));
     try!(do_pad(output, post_appstate_pad as usize));
@@ -393,5 +660,17 @@ fn do_work(
     // Pad to get a power of 2 sized flash app.
     try!(do_pad(output, ending_pad as usize));
 
-    Ok(())
+    Ok(AppSizes {
+        header: header_length as u32,
+        text: text.data.len() as u32,
+        got: got_size,
+        data: data_size,
+        bss: bss_size,
+        relocation: rel_data.len() as u32,
+        stack: stack_len,
+        app_heap: app_heap_len,
+        kernel_heap: kernel_heap_len,
+        minimum_ram_size: minimum_ram_size,
+        total_size: total_size,
+    })
 }
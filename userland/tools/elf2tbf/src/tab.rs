@@ -0,0 +1,318 @@
+//! Tock Application Bundles.
+//!
+//! A TAB is a `tar` archive with one TBF per architecture or board, named
+//! `<tag>.bin`, and a `metadata.toml` manifest describing the app. Deployment
+//! tools read the manifest and pick the TBF matching their target, so an app
+//! only needs to be distributed as a single file.
+
+use std::io;
+use std::io::Write;
+use std::str;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Version of the TAB format written by this tool.
+const TAB_VERSION: u32 = 1;
+
+/// Name of the manifest inside the archive.
+pub const METADATA_FILE: &'static str = "metadata.toml";
+
+/// Size of tar headers and of the blocks file contents are padded to.
+const BLOCK_SIZE: usize = 512;
+
+/// A file in a TAB.
+pub struct TabEntry {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// Information about an app that goes into the manifest.
+pub struct Metadata {
+    pub name: String,
+    pub app_version: Option<String>,
+    pub minimum_kernel_version: Option<String>,
+    /// The tag, file name and total size of each TBF in the bundle.
+    pub tbfs: Vec<(String, String, usize)>,
+}
+
+/// Quote a string for the manifest.
+fn toml_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04X}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// The current time as an RFC 3339 timestamp in UTC.
+fn build_date() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // Convert days since the epoch to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days as i64 + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
+
+impl Metadata {
+    pub fn to_toml(&self) -> String {
+        let mut toml = String::new();
+        toml.push_str(&format!("tab-version = {}\n", TAB_VERSION));
+        toml.push_str(&format!("name = {}\n", toml_string(&self.name)));
+        if let Some(ref version) = self.app_version {
+            toml.push_str(&format!("app-version = {}\n", toml_string(version)));
+        }
+        if let Some(ref version) = self.minimum_kernel_version {
+            toml.push_str(&format!(
+                "minimum-tock-kernel-version = {}\n",
+                toml_string(version)
+            ));
+        }
+        toml.push_str("only-for-boards = \"\"\n");
+        toml.push_str(&format!("build-date = {}\n", build_date()));
+
+        for &(ref tag, ref file, size) in self.tbfs.iter() {
+            toml.push_str(&format!("\n[tbf.{}]\n", toml_string(tag)));
+            toml.push_str(&format!("file = {}\n", toml_string(file)));
+            toml.push_str(&format!("size = {}\n", size));
+        }
+        toml
+    }
+}
+
+/// Write `value` as a NUL terminated octal number filling `field`.
+fn write_octal(field: &mut [u8], value: u64) {
+    let len = field.len() - 1;
+    let digits = format!("{:0width$o}", value, width = len);
+    field[..len].copy_from_slice(digits.as_bytes());
+    field[len] = 0;
+}
+
+fn read_octal(field: &[u8]) -> Option<u64> {
+    let digits: Vec<u8> = field
+        .iter()
+        .cloned()
+        .take_while(|b| *b != 0)
+        .filter(|b| *b != b' ')
+        .collect();
+    str::from_utf8(&digits)
+        .ok()
+        .and_then(|digits| u64::from_str_radix(digits, 8).ok())
+}
+
+fn tar_header(name: &str, size: usize, mtime: u64) -> io::Result<[u8; BLOCK_SIZE]> {
+    let mut header = [0u8; BLOCK_SIZE];
+    if name.len() >= 100 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("File name too long for TAB: {}", name),
+        ));
+    }
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut header[100..108], 0o644);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], size as u64);
+    write_octal(&mut header[136..148], mtime);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is calculated with the checksum field set to spaces.
+    for b in header[148..156].iter_mut() {
+        *b = b' ';
+    }
+    let checksum: u32 = header.iter().map(|b| *b as u32).sum();
+    write_octal(&mut header[148..155], checksum as u64);
+    Ok(header)
+}
+
+/// Write a TAB containing `entries`.
+pub fn write_tab(output: &mut Write, entries: &[TabEntry]) -> io::Result<()> {
+    let mtime = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let padding = [0u8; BLOCK_SIZE];
+
+    for entry in entries.iter() {
+        try!(output.write_all(&try!(tar_header(&entry.name, entry.data.len(), mtime))));
+        try!(output.write_all(&entry.data));
+        let remainder = entry.data.len() % BLOCK_SIZE;
+        if remainder != 0 {
+            try!(output.write_all(&padding[remainder..]));
+        }
+    }
+
+    // A tar archive ends with two empty blocks.
+    try!(output.write_all(&padding));
+    output.write_all(&padding)
+}
+
+/// Read all regular files from a TAB.
+pub fn read_tab(tab: &[u8]) -> io::Result<Vec<TabEntry>> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+    let mut entries = Vec::new();
+    let mut offset = 0;
+
+    while offset + BLOCK_SIZE <= tab.len() {
+        let header = &tab[offset..offset + BLOCK_SIZE];
+        if header.iter().all(|b| *b == 0) {
+            break;
+        }
+
+        let name_len = header[..100].iter().position(|b| *b == 0).unwrap_or(100);
+        let name = try!(
+            str::from_utf8(&header[..name_len]).map_err(|_| invalid("Invalid file name in TAB"))
+        );
+        let size = try!(read_octal(&header[124..136]).ok_or(invalid("Invalid file size in TAB")));
+        let size = size as usize;
+
+        let start = offset + BLOCK_SIZE;
+        if start + size > tab.len() {
+            return Err(invalid("TAB is truncated"));
+        }
+
+        // Only regular files are of interest.
+        if header[156] == b'0' || header[156] == 0 {
+            entries.push(TabEntry {
+                name: name.to_string(),
+                data: tab[start..start + size].to_vec(),
+            });
+        }
+
+        offset = start + (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<TabEntry> {
+        vec![
+            TabEntry {
+                name: "cortex-m4.bin".to_string(),
+                data: vec![0x42; BLOCK_SIZE],
+            },
+            TabEntry {
+                name: METADATA_FILE.to_string(),
+                data: vec![b'x'; 700],
+            },
+        ]
+    }
+
+    fn write(entries: &[TabEntry]) -> Vec<u8> {
+        let mut tab = Vec::new();
+        write_tab(&mut tab, entries).unwrap();
+        tab
+    }
+
+    #[test]
+    fn round_trip() {
+        let tab = write(&entries());
+        let read = read_tab(&tab).unwrap();
+        assert_eq!(read.len(), 2);
+        for (read, written) in read.iter().zip(entries().iter()) {
+            assert_eq!(read.name, written.name);
+            assert_eq!(read.data, written.data);
+        }
+    }
+
+    #[test]
+    fn files_are_padded_to_blocks() {
+        let tab = write(&entries());
+
+        // A header and a block for the first file, a header and two blocks for
+        // the second, and two empty blocks to end the archive.
+        assert_eq!(tab.len(), 7 * BLOCK_SIZE);
+        let second = 2 * BLOCK_SIZE;
+        assert!(tab[second + BLOCK_SIZE + 700..second + 3 * BLOCK_SIZE]
+            .iter()
+            .all(|b| *b == 0));
+        assert!(tab[5 * BLOCK_SIZE..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn header_checksums() {
+        let tab = write(&entries());
+        for &(offset, size) in &[(0, BLOCK_SIZE as u64), (2 * BLOCK_SIZE, 700)] {
+            let mut header = tab[offset..offset + BLOCK_SIZE].to_vec();
+            let stored = read_octal(&header[148..156]).unwrap();
+            for b in header[148..156].iter_mut() {
+                *b = b' ';
+            }
+            let computed: u64 = header.iter().map(|b| *b as u64).sum();
+            assert_eq!(stored, computed);
+            assert_eq!(&header[257..263], b"ustar\0");
+            assert_eq!(read_octal(&header[124..136]), Some(size));
+        }
+    }
+
+    #[test]
+    fn long_names_are_rejected() {
+        let entry = TabEntry {
+            name: "a".repeat(100),
+            data: vec![],
+        };
+        let mut tab = Vec::new();
+        assert!(write_tab(&mut tab, &[entry]).is_err());
+    }
+
+    #[test]
+    fn truncated_tab_is_an_error() {
+        let tab = write(&entries());
+        // The second file ends in the middle of its data
+        assert!(read_tab(&tab[..3 * BLOCK_SIZE + 100]).is_err());
+    }
+
+    #[test]
+    fn metadata_values_are_escaped() {
+        assert_eq!(toml_string("blink"), "\"blink\"");
+        assert_eq!(
+            toml_string("a \"b\" \\ c\n\t"),
+            "\"a \\\"b\\\" \\\\ c\\u000A\\u0009\""
+        );
+
+        let metadata = Metadata {
+            name: "say \"hi\"".to_string(),
+            app_version: Some("1.0\\beta".to_string()),
+            minimum_kernel_version: None,
+            tbfs: vec![("cortex-m4".to_string(), "cortex-m4.bin".to_string(), 1024)],
+        };
+        let toml = metadata.to_toml();
+        assert!(toml.starts_with("tab-version = 1\n"));
+        assert!(toml.contains("\nname = \"say \\\"hi\\\"\"\n"));
+        assert!(toml.contains("\napp-version = \"1.0\\\\beta\"\n"));
+        assert!(!toml.contains("minimum-tock-kernel-version"));
+        assert!(toml.contains("\n[tbf.\"cortex-m4\"]\nfile = \"cortex-m4.bin\"\nsize = 1024\n"));
+    }
+}